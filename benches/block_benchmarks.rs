@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use sloppycomp::block_compress::BlockCompression;
+use sloppycomp::compression::Algorithm;
+
+fn block_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block file reads");
+    group.sample_size(10);
+
+    let mut input_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    input_file.push("benches/test-files/sloppy-compressor-bench-plaintext");
+    group.throughput(Throughput::Bytes(
+        input_file.metadata().expect("Error on file read").len(),
+    ));
+
+    // a read per 128 byte block, as before the input was buffered
+    group.bench_function("block compress file read per block", |b| {
+        let compressor = BlockCompression::new().with_read_buffer_size(128);
+
+        b.iter(|| {
+            let mut file = File::open(&input_file).unwrap();
+            compressor.compress(&mut file, &mut io::sink()).unwrap()
+        })
+    });
+    group.bench_function("block compress file 64 KiB reads", |b| {
+        let compressor = BlockCompression::new();
+
+        b.iter(|| {
+            let mut file = File::open(&input_file).unwrap();
+            compressor.compress(&mut file, &mut io::sink()).unwrap()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, block_benchmarks);
+criterion_main!(benches);
@@ -2,10 +2,9 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::PathBuf;
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 
-use sloppycomp::compression::Algorithm;
-use sloppycomp::lz77;
+use sloppycomp::lz77::{self, nodes::NodeType};
 
 fn lz77_benchmarks(c: &mut Criterion) {
     let mut group = c.benchmark_group("lz77");
@@ -20,34 +19,234 @@ fn lz77_benchmarks(c: &mut Criterion) {
         file.read_to_end(&mut file_bytes)
             .expect("Error on file read");
 
-        let compressor = lz77::Lz77Compression {};
+        let compressor = lz77::Lz77Compression::new();
 
-        b.iter(|| {
-            compressor
-                .compress_bytes(&file_bytes, "/tmp/sloppy-compressor-compress-output")
-                .unwrap();
-        })
+        b.iter(|| compressor.encode(&file_bytes))
     });
 
     group.bench_function("lz77 decompress", |b| {
         let mut input_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        input_file.push("benches/test-files/sloppy-compressor-bench-compressed");
+        input_file.push("benches/test-files/sloppy-compressor-bench-plaintext");
         let mut file = File::open(input_file).unwrap();
 
         let mut file_bytes = Vec::new();
         file.read_to_end(&mut file_bytes)
             .expect("Error on file read");
 
-        let compressor = lz77::Lz77Compression {};
+        let compressor = lz77::Lz77Compression::new();
 
-        b.iter(|| {
-            compressor
-                .decompress_bytes(&file_bytes, "/tmp/sloppy-compressor-decompress-output")
-                .unwrap();
-        })
+        // compress up front so the fixture always matches the current stream format
+        let compressed_bytes = compressor.encode(&file_bytes);
+
+        b.iter(|| compressor.decode(&compressed_bytes).unwrap())
+    });
+
+    group.finish();
+
+    // the linear matcher is far too slow for the whole fixture
+    let mut group = c.benchmark_group("lz77 matchers");
+    group.sample_size(10);
+
+    let mut input_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    input_file.push("benches/test-files/sloppy-compressor-bench-plaintext");
+    let mut plaintext = Vec::new();
+    File::open(input_file)
+        .unwrap()
+        .take(64 * 1024)
+        .read_to_end(&mut plaintext)
+        .expect("Error on file read");
+    group.throughput(Throughput::Bytes(plaintext.len() as u64));
+    group.bench_function("lz77 compress prefix index", |b| {
+        let compressor = lz77::Lz77Compression::new();
+
+        b.iter(|| compressor.encode(&plaintext))
+    });
+    group.bench_function("lz77 compress fastest level", |b| {
+        let compressor = lz77::Lz77Compression::new().with_level(lz77::Level::Fastest);
+
+        b.iter(|| compressor.encode(&plaintext))
+    });
+    group.bench_function("lz77 compress linear", |b| {
+        let compressor = lz77::Lz77Compression::new().with_linear_matcher();
+
+        b.iter(|| compressor.encode(&plaintext))
     });
 
     group.finish();
+
+    let mut group = c.benchmark_group("lz77 incompressible");
+    group.sample_size(10);
+
+    let random_bytes = pseudo_random_bytes(1024 * 1024);
+    group.throughput(Throughput::Bytes(random_bytes.len() as u64));
+    group.bench_function("lz77 compress random", |b| {
+        let compressor = lz77::Lz77Compression::new();
+
+        b.iter(|| compressor.encode(&random_bytes))
+    });
+
+    group.finish();
+
+    let mut group = c.benchmark_group("lz77 reference heavy");
+    group.sample_size(10);
+
+    // short repeats of a few phrases decode almost entirely from references
+    let phrases: [&[u8]; 4] = [b"GET /index.html ", b"200 ", b"GET /style.css ", b"304 "];
+    let repetitive: Vec<u8> = phrases
+        .iter()
+        .cycle()
+        .take(64 * 1024)
+        .copied()
+        .flatten()
+        .copied()
+        .collect();
+    group.throughput(Throughput::Bytes(repetitive.len() as u64));
+    group.bench_function("lz77 decompress repetitive", |b| {
+        let compressor = lz77::Lz77Compression::new();
+        let compressed_bytes = compressor.encode(&repetitive);
+
+        b.iter(|| compressor.decode(&compressed_bytes).unwrap())
+    });
+
+    // every position has many long candidates, so stopping at a good enough one pays off
+    group.bench_function("lz77 compress repetitive", |b| {
+        let compressor = lz77::Lz77Compression::new();
+
+        b.iter(|| compressor.encode(&repetitive))
+    });
+    group.bench_function("lz77 compress repetitive nice length 32", |b| {
+        let compressor = lz77::Lz77Compression::new().with_nice_match_length(32);
+
+        b.iter(|| compressor.encode(&repetitive))
+    });
+
+    // every record starts with the same few bytes, so each position has hundreds of candidates
+    // but none of them go much further
+    let common_prefixes: Vec<u8> = pseudo_random_bytes(48 * 1024)
+        .chunks(3)
+        .flat_map(|noise| [&b"id="[..], noise].concat())
+        .collect();
+    group.throughput(Throughput::Bytes(common_prefixes.len() as u64));
+    group.bench_function("lz77 compress common prefixes", |b| {
+        let compressor = lz77::Lz77Compression::new();
+
+        b.iter(|| compressor.encode(&common_prefixes))
+    });
+    group.bench_function("lz77 compress common prefixes max candidates 8", |b| {
+        let compressor = lz77::Lz77Compression::new().with_max_candidates(8);
+
+        b.iter(|| compressor.encode(&common_prefixes))
+    });
+
+    group.finish();
+
+    let mut group = c.benchmark_group("lz77 node decoding");
+    group.sample_size(10);
+
+    let large_repetitive = repetitive.repeat(16);
+    let compressor = lz77::Lz77Compression::new();
+    let nodes: Vec<NodeType> = compressor.nodes(&large_repetitive).collect();
+    group.throughput(Throughput::Bytes(large_repetitive.len() as u64));
+    group.bench_function("lz77 decode nodes preallocated", |b| {
+        b.iter(|| compressor.decode_nodes_to_vec(&nodes).unwrap())
+    });
+    group.bench_function("lz77 decode nodes growing", |b| {
+        b.iter(|| decode_nodes_growing(&nodes))
+    });
+
+    group.finish();
+
+    // a megabyte of a repeating pattern, built as nodes by hand: references can't overlap the
+    // bytes they copy, so a period of 1 or 2 needs a chain of doubling references, which a
+    // run node or an overlapping copy would replace
+    let mut group = c.benchmark_group("lz77 short period references");
+    group.sample_size(10);
+
+    let len = 1 << 20;
+    group.throughput(Throughput::Bytes(len as u64));
+    for pattern in [&b"a"[..], b"ab"] {
+        let nodes = periodic_nodes(pattern, len);
+        assert_eq!(
+            pattern.repeat(len / pattern.len()),
+            compressor.decode_nodes_to_vec(&nodes).unwrap()
+        );
+        group.bench_function(
+            format!("lz77 decode offset {} references", pattern.len()),
+            |b| b.iter(|| compressor.decode_nodes_to_vec(&nodes).unwrap()),
+        );
+        group.bench_function(
+            format!("lz77 copy offset {} byte by byte", pattern.len()),
+            |b| b.iter(|| copy_overlapping(pattern, len)),
+        );
+    }
+    let run = [NodeType::run(b'a', len as u32)];
+    group.bench_function("lz77 decode run", |b| {
+        b.iter(|| compressor.decode_nodes_to_vec(&run).unwrap())
+    });
+
+    group.finish();
+}
+
+/// Nodes decoding to `len` bytes of `pattern` over and over: the pattern as literals until
+/// there are 2 bytes to copy, then references as long as the window and max match length
+/// allow to the nearest copy.
+fn periodic_nodes(pattern: &[u8], len: usize) -> Vec<NodeType> {
+    let mut nodes = Vec::new();
+    let mut written = 0;
+    while written < len {
+        let reach = written.min(lz77::WindowSize::DEFAULT.get().into());
+        let offset = reach - reach % pattern.len();
+        let length = offset.min(len - written).min(lz77::MAX_MATCH_LENGTH.into());
+        if length < 2 {
+            nodes.push(NodeType::literal(pattern[written % pattern.len()]));
+            written += 1;
+        } else {
+            nodes.push(NodeType::reference(offset as u16, length as u16));
+            written += length;
+        }
+    }
+    nodes
+}
+
+/// `pattern` followed by a single copy of `len - pattern.len()` bytes from `pattern.len()`
+/// back, made a byte at a time as a decoder allowing overlapping references would have to.
+fn copy_overlapping(pattern: &[u8], len: usize) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(len);
+    decoded.extend_from_slice(pattern);
+    for i in pattern.len()..len {
+        decoded.push(decoded[i - pattern.len()]);
+    }
+    decoded
+}
+
+/// `decode_nodes_to_vec` without sizing the output from the nodes first, as the baseline for
+/// its single allocation.
+fn decode_nodes_growing(nodes: &[NodeType]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    for node in nodes {
+        match *node {
+            NodeType::ByteLiteral { lit } => decoded.push(lit),
+            NodeType::Reference { offset, length } => {
+                let start = decoded.len() - usize::from(offset);
+                decoded.extend_from_within(start..start + usize::from(length));
+            }
+            NodeType::Run { byte, count } => {
+                decoded.resize(decoded.len() + count as usize, byte);
+            }
+        }
+    }
+    decoded
+}
+
+/// Deterministic noise so runs are comparable between baselines.
+fn pseudo_random_bytes(len: usize) -> Vec<u8> {
+    let mut seed: u32 = 1;
+    (0..len)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
 }
 
 criterion_group!(benches, lz77_benchmarks);
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use sloppycomp::compression::Algorithm;
+use sloppycomp::lz77::Lz77Compression;
+
+// Arbitrary bytes must only ever produce an error, never a panic.
+fuzz_target!(|data: &[u8]| {
+    // a run node can claim gigabytes in a few bytes
+    let compressor = Lz77Compression::new().with_max_output_bytes(1 << 24);
+    let _ = compressor.decode(data);
+    let _ = compressor.decompress_to_vec(data);
+    let _ = compressor.decode_into(data, &mut [0; 4096]);
+});
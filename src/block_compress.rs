@@ -1,72 +1,1592 @@
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::Entry;
-use std::fs::File;
-use std::io;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::io::prelude::*;
-use std::{collections::HashMap, io::BufReader};
+use std::io::{self, BufReader, BufWriter};
 
-use super::compression;
+use super::compression::{self, CompressionStats, CountingWriter};
+use crate::error::{DeserialiseError, Error, Result};
+use crate::frame::{self, Format};
 
 const BLOCK_SIZE: usize = 128;
+/// Bytes read from the input at a time when compressing, see
+/// `BlockCompression::with_read_buffer_size`.
+const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Everything after the frame header: a variant tag byte, then the variant. The blocks are
+/// written by hand, see `Compressed::write`, and the rest with bincode's varint encoding.
+#[derive(Debug)]
+enum Body {
+    Blocks(Compressed),
+    /// The input as-is, written when the blocks would take more space than it, or when
+    /// streaming an input smaller than a block.
+    Stored(Stored),
+    /// Followed by a `Record` per block of the input and then `Record::End`, see
+    /// `BlockCompression::with_streaming`.
+    Streamed(Streamed),
+}
+
+impl Body {
+    fn hasher(&self) -> &str {
+        match self {
+            Body::Blocks(compressed) => &compressed.hasher,
+            Body::Stored(stored) => &stored.hasher,
+            Body::Streamed(streamed) => &streamed.hasher,
+        }
+    }
+
+    /// Serialises the body, returning the number of bytes written.
+    fn write(&self, output: &mut dyn Write) -> Result<usize> {
+        // the same tags bincode gave the variants when it serialised the whole body
+        let size = match self {
+            Body::Blocks(compressed) => {
+                output.write_all(&[0])?;
+                compressed.write(output)?
+            }
+            Body::Stored(stored) => {
+                output.write_all(&[1])?;
+                compression::write_compressed(stored, output)?
+            }
+            Body::Streamed(streamed) => {
+                output.write_all(&[2])?;
+                compression::write_compressed(streamed, output)?
+            }
+        };
+        Ok(1 + size)
+    }
+
+    fn read(input: &mut dyn Read) -> Result<Body> {
+        match read_byte(input)? {
+            0 => Ok(Body::Blocks(Compressed::read(input)?)),
+            1 => Ok(Body::Stored(read_serialised(input)?)),
+            2 => Ok(Body::Streamed(read_serialised(input)?)),
+            tag => Err(DeserialiseError::UnknownMode(tag).into()),
+        }
+    }
+}
+
+/// Serialised by `write` rather than bincode:
+///
+/// ```text
+///   hasher name length varint | hasher name
+///   unique block count varint | per block: BLOCK_SIZE - length varint
+///   block data length varint | block data
+///   0 | 1 followed by the md5 of each unique block
+///   block map length varint | per entry: zig-zag varint of index - (previous index + 1)
+///   crc, 4 bytes little-endian
+/// ```
+///
+/// Every block but a trailing partial one is full, and a new block is numbered one more than
+/// the one before it, so block lengths and most of the block map take a single byte each
+/// however many unique blocks there are.
+#[derive(Debug)]
+#[cfg_attr(test, derive(Serialize))]
 struct Compressed {
+    /// `BlockHasher::name` of the hasher that found the duplicate blocks. Decompression
+    /// doesn't re-hash, so this is only for diagnostics.
+    hasher: String,
+    /// Length of each unique block. Blocks are packed back to back in `block_data`, so a
+    /// block's offset is the sum of the lengths before it.
+    ///
+    /// Unique blocks are numbered in the order they first appear in the input. That's part of
+    /// the format rather than an accident of the implementation: the same input and settings
+    /// always compress to the same bytes, so compressed files can be diffed and builds that
+    /// produce them are reproducible.
+    block_lengths: Vec<u32>,
+    block_data: Vec<u8>,
+    /// Index of the unique block for each block of the input, in order.
     block_map: Vec<u32>,
-    blocks: Vec<Vec<u8>>,
+    /// CRC-32 of the whole input, checked before any output is written.
+    crc: u32,
+    /// md5 of each unique block, if `BlockCompression::with_block_hashes` was set, checked
+    /// before the CRC so corruption can be traced to a block.
+    block_hashes: Option<Vec<[u8; 16]>>,
 }
 
-pub struct BlockCompression {}
+#[derive(Serialize, Deserialize, Debug)]
+struct Stored {
+    /// The hasher that was tried, for `BlockCompression::hasher_name`.
+    hasher: String,
+    data: Vec<u8>,
+    crc: u32,
+}
 
-/// Compresses a file by looking for matching block patterns.block_compress
-///
-/// Inspired by the rsync algo - this algorithm reads in a file block by block, taking a
-/// checksum of the block. When we take a block's checksum we check it against all other previously
-/// seen checksums. If we find a hit then we store a reference to the previous block, rather than storing
-/// the raw data again.
+#[derive(Serialize, Deserialize, Debug)]
+struct Streamed {
+    hasher: String,
+}
+
+/// The blocks of a streamed body, each serialised on its own as it's found.
 ///
-/// This is a poor compression method - there is a good chance that it makes your file larger
-/// due to the overheads of the data structure on disk.
-impl compression::Algorithm for BlockCompression {
-    fn compress(&self, mut file: File, output_file_path: &str) -> io::Result<()> {
+/// Unique blocks are kept in numbered slots for later references. A slot is reused once the
+/// block in it has been forgotten, so decompressing needs no more slots than the compressor
+/// retained blocks.
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    /// A block that isn't retained, to be written out and kept in `slot`, replacing whatever
+    /// was there.
+    Block {
+        slot: u32,
+        data: Vec<u8>,
+        /// md5 of `data`, if `BlockCompression::with_block_hashes` was set.
+        hash: Option<[u8; 16]>,
+    },
+    /// A repeat of the block in the slot.
+    Reference(u32),
+    /// The end of the input, with the CRC-32 of all of it.
+    End { crc: u32 },
+}
+
+/// Hashes blocks to find the duplicates. Blocks with the same hash are compared byte for byte
+/// before one is stored as a reference to the other, so a collision only costs a missed
+/// duplicate.
+pub trait BlockHasher {
+    /// Recorded in the compressed output, see `BlockCompression::hasher_name`.
+    fn name(&self) -> &'static str;
+
+    fn hash(&self, block: &[u8]) -> Vec<u8>;
+}
+
+/// The default, and the only hasher before the hasher was configurable.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Md5Hasher;
+
+impl BlockHasher for Md5Hasher {
+    fn name(&self) -> &'static str {
+        "md5"
+    }
+
+    fn hash(&self, block: &[u8]) -> Vec<u8> {
+        md5::compute(block).0.to_vec()
+    }
+}
+
+/// 64 bit FNV-1a. Much faster than md5 but more likely to collide.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fnv1aHasher;
+
+impl BlockHasher for Fnv1aHasher {
+    fn name(&self) -> &'static str {
+        "fnv1a"
+    }
+
+    fn hash(&self, block: &[u8]) -> Vec<u8> {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in block {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash.to_be_bytes().to_vec()
+    }
+}
+
+#[derive(Default)]
+pub struct BlockCompression<H = Md5Hasher> {
+    max_retained_blocks: Option<usize>,
+    block_hashes: bool,
+    streaming: bool,
+    read_buffer_size: Option<usize>,
+    hasher: H,
+}
+
+impl BlockCompression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the name of the hasher that found the duplicate blocks in a compressed stream.
+    pub fn hasher_name(input: &mut dyn Read) -> Result<String> {
+        Ok(read_body(input)?.hasher().to_string())
+    }
+}
+
+impl<H> BlockCompression<H> {
+    /// Finds duplicate blocks with `hasher` rather than md5. The output decompresses the same
+    /// way whichever hasher made it.
+    pub fn with_hasher<H2>(self, hasher: H2) -> BlockCompression<H2> {
+        BlockCompression {
+            max_retained_blocks: self.max_retained_blocks,
+            block_hashes: self.block_hashes,
+            streaming: self.streaming,
+            read_buffer_size: self.read_buffer_size,
+            hasher,
+        }
+    }
+
+    /// Only remembers the `max_retained_blocks` most recently seen unique blocks as candidates
+    /// for deduplication, rather than every block in the input.
+    ///
+    /// Bounds the memory the block index takes on large inputs with few repeats, at the cost
+    /// of storing a block again once it has been forgotten. The compressed output is read the
+    /// same way either way.
+    pub fn with_max_retained_blocks(mut self, max_retained_blocks: usize) -> Self {
+        self.max_retained_blocks = Some(max_retained_blocks);
+        self
+    }
+
+    /// Stores the md5 of every unique block, and checks each one when decompressing, so
+    /// corruption is reported with the index of the block it hit rather than only failing the
+    /// whole input's CRC.
+    ///
+    /// Costs 16 bytes per unique block. An input stored as-is has only its CRC.
+    pub fn with_block_hashes(mut self) -> Self {
+        self.block_hashes = true;
+        self
+    }
+
+    /// Writes each block to the output as soon as it's read, a new block in full and a repeat
+    /// as a reference, rather than collecting them all and serialising them at the end.
+    ///
+    /// Along with `with_max_retained_blocks`, compressing and decompressing then take the same
+    /// bounded memory however large the input is. Only an input smaller than a block is stored
+    /// as-is, and as the CRC comes last, decompression may write some output before finding
+    /// it doesn't match.
+    pub fn with_streaming(mut self) -> Self {
+        self.streaming = true;
+        self
+    }
+
+    /// Reads the input `read_buffer_size` bytes at a time when compressing, rather than the
+    /// default 64 KiB, and carves the blocks out of that.
+    ///
+    /// The blocks are the same whatever the size, so the output is too. Smaller buffers use
+    /// less memory but need more reads.
+    pub fn with_read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.read_buffer_size = Some(read_buffer_size);
+        self
+    }
+
+    /// Buffers `input` so each block doesn't cost a read of its own.
+    fn buffered<'a>(&self, input: &'a mut dyn Read) -> BufReader<&'a mut dyn Read> {
+        BufReader::with_capacity(
+            self.read_buffer_size.unwrap_or(DEFAULT_READ_BUFFER_SIZE),
+            input,
+        )
+    }
+}
+
+impl<H: BlockHasher> BlockCompression<H> {
+    /// Splits `input` into blocks and deduplicates them, returning them along with the
+    /// input's length.
+    fn compress_blocks(&self, input: &mut dyn Read) -> Result<(Compressed, usize)> {
+        let mut input = self.buffered(input);
         let mut buffer = [0; BLOCK_SIZE];
         let mut block_map = Vec::new();
-        let mut block_hashes = HashMap::new();
-        let mut blocks: Vec<Vec<u8>> = Vec::new();
-        println!("Original Size: {}", file.metadata()?.len());
+        let mut retained = RetainedBlocks::new(self.max_retained_blocks);
+        let mut block_lengths = Vec::new();
+        let mut block_starts = Vec::new();
+        let mut block_data = Vec::new();
+        let mut original_size = 0;
+        let mut crc = 0;
         loop {
-            let n = file.read(&mut buffer[..])?;
+            let n = read_block(&mut input, &mut buffer)?;
             if n == 0 {
                 break;
             }
+            original_size += n;
             let b = &buffer[..n];
-            let strong = strong_hash(b);
-            match block_hashes.entry(strong) {
-                Entry::Occupied(entry) => block_map.push(*entry.get()),
-                Entry::Vacant(entry) => {
-                    blocks.push(b.to_vec());
-                    let new_block_index = (blocks.len() - 1) as u32;
-                    entry.insert(new_block_index);
+            crc = crc32(crc, b);
+            let strong = self.hasher.hash(b);
+            let duplicate = retained.get(&strong).filter(|&block_index| {
+                let start = block_starts[block_index as usize];
+                block_data[start..start + block_lengths[block_index as usize] as usize] == *b
+            });
+            match duplicate {
+                Some(block_index) => block_map.push(block_index),
+                None => {
+                    block_lengths.push(n as u32);
+                    block_starts.push(block_data.len());
+                    block_data.extend_from_slice(b);
+                    let new_block_index = (block_lengths.len() - 1) as u32;
+                    retained.insert(strong, new_block_index);
                     block_map.push(new_block_index);
                 }
             };
         }
-        let compressed = Compressed { block_map, blocks };
-        compression::write_compressed(&compressed, output_file_path)
+        let block_hashes = if self.block_hashes {
+            let mut hashes = Vec::with_capacity(block_starts.len());
+            for (&start, &length) in block_starts.iter().zip(&block_lengths) {
+                hashes.push(md5::compute(&block_data[start..start + length as usize]).0);
+            }
+            Some(hashes)
+        } else {
+            None
+        };
+        let compressed = Compressed {
+            hasher: self.hasher.name().to_string(),
+            block_lengths,
+            block_data,
+            block_map,
+            crc,
+            block_hashes,
+        };
+        Ok((compressed, original_size))
+    }
+
+    /// Writes a `Record` for each block of `input` to `output` as it goes, returning the stats
+    /// for the records alone.
+    fn compress_records(
+        &self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<CompressionStats> {
+        let mut input = self.buffered(input);
+        let mut buffer = [0; BLOCK_SIZE];
+        // every slot has to be free to reuse before a new block is written into it
+        let mut retained = RetainedBlocks::new(self.max_retained_blocks.map(|limit| limit.max(1)));
+        let mut slots: Vec<Vec<u8>> = Vec::new();
+        let mut stats = CompressionStats {
+            unique_blocks: Some(0),
+            ..CompressionStats::default()
+        };
+        let mut crc = 0;
+        loop {
+            let n = read_block(&mut input, &mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            stats.original_bytes += n;
+            let b = &buffer[..n];
+            crc = crc32(crc, b);
+            let strong = self.hasher.hash(b);
+            let duplicate = retained
+                .get(&strong)
+                .filter(|&slot| slots[slot as usize] == b);
+            let record = match duplicate {
+                Some(slot) => Record::Reference(slot),
+                None => {
+                    let slot = retained.make_room(&strong).unwrap_or(slots.len() as u32);
+                    retained.insert(strong, slot);
+                    stats.unique_blocks = stats.unique_blocks.map(|count| count + 1);
+                    if slot as usize == slots.len() {
+                        slots.push(b.to_vec());
+                    } else {
+                        slots[slot as usize] = b.to_vec();
+                    }
+                    Record::Block {
+                        slot,
+                        data: b.to_vec(),
+                        hash: if self.block_hashes {
+                            Some(md5::compute(b).0)
+                        } else {
+                            None
+                        },
+                    }
+                }
+            };
+            stats.compressed_bytes += compression::write_compressed(&record, output)?;
+        }
+        stats.compressed_bytes += compression::write_compressed(&Record::End { crc }, output)?;
+        Ok(stats)
+    }
+
+    /// Compresses `input` entirely in memory, producing the same bytes as `compress`.
+    pub fn compress_bytes(&self, input: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        compression::Algorithm::compress(self, &mut &input[..], &mut compressed)
+            .expect("Compressing from and to memory can't fail");
+        compressed
+    }
+
+    /// Decompresses a stream produced by `compress_bytes` or `compress` entirely in memory.
+    pub fn decompress_bytes(&self, compressed: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        compression::Algorithm::decompress(self, &mut &compressed[..], &mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+/// Compresses a file by looking for matching block patterns.block_compress
+///
+/// Inspired by the rsync algo - this algorithm reads in a file block by block, taking a
+/// checksum of the block. When we take a block's checksum we check it against all other previously
+/// seen checksums. If we find a hit then we store a reference to the previous block, rather than storing
+/// the raw data again.
+///
+/// Every block is a full `BLOCK_SIZE` bytes however the input's reads happen to be split, apart
+/// from a trailing partial block at the end of the input. That block is stored at its true
+/// length, so it only deduplicates against an identical trailing block.
+///
+/// This is a poor compression method - without many repeated blocks the block lengths and map
+/// take more space than they save. When they would, the input is stored as-is instead, so the
+/// output is never more than a few bytes of header and checksum larger than the input.
+impl<H: BlockHasher> compression::Algorithm for BlockCompression<H> {
+    fn name(&self) -> &'static str {
+        Format::Block.name()
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        Format::Block.magic()
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<CompressionStats> {
+        let mut header = Vec::new();
+        frame::write_header(&mut header, frame::Header::current(Format::Block));
+        let (body, original_size, unique_blocks) = if self.streaming {
+            let mut first_block = [0; BLOCK_SIZE];
+            let n = read_block(input, &mut first_block)?;
+            if n == BLOCK_SIZE {
+                let mut output = CountingWriter::new(BufWriter::new(output));
+                output.write_all(&header)?;
+                let body = Body::Streamed(Streamed {
+                    hasher: self.hasher.name().to_string(),
+                });
+                body.write(&mut output)?;
+                let mut stats =
+                    self.compress_records(&mut (&first_block[..]).chain(input), &mut output)?;
+                output.flush()?;
+                stats.compressed_bytes = output.count() as usize;
+                return Ok(stats);
+            }
+            // the whole input fits in a block, which can't repeat, so the records would only
+            // add to it
+            let stored = Stored {
+                hasher: self.hasher.name().to_string(),
+                data: first_block[..n].to_vec(),
+                crc: crc32(0, &first_block[..n]),
+            };
+            (Body::Stored(stored), n, usize::from(n > 0))
+        } else {
+            let (compressed, original_size) = self.compress_blocks(input)?;
+            let unique_blocks = compressed.block_lengths.len();
+            let body = if compressed.serialised_size() > original_size {
+                compressed.stored_if_smaller()?
+            } else {
+                Body::Blocks(compressed)
+            };
+            (body, original_size, unique_blocks)
+        };
+        output.write_all(&header)?;
+        let body_size = body.write(output)?;
+        output.flush()?;
+        Ok(CompressionStats {
+            original_bytes: original_size,
+            compressed_bytes: header.len() + body_size,
+            unique_blocks: Some(unique_blocks),
+            ..CompressionStats::default()
+        })
+    }
+
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+        let mut input = open(input)?;
+        match Body::read(&mut input)? {
+            Body::Blocks(compressed) => {
+                let blocks = compressed.mapped_blocks()?;
+                let crc = blocks.iter().fold(0, |crc, block| crc32(crc, block));
+                if crc != compressed.crc {
+                    return Err(DeserialiseError::ChecksumMismatch.into());
+                }
+                for block in blocks {
+                    output.write_all(block)?;
+                }
+            }
+            Body::Stored(stored) => {
+                if crc32(0, &stored.data) != stored.crc {
+                    return Err(DeserialiseError::ChecksumMismatch.into());
+                }
+                output.write_all(&stored.data)?;
+            }
+            Body::Streamed(_) => decompress_records(&mut input, output)?,
+        }
+        Ok(output.flush()?)
+    }
+}
+
+impl Compressed {
+    /// Writes the blocks in the layout above, returning the number of bytes written.
+    fn write(&self, output: &mut dyn Write) -> io::Result<usize> {
+        let (head, tail) = self.head_and_tail();
+        output.write_all(&head)?;
+        output.write_all(&self.block_data)?;
+        output.write_all(&tail)?;
+        Ok(head.len() + self.block_data.len() + tail.len())
+    }
+
+    /// The number of bytes `write` would write.
+    fn serialised_size(&self) -> usize {
+        let (head, tail) = self.head_and_tail();
+        head.len() + self.block_data.len() + tail.len()
+    }
+
+    /// Everything before the block data, and everything after it.
+    fn head_and_tail(&self) -> (Vec<u8>, Vec<u8>) {
+        let mut head = Vec::new();
+        write_varint(&mut head, self.hasher.len() as u64);
+        head.extend_from_slice(self.hasher.as_bytes());
+        write_varint(&mut head, self.block_lengths.len() as u64);
+        for &length in &self.block_lengths {
+            write_varint(
+                &mut head,
+                (BLOCK_SIZE as u64).wrapping_sub(u64::from(length)),
+            );
+        }
+        write_varint(&mut head, self.block_data.len() as u64);
+
+        let mut tail = Vec::new();
+        match &self.block_hashes {
+            Some(hashes) => {
+                tail.push(1);
+                for hash in hashes {
+                    tail.extend_from_slice(hash);
+                }
+            }
+            None => tail.push(0),
+        }
+        write_varint(&mut tail, self.block_map.len() as u64);
+        let mut next = 0;
+        for &index in &self.block_map {
+            let delta = i64::from(index) - next;
+            write_varint(&mut tail, ((delta << 1) ^ (delta >> 63)) as u64);
+            next = i64::from(index) + 1;
+        }
+        tail.extend_from_slice(&self.crc.to_le_bytes());
+        (head, tail)
+    }
+
+    /// Reads blocks written by `write`, checking only that the lengths and map entries fit
+    /// their fields. `mapped_blocks` checks they agree with each other.
+    fn read(input: &mut dyn Read) -> Result<Compressed> {
+        let hasher_len = read_varint(input)?;
+        let hasher =
+            String::from_utf8(read_bytes(input, hasher_len)?).map_err(|err| err.utf8_error())?;
+        let mut block_lengths = Vec::new();
+        for _ in 0..read_varint(input)? {
+            let shortfall = read_varint(input)?;
+            if shortfall >= BLOCK_SIZE as u64 {
+                return Err(DeserialiseError::InvalidBlock.into());
+            }
+            block_lengths.push((BLOCK_SIZE as u64 - shortfall) as u32);
+        }
+        let block_data_len = read_varint(input)?;
+        let block_data = read_bytes(input, block_data_len)?;
+
+        let block_hashes = match read_byte(input)? {
+            0 => None,
+            1 => {
+                let mut hashes = Vec::new();
+                for _ in &block_lengths {
+                    let mut hash = [0; 16];
+                    read_exact(input, &mut hash)?;
+                    hashes.push(hash);
+                }
+                Some(hashes)
+            }
+            _ => return Err(DeserialiseError::InvalidBlock.into()),
+        };
+        let mut block_map = Vec::new();
+        let mut next: i64 = 0;
+        for _ in 0..read_varint(input)? {
+            let zig_zag = read_varint(input)?;
+            let delta = (zig_zag >> 1) as i64 ^ -((zig_zag & 1) as i64);
+            let index = next
+                .checked_add(delta)
+                .and_then(|index| u32::try_from(index).ok())
+                .ok_or(DeserialiseError::InvalidBlock)?;
+            block_map.push(index);
+            next = i64::from(index) + 1;
+        }
+        let mut crc = [0; 4];
+        read_exact(input, &mut crc)?;
+        Ok(Compressed {
+            hasher,
+            block_lengths,
+            block_data,
+            block_map,
+            crc: u32::from_le_bytes(crc),
+            block_hashes,
+        })
+    }
+
+    /// The unique block for each block of the input, in order, checking that the block
+    /// lengths add up to the block data, that the block map only refers to them and that
+    /// each matches its stored md5, if any.
+    fn mapped_blocks(&self) -> Result<Vec<&[u8]>> {
+        let mut blocks = Vec::with_capacity(self.block_lengths.len());
+        let mut block_start = 0;
+        for &length in &self.block_lengths {
+            let block_end = block_start + length as usize;
+            let block = self
+                .block_data
+                .get(block_start..block_end)
+                .ok_or(DeserialiseError::InvalidBlock)?;
+            blocks.push(block);
+            block_start = block_end;
+        }
+        if block_start != self.block_data.len() {
+            return Err(DeserialiseError::InvalidBlock.into());
+        }
+        if let Some(hashes) = &self.block_hashes {
+            if hashes.len() != blocks.len() {
+                return Err(DeserialiseError::InvalidBlock.into());
+            }
+            for (index, (block, hash)) in blocks.iter().zip(hashes).enumerate() {
+                if md5::compute(block).0 != *hash {
+                    return Err(DeserialiseError::BlockHashMismatch(index).into());
+                }
+            }
+        }
+
+        self.block_map
+            .iter()
+            .map(|&index| {
+                blocks
+                    .get(index as usize)
+                    .copied()
+                    .ok_or_else(|| DeserialiseError::InvalidBlock.into())
+            })
+            .collect()
+    }
+
+    /// The input as-is, if that serialises smaller than the blocks.
+    fn stored_if_smaller(self) -> Result<Body> {
+        let stored = Stored {
+            hasher: self.hasher.clone(),
+            data: self.mapped_blocks()?.concat(),
+            crc: self.crc,
+        };
+        if compression::compressed_size(&stored)? < self.serialised_size() {
+            Ok(Body::Stored(stored))
+        } else {
+            Ok(Body::Blocks(self))
+        }
+    }
+}
+
+/// Checks the frame header, returning the rest of the stream.
+fn open(input: &mut dyn Read) -> Result<BufReader<&mut dyn Read>> {
+    let mut buf_reader = BufReader::new(input);
+    let mut start = Vec::new();
+    Read::by_ref(&mut buf_reader)
+        .take(frame::HEADER_SIZE as u64)
+        .read_to_end(&mut start)?;
+    let (header, _) = frame::read_header(&start)?;
+    header.expect(Format::Block)?;
+    Ok(buf_reader)
+}
+
+/// Checks the frame header before reading the rest of the stream. The records of a streamed
+/// body are left unread.
+fn read_body(input: &mut dyn Read) -> Result<Body> {
+    Body::read(&mut open(input)?)
+}
+
+/// Writes out the blocks of a streamed body's records as they're read, then checks the CRC.
+fn decompress_records(input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+    let mut slots: Vec<Vec<u8>> = Vec::new();
+    let mut unique_blocks = 0;
+    let mut crc = 0;
+    loop {
+        let block = match read_serialised(input)? {
+            Record::Block { slot, data, hash } => {
+                if data.is_empty() || data.len() > BLOCK_SIZE {
+                    return Err(DeserialiseError::InvalidBlock.into());
+                }
+                if hash.is_some_and(|hash| md5::compute(&data).0 != hash) {
+                    return Err(DeserialiseError::BlockHashMismatch(unique_blocks).into());
+                }
+                unique_blocks += 1;
+                let slot = slot as usize;
+                if slot == slots.len() {
+                    slots.push(data);
+                } else {
+                    *slots.get_mut(slot).ok_or(DeserialiseError::InvalidBlock)? = data;
+                }
+                &slots[slot]
+            }
+            Record::Reference(slot) => slots
+                .get(slot as usize)
+                .ok_or(DeserialiseError::InvalidBlock)?,
+            Record::End { crc: expected } => {
+                if crc != expected {
+                    return Err(DeserialiseError::ChecksumMismatch.into());
+                }
+                return Ok(());
+            }
+        };
+        crc = crc32(crc, block);
+        output.write_all(block)?;
+    }
+}
+
+/// The unique blocks that later blocks can be deduplicated against, by hash, forgetting the
+/// least recently used once there are more than `limit`.
+struct RetainedBlocks {
+    limit: Option<usize>,
+    // only ever looked up, never iterated, so its ordering can't reach the output
+    blocks: HashMap<Vec<u8>, (u32, u64)>,
+    // hashes by when they were last used, oldest first
+    recency: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+}
+
+impl RetainedBlocks {
+    fn new(limit: Option<usize>) -> Self {
+        RetainedBlocks {
+            limit,
+            blocks: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The index of the block with this hash, if it's still retained.
+    fn get(&mut self, hash: &[u8]) -> Option<u32> {
+        let (block_index, last_used) = self.blocks.get_mut(hash)?;
+        if self.limit.is_some() {
+            let hash = self.recency.remove(last_used).unwrap();
+            *last_used = self.clock;
+            self.recency.insert(self.clock, hash);
+            self.clock += 1;
+        }
+        Some(*block_index)
+    }
+
+    /// Forgets the block with this hash, or else the least recently used block if there's no
+    /// room for another, returning its index.
+    fn make_room(&mut self, hash: &[u8]) -> Option<u32> {
+        let (block_index, last_used) = match self.blocks.remove(hash) {
+            Some(forgotten) => forgotten,
+            None if self.blocks.len() < self.limit.unwrap_or(usize::MAX) => return None,
+            None => {
+                let oldest = *self.recency.keys().next()?;
+                let hash = &self.recency[&oldest];
+                self.blocks.remove(hash).unwrap()
+            }
+        };
+        self.recency.remove(&last_used);
+        Some(block_index)
+    }
+
+    /// Retains a block, replacing any other with the same hash.
+    fn insert(&mut self, hash: Vec<u8>, block_index: u32) {
+        if let Some((_, last_used)) = self.blocks.remove(&hash) {
+            self.recency.remove(&last_used);
+        }
+        if self.limit.is_some() {
+            self.recency.insert(self.clock, hash.clone());
+        }
+        self.blocks.insert(hash, (block_index, self.clock));
+        self.clock += 1;
+        while self.blocks.len() > self.limit.unwrap_or(usize::MAX) {
+            let oldest = *self.recency.keys().next().unwrap();
+            let hash = self.recency.remove(&oldest).unwrap();
+            self.blocks.remove(&hash);
+        }
     }
+}
+
+/// Fills `buffer` unless the input runs out first, returning how many bytes were read.
+fn read_block(input: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Writes `value` as an unsigned LEB128 varint: 7 bits per byte, least significant first, with
+/// the top bit set on every byte but the last.
+fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+/// Reads a varint written by `write_varint`.
+fn read_varint(input: &mut dyn Read) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(input)?;
+        let bits = u64::from(byte & 0x7f);
+        if (bits << shift) >> shift != bits {
+            return Err(DeserialiseError::InvalidLength.into());
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DeserialiseError::InvalidLength.into())
+}
+
+fn read_byte(input: &mut dyn Read) -> Result<u8> {
+    let mut byte = [0];
+    read_exact(input, &mut byte)?;
+    Ok(byte[0])
+}
+
+/// Reads `len` bytes, only allocating as they arrive so a corrupt length can't exhaust memory.
+fn read_bytes(input: &mut dyn Read, len: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(DeserialiseError::Truncated.into());
+    }
+    Ok(bytes)
+}
+
+/// `compression::read_compressed`, with running out of input reported as `Truncated`.
+fn read_serialised<T: serde::de::DeserializeOwned>(input: &mut dyn Read) -> Result<T> {
+    compression::read_compressed(input).map_err(|err| match err {
+        Error::Bincode(err) => match *err {
+            bincode::ErrorKind::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                DeserialiseError::Truncated.into()
+            }
+            err => Error::Bincode(Box::new(err)),
+        },
+        err => err,
+    })
+}
 
-    fn decompress(&self, compressed_file: File, output_file_path: &str) -> io::Result<()> {
-        let buf_reader = BufReader::new(compressed_file);
-        let compressed: Compressed = bincode::deserialize_from(buf_reader).unwrap();
-        let mut file = File::create(output_file_path)?;
-        for index in compressed.block_map {
-            let block_data = &compressed.blocks[index as usize];
-            file.write_all(&block_data)?;
+/// `Read::read_exact`, with running out of input reported as `Truncated`.
+fn read_exact(input: &mut dyn Read, buffer: &mut [u8]) -> Result<()> {
+    input.read_exact(buffer).map_err(|err| {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            DeserialiseError::Truncated.into()
+        } else {
+            err.into()
+        }
+    })
+}
+
+/// Continues the CRC-32 (as used by zip and gzip) `crc` of the bytes so far over `bytes`.
+/// Start from 0.
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = 0u32.wrapping_sub(crc & 1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
         }
-        Ok(())
     }
+    !crc
 }
 
-fn strong_hash(buf: &[u8]) -> String {
-    let hash_digest = md5::compute(buf);
-    hex::encode(hash_digest.0)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Algorithm;
+    use crate::test_util::{pseudo_random, pseudo_random_bytes};
+
+    /// The blocks of a stream that wasn't stored.
+    fn read_blocks(compressed: &[u8]) -> Compressed {
+        match read_body(&mut &compressed[..]).unwrap() {
+            Body::Blocks(compressed) => compressed,
+            Body::Stored(_) => panic!("the input was stored as-is"),
+            Body::Streamed(_) => panic!("the blocks were streamed"),
+        }
+    }
+
+    #[test]
+    fn round_trips_in_memory() {
+        let bytes: Vec<u8> = b"a block of text that repeats. "
+            .iter()
+            .cycle()
+            .take(BLOCK_SIZE * 10)
+            .copied()
+            .collect();
+
+        let mut compressed = Vec::new();
+        BlockCompression::new()
+            .compress(&mut &bytes[..], &mut compressed)
+            .unwrap();
+
+        let mut decompressed = Vec::new();
+        BlockCompression::new()
+            .decompress(&mut &compressed[..], &mut decompressed)
+            .unwrap();
+        assert_eq!(bytes, decompressed);
+
+        // the slice methods are the same streams without the trait
+        let compressor = BlockCompression::new();
+        assert_eq!(compressed, compressor.compress_bytes(&bytes));
+        assert_eq!(bytes, compressor.decompress_bytes(&compressed).unwrap());
+        assert!(compressor.decompress_bytes(&compressed[..10]).is_err());
+    }
+
+    #[test]
+    fn stats_count_the_unique_blocks() {
+        let block = |byte: u8| vec![byte; BLOCK_SIZE];
+        let a_b_a_b_c = [
+            block(b'a'),
+            block(b'b'),
+            block(b'a'),
+            block(b'b'),
+            block(b'c'),
+        ]
+        .concat();
+        for compressor in [
+            BlockCompression::new(),
+            BlockCompression::new().with_streaming(),
+        ] {
+            let (compressed, stats) = compressor.compress_with_stats(&a_b_a_b_c).unwrap();
+            assert_eq!(Some(3), stats.unique_blocks);
+            assert_eq!(compressed.len(), stats.compressed_bytes);
+        }
+        // forgotten blocks are stored, and counted, again
+        let (_, stats) = BlockCompression::new()
+            .with_streaming()
+            .with_max_retained_blocks(1)
+            .compress_with_stats(&a_b_a_b_c)
+            .unwrap();
+        assert_eq!(Some(5), stats.unique_blocks);
+    }
+
+    #[test]
+    fn output_is_deterministic_with_blocks_in_first_appearance_order() {
+        let block = |fill: u8| vec![fill; BLOCK_SIZE];
+        let bytes = [
+            block(b'a'),
+            block(b'b'),
+            block(b'a'),
+            block(b'c'),
+            block(b'b'),
+        ]
+        .concat();
+
+        let compressed = read_blocks(&BlockCompression::new().compress_bytes(&bytes));
+        assert_eq!(vec![0, 1, 0, 2, 1], compressed.block_map);
+        assert_eq!(
+            [block(b'a'), block(b'b'), block(b'c')].concat(),
+            compressed.block_data
+        );
+
+        // every run, with fresh compressors and so freshly seeded hash maps, writes the same
+        // bytes
+        let mixed: Vec<u8> = (0..BLOCK_SIZE * 200)
+            .zip(pseudo_random(7))
+            .map(|(i, random)| {
+                if (i / BLOCK_SIZE).is_multiple_of(3) {
+                    random
+                } else {
+                    (i % 5) as u8
+                }
+            })
+            .collect();
+        for input in [&bytes, &mixed] {
+            let first = BlockCompression::new().compress_bytes(input);
+            for _ in 0..3 {
+                assert_eq!(first, BlockCompression::new().compress_bytes(input));
+            }
+            let limited = BlockCompression::new()
+                .with_hasher(Fnv1aHasher)
+                .with_max_retained_blocks(4);
+            assert_eq!(limited.compress_bytes(input), limited.compress_bytes(input));
+        }
+    }
+
+    #[test]
+    fn trailing_partial_block_round_trips() {
+        let bytes: Vec<u8> = (0..BLOCK_SIZE + 5).map(|i| (i % 7) as u8).collect();
+
+        let compressed = BlockCompression::new().compress_to_vec(&bytes).unwrap();
+        let decompressed = BlockCompression::new()
+            .decompress_to_vec(&compressed)
+            .unwrap();
+        assert_eq!(bytes, decompressed);
+    }
+
+    #[test]
+    fn short_reads_do_not_change_the_blocks() {
+        // hands out at most 3 bytes per read, like a pipe might
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = buf.len().min(3).min(self.0.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let bytes = b"0123456789abcdef".repeat(BLOCK_SIZE / 16 * 3);
+
+        let mut trickled = Vec::new();
+        let stats = BlockCompression::new()
+            .compress(&mut Trickle(&bytes), &mut trickled)
+            .unwrap();
+
+        assert_eq!(
+            BlockCompression::new().compress_to_vec(&bytes).unwrap(),
+            trickled
+        );
+        // three identical blocks, stored once beside the header and checksum
+        assert!(stats.compressed_bytes < BLOCK_SIZE + 24);
+    }
+
+    #[test]
+    fn reads_are_buffered_without_changing_the_blocks() {
+        struct CountingReads<'a> {
+            bytes: &'a [u8],
+            reads: usize,
+        }
+        impl Read for CountingReads<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.reads += 1;
+                self.bytes.read(buf)
+            }
+        }
+        let compressed_with_reads = |compressor: BlockCompression, bytes: &[u8]| {
+            let mut input = CountingReads { bytes, reads: 0 };
+            let mut compressed = Vec::new();
+            compressor.compress(&mut input, &mut compressed).unwrap();
+            (compressed, input.reads)
+        };
+        let mut bytes = b"0123456789abcdef".repeat(64 * 1024);
+        bytes.extend_from_slice(b"an uneven tail");
+
+        let (buffered, buffered_reads) = compressed_with_reads(BlockCompression::new(), &bytes);
+        // the tail, then an empty read each time the end is reached
+        assert!(buffered_reads <= bytes.len() / DEFAULT_READ_BUFFER_SIZE + 3);
+        for read_buffer_size in [0, 1, BLOCK_SIZE, 1000] {
+            let (compressed, reads) = compressed_with_reads(
+                BlockCompression::new().with_read_buffer_size(read_buffer_size),
+                &bytes,
+            );
+            assert_eq!(buffered, compressed, "{}", read_buffer_size);
+            assert!(reads > buffered_reads, "{}", read_buffer_size);
+        }
+    }
+
+    #[test]
+    fn unique_blocks_add_little_overhead() {
+        let bytes = pseudo_random_bytes(64 * 1024);
+
+        let mut compressed = Vec::new();
+        let stats = BlockCompression::new()
+            .compress(&mut &bytes[..], &mut compressed)
+            .unwrap();
+
+        assert_eq!(bytes.len(), stats.original_bytes);
+        assert_eq!(compressed.len(), stats.compressed_bytes);
+        assert!(stats.expanded());
+        // a length byte per block, and 1 to 3 bytes of map entry
+        assert!(stats.ratio() < 1.03, "ratio {}", stats.ratio());
+        assert_eq!(
+            bytes,
+            BlockCompression::new()
+                .decompress_to_vec(&compressed)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn inputs_the_blocks_would_expand_are_stored() {
+        // no block repeats, so the block lengths and map are pure overhead
+        let bytes = pseudo_random_bytes(BLOCK_SIZE * 20);
+        let compressor = BlockCompression::new();
+        let compressed = compressor.compress_bytes(&bytes);
+
+        let stored = match read_body(&mut &compressed[..]).unwrap() {
+            Body::Stored(stored) => stored,
+            _ => panic!("the blocks were kept"),
+        };
+        assert_eq!(bytes, stored.data);
+        assert_eq!("md5", stored.hasher);
+        // magic, version, variant, hasher name, data length and checksum
+        assert!(compressed.len() <= bytes.len() + 20, "{}", compressed.len());
+        assert_eq!(bytes, compressor.decompress_bytes(&compressed).unwrap());
+
+        let mut corrupt = compressed.clone();
+        corrupt[30] ^= 1;
+        assert!(matches!(
+            compressor.decompress_bytes(&corrupt),
+            Err(crate::error::Error::Deserialise(
+                DeserialiseError::ChecksumMismatch
+            ))
+        ));
+
+        // a repetitive input keeps its blocks
+        let repetitive = b"0123456789abcdef".repeat(BLOCK_SIZE / 16 * 20);
+        let compressed = compressor.compress_bytes(&repetitive);
+        assert_eq!(20, read_blocks(&compressed).block_map.len());
+    }
+
+    #[test]
+    fn inputs_smaller_than_a_block_are_stored() {
+        for compressor in &[
+            BlockCompression::new(),
+            BlockCompression::new().with_streaming(),
+        ] {
+            for bytes in &[&b""[..], b"0123456789", &[7; BLOCK_SIZE - 1]] {
+                let compressed = compressor.compress_bytes(bytes);
+                let stored = match read_body(&mut &compressed[..]).unwrap() {
+                    Body::Stored(stored) => stored,
+                    _ => panic!("{} bytes weren't stored", bytes.len()),
+                };
+                assert_eq!(*bytes, &stored.data[..]);
+                assert!(compressed.len() <= bytes.len() + 20, "{}", compressed.len());
+                assert_eq!(
+                    *bytes,
+                    &compressor.decompress_bytes(&compressed).unwrap()[..]
+                );
+            }
+        }
+
+        // a whole block is streamed as usual
+        let compressor = BlockCompression::new().with_streaming();
+        let compressed = compressor.compress_bytes(&[7; BLOCK_SIZE]);
+        assert!(matches!(
+            read_body(&mut &compressed[..]).unwrap(),
+            Body::Streamed(_)
+        ));
+        assert_eq!(
+            vec![7; BLOCK_SIZE],
+            compressor.decompress_bytes(&compressed).unwrap()
+        );
+    }
+
+    #[test]
+    fn blocks_serialise_smaller_than_with_bincode() {
+        // enough unique blocks that bincode needs 3 bytes for most of their indexes
+        let blocks: Vec<u8> = (0..600u32 * BLOCK_SIZE as u32)
+            .map(|i| (i / BLOCK_SIZE as u32 * 7 + i % 5) as u8 ^ (i >> 10) as u8)
+            .collect();
+        let bytes = [&blocks[..], &blocks[..], b"tail"].concat();
+        let compressed = BlockCompression::new().compress_bytes(&bytes);
+        let blocks = read_blocks(&compressed);
+        assert_eq!(601, blocks.block_lengths.len());
+        assert_eq!(1201, blocks.block_map.len());
+
+        let manual = blocks.serialised_size();
+        let bincode = compression::compressed_size(&blocks).unwrap();
+        assert!(manual + 1000 < bincode, "{} vs {}", manual, bincode);
+        assert_eq!(frame::HEADER_SIZE + 1 + manual, compressed.len());
+        assert_eq!(
+            bytes,
+            BlockCompression::new()
+                .decompress_bytes(&compressed)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn truncated_blocks_are_rejected() {
+        let bytes = [
+            &b"0123456789abcdef".repeat(BLOCK_SIZE / 16 * 3)[..],
+            b"tail",
+        ]
+        .concat();
+        let compressed = BlockCompression::new()
+            .with_block_hashes()
+            .compress_bytes(&bytes);
+        read_blocks(&compressed);
+        // streamed records are read by bincode, and a short input is stored as-is
+        let streamed = BlockCompression::new()
+            .with_streaming()
+            .compress_to_vec(&bytes)
+            .unwrap();
+        let stored = BlockCompression::new().compress_bytes(b"tail");
+        for compressed in [compressed, streamed, stored] {
+            for len in frame::HEADER_SIZE + 1..compressed.len() {
+                let err = BlockCompression::new()
+                    .decompress_bytes(&compressed[..len])
+                    .unwrap_err();
+                assert!(
+                    matches!(err, Error::Deserialise(DeserialiseError::Truncated)),
+                    "{}: {:?}",
+                    len,
+                    err
+                );
+                assert_eq!(io::ErrorKind::InvalidData, io::Error::from(err).kind());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_block_maps_outside_the_blocks() {
+        let compressed = |block_lengths, block_map| {
+            let mut bytes = Vec::new();
+            frame::write_header(&mut bytes, frame::Header::current(Format::Block));
+            let body = Body::Blocks(Compressed {
+                hasher: "md5".to_string(),
+                block_lengths,
+                block_data: b"abcd".to_vec(),
+                block_map,
+                crc: crc32(0, b"cdab"),
+                block_hashes: None,
+            });
+            body.write(&mut bytes).unwrap();
+            bytes
+        };
+
+        for corrupt in [
+            compressed(vec![2, 2], vec![0, 2]),
+            compressed(vec![2, 3], vec![0]),
+            compressed(vec![2, 1], vec![0]),
+        ] {
+            assert!(matches!(
+                BlockCompression::new().decompress_to_vec(&corrupt),
+                Err(crate::error::Error::Deserialise(
+                    DeserialiseError::InvalidBlock
+                ))
+            ));
+        }
+        assert_eq!(
+            b"cdab".to_vec(),
+            BlockCompression::new()
+                .decompress_to_vec(&compressed(vec![2, 2], vec![1, 0]))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn retained_blocks_are_capped_least_recently_used_first() {
+        let block = |byte: u8| vec![byte; BLOCK_SIZE];
+        let stored_blocks = |compressor: &BlockCompression, bytes: &[u8]| {
+            let compressed = compressor.compress_to_vec(bytes).unwrap();
+            assert_eq!(
+                bytes,
+                &compressor.decompress_to_vec(&compressed).unwrap()[..]
+            );
+            let (compressed, _) = compressor.compress_blocks(&mut &bytes[..]).unwrap();
+            compressed.block_lengths.len()
+        };
+        let capped = BlockCompression::new().with_max_retained_blocks(2);
+
+        // 'a' is forgotten once 'c' arrives, so is stored twice
+        let a_b_c_a = [block(b'a'), block(b'b'), block(b'c'), block(b'a')].concat();
+        assert_eq!(3, stored_blocks(&BlockCompression::new(), &a_b_c_a));
+        assert_eq!(4, stored_blocks(&capped, &a_b_c_a));
+        let nothing_retained = BlockCompression::new().with_max_retained_blocks(0);
+        assert_eq!(4, stored_blocks(&nothing_retained, &a_b_c_a));
+
+        // using 'a' again makes 'b' the one forgotten for 'c'
+        let a_b_a_c_a = [
+            block(b'a'),
+            block(b'b'),
+            block(b'a'),
+            block(b'c'),
+            block(b'a'),
+        ]
+        .concat();
+        assert_eq!(3, stored_blocks(&capped, &a_b_a_c_a));
+    }
+
+    /// The records of a streamed body, up to and including `Record::End`.
+    fn read_records(compressed: &[u8]) -> Vec<Record> {
+        let mut compressed = compressed;
+        let mut input = open(&mut compressed).unwrap();
+        let body = Body::read(&mut input).unwrap();
+        assert!(matches!(body, Body::Streamed(_)));
+        let mut records = Vec::new();
+        loop {
+            let record = compression::read_compressed(&mut input).unwrap();
+            let end = matches!(record, Record::End { .. });
+            records.push(record);
+            if end {
+                return records;
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_writes_blocks_inline_and_round_trips() {
+        let block = |byte: u8| vec![byte; BLOCK_SIZE];
+        let a_b_a_c_a_b = [
+            block(b'a'),
+            block(b'b'),
+            block(b'a'),
+            block(b'c'),
+            block(b'a'),
+            block(b'b'),
+            b"tail".to_vec(),
+        ]
+        .concat();
+        let streaming = BlockCompression::new().with_streaming();
+
+        let compressed = streaming.compress_to_vec(&a_b_a_c_a_b).unwrap();
+        assert_eq!(
+            a_b_a_c_a_b,
+            streaming.decompress_to_vec(&compressed).unwrap()
+        );
+        // any block compressor reads it
+        assert_eq!(
+            a_b_a_c_a_b,
+            BlockCompression::new()
+                .decompress_to_vec(&compressed)
+                .unwrap()
+        );
+        assert_eq!(
+            "md5",
+            BlockCompression::hasher_name(&mut &compressed[..]).unwrap()
+        );
+        let slots: Vec<_> = read_records(&compressed)
+            .iter()
+            .map(|record| match record {
+                Record::Block { slot, .. } => format!("block {}", slot),
+                Record::Reference(slot) => format!("ref {}", slot),
+                Record::End { .. } => "end".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            vec!["block 0", "block 1", "ref 0", "block 2", "ref 0", "ref 1", "block 3", "end"],
+            slots
+        );
+
+        // with two slots 'b' is forgotten for 'c', and 'b' reuses the slot 'c' took from it
+        let capped = streaming.with_max_retained_blocks(2).with_block_hashes();
+        let compressed = capped.compress_to_vec(&a_b_a_c_a_b).unwrap();
+        assert_eq!(a_b_a_c_a_b, capped.decompress_to_vec(&compressed).unwrap());
+        let written_slots: Vec<u32> = read_records(&compressed)
+            .iter()
+            .filter_map(|record| match record {
+                Record::Block { slot, hash, .. } => {
+                    assert!(hash.is_some());
+                    Some(*slot)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![0, 1, 1, 1, 0], written_slots);
+
+        for bytes in [&b""[..], b"x", &block(b'z')] {
+            for compressor in [
+                BlockCompression::new().with_streaming(),
+                BlockCompression::new()
+                    .with_streaming()
+                    .with_max_retained_blocks(0),
+            ] {
+                let compressed = compressor.compress_to_vec(bytes).unwrap();
+                assert_eq!(
+                    bytes,
+                    &compressor.decompress_to_vec(&compressed).unwrap()[..]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_rejects_corrupt_records() {
+        let bytes = b"0123456789abcdef".repeat(BLOCK_SIZE / 16 * 3);
+        let streamed = |records: &[Record]| {
+            let mut stream = Vec::new();
+            frame::write_header(&mut stream, frame::Header::current(Format::Block));
+            let body = Body::Streamed(Streamed {
+                hasher: "md5".to_string(),
+            });
+            body.write(&mut stream).unwrap();
+            for record in records {
+                compression::write_compressed(record, &mut stream).unwrap();
+            }
+            stream
+        };
+        let block = |slot, data: &[u8]| Record::Block {
+            slot,
+            data: data.to_vec(),
+            hash: Some(md5::compute(data).0),
+        };
+        let decompressed = |stream: &[u8]| BlockCompression::new().decompress_to_vec(stream);
+
+        assert_eq!(
+            b"abab".to_vec(),
+            decompressed(&streamed(&[
+                block(0, b"ab"),
+                Record::Reference(0),
+                Record::End {
+                    crc: crc32(0, b"abab")
+                },
+            ]))
+            .unwrap()
+        );
+        assert!(matches!(
+            decompressed(&streamed(&[block(0, b"ab"), Record::End { crc: 1 }])),
+            Err(crate::error::Error::Deserialise(
+                DeserialiseError::ChecksumMismatch
+            ))
+        ));
+        for invalid in [
+            streamed(&[Record::Reference(0)]),
+            streamed(&[block(1, b"ab")]),
+            streamed(&[block(0, b"")]),
+            streamed(&[block(0, &bytes)]),
+        ] {
+            assert!(matches!(
+                decompressed(&invalid),
+                Err(crate::error::Error::Deserialise(
+                    DeserialiseError::InvalidBlock
+                ))
+            ));
+        }
+        let mut wrong_hash = block(0, b"cd");
+        if let Record::Block { data, .. } = &mut wrong_hash {
+            data[0] = b'x';
+        }
+        assert!(matches!(
+            decompressed(&streamed(&[block(0, b"ab"), wrong_hash])),
+            Err(crate::error::Error::Deserialise(
+                DeserialiseError::BlockHashMismatch(1)
+            ))
+        ));
+
+        // cut off before the end record
+        let compressed = BlockCompression::new()
+            .with_streaming()
+            .compress_to_vec(&bytes)
+            .unwrap();
+        assert!(decompressed(&compressed[..compressed.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn round_trips_with_either_hasher_and_records_it() {
+        let bytes = [
+            b"0123456789abcdef".repeat(BLOCK_SIZE / 16 * 3),
+            b"tail".to_vec(),
+        ]
+        .concat();
+
+        let md5 = BlockCompression::new().compress_to_vec(&bytes).unwrap();
+        let fnv1a = BlockCompression::new()
+            .with_hasher(Fnv1aHasher)
+            .compress_to_vec(&bytes)
+            .unwrap();
+
+        for (compressed, name) in [(md5, "md5"), (fnv1a, "fnv1a")] {
+            assert_eq!(
+                name,
+                BlockCompression::hasher_name(&mut &compressed[..]).unwrap()
+            );
+            // either hasher's output decompresses without knowing which was used
+            assert_eq!(
+                bytes,
+                BlockCompression::new()
+                    .decompress_to_vec(&compressed)
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn colliding_blocks_are_compared_byte_for_byte() {
+        // every block has the same hash
+        struct Colliding;
+        impl BlockHasher for Colliding {
+            fn name(&self) -> &'static str {
+                "colliding"
+            }
+
+            fn hash(&self, _: &[u8]) -> Vec<u8> {
+                vec![0]
+            }
+        }
+
+        let block = |byte: u8| vec![byte; BLOCK_SIZE];
+        let bytes = [
+            block(b'a'),
+            block(b'a'),
+            block(b'b'),
+            block(b'b'),
+            block(b'a'),
+        ]
+        .concat();
+        let compressed = BlockCompression::new()
+            .with_hasher(Colliding)
+            .compress_to_vec(&bytes)
+            .unwrap();
+        assert_eq!(
+            bytes,
+            BlockCompression::new()
+                .decompress_to_vec(&compressed)
+                .unwrap()
+        );
+
+        // only the most recent block is kept per hash, so the last 'a' is stored again
+        assert_eq!(vec![0, 0, 1, 1, 2], read_blocks(&compressed).block_map);
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(
+            0xcbf2_9ce4_8422_2325u64.to_be_bytes().to_vec(),
+            Fnv1aHasher.hash(b"")
+        );
+        assert_eq!(
+            0xaf63_dc4c_8601_ec8cu64.to_be_bytes().to_vec(),
+            Fnv1aHasher.hash(b"a")
+        );
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(0, crc32(0, b""));
+        assert_eq!(0xCBF4_3926, crc32(0, b"123456789"));
+        assert_eq!(crc32(0, b"123456789"), crc32(crc32(0, b"1234"), b"56789"));
+    }
+
+    #[test]
+    fn corrupt_blocks_fail_the_checksum_before_any_output() {
+        let bytes = b"0123456789abcdef".repeat(BLOCK_SIZE / 16 * 3);
+        let compressed = BlockCompression::new().compress_to_vec(&bytes).unwrap();
+
+        let mut corrupt = read_blocks(&compressed);
+        corrupt.block_data[7] ^= 1;
+        let mut corrupt_bytes = Vec::new();
+        frame::write_header(&mut corrupt_bytes, frame::Header::current(Format::Block));
+        Body::Blocks(corrupt).write(&mut corrupt_bytes).unwrap();
+
+        let mut output = Vec::new();
+        let result = BlockCompression::new().decompress(&mut &corrupt_bytes[..], &mut output);
+        assert!(matches!(
+            result,
+            Err(crate::error::Error::Deserialise(
+                DeserialiseError::ChecksumMismatch
+            ))
+        ));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn block_hashes_name_the_corrupt_block() {
+        let bytes: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i / 3) as u8).collect();
+        let bytes = [&bytes[..], &bytes[..]].concat();
+        let compressor = BlockCompression::new().with_block_hashes();
+        let compressed = compressor.compress_to_vec(&bytes).unwrap();
+        let unhashed = BlockCompression::new().compress_to_vec(&bytes).unwrap();
+        // 16 bytes per unique block
+        assert_eq!(unhashed.len() + 4 * 16, compressed.len());
+        // decompression checks the hashes whether or not it was asked to store them
+        assert_eq!(
+            bytes,
+            BlockCompression::new()
+                .decompress_to_vec(&compressed)
+                .unwrap()
+        );
+
+        let mut corrupt = read_blocks(&compressed);
+        assert_eq!(4, corrupt.block_hashes.as_ref().unwrap().len());
+        corrupt.block_data[2 * BLOCK_SIZE + 5] ^= 1;
+        let mut corrupt_bytes = Vec::new();
+        frame::write_header(&mut corrupt_bytes, frame::Header::current(Format::Block));
+        Body::Blocks(corrupt).write(&mut corrupt_bytes).unwrap();
+
+        let mut output = Vec::new();
+        let result = compressor.decompress(&mut &corrupt_bytes[..], &mut output);
+        assert!(matches!(
+            result,
+            Err(crate::error::Error::Deserialise(
+                DeserialiseError::BlockHashMismatch(2)
+            ))
+        ));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn rejects_streams_without_the_magic_bytes() {
+        let compressed = BlockCompression::new().compress_to_vec(b"abc").unwrap();
+        assert!(compressed.starts_with(Format::Block.magic()));
+
+        for corrupt in [&compressed[..2], &compressed[4..]] {
+            assert!(matches!(
+                BlockCompression::new().decompress_to_vec(corrupt),
+                Err(crate::error::Error::Deserialise(DeserialiseError::BadMagic))
+            ));
+        }
+    }
 }
@@ -1,21 +1,762 @@
-use std::fs::File;
-use std::io;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fmt, io, process};
+
+use bincode::Options;
+
+use crate::error::Result;
+use crate::frame::{self, Format};
+use crate::lz77::nodes::NodeHistogram;
+use crate::{block_compress, lz77};
+
+pub mod pipeline;
+
+/// Sizes measured while compressing a single input.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompressionStats {
+    pub original_bytes: usize,
+    pub compressed_bytes: usize,
+    /// The lz77 nodes that were emitted, `None` for algorithms that don't emit nodes.
+    pub histogram: Option<NodeHistogram>,
+    /// The md5 of the original bytes, when it was recorded in the compressed header.
+    pub content_hash: Option<[u8; 16]>,
+    /// Distinct blocks found, `None` for algorithms that don't split the input into blocks.
+    pub unique_blocks: Option<usize>,
+    /// How long compressing took, when it was timed, see `Algorithm::compress_with_stats`.
+    pub elapsed: Option<Duration>,
+}
+
+impl CompressionStats {
+    /// compressed size / original size, 1.0 for an empty input.
+    pub fn ratio(&self) -> f64 {
+        if self.original_bytes == 0 {
+            1.0
+        } else {
+            self.compressed_bytes as f64 / self.original_bytes as f64
+        }
+    }
+
+    /// The number of lz77 nodes emitted, if the algorithm emits nodes.
+    pub fn node_count(&self) -> Option<usize> {
+        self.histogram.map(|histogram| histogram.nodes())
+    }
+
+    /// True when compressing made the input larger.
+    pub fn expanded(&self) -> bool {
+        self.compressed_bytes > self.original_bytes
+    }
+
+    /// `content_hash` as lowercase hex, for use as a key in content-addressed storage.
+    pub fn content_hash_hex(&self) -> Option<String> {
+        self.content_hash.map(|hash| hex(&hash))
+    }
+}
+
+/// `bytes` as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Original size: {}, compressed size: {}, ratio: {:.4}",
+            self.original_bytes,
+            self.compressed_bytes,
+            self.ratio()
+        )
+    }
+}
 
 pub trait Algorithm {
-    fn compress(&self, file: File, output_file_path: &str) -> io::Result<()>;
-    fn decompress(&self, compressed_file: File, output_file_path: &str) -> io::Result<()>;
+    /// The name the algorithm is registered under in `registry`.
+    fn name(&self) -> &'static str;
+
+    /// The bytes every stream written by this algorithm starts with.
+    fn magic(&self) -> &'static [u8];
+
+    /// Compresses `input` into `output`, flushing `output` before returning.
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<CompressionStats>;
+
+    /// Decompresses `input` into `output`, flushing `output` before returning, so a buffered
+    /// writer that fails to write the tail fails the decompression rather than its `Drop`
+    /// swallowing the error.
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<()>;
+
+    /// Compresses `input` entirely in memory.
+    fn compress_to_vec(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        self.compress(&mut &input[..], &mut compressed)?;
+        Ok(compressed)
+    }
+
+    /// Compresses `input` entirely in memory, returning the stats along with the output.
+    /// `CompressionStats::elapsed` is how long compressing took.
+    fn compress_with_stats(&self, input: &[u8]) -> Result<(Vec<u8>, CompressionStats)> {
+        let mut compressed = Vec::new();
+        let start = Instant::now();
+        let mut stats = self.compress(&mut &input[..], &mut compressed)?;
+        stats.elapsed = Some(start.elapsed());
+        Ok((compressed, stats))
+    }
+
+    /// Decompresses `compressed` entirely in memory.
+    fn decompress_to_vec(&self, compressed: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        self.decompress(&mut &compressed[..], &mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    /// The number of bytes `decompress` would write for `input`, without writing them, e.g.
+    /// to check there's enough disk space first.
+    ///
+    /// By default this decompresses, counting the bytes rather than keeping them. Algorithms
+    /// that record the original length up front read it from there instead.
+    fn decompressed_size(&self, input: &mut dyn Read) -> Result<u64> {
+        let mut counter = CountingWriter::new(io::sink());
+        self.decompress(input, &mut counter)?;
+        Ok(counter.count())
+    }
+
+    /// Compresses `file` into a new file at `output_file_path`, only replacing anything
+    /// already there if `force` is set. See `write_atomically`.
+    fn compress_file(
+        &self,
+        mut file: File,
+        output_file_path: &str,
+        force: bool,
+    ) -> Result<CompressionStats> {
+        self.compress_from_reader(&mut file, output_file_path, force)
+    }
+
+    /// Compresses everything `reader` yields, e.g. a socket or an in-memory cursor, into a new
+    /// file at `output_file_path`, as `compress_file` does a `File`.
+    ///
+    /// By default the input is read as `compress` reads it. lz77 streams it through the chunks
+    /// `Lz77Encoder` writes instead, so a large input becomes a series of continuing streams,
+    /// and block compression's `with_streaming` works through it a buffer at a time.
+    fn compress_from_reader(
+        &self,
+        reader: &mut dyn Read,
+        output_file_path: &str,
+        force: bool,
+    ) -> Result<CompressionStats> {
+        write_atomically(output_file_path, force, |out_file| {
+            self.compress(reader, out_file)
+        })
+    }
+
+    /// Decompresses `compressed_file` into a new file at `output_file_path`, only replacing
+    /// anything already there if `force` is set. See `write_atomically`.
+    fn decompress_file(
+        &self,
+        mut compressed_file: File,
+        output_file_path: &str,
+        force: bool,
+    ) -> Result<()> {
+        write_atomically(output_file_path, force, |out_file| {
+            self.decompress(&mut compressed_file, out_file)
+        })
+    }
 }
 
-pub fn write_compressed<T>(compressed: &T, output_file_path: &str) -> io::Result<()>
+/// Passes everything written to it on to the writer it wraps, counting the bytes as they go,
+/// so the size of a compressed output is known without buffering it to measure.
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// The bytes the inner writer has accepted so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub type Constructor = fn() -> Box<dyn Algorithm>;
+
+/// Every algorithm, keyed by its name, each with its default settings. The pipeline defaults to
+/// block deduplication followed by lz77.
+pub fn registry() -> BTreeMap<&'static str, Constructor> {
+    let mut algorithms: BTreeMap<&'static str, Constructor> = BTreeMap::new();
+    algorithms.insert(
+        "block",
+        || Box::new(block_compress::BlockCompression::new()),
+    );
+    algorithms.insert("lz77", || Box::new(lz77::Lz77Compression::new()));
+    algorithms.insert("pipeline", || {
+        Box::new(
+            pipeline::Pipeline::new()
+                .with_stage(block_compress::BlockCompression::new())
+                .with_stage(lz77::Lz77Compression::new()),
+        )
+    });
+    algorithms
+}
+
+/// What a compressed stream's header says about it, see `inspect`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileInfo {
+    pub algorithm: &'static str,
+    pub version: u8,
+    /// Bytes the stream decompresses to, when the format records it up front.
+    pub original_len: Option<usize>,
+    /// Whether decompression checks the output against a stored checksum.
+    pub has_checksum: bool,
+    /// The md5 of the original bytes, if the header records one.
+    pub content_hash: Option<[u8; 16]>,
+}
+
+/// Reads just the header at the start of `input` to tell whether it's a sloppy-compressor
+/// stream and how it was written, without decompressing anything. At most a few bytes are
+/// read, so `input` can be a file of any size.
+pub fn inspect(input: &mut dyn Read) -> Result<FileInfo> {
+    let mut start = Vec::new();
+    input
+        .take(lz77::MAX_HEADER_SIZE as u64)
+        .read_to_end(&mut start)?;
+    let (header, _) = frame::read_header(&start)?;
+    let (original_len, has_checksum, content_hash) = match header.format {
+        Format::Lz77 => {
+            let (original_len, content_hash) = lz77::header_summary(&start)?;
+            (Some(original_len), content_hash.is_some(), content_hash)
+        }
+        // the length is only known once the block map has been read
+        Format::Block => (None, true, None),
+        // any checksums are in the stages' own headers, only reached by decompressing
+        Format::Pipeline => (None, false, None),
+    };
+    Ok(FileInfo {
+        algorithm: header.format.name(),
+        version: header.version,
+        original_len,
+        has_checksum,
+        content_hash,
+    })
+}
+
+/// Serialises `compressed` with bincode's varint encoding, returning the number of bytes
+/// written. Read it back with `read_compressed`.
+pub fn write_compressed<T>(compressed: &T, output: &mut dyn Write) -> Result<usize>
 where
     T: serde::Serialize,
 {
-    let encoded = bincode::serialize(compressed).unwrap();
-    write_to_new_file(&encoded, output_file_path)
+    let encoded = bincode::DefaultOptions::new().serialize(compressed)?;
+    output.write_all(&encoded)?;
+    Ok(encoded.len())
+}
+
+/// The number of bytes `write_compressed` would write for `compressed`.
+pub fn compressed_size<T>(compressed: &T) -> Result<usize>
+where
+    T: serde::Serialize,
+{
+    Ok(bincode::DefaultOptions::new().serialized_size(compressed)? as usize)
+}
+
+pub fn read_compressed<T>(input: &mut dyn Read) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    Ok(bincode::DefaultOptions::new().deserialize_from(input)?)
+}
+
+/// Writes `read_from` to `output_file_path`, only replacing anything there if `force` is set.
+/// See `write_atomically`.
+pub fn write_to_new_file(read_from: &[u8], output_file_path: &str, force: bool) -> io::Result<()> {
+    let mut pending = PendingFile::create(output_file_path, force)?;
+    pending.file.write_all(read_from)?;
+    pending.persist()
+}
+
+/// Runs `write` against a temporary file beside `output_file_path`, and only renames it over
+/// `output_file_path` once `write` has succeeded.
+///
+/// If `write` fails or panics the temporary file is removed, leaving whatever was at
+/// `output_file_path` untouched rather than half written.
+///
+/// Unless `force` is set, an existing file at `output_file_path` is never replaced: this fails
+/// with `io::ErrorKind::AlreadyExists` before calling `write`, or when renaming if the file
+/// appeared in the meantime.
+pub fn write_atomically<T, F>(output_file_path: &str, force: bool, write: F) -> Result<T>
+where
+    F: FnOnce(&mut File) -> Result<T>,
+{
+    let mut pending = PendingFile::create(output_file_path, force)?;
+    let written = write(&mut pending.file)?;
+    pending.persist()?;
+    Ok(written)
+}
+
+/// A temporary file that becomes `path` on `persist`, and is removed if dropped before then.
+struct PendingFile {
+    file: File,
+    temp_path: PathBuf,
+    path: PathBuf,
+    /// Whether `persist` may replace an existing file at `path`.
+    force: bool,
+    persisted: bool,
+}
+
+impl PendingFile {
+    fn create(path: &str, force: bool) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        if !force && path.exists() {
+            return Err(already_exists(&path));
+        }
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+        // in the same directory, so the rename can't cross file systems
+        let temp_name = format!(".{}.{}.tmp", file_name.to_string_lossy(), process::id());
+        let temp_path = path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(temp_name);
+        Ok(PendingFile {
+            file: File::create(&temp_path)?,
+            temp_path,
+            path,
+            force,
+            persisted: false,
+        })
+    }
+
+    fn persist(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        if self.force {
+            fs::rename(&self.temp_path, &self.path)?;
+        } else {
+            // unlike a rename, linking fails rather than replace a file created since `create`
+            fs::hard_link(&self.temp_path, &self.path).map_err(|err| {
+                if err.kind() == io::ErrorKind::AlreadyExists {
+                    already_exists(&self.path)
+                } else {
+                    err
+                }
+            })?;
+            fs::remove_file(&self.temp_path)?;
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
 }
 
-pub fn write_to_new_file(read_from: &Vec<u8>, output_file_path: &str) -> io::Result<()> {
-    let mut out_file = File::create(output_file_path)?;
-    out_file.write_all(read_from)
+impl Drop for PendingFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{DeserialiseError, Error};
+    use crate::test_util::ScratchDir;
+    use std::panic;
+
+    // writes part of its output, then fails or panics
+    struct FailsPartWay;
+
+    impl Algorithm for FailsPartWay {
+        fn name(&self) -> &'static str {
+            "fails-part-way"
+        }
+
+        fn magic(&self) -> &'static [u8] {
+            b"FAIL"
+        }
+
+        fn compress(&self, _: &mut dyn Read, output: &mut dyn Write) -> Result<CompressionStats> {
+            output.write_all(b"half a stream")?;
+            Err(DeserialiseError::Truncated.into())
+        }
+
+        fn decompress(&self, _: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+            output.write_all(b"half a file").unwrap();
+            panic!("decompression failed part way");
+        }
+    }
+
+    /// Takes writes, but fails to flush them.
+    struct FailsToFlush(Vec<u8>);
+
+    impl Write for FailsToFlush {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn flush_errors_fail_compression_and_decompression() {
+        let input = b"flushed or failed ".repeat(20);
+        for (name, constructor) in registry() {
+            let compressor = constructor();
+            let compressed = compressor.compress_to_vec(&input).unwrap();
+
+            let mut output = FailsToFlush(Vec::new());
+            let result = compressor.compress(&mut &input[..], &mut output);
+            assert!(matches!(result, Err(Error::Io(_))), "{}", name);
+            let mut output = FailsToFlush(Vec::new());
+            let result = compressor.decompress(&mut &compressed[..], &mut output);
+            assert!(matches!(result, Err(Error::Io(_))), "{}", name);
+            // everything was written, only the flush failed
+            assert_eq!(input, output.0, "{}", name);
+
+            // a buffered writer hands the tail over on the explicit flush
+            let mut buffered = io::BufWriter::with_capacity(1 << 16, Vec::new());
+            compressor
+                .decompress(&mut &compressed[..], &mut buffered)
+                .unwrap();
+            assert_eq!(
+                input.len(),
+                buffered.buffer().len() + buffered.get_ref().len()
+            );
+            assert!(buffered.buffer().is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn compresses_from_readers_without_a_file() {
+        let dir = ScratchDir::new("reader");
+        let input = b"read from a cursor, not a file. ".repeat(200);
+        for (name, constructor) in registry() {
+            let compressor = constructor();
+            let destination = dir.join(name);
+            let mut cursor = io::Cursor::new(input.clone());
+            let stats = compressor
+                .compress_from_reader(&mut cursor, destination.to_str().unwrap(), false)
+                .unwrap();
+            let compressed = fs::read(&destination).unwrap();
+            assert_eq!(input.len() as u64, cursor.position(), "{}", name);
+            assert_eq!(compressed.len(), stats.compressed_bytes, "{}", name);
+            assert_eq!(input, compressor.decompress_to_vec(&compressed).unwrap());
+        }
+    }
+
+    #[test]
+    fn failed_writes_leave_the_destination_untouched() {
+        let dir = ScratchDir::new("atomic");
+        let input = dir.join("input");
+        fs::write(&input, b"input").unwrap();
+        let destination = dir.join("destination");
+        fs::write(&destination, b"original").unwrap();
+        let destination_path = destination.to_str().unwrap();
+
+        let compressed =
+            FailsPartWay.compress_file(File::open(&input).unwrap(), destination_path, true);
+        assert!(compressed.is_err());
+        let panicked = panic::catch_unwind(|| {
+            FailsPartWay.decompress_file(File::open(&input).unwrap(), destination_path, true)
+        });
+        assert!(panicked.is_err());
+
+        let destination_contents = fs::read(&destination).unwrap();
+        let files = dir.read_dir().unwrap().count();
+        assert_eq!(b"original".to_vec(), destination_contents);
+        // no temporary files left behind
+        assert_eq!(2, files);
+    }
+
+    #[test]
+    fn successful_writes_replace_the_destination() {
+        let dir = ScratchDir::new("replace");
+        let destination = dir.join("destination");
+        fs::write(&destination, b"a much longer original").unwrap();
+
+        write_to_new_file(b"new", destination.to_str().unwrap(), true).unwrap();
+        let destination_contents = fs::read(&destination).unwrap();
+        let files = dir.read_dir().unwrap().count();
+        assert_eq!(b"new".to_vec(), destination_contents);
+        assert_eq!(1, files);
+    }
+
+    #[test]
+    fn existing_destinations_are_only_replaced_when_forced() {
+        let dir = ScratchDir::new("no-clobber");
+        let destination = dir.join("destination");
+        let destination_path = destination.to_str().unwrap();
+
+        // a new file is written either way
+        write_to_new_file(b"first", destination_path, false).unwrap();
+        let first = fs::read(&destination).unwrap();
+
+        let refused = write_to_new_file(b"second", destination_path, false);
+        let mut called = false;
+        let refused_atomically = write_atomically(destination_path, false, |_| {
+            called = true;
+            Ok(())
+        });
+        let after_refusal = fs::read(&destination).unwrap();
+
+        write_to_new_file(b"forced", destination_path, true).unwrap();
+        let forced = fs::read(&destination).unwrap();
+        let files = dir.read_dir().unwrap().count();
+
+        assert_eq!(b"first".to_vec(), first);
+        assert_eq!(io::ErrorKind::AlreadyExists, refused.unwrap_err().kind());
+        assert!(matches!(
+            refused_atomically,
+            Err(crate::error::Error::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists
+        ));
+        assert!(!called);
+        assert_eq!(b"first".to_vec(), after_refusal);
+        assert_eq!(b"forced".to_vec(), forced);
+        assert_eq!(1, files);
+    }
+
+    #[test]
+    fn inspect_reads_only_the_header() {
+        let input = b"inspect me ".repeat(1000);
+        let lz77_bytes = lz77::Lz77Compression::new()
+            .compress_to_vec(&input)
+            .unwrap();
+        let mut reader = io::Cursor::new(&lz77_bytes);
+        assert_eq!(
+            FileInfo {
+                algorithm: "lz77",
+                version: Format::Lz77.version(),
+                original_len: Some(input.len()),
+                has_checksum: false,
+                content_hash: None,
+            },
+            inspect(&mut reader).unwrap()
+        );
+        assert!(reader.position() <= lz77::MAX_HEADER_SIZE as u64);
+
+        let hashed = lz77::Lz77Compression::new()
+            .with_content_hash()
+            .compress_to_vec(&input)
+            .unwrap();
+        let info = inspect(&mut &hashed[..]).unwrap();
+        assert!(info.has_checksum);
+        assert_eq!(Some(md5::compute(&input).0), info.content_hash);
+
+        let block_bytes = block_compress::BlockCompression::new()
+            .compress_to_vec(&input)
+            .unwrap();
+        let info = inspect(&mut &block_bytes[..]).unwrap();
+        assert_eq!("block", info.algorithm);
+        assert_eq!(Format::Block.version(), info.version);
+        assert_eq!(None, info.original_len);
+        assert!(info.has_checksum);
+    }
+
+    // accepts at most 3 bytes a write, then fails once it holds 9
+    struct ShortWrites(Vec<u8>);
+
+    impl Write for ShortWrites {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0.len() == 9 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn counting_writer_counts_what_the_inner_writer_accepts() {
+        let mut counter = CountingWriter::new(Vec::new());
+        counter.write_all(b"abcde").unwrap();
+        counter.write_all(b"").unwrap();
+        counter.write_all(b"fg").unwrap();
+        assert_eq!(7, counter.count());
+        assert_eq!(b"abcdefg", &counter.into_inner()[..]);
+
+        // only what was taken from each short write, and nothing for a failed one
+        let mut counter = CountingWriter::new(ShortWrites(Vec::new()));
+        assert_eq!(3, counter.write(b"abcde").unwrap());
+        assert_eq!(3, counter.count());
+        assert!(counter.write_all(b"fghijklm").is_err());
+        assert_eq!(9, counter.count());
+        assert_eq!(b"abcfghijk", &counter.get_ref().0[..]);
+    }
+
+    #[test]
+    fn compressed_bytes_match_what_was_written() {
+        let mut input = b"some text, some text, some text".repeat(200);
+        input.extend(vec![7; 1000]);
+        for (name, constructor) in registry() {
+            let compressor = constructor();
+            let mut counter = CountingWriter::new(io::sink());
+            let stats = compressor.compress(&mut &input[..], &mut counter).unwrap();
+            assert_eq!(counter.count() as usize, stats.compressed_bytes, "{}", name);
+        }
+        let streaming = block_compress::BlockCompression::new().with_streaming();
+        let mut counter = CountingWriter::new(io::sink());
+        let stats = streaming.compress(&mut &input[..], &mut counter).unwrap();
+        assert_eq!(counter.count() as usize, stats.compressed_bytes);
+    }
+
+    #[test]
+    fn decompressed_size_reads_headers_or_counts() {
+        let input = b"how big will this be? ".repeat(500);
+        for (name, constructor) in registry() {
+            let compressor = constructor();
+            let compressed = compressor.compress_to_vec(&input).unwrap();
+            assert_eq!(
+                input.len() as u64,
+                compressor.decompressed_size(&mut &compressed[..]).unwrap(),
+                "{}",
+                name
+            );
+            assert!(compressor
+                .decompressed_size(&mut &compressed[..compressed.len() / 2])
+                .is_err());
+        }
+
+        // lz77 adds up the headers of concatenated streams without decoding them
+        let lz77 = lz77::Lz77Compression::new();
+        let mut concatenated = lz77.encode(&input);
+        concatenated.extend(lz77.encode(b"abc"));
+        concatenated.extend(lz77.encode(&[0xff; 10]));
+        assert_eq!(
+            input.len() as u64 + 13,
+            lz77.decompressed_size(&mut &concatenated[..]).unwrap()
+        );
+        let limited = lz77::Lz77Compression::new().with_max_output_bytes(10);
+        assert_eq!(
+            input.len() as u64 + 13,
+            limited.decompressed_size(&mut &concatenated[..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn inspect_rejects_other_bytes() {
+        let lz77_bytes = lz77::Lz77Compression::new()
+            .compress_to_vec(b"abc")
+            .unwrap();
+        for not_a_stream in [&b""[..], b"plain text, not compressed", &lz77_bytes[..6]] {
+            assert!(matches!(
+                inspect(&mut &not_a_stream[..]),
+                Err(crate::error::Error::Deserialise(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn registry_names_match_the_algorithms() {
+        let algorithms = registry();
+        assert!(algorithms.len() >= 2);
+        for (name, constructor) in algorithms {
+            assert_eq!(name, constructor().name());
+        }
+    }
+
+    #[test]
+    fn compress_with_stats_describes_its_output() {
+        let input = b"stats for the bytes they describe. ".repeat(200);
+        for (name, constructor) in registry() {
+            let compressor = constructor();
+            let (compressed, stats) = compressor.compress_with_stats(&input).unwrap();
+
+            assert_eq!(
+                compressor.compress_to_vec(&input).unwrap(),
+                compressed,
+                "{}",
+                name
+            );
+            assert_eq!(input.len(), stats.original_bytes, "{}", name);
+            assert_eq!(compressed.len(), stats.compressed_bytes, "{}", name);
+            assert_eq!(
+                compressed.len() as f64 / input.len() as f64,
+                stats.ratio(),
+                "{}",
+                name
+            );
+            assert!(stats.elapsed.is_some(), "{}", name);
+            assert_eq!(
+                input,
+                compressor.decompress_to_vec(&compressed).unwrap(),
+                "{}",
+                name
+            );
+        }
+
+        let lz77 = lz77::Lz77Compression::new();
+        let (_, stats) = lz77.compress_with_stats(&input).unwrap();
+        assert_eq!(Some(lz77.nodes(&input).count()), stats.node_count());
+        assert_eq!(None, stats.unique_blocks);
+
+        let (_, stats) = block_compress::BlockCompression::new()
+            .compress_with_stats(&input)
+            .unwrap();
+        assert_eq!(None, stats.node_count());
+        assert!(stats.unique_blocks.is_some());
+    }
+
+    #[test]
+    fn stats_report_ratio_and_expansion() {
+        let shrunk = CompressionStats {
+            original_bytes: 200,
+            compressed_bytes: 50,
+            ..CompressionStats::default()
+        };
+        assert_eq!(0.25, shrunk.ratio());
+        assert!(!shrunk.expanded());
+        assert_eq!(
+            "Original size: 200, compressed size: 50, ratio: 0.2500",
+            shrunk.to_string()
+        );
+
+        let grown = CompressionStats {
+            original_bytes: 0,
+            compressed_bytes: 8,
+            content_hash: Some([0xab; 16]),
+            ..CompressionStats::default()
+        };
+        assert_eq!(1.0, grown.ratio());
+        assert!(grown.expanded());
+        assert_eq!(Some("ab".repeat(16)), grown.content_hash_hex());
+        assert_eq!(None, shrunk.content_hash_hex());
+    }
 }
@@ -0,0 +1,307 @@
+//! Chains algorithms, feeding the output of each into the next.
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::prelude::*;
+
+use crate::compression::{registry, Algorithm, CompressionStats};
+use crate::error::{DeserialiseError, Error, Result};
+use crate::frame::{self, Format};
+
+/// Compresses with each stage in turn, e.g. block deduplication and then lz77 over what's left.
+///
+/// Layout: the frame header, the number of stages, each stage's name as a length byte followed
+/// by its bytes, then the output of the last stage.
+///
+/// Pipelines don't nest: a stage that is itself a pipeline fails to compress, and a stream
+/// naming one as a stage is rejected, so a crafted stream can't recurse without end.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Algorithm>>,
+}
+
+impl Pipeline {
+    /// A pipeline with no stages, which stores its input as-is.
+    pub fn new() -> Self {
+        Pipeline { stages: Vec::new() }
+    }
+
+    /// Adds `stage` after the stages already added, so it compresses their output.
+    pub fn with_stage<A: Algorithm + 'static>(mut self, stage: A) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// The names of the stages, in the order they compress.
+    pub fn stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+}
+
+/// The stage names recorded at the start of a pipeline stream, in the order they compressed,
+/// along with the bytes the last of them wrote.
+pub fn read_chain(bytes: &[u8]) -> Result<(Vec<String>, &[u8])> {
+    let (header, mut rest) = frame::read_header(bytes)?;
+    header.expect(Format::Pipeline)?;
+    let count = take(&mut rest, 1)?[0];
+    let mut names = Vec::with_capacity(count.into());
+    for _ in 0..count {
+        let len = take(&mut rest, 1)?[0];
+        let name = std::str::from_utf8(take(&mut rest, len.into())?)
+            .map_err(|_| DeserialiseError::UnknownStage)?;
+        if name == Format::Pipeline.name() {
+            return Err(DeserialiseError::NestedPipeline.into());
+        }
+        names.push(name.to_string());
+    }
+    Ok((names, rest))
+}
+
+/// The first `len` bytes of `bytes`, which then start after them.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(DeserialiseError::Truncated.into());
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+impl Algorithm for Pipeline {
+    fn name(&self) -> &'static str {
+        Format::Pipeline.name()
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        Format::Pipeline.magic()
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<CompressionStats> {
+        let names = self.stage_names();
+        if names.contains(&Format::Pipeline.name()) {
+            return Err(Error::InvalidStages);
+        }
+        let mut header = Vec::new();
+        frame::write_header(&mut header, frame::Header::current(Format::Pipeline));
+        header.push(u8::try_from(names.len()).map_err(|_| Error::InvalidStages)?);
+        for name in names {
+            header.push(u8::try_from(name.len()).map_err(|_| Error::InvalidStages)?);
+            header.extend_from_slice(name.as_bytes());
+        }
+
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        let original_bytes = bytes.len();
+        for stage in &self.stages {
+            bytes = stage.compress_to_vec(&bytes)?;
+        }
+        output.write_all(&header)?;
+        output.write_all(&bytes)?;
+        output.flush()?;
+        Ok(CompressionStats {
+            original_bytes,
+            compressed_bytes: header.len() + bytes.len(),
+            ..CompressionStats::default()
+        })
+    }
+
+    /// Undoes the stages recorded in the stream, last first, with this pipeline's stages,
+    /// which must be the ones recorded. A pipeline without stages undoes any chain, each stage
+    /// with the registry's default settings.
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+        let mut compressed = Vec::new();
+        input.read_to_end(&mut compressed)?;
+        let (names, body) = read_chain(&compressed)?;
+        let mut defaults = Vec::new();
+        let stages = if self.stages.is_empty() {
+            let algorithms = registry();
+            for name in &names {
+                let constructor = algorithms
+                    .get(name.as_str())
+                    .ok_or(DeserialiseError::UnknownStage)?;
+                defaults.push(constructor());
+            }
+            &defaults
+        } else if names.iter().map(String::as_str).eq(self.stage_names()) {
+            &self.stages
+        } else {
+            return Err(DeserialiseError::StageMismatch.into());
+        };
+
+        let mut bytes = Cow::Borrowed(body);
+        for stage in stages.iter().rev() {
+            bytes = Cow::Owned(stage.decompress_to_vec(&bytes)?);
+        }
+        output.write_all(&bytes)?;
+        output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_compress::BlockCompression;
+    use crate::error::Error;
+    use crate::lz77::Lz77Compression;
+    use crate::test_util::ScratchDir;
+    use std::fs::{self, File};
+
+    #[test]
+    fn two_stage_pipelines_round_trip_files() {
+        let dir = ScratchDir::new("pipeline");
+        let plain = dir.join("plain");
+        let compressed = dir.join("compressed");
+        let decompressed = dir.join("decompressed");
+        let input = b"a line that repeats, more or less. ".repeat(500);
+        fs::write(&plain, &input).unwrap();
+
+        let pipeline = Pipeline::new()
+            .with_stage(Lz77Compression::new())
+            .with_stage(BlockCompression::new());
+        let stats = pipeline
+            .compress_file(
+                File::open(&plain).unwrap(),
+                compressed.to_str().unwrap(),
+                false,
+            )
+            .unwrap();
+        // any pipeline can undo the chain recorded in the stream
+        Pipeline::new()
+            .decompress_file(
+                File::open(&compressed).unwrap(),
+                decompressed.to_str().unwrap(),
+                false,
+            )
+            .unwrap();
+        let compressed_bytes = fs::read(&compressed).unwrap();
+        let decompressed_bytes = fs::read(&decompressed).unwrap();
+
+        assert_eq!(input, decompressed_bytes);
+        assert_eq!(input.len(), stats.original_bytes);
+        assert_eq!(compressed_bytes.len(), stats.compressed_bytes);
+
+        let (names, body) = read_chain(&compressed_bytes).unwrap();
+        assert_eq!(vec!["lz77", "block"], names);
+        assert_eq!(
+            &compressed_bytes[frame::HEADER_SIZE..frame::HEADER_SIZE + 12],
+            b"\x02\x04lz77\x05block"
+        );
+        // the body is the last stage's output, undone first
+        let lz77_bytes = BlockCompression::new().decompress_to_vec(body).unwrap();
+        assert_eq!(
+            input,
+            Lz77Compression::new()
+                .decompress_to_vec(&lz77_bytes)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_or_truncated_chains_are_rejected() {
+        let pipeline = Pipeline::new().with_stage(Lz77Compression::new());
+        let compressed = pipeline.compress_to_vec(b"abcabcabc").unwrap();
+        assert_eq!(
+            b"abcabcabc".to_vec(),
+            pipeline.decompress_to_vec(&compressed).unwrap()
+        );
+
+        let mut renamed = compressed.clone();
+        renamed[frame::HEADER_SIZE + 2..frame::HEADER_SIZE + 6].copy_from_slice(b"lz78");
+        assert!(matches!(
+            Pipeline::new().decompress_to_vec(&renamed),
+            Err(Error::Deserialise(DeserialiseError::UnknownStage))
+        ));
+        assert!(matches!(
+            pipeline.decompress_to_vec(&renamed),
+            Err(Error::Deserialise(DeserialiseError::StageMismatch))
+        ));
+        assert!(matches!(
+            pipeline.decompress_to_vec(&compressed[..frame::HEADER_SIZE + 3]),
+            Err(Error::Deserialise(DeserialiseError::Truncated))
+        ));
+        let lz77_bytes = Lz77Compression::new().compress_to_vec(b"abc").unwrap();
+        assert!(matches!(
+            pipeline.decompress_to_vec(&lz77_bytes),
+            Err(Error::Deserialise(DeserialiseError::WrongAlgorithm { .. }))
+        ));
+    }
+
+    #[test]
+    fn stages_keep_their_settings_when_decompressing() {
+        let dictionary: &[u8] = b"a line that repeats, more or less. ";
+        let input = b"a line that repeats, more or less, but not quite. ".repeat(20);
+        let pipeline = Pipeline::new()
+            .with_stage(BlockCompression::new())
+            .with_stage(Lz77Compression::new().with_dictionary(dictionary));
+        let compressed = pipeline.compress_to_vec(&input).unwrap();
+
+        assert_eq!(input, pipeline.decompress_to_vec(&compressed).unwrap());
+        // the registry's lz77 has no dictionary, and the wrong stages are refused outright
+        assert!(Pipeline::new().decompress_to_vec(&compressed).is_err());
+        assert!(matches!(
+            Pipeline::new()
+                .with_stage(Lz77Compression::new().with_dictionary(dictionary))
+                .decompress_to_vec(&compressed),
+            Err(Error::Deserialise(DeserialiseError::StageMismatch))
+        ));
+
+        let capped = Pipeline::new().with_stage(Lz77Compression::new().with_max_output_bytes(100));
+        let compressed = capped.compress_to_vec(&input).unwrap();
+        assert!(matches!(
+            capped.decompress_to_vec(&compressed),
+            Err(Error::OutputLimitExceeded { limit: 100 })
+        ));
+    }
+
+    #[test]
+    fn pipelines_do_not_nest() {
+        let nested = Pipeline::new().with_stage(Pipeline::new());
+        assert!(matches!(
+            nested.compress_to_vec(b"abc"),
+            Err(Error::InvalidStages)
+        ));
+
+        // a stream naming itself as its stage, over and over, is rejected up front
+        let mut crafted = Vec::new();
+        frame::write_header(&mut crafted, frame::Header::current(Format::Pipeline));
+        crafted.extend_from_slice(b"\x01\x08pipeline");
+        let mut nested_stream = crafted.clone();
+        for _ in 0..10_000 {
+            nested_stream.extend_from_slice(&crafted);
+        }
+        assert!(matches!(
+            Pipeline::new().decompress_to_vec(&nested_stream),
+            Err(Error::Deserialise(DeserialiseError::NestedPipeline))
+        ));
+    }
+
+    #[test]
+    fn stage_names_must_fit_a_length_byte() {
+        struct LongName;
+        impl Algorithm for LongName {
+            fn name(&self) -> &'static str {
+                concat!(
+                    "a stage whose name is far too long to record in the single byte the ",
+                    "pipeline has for each name's length, which is a good deal longer than any ",
+                    "algorithm needs, but which still has to fail cleanly rather than writing a ",
+                    "stream whose chain of stage names can't be read back by any pipeline",
+                )
+            }
+            fn magic(&self) -> &'static [u8] {
+                b"LONG"
+            }
+            fn compress(&self, _: &mut dyn Read, _: &mut dyn Write) -> Result<CompressionStats> {
+                Ok(CompressionStats::default())
+            }
+            fn decompress(&self, _: &mut dyn Read, _: &mut dyn Write) -> Result<()> {
+                Ok(())
+            }
+        }
+        assert!(LongName.name().len() > 255);
+        assert!(matches!(
+            Pipeline::new().with_stage(LongName).compress_to_vec(b"abc"),
+            Err(Error::InvalidStages)
+        ));
+    }
+}
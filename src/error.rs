@@ -0,0 +1,303 @@
+use alloc::string::String;
+use core::{fmt, str::Utf8Error};
+#[cfg(feature = "std")]
+use std::{error, io};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Everything that can go wrong compressing or decompressing.
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// The block format could not be (de)serialised.
+    #[cfg(feature = "std")]
+    Bincode(bincode::Error),
+    /// The compressed lz77 stream is malformed.
+    Deserialise(DeserialiseError),
+    /// Decompressed bytes that were expected to be text aren't valid UTF-8.
+    Utf8(Utf8Error),
+    /// A search window size outside the 1 to 65535 bytes the format can describe.
+    InvalidWindowSize(usize),
+    /// A file name to record in the header that isn't a plain name of at most 255 bytes.
+    InvalidFileName(String),
+    /// A recorded modification time, in seconds since the Unix epoch, too far off for this
+    /// platform to set on a file.
+    InvalidModifiedTime(u64),
+    /// The buffer given to decode into is shorter than the decompressed bytes.
+    OutputTooSmall { needed: usize, available: usize },
+    /// A pipeline with more than 255 stages, a stage name longer than 255 bytes, or a stage
+    /// that is itself a pipeline.
+    InvalidStages,
+    /// Decompressing would produce more bytes than the configured maximum.
+    OutputLimitExceeded { limit: usize },
+    /// The decompressed bytes couldn't be allocated.
+    OutOfMemory { needed: usize },
+    /// A range of decompressed bytes asked for runs past the end of the stream.
+    RangeOutOfBounds { end: u64, len: u64 },
+}
+
+/// Reasons a compressed stream can't be decoded.
+#[derive(Debug, PartialEq)]
+pub enum DeserialiseError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownMode(u8),
+    /// The stream starts with the magic bytes of a different algorithm.
+    WrongAlgorithm {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The stream ended part way through a node or before the end marker.
+    Truncated,
+    /// A reference length decoded to more than a `u16` can hold, or the header's original
+    /// length to more than a `usize`.
+    InvalidLength,
+    /// The nodes decode to a different number of bytes than the header's original length.
+    LengthMismatch,
+    /// An archive entry's name isn't a plain file name.
+    InvalidArchiveEntry,
+    /// The header's search window size is 0.
+    InvalidWindowSize,
+    /// The header's original file name isn't a plain file name.
+    InvalidFileName,
+    /// The header's fixed offset width is 0 or wider than any window needs.
+    InvalidOffsetBits(u8),
+    /// A block map entry or block length points outside the stored blocks.
+    InvalidBlock,
+    /// The decompressed data doesn't match the checksum stored with it.
+    ChecksumMismatch,
+    /// A sync marker is missing its pattern, or records a different position than the bytes
+    /// decoded before it.
+    BadSyncMarker,
+    /// The bits padding a marker out to a whole byte aren't all zero.
+    NonZeroPadding,
+    /// The block index at the end of a seekable stream is missing or inconsistent.
+    InvalidIndex,
+    /// A pipeline stage names an algorithm that isn't in the registry.
+    UnknownStage,
+    /// A pipeline stage is itself a pipeline.
+    NestedPipeline,
+    /// The stages recorded in a pipeline stream aren't the ones the pipeline was built with.
+    StageMismatch,
+    /// A stream continuing the one before it has a different search window size.
+    WindowChanged,
+    /// The unique block at this index doesn't match the md5 stored for it.
+    BlockHashMismatch(usize),
+    /// A reference points outside the history the decoder has available.
+    InvalidReference {
+        offset: u16,
+        length: u16,
+        available: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            Error::Io(err) => write!(f, "{}", err),
+            #[cfg(feature = "std")]
+            Error::Bincode(err) => write!(f, "Could not (de)serialise block data: {}", err),
+            Error::Deserialise(err) => write!(f, "Corrupt compressed stream: {}", err),
+            Error::Utf8(err) => write!(f, "Decompressed data is not valid UTF-8: {}", err),
+            Error::InvalidWindowSize(size) => write!(
+                f,
+                "Unsupported search window size {}, it must be between 1 and 65535",
+                size
+            ),
+            Error::InvalidFileName(name) => write!(
+                f,
+                "Can't record {:?} as the original file name, it must be a plain name of at most \
+                 255 bytes",
+                name
+            ),
+            Error::InvalidModifiedTime(secs) => write!(
+                f,
+                "Can't restore a modification time {} seconds after the epoch on this platform",
+                secs
+            ),
+            Error::InvalidStages => write!(
+                f,
+                "A pipeline can record at most 255 stages, each named in at most 255 bytes, \
+                 and none of them a pipeline"
+            ),
+            Error::OutputTooSmall { needed, available } => write!(
+                f,
+                "Decompressing needs {} bytes of output but only {} were given",
+                needed, available
+            ),
+            Error::OutputLimitExceeded { limit } => write!(
+                f,
+                "Decompressing would produce more than the {} byte output limit",
+                limit
+            ),
+            Error::OutOfMemory { needed } => write!(
+                f,
+                "Could not allocate {} bytes for the decompressed output",
+                needed
+            ),
+            Error::RangeOutOfBounds { end, len } => write!(
+                f,
+                "The range ends at byte {} but the stream only decompresses to {} bytes",
+                end, len
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DeserialiseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeserialiseError::BadMagic => {
+                write!(
+                    f,
+                    "not a sloppy-compressor stream, the magic bytes are missing"
+                )
+            }
+            DeserialiseError::WrongAlgorithm { expected, found } => write!(
+                f,
+                "this file was compressed with '{}', not '{}'",
+                found, expected
+            ),
+            DeserialiseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            DeserialiseError::UnknownMode(mode) => write!(f, "unknown lz77 storage mode {}", mode),
+            DeserialiseError::Truncated => write!(f, "the stream ended before its end marker"),
+            DeserialiseError::InvalidLength => {
+                write!(f, "a length is larger than the format allows")
+            }
+            DeserialiseError::LengthMismatch => {
+                write!(f, "the stream does not decode to the length in its header")
+            }
+            DeserialiseError::InvalidArchiveEntry => {
+                write!(f, "an archive entry is not named with a plain file name")
+            }
+            DeserialiseError::InvalidWindowSize => {
+                write!(f, "the header's search window size is 0")
+            }
+            DeserialiseError::InvalidFileName => {
+                write!(
+                    f,
+                    "the header's original file name is not a plain file name"
+                )
+            }
+            DeserialiseError::InvalidOffsetBits(bits) => {
+                write!(f, "invalid fixed offset width of {} bits", bits)
+            }
+            DeserialiseError::InvalidBlock => {
+                write!(f, "a block reference falls outside the stored blocks")
+            }
+            DeserialiseError::BadSyncMarker => {
+                write!(f, "a sync marker does not match the bytes before it")
+            }
+            DeserialiseError::InvalidIndex => {
+                write!(
+                    f,
+                    "the seekable index at the end of the stream is missing or damaged"
+                )
+            }
+            DeserialiseError::NonZeroPadding => {
+                write!(f, "the padding after a marker is not all zero bits")
+            }
+            DeserialiseError::UnknownStage => {
+                write!(f, "a pipeline stage names an unknown algorithm")
+            }
+            DeserialiseError::NestedPipeline => {
+                write!(f, "a pipeline stage is itself a pipeline")
+            }
+            DeserialiseError::StageMismatch => {
+                write!(
+                    f,
+                    "the pipeline's stages don't match those recorded in the stream"
+                )
+            }
+            DeserialiseError::WindowChanged => {
+                write!(
+                    f,
+                    "a stream continuing the one before it has a different search window size"
+                )
+            }
+            DeserialiseError::BlockHashMismatch(index) => {
+                write!(f, "stored block {} does not match its md5", index)
+            }
+            DeserialiseError::ChecksumMismatch => {
+                write!(f, "the decompressed data does not match its checksum")
+            }
+            DeserialiseError::InvalidReference {
+                offset,
+                length,
+                available,
+            } => write!(
+                f,
+                "reference (offset: {}, length: {}) falls outside the {} bytes of history",
+                offset, length, available
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Bincode(err) => Some(err),
+            Error::Deserialise(err) => Some(err),
+            Error::Utf8(err) => Some(err),
+            Error::InvalidWindowSize(_)
+            | Error::InvalidFileName(_)
+            | Error::InvalidModifiedTime(_)
+            | Error::InvalidStages
+            | Error::OutputTooSmall { .. }
+            | Error::OutputLimitExceeded { .. }
+            | Error::OutOfMemory { .. }
+            | Error::RangeOutOfBounds { .. } => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for DeserialiseError {}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// For `Read` and `Write` implementations, which can only fail with an `io::Error`. Corrupt
+/// streams, including block data bincode couldn't read, are `InvalidData`.
+#[cfg(feature = "std")]
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::Deserialise(_) | Error::Utf8(_) | Error::Bincode(_) => {
+                io::Error::new(io::ErrorKind::InvalidData, err)
+            }
+            err => io::Error::other(err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Bincode(err)
+    }
+}
+
+impl From<DeserialiseError> for Error {
+    fn from(err: DeserialiseError) -> Self {
+        Error::Deserialise(err)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(err: Utf8Error) -> Self {
+        Error::Utf8(err)
+    }
+}
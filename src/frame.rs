@@ -0,0 +1,163 @@
+//! The magic bytes and format version every compressed stream starts with, whichever algorithm
+//! wrote it. Each algorithm follows this with its own header fields and body.
+
+use alloc::vec::Vec;
+
+use crate::error::DeserialiseError;
+
+/// The magic bytes followed by the format version.
+pub const HEADER_SIZE: usize = 5;
+const MAGIC_SIZE: usize = 4;
+
+/// The stream formats, one per algorithm.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Format {
+    Lz77,
+    Block,
+    Pipeline,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Lz77, Format::Block, Format::Pipeline];
+
+    /// The algorithm name used on the CLI.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Lz77 => "lz77",
+            Format::Block => "block",
+            Format::Pipeline => "pipeline",
+        }
+    }
+
+    pub fn magic(self) -> &'static [u8; MAGIC_SIZE] {
+        match self {
+            Format::Lz77 => b"SLZ7",
+            Format::Block => b"SLZB",
+            Format::Pipeline => b"SLZP",
+        }
+    }
+
+    /// The version written, and the only one read.
+    pub fn version(self) -> u8 {
+        match self {
+            // Version 1 was the original headerless stream with 7 and 11 bit offsets only,
+            // version 2 had no storage mode, version 3 stored bytes without a length and
+            // version 4 gave only the stored bytes a length, rather than recording the
+            // original length in the header. Version 5 had no run nodes.
+            // Version 6 had no fixed offsets. Version 7 had no content hash.
+            // Version 8 had no repeat offsets. Version 9 had no original file. Version 10
+            // couldn't continue the stream before.
+            Format::Lz77 => 11,
+            // Version 1 stored each unique block with its own length prefix and a fixed width
+            // u32 per block map entry. Version 2 had no checksum, version 3 didn't name the
+            // hasher, version 4 couldn't store the input as-is, version 5 couldn't hash each
+            // block, version 6 couldn't stream them and version 7 serialised the blocks with
+            // bincode.
+            Format::Block => 8,
+            Format::Pipeline => 1,
+        }
+    }
+
+    /// The format whose magic bytes `bytes` starts with, if any.
+    pub fn detect(bytes: &[u8]) -> Option<Format> {
+        Format::ALL
+            .iter()
+            .copied()
+            .find(|format| bytes.starts_with(format.magic()))
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Header {
+    pub format: Format,
+    pub version: u8,
+}
+
+impl Header {
+    /// The header for streams written by this version of the crate.
+    pub fn current(format: Format) -> Self {
+        Header {
+            format,
+            version: format.version(),
+        }
+    }
+
+    /// Fails with `WrongAlgorithm` unless the stream was written in `format`.
+    pub fn expect(&self, format: Format) -> Result<(), DeserialiseError> {
+        if self.format != format {
+            return Err(DeserialiseError::WrongAlgorithm {
+                expected: format.name(),
+                found: self.format.name(),
+            });
+        }
+        Ok(())
+    }
+}
+
+pub fn write_header(output: &mut Vec<u8>, header: Header) {
+    output.extend_from_slice(header.format.magic());
+    output.push(header.version);
+}
+
+/// Recognises the format from the magic bytes and checks its version is the current one,
+/// returning the header along with the bytes that follow it.
+pub fn read_header(bytes: &[u8]) -> Result<(Header, &[u8]), DeserialiseError> {
+    let format = Format::detect(bytes).ok_or(DeserialiseError::BadMagic)?;
+    let version = *bytes.get(MAGIC_SIZE).ok_or(DeserialiseError::Truncated)?;
+    if version != format.version() {
+        return Err(DeserialiseError::UnsupportedVersion(version));
+    }
+    Ok((Header { format, version }, &bytes[HEADER_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_round_trip() {
+        for &format in &Format::ALL {
+            let mut bytes = Vec::new();
+            write_header(&mut bytes, Header::current(format));
+            assert_eq!(HEADER_SIZE, bytes.len());
+            bytes.push(0xff);
+
+            let (header, rest) = read_header(&bytes).unwrap();
+            assert_eq!(Header::current(format), header);
+            assert_eq!(&[0xff], rest);
+            assert_eq!(Ok(()), header.expect(format));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_unknown_versions() {
+        assert_eq!(Err(DeserialiseError::BadMagic), read_header(b""));
+        assert_eq!(Err(DeserialiseError::BadMagic), read_header(b"SLZ"));
+        assert_eq!(
+            Err(DeserialiseError::BadMagic),
+            read_header(b"not a stream")
+        );
+        assert_eq!(Err(DeserialiseError::Truncated), read_header(b"SLZ7"));
+
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, Header::current(Format::Block));
+        bytes[MAGIC_SIZE] += 1;
+        assert_eq!(
+            Err(DeserialiseError::UnsupportedVersion(
+                Format::Block.version() + 1
+            )),
+            read_header(&bytes)
+        );
+    }
+
+    #[test]
+    fn expecting_another_format_names_both() {
+        assert_eq!(
+            Err(DeserialiseError::WrongAlgorithm {
+                expected: "lz77",
+                found: "block",
+            }),
+            Header::current(Format::Block).expect(Format::Lz77)
+        );
+    }
+}
@@ -1,3 +1,15 @@
+//! The lz77 codec only needs `alloc`. Everything built on `std::io`, files and threads sits
+//! behind the default `std` feature.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod block_compress;
+#[cfg(feature = "std")]
 pub mod compression;
+pub mod error;
+pub mod frame;
 pub mod lz77;
+#[cfg(test)]
+mod test_util;
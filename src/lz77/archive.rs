@@ -0,0 +1,141 @@
+use std::convert::TryFrom;
+use std::str;
+
+use super::original_file::is_plain_file_name;
+use crate::error::DeserialiseError;
+
+/// Packs named files into a single buffer so they can be compressed as one stream.
+///
+/// Layout: a big-endian `u32` entry count, then per entry a big-endian `u16` name length, the
+/// UTF-8 name and a big-endian `u64` file length. The file contents follow, concatenated in
+/// table order.
+pub fn write_archive(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    archive.extend_from_slice(&u32::try_from(files.len()).unwrap().to_be_bytes());
+    for (name, contents) in files {
+        archive.extend_from_slice(&u16::try_from(name.len()).unwrap().to_be_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(&(contents.len() as u64).to_be_bytes());
+    }
+    for (_, contents) in files {
+        archive.extend_from_slice(contents);
+    }
+    archive
+}
+
+/// Splits an archive back into its named files.
+///
+/// Names are relative paths, with `/` between directories. Every part must be a plain name,
+/// not `.`, `..` or empty, so an archive can't write outside the directory it is extracted
+/// into.
+pub fn read_archive(archive: &[u8]) -> Result<Vec<(&str, &[u8])>, DeserialiseError> {
+    let entry_count = u32::from_be_bytes(read_array(archive, 0)?) as usize;
+    let mut ptr = 4;
+
+    let mut table = Vec::new();
+    for _ in 0..entry_count {
+        let name_len = u16::from_be_bytes(read_array(archive, ptr)?) as usize;
+        ptr += 2;
+        let name_bytes = archive
+            .get(ptr..ptr + name_len)
+            .ok_or(DeserialiseError::Truncated)?;
+        let name = str::from_utf8(name_bytes).map_err(|_| DeserialiseError::InvalidArchiveEntry)?;
+        if !is_relative_path(name) {
+            return Err(DeserialiseError::InvalidArchiveEntry);
+        }
+        ptr += name_len;
+        let length = u64::from_be_bytes(read_array(archive, ptr)?);
+        ptr += 8;
+        table.push((name, length));
+    }
+
+    let mut files = Vec::with_capacity(table.len());
+    for (name, length) in table {
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| ptr.checked_add(length))
+            .filter(|&end| end <= archive.len())
+            .ok_or(DeserialiseError::Truncated)?;
+        files.push((name, &archive[ptr..end]));
+        ptr = end;
+    }
+    Ok(files)
+}
+
+fn is_relative_path(name: &str) -> bool {
+    name.split('/').all(is_plain_file_name)
+}
+
+fn read_array<const N: usize>(bytes: &[u8], index: usize) -> Result<[u8; N], DeserialiseError> {
+    bytes
+        .get(index..index + N)
+        .map(|b| <[u8; N]>::try_from(b).unwrap())
+        .ok_or(DeserialiseError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_round_trips() {
+        let files = vec![
+            ("a.txt".to_string(), b"hello".to_vec()),
+            ("empty".to_string(), vec![]),
+            ("b.txt".to_string(), b"world".to_vec()),
+        ];
+        let archive = write_archive(&files);
+
+        let read = read_archive(&archive).unwrap();
+        assert_eq!(
+            vec![
+                ("a.txt", &b"hello"[..]),
+                ("empty", &[][..]),
+                ("b.txt", &b"world"[..])
+            ],
+            read
+        );
+    }
+
+    #[test]
+    fn truncated_archives_are_rejected() {
+        let archive = write_archive(&[("a".to_string(), vec![1, 2, 3])]);
+        for len in 0..archive.len() {
+            assert_eq!(
+                Err(DeserialiseError::Truncated),
+                read_archive(&archive[..len])
+            );
+        }
+    }
+
+    #[test]
+    fn names_that_leave_the_output_directory_are_rejected() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../escape",
+            "dir/../../escape",
+            "/absolute",
+            "dir/",
+            "dir//file",
+            "dir\\file",
+            "..\\escape",
+        ] {
+            let archive = write_archive(&[(name.to_string(), vec![])]);
+            assert_eq!(
+                Err(DeserialiseError::InvalidArchiveEntry),
+                read_archive(&archive)
+            );
+        }
+    }
+
+    #[test]
+    fn nested_names_are_allowed() {
+        let archive = write_archive(&[("dir/sub/file.txt".to_string(), b"nested".to_vec())]);
+        assert_eq!(
+            vec![("dir/sub/file.txt", &b"nested"[..])],
+            read_archive(&archive).unwrap()
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use crate::error::DeserialiseError;
+
+/// Reads bits from bytes, most significant bit first, the counterpart to `BitWriter`. Reading
+/// past the end is a `Truncated` error rather than a panic.
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// Bits available, which is fewer than `bytes` holds only in tests.
+    len: usize,
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader {
+            bytes,
+            len: bytes.len() * 8,
+            position: 0,
+        }
+    }
+
+    /// A reader over only the first `len` bits of `bytes`, for streams that end part way
+    /// through a byte.
+    #[cfg(test)]
+    pub fn with_len(bytes: &'a [u8], len: usize) -> Self {
+        assert!(len <= bytes.len() * 8);
+        BitReader {
+            bytes,
+            len,
+            position: 0,
+        }
+    }
+
+    /// Returns the next `count` bits as a big-endian integer without consuming them. `count`
+    /// must be no more than 16.
+    pub fn peek_bits(&self, count: usize) -> Result<u16, DeserialiseError> {
+        debug_assert!(count <= 16);
+        if self.len - self.position < count {
+            return Err(DeserialiseError::Truncated);
+        }
+        // the bits always fall within the 3 bytes from the current one
+        let first = self.position / 8;
+        let byte = |i: usize| u32::from(self.bytes.get(first + i).copied().unwrap_or(0));
+        let window = byte(0) << 16 | byte(1) << 8 | byte(2);
+        let shift = 24 - self.position % 8 - count;
+        Ok((window >> shift & ((1 << count) - 1)) as u16)
+    }
+
+    /// Reads the next `count` bits as a big-endian integer. `count` must be no more than 16.
+    pub fn read_bits(&mut self, count: usize) -> Result<u16, DeserialiseError> {
+        let value = self.peek_bits(count)?;
+        self.position += count;
+        Ok(value)
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, DeserialiseError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Bits read so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Reads up to the next byte boundary, failing with `NonZeroPadding` unless every bit
+    /// skipped is zero, as `BitWriter` pads them.
+    pub fn read_padding(&mut self) -> Result<(), DeserialiseError> {
+        let count = ((8 - self.position % 8) % 8).min(self.len - self.position);
+        if self.read_bits(count)? != 0 {
+            return Err(DeserialiseError::NonZeroPadding);
+        }
+        Ok(())
+    }
+
+    /// The bytes after the one holding the last bit read, skipping any padding.
+    pub fn remaining_bytes(&self) -> &'a [u8] {
+        &self.bytes[self.position.div_ceil(8)..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bits_most_significant_first() {
+        let mut reader = BitReader::new(&[0b1010_1010, 0b1011_1100, 0b1101_0000]);
+        assert_eq!(Ok(true), reader.read_bit());
+        assert_eq!(Ok(0b010), reader.read_bits(3));
+        assert_eq!(Ok(0xabcd), reader.read_bits(16));
+        assert_eq!(20, reader.position());
+        assert_eq!(Ok(0), reader.read_bits(4));
+    }
+
+    #[test]
+    fn reads_values_straddling_byte_boundaries() {
+        let bytes = [0b0001_0110, 0b1001_1000];
+        let mut reader = BitReader::new(&bytes);
+        reader.read_bits(2).unwrap();
+        assert_eq!(Ok(0b010_1101_0011), reader.read_bits(11));
+
+        let mut reader = BitReader::new(&bytes);
+        reader.read_bits(7).unwrap();
+        assert_eq!(Ok(0b0_1001_1000), reader.read_bits(9));
+    }
+
+    #[test]
+    fn peeking_does_not_consume() {
+        let mut reader = BitReader::new(&[0b1100_0000, 0]);
+        assert_eq!(Ok(0b1_1000_0000), reader.peek_bits(9));
+        assert_eq!(Ok(0b1_1000_0000), reader.peek_bits(9));
+        assert_eq!(0, reader.position());
+        assert_eq!(Ok(0b11), reader.read_bits(2));
+    }
+
+    #[test]
+    fn underruns_are_truncated_errors() {
+        let mut reader = BitReader::new(&[0xff]);
+        assert_eq!(Err(DeserialiseError::Truncated), reader.peek_bits(9));
+        assert_eq!(Ok(0x7f), reader.read_bits(7));
+        assert_eq!(Err(DeserialiseError::Truncated), reader.read_bits(2));
+        // a failed read leaves the position alone
+        assert_eq!(Ok(true), reader.read_bit());
+        assert_eq!(Err(DeserialiseError::Truncated), reader.read_bit());
+
+        let reader = BitReader::with_len(&[0xff], 3);
+        assert_eq!(Err(DeserialiseError::Truncated), reader.peek_bits(4));
+        assert_eq!(
+            Err(DeserialiseError::Truncated),
+            BitReader::new(&[]).read_bit()
+        );
+    }
+
+    #[test]
+    fn remaining_bytes_skip_the_partial_byte() {
+        let bytes = [1, 2, 3];
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(&[1, 2, 3], reader.remaining_bytes());
+        reader.read_bits(1).unwrap();
+        assert_eq!(&[2, 3], reader.remaining_bytes());
+        reader.read_bits(15).unwrap();
+        assert_eq!(&[3], reader.remaining_bytes());
+    }
+
+    #[test]
+    fn padding_must_be_zero() {
+        let mut reader = BitReader::new(&[0b1100_0000, 0b1000_0001]);
+        assert_eq!(Ok(()), reader.read_padding());
+        reader.read_bits(2).unwrap();
+        assert_eq!(Ok(()), reader.read_padding());
+        assert_eq!(8, reader.position());
+        reader.read_bit().unwrap();
+        assert_eq!(Err(DeserialiseError::NonZeroPadding), reader.read_padding());
+
+        // a stream that ends part way through a byte has nothing left to pad
+        let mut reader = BitReader::with_len(&[0b1111_1111], 3);
+        reader.read_bits(3).unwrap();
+        assert_eq!(Ok(()), reader.read_padding());
+    }
+}
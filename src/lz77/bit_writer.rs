@@ -0,0 +1,115 @@
+use core::iter;
+
+#[cfg(test)]
+use alloc::vec::Vec;
+#[cfg(test)]
+use bitvec::prelude::*;
+
+/// Packs bits into bytes, most significant bit first, handing each byte to `output` as soon as
+/// it's complete. Only the last partial byte is buffered.
+pub struct BitWriter<W> {
+    output: W,
+    /// The bits that don't make up a whole byte yet, in the lowest `pending_bits` bits.
+    pending: u64,
+    pending_bits: u32,
+    /// Bits written so far, not counting the padding `finish` adds.
+    bits_written: usize,
+}
+
+impl<W: Extend<u8>> BitWriter<W> {
+    pub fn new(output: W) -> Self {
+        BitWriter {
+            output,
+            pending: 0,
+            pending_bits: 0,
+            bits_written: 0,
+        }
+    }
+
+    /// Writes the lowest `count` bits of `value`, most significant first. `count` must be no
+    /// more than 32.
+    pub fn write_bits(&mut self, value: u32, count: u32) {
+        debug_assert!(count <= 32);
+        let value = u64::from(value) & ((1 << count) - 1);
+        self.pending = self.pending << count | value;
+        self.pending_bits += count;
+        self.bits_written += count as usize;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.output
+                .extend(iter::once((self.pending >> self.pending_bits) as u8));
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        self.write_bits(u32::from(bit), 1);
+    }
+
+    /// Pads the last partial byte with zeros, so the next bit written starts a new byte.
+    pub fn pad_to_byte(&mut self) {
+        if self.pending_bits > 0 {
+            self.write_bits(0, 8 - self.pending_bits);
+        }
+    }
+
+    /// Pads the last partial byte with zeros and returns the output.
+    pub fn finish(mut self) -> W {
+        if self.pending_bits > 0 {
+            let padding = 8 - self.pending_bits;
+            self.output
+                .extend(iter::once((self.pending << padding) as u8));
+        }
+        self.output
+    }
+}
+
+#[cfg(test)]
+impl BitWriter<Vec<u8>> {
+    /// The bits written so far, without any padding.
+    pub fn into_bitvec(self) -> BitVec<Msb0, u8> {
+        let bits_written = self.bits_written;
+        let mut bits = BitVec::from_vec(self.finish());
+        bits.truncate(bits_written);
+        bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn packs_bits_most_significant_first() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bit(true);
+        writer.write_bits(0b010, 3);
+        writer.write_bits(0xabcd, 16);
+        assert_eq!(20, writer.bits_written);
+        assert_eq!(vec![0b1010_1010, 0b1011_1100, 0b1101_0000], writer.finish());
+    }
+
+    #[test]
+    fn whole_bytes_are_handed_over_as_they_complete() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(0b1_1110_0001, 9);
+        assert_eq!(vec![0b1111_0000], writer.output);
+        writer.write_bits(0xffff_ffff, 32);
+        assert_eq!(5, writer.output.len());
+        // only the bits asked for are written, whatever else is set in the value
+        writer.write_bits(0xffff_fff0, 7);
+        assert_eq!(48, writer.bits_written);
+        assert_eq!(
+            vec![0b1111_0000, 0xff, 0xff, 0xff, 0xff, 0b1111_0000],
+            writer.finish()
+        );
+    }
+
+    #[test]
+    fn into_bitvec_drops_the_padding() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(0b101, 3);
+        assert_eq!(bitvec![Msb0, u8; 1, 0, 1], writer.into_bitvec());
+    }
+}
@@ -1,143 +1,531 @@
-use crate::lz77::window_byte_container::ByteBuffer;
-use std::cmp;
-use std::convert::TryFrom;
-use std::io::BufWriter;
-use std::io::Write;
+use alloc::{vec, vec::Vec};
+use core::cmp;
+use core::convert::TryFrom;
+use core::mem;
+use core::ops::Range;
 
+use crate::error::{DeserialiseError, Error, Result};
 use crate::lz77::nodes::NodeType;
-use crate::lz77::window_byte_container::ByteWindow;
 
-use super::window_byte_container::IndexableByteWindow;
+use super::serialisation;
+use super::suffix_array::SuffixArray;
+use super::window_byte_container::{find_length_of_series_match, PrefixIndex, PrefixTables};
 
-const SEARCH_WINDOW_SIZE: u16 = 2047;
-const PREFIX_WINDOW_SIZE: u16 = 2048;
+#[cfg(test)]
+pub const SEARCH_WINDOW_SIZE: u16 = super::WindowSize::DEFAULT.get();
+/// The longest match a reference may cover, the limit of the length encoding. Longer runs
+/// are split across several references.
+pub const MAX_MATCH_LENGTH: u16 = 2047;
+/// Matches at least this long are taken outright by the optimal parse, which doesn't look
+/// for a cheaper path through the bytes they cover. Without it every position of a long run
+/// is weighed against every offset in the window.
+const OPTIMAL_SKIP_LENGTH: u16 = 128;
+/// How many input bytes are processed between calls to the progress callback.
+pub const PROGRESS_INTERVAL: usize = 64 * 1024;
 
-pub fn build_lz77_node_list<C>(to_compress: &[u8], mut callback: C)
+#[cfg(test)]
+pub fn build_lz77_node_list<C>(to_compress: &[u8], callback: C)
 where
     C: FnMut(NodeType),
 {
-    let mut byte_ptr = 0;
-
-    let mut search_window =
-        IndexableByteWindow::with_max_window_size(to_compress, usize::from(SEARCH_WINDOW_SIZE));
-    let mut prefix_window =
-        ByteWindow::with_max_window_size(to_compress, usize::from(PREFIX_WINDOW_SIZE));
-
-    loop {
-        let c = to_compress[byte_ptr];
-        search_window.advance_to_pointer(byte_ptr);
-        prefix_window.advance_to_pointer(byte_ptr + usize::from(PREFIX_WINDOW_SIZE) + 1);
-
-        match calculate_reference_node(c, &search_window, &prefix_window) {
-            Some(NodeType::Reference { offset, length }) => {
-                byte_ptr += usize::from(length);
-                callback(NodeType::Reference { offset, length });
+    build_lz77_node_list_from(
+        to_compress,
+        0,
+        &MatchOptions::default(),
+        &mut PrefixTables::default(),
+        callback,
+        |_, _| {},
+    )
+}
+
+/// How `build_lz77_node_list_from` searches for matches. The default searches the whole of
+/// the default window for the longest match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchOptions {
+    /// How far back a match may start.
+    pub search_window_size: u16,
+    /// No match is longer than this, which must be between 2 and `MAX_MATCH_LENGTH`.
+    pub max_match_length: u16,
+    /// The search for a longer match stops as soon as one at least this long is found, so
+    /// `max_match_length` or more always finds the longest.
+    pub nice_match_length: u16,
+    /// The search also stops after comparing this many earlier positions, taking the longest
+    /// of those.
+    pub max_candidates: usize,
+    /// Only take a match when the split codec's reference takes fewer bits than the literals
+    /// it replaces, otherwise emit a literal and carry on from the next byte.
+    pub profitable_only: bool,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            search_window_size: super::WindowSize::DEFAULT.get(),
+            max_match_length: MAX_MATCH_LENGTH,
+            nice_match_length: MAX_MATCH_LENGTH,
+            max_candidates: usize::MAX,
+            profitable_only: false,
+        }
+    }
+}
+
+/// Builds the node list for `to_compress[start..]`, searching for matches as `options` says.
+///
+/// Bytes before `start` are never emitted, they only pre-fill the search window so the
+/// compressed bytes can back-reference them (e.g. a preset dictionary).
+///
+/// The match tables are reset and built up in `tables`, which are left holding them for the
+/// next input to reuse.
+///
+/// `progress` is called with `(bytes_processed, total_bytes)` of `to_compress[start..]` each
+/// time another `PROGRESS_INTERVAL` bytes have been processed, and once more at the end.
+pub fn build_lz77_node_list_from<C, P>(
+    to_compress: &[u8],
+    start: usize,
+    options: &MatchOptions,
+    tables: &mut PrefixTables,
+    mut callback: C,
+    mut progress: P,
+) where
+    C: FnMut(NodeType),
+    P: FnMut(usize, usize),
+{
+    let mut byte_ptr = start;
+    let total = to_compress.len() - start;
+    let mut next_progress = start + PROGRESS_INTERVAL;
+
+    let mut prefix_index = PrefixIndex::with_tables(
+        to_compress,
+        usize::from(options.search_window_size),
+        mem::take(tables),
+    );
+
+    while byte_ptr < to_compress.len() {
+        prefix_index.advance_to_pointer(byte_ptr);
+
+        let node = match prefix_index.longest_match(
+            usize::from(options.max_match_length),
+            usize::from(options.nice_match_length),
+            options.max_candidates,
+        ) {
+            Some((offset, length)) if !options.profitable_only || saves_bits(offset, length) => {
+                NodeType::reference(
+                    u16::try_from(offset).unwrap(),
+                    u16::try_from(length).unwrap(),
+                )
+            }
+            _ => NodeType::literal(to_compress[byte_ptr]),
+        };
+        byte_ptr += node.decoded_len();
+        callback(node);
+
+        if byte_ptr >= next_progress && byte_ptr < to_compress.len() {
+            progress(byte_ptr - start, total);
+            while next_progress <= byte_ptr {
+                next_progress += PROGRESS_INTERVAL;
+            }
+        }
+    }
+    *tables = prefix_index.into_tables();
+    progress(total, total);
+}
+
+/// Whether a reference takes fewer bits with the split codec than the literals it replaces.
+fn saves_bits(offset: usize, length: usize) -> bool {
+    let reference_bits = serialisation::reference_bits(
+        u16::try_from(offset).unwrap(),
+        u16::try_from(length).unwrap(),
+    );
+    reference_bits < length * serialisation::LITERAL_BITS
+}
+
+/// Builds the same node list as `build_lz77_node_list_from`, but by comparing against every
+/// position in the search window rather than only those the prefix index offers.
+///
+/// Hundreds of times slower on ordinary inputs. Kept as the reference the indexed matcher is
+/// checked and benchmarked against. Matches are capped and progress is reported as for
+/// `build_lz77_node_list_from`, and the whole window is always searched.
+pub fn build_linear_node_list_from<C, P>(
+    to_compress: &[u8],
+    start: usize,
+    search_window_size: u16,
+    max_match_length: u16,
+    mut callback: C,
+    mut progress: P,
+) where
+    C: FnMut(NodeType),
+    P: FnMut(usize, usize),
+{
+    let mut byte_ptr = start;
+    let total = to_compress.len() - start;
+    let mut next_progress = start + PROGRESS_INTERVAL;
+
+    while byte_ptr < to_compress.len() {
+        let lookahead_end = cmp::min(to_compress.len(), byte_ptr + usize::from(max_match_length));
+        let bytes_to_compress = &to_compress[byte_ptr..lookahead_end];
+        let window_start = byte_ptr.saturating_sub(usize::from(search_window_size));
+        // most recent first, an older match only replacing it if at least 2 bytes longer
+        let mut best = (0, 0);
+        for location in (window_start..byte_ptr).rev() {
+            let length =
+                find_length_of_series_match(&to_compress[location..byte_ptr], bytes_to_compress);
+            if length > best.1 + 1 {
+                best = (byte_ptr - location, length);
             }
-            Some(_) => panic!("Only Refernce nodes should be returned"),
-            None => {
-                callback(NodeType::ByteLiteral { lit: c });
-                byte_ptr += 1;
+        }
+        let node = if best.1 >= 2 {
+            NodeType::reference(
+                u16::try_from(best.0).unwrap(),
+                u16::try_from(best.1).unwrap(),
+            )
+        } else {
+            NodeType::literal(to_compress[byte_ptr])
+        };
+        byte_ptr += node.decoded_len();
+        callback(node);
+
+        if byte_ptr >= next_progress && byte_ptr < to_compress.len() {
+            progress(byte_ptr - start, total);
+            while next_progress <= byte_ptr {
+                next_progress += PROGRESS_INTERVAL;
             }
         }
+    }
+    progress(total, total);
+}
+
+/// Builds a node list like `build_lz77_node_list_from`, but taking the match at each
+/// position that costs the fewest bits per byte, found among the neighbours of the position
+/// in a suffix array over the whole of `to_compress`.
+///
+/// The suffix array is built once up front. Sorting it takes around 40 bytes of memory per
+/// byte at its peak, and the finished array 16.
+/// Matches are capped and progress is reported as for `build_lz77_node_list_from`.
+pub fn build_suffix_node_list_from<C, P>(
+    to_compress: &[u8],
+    start: usize,
+    search_window_size: u16,
+    max_match_length: u16,
+    mut callback: C,
+    mut progress: P,
+) where
+    C: FnMut(NodeType),
+    P: FnMut(usize, usize),
+{
+    let mut byte_ptr = start;
+    let total = to_compress.len() - start;
+    let mut next_progress = start + PROGRESS_INTERVAL;
+    let mut suffix_array = SuffixArray::new(to_compress);
+
+    while byte_ptr < to_compress.len() {
+        let node = match suffix_array.best_match(
+            byte_ptr,
+            usize::from(search_window_size),
+            usize::from(max_match_length),
+        ) {
+            Some((offset, length)) => NodeType::reference(
+                u16::try_from(offset).unwrap(),
+                u16::try_from(length).unwrap(),
+            ),
+            None => NodeType::literal(to_compress[byte_ptr]),
+        };
+        byte_ptr += node.decoded_len();
+        callback(node);
 
-        if byte_ptr > to_compress.len() - 1 {
-            break;
+        if byte_ptr >= next_progress && byte_ptr < to_compress.len() {
+            progress(byte_ptr - start, total);
+            while next_progress <= byte_ptr {
+                next_progress += PROGRESS_INTERVAL;
+            }
         }
     }
+    progress(total, total);
+}
+
+/// Builds the node list for `to_compress[start..]` that takes the fewest bits to serialise.
+///
+/// Rather than taking the longest match at each position, every match length at every
+/// position is weighed by its real encoded size and the cheapest path through the input is
+/// taken. Much slower than the greedy matcher, and keeps a cost and a node per input byte in
+/// memory. Matches are capped, `tables` reused and progress reported as for
+/// `build_lz77_node_list_from`.
+pub fn build_optimal_node_list_from<C, P>(
+    to_compress: &[u8],
+    start: usize,
+    search_window_size: u16,
+    max_match_length: u16,
+    tables: &mut PrefixTables,
+    callback: C,
+    progress: P,
+) where
+    C: FnMut(NodeType),
+    P: FnMut(usize, usize),
+{
+    build_optimal_node_list_skipping(
+        to_compress,
+        start,
+        search_window_size,
+        max_match_length,
+        OPTIMAL_SKIP_LENGTH,
+        tables,
+        callback,
+        progress,
+    );
 }
 
-fn calculate_reference_node(
-    first_uncompressed_byte: u8,
-    search_window: &IndexableByteWindow,
-    bytes_to_compressed: &ByteWindow,
-) -> Option<NodeType> {
-    let mut offset = 0;
-    let mut length = 0;
+// `build_optimal_node_list_from` with the length of match taken outright as a parameter, so
+// the tests can weigh the shortcut against a parse without it.
+#[allow(clippy::too_many_arguments)]
+fn build_optimal_node_list_skipping<C, P>(
+    to_compress: &[u8],
+    start: usize,
+    search_window_size: u16,
+    max_match_length: u16,
+    skip_length: u16,
+    tables: &mut PrefixTables,
+    callback: C,
+    mut progress: P,
+) where
+    C: FnMut(NodeType),
+    P: FnMut(usize, usize),
+{
+    let total = to_compress.len() - start;
+    let mut next_progress = start + PROGRESS_INTERVAL;
+
+    // the fewest bits that encode the first i bytes, and the last node on that path
+    let mut cheapest = vec![(usize::MAX, NodeType::literal(0)); total + 1];
+    cheapest[0].0 = 0;
 
-    // find a byte sequence in the previously compressed bytes.
-    // reverse iterate from the end of the compressed bytes, find a matching byte, then
-    // moving forward through the compressed bytes and the bytes to be compressed, find the
-    // longest matching sequence.
-    // Moving from back to front is preferable as it will result in a smaller offset value
-    // and has potential use in the future for the search window length extending into the
-    // uncompressed bytes (to represend repeating blocks).
-    let compressed_bytes = search_window.window();
-    let bytes_to_compressed = bytes_to_compressed.window();
-    if compressed_bytes.len() > 0 {
-        let byte_locations = search_window.byte_locations().get(&first_uncompressed_byte);
+    let mut prefix_index = PrefixIndex::with_tables(
+        to_compress,
+        usize::from(search_window_size),
+        mem::take(tables),
+    );
 
-        if let Some(byte_locations) = &byte_locations {
-            for location in byte_locations.iter().rev() {
-                let local_location = search_window.location_to_window_index(*location);
+    let mut skip_to = start;
+    for byte_ptr in start..to_compress.len() {
+        if byte_ptr >= next_progress {
+            progress(byte_ptr - start, total);
+            next_progress += PROGRESS_INTERVAL;
+        }
+        if byte_ptr < skip_to {
+            continue;
+        }
+        prefix_index.advance_to_pointer(byte_ptr);
 
-                let series_match = find_length_of_series_match(
-                    &compressed_bytes[local_location + 1..],
-                    bytes_to_compressed,
-                );
-                if series_match > length {
-                    offset = search_window.window().len() - local_location;
-                    length = series_match + 1; // + 1 to include the 'first_uncompressed_byte' char
-                }
+        let here = cheapest[byte_ptr - start].0;
+        let mut relax = |node: NodeType, bits: usize| {
+            let there = &mut cheapest[byte_ptr - start + node.decoded_len()];
+            if here + bits < there.0 {
+                *there = (here + bits, node);
             }
+        };
+        relax(
+            NodeType::literal(to_compress[byte_ptr]),
+            serialisation::LITERAL_BITS,
+        );
+        let mut longest = 0;
+        prefix_index.for_each_match(usize::from(max_match_length), |offset, length| {
+            let offset = u16::try_from(offset).unwrap();
+            let length = u16::try_from(length).unwrap();
+            relax(
+                NodeType::reference(offset, length),
+                serialisation::reference_bits(offset, length),
+            );
+            longest = length;
+        });
+        if longest >= skip_length {
+            skip_to = byte_ptr + usize::from(longest);
         }
     }
 
-    if length == 0 {
-        return Option::None::<NodeType>;
+    *tables = prefix_index.into_tables();
+
+    // walk the cheapest path back from the end, then hand it over front to back
+    let mut nodes = Vec::new();
+    let mut position = total;
+    while position > 0 {
+        let node = cheapest[position].1;
+        position -= node.decoded_len();
+        nodes.push(node);
     }
+    nodes.into_iter().rev().for_each(callback);
+    progress(total, total);
+}
 
-    Option::Some(NodeType::Reference {
-        offset: u16::try_from(offset).unwrap(),
-        length: u16::try_from(length).unwrap(),
-    })
+/// Builds a node list for `to_compress[start..]` with a literal for every byte and no
+/// references, as a baseline and for checking the literal encoding on its own. Progress is
+/// only reported once, at the end.
+pub fn build_literal_node_list_from<C, P>(
+    to_compress: &[u8],
+    start: usize,
+    callback: C,
+    mut progress: P,
+) where
+    C: FnMut(NodeType),
+    P: FnMut(usize, usize),
+{
+    to_compress[start..]
+        .iter()
+        .copied()
+        .map(NodeType::literal)
+        .for_each(callback);
+    let total = to_compress.len() - start;
+    progress(total, total);
 }
 
-fn find_length_of_series_match(left: &[u8], right: &[u8]) -> usize {
-    let max_count = cmp::min(left.len(), right.len());
-    for i in 0..max_count {
-        if left[i] != right[i] {
-            return i;
+// References may only reach back `search_window_size` bytes, the history a streaming decoder
+// would need to keep in memory, which is why the window size is serialised.
+//
+// `dictionary` seeds the history and must match the one used during compression. Decoded
+// bytes are appended to `output`, and anything already in it isn't part of the history.
+// `output` grows once, by the nodes' decoded length, and is filled by appending rather than
+// zeroed first. Callers should check that length, see `decoded_len`, against what the header
+// claims and any output limit first: a few bytes of run nodes can claim gigabytes.
+pub fn decompress_nodes(
+    nodes: &[NodeType],
+    dictionary: &[u8],
+    search_window_size: u16,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    let start = output.len();
+    let needed = decoded_len(nodes)?;
+    output
+        .try_reserve_exact(needed)
+        .map_err(|_| Error::OutOfMemory { needed })?;
+    let search_window_size = usize::from(search_window_size);
+    let dictionary = &dictionary[dictionary.len().saturating_sub(search_window_size)..];
+
+    for node in nodes {
+        let written = output.len() - start;
+        match *node {
+            NodeType::ByteLiteral { lit } => output.push(lit),
+            NodeType::Reference { offset, length } => {
+                let (from_dictionary, from_output) = reference_source(
+                    dictionary.len(),
+                    written,
+                    search_window_size,
+                    offset,
+                    length,
+                )?;
+                output.extend_from_slice(&dictionary[from_dictionary]);
+                output.extend_from_within(start + from_output.start..start + from_output.end);
+            }
+            NodeType::Run { byte, count } => output.resize(output.len() + count as usize, byte),
         }
     }
-    max_count
+    Ok(())
 }
 
-// need to keep the search window in memory, which means the length of it needs to be serialised.
-pub fn decompress_nodes<W: Write>(nodes: Vec<NodeType>, writer: &mut W) {
-    let mut search_buffer: ByteBuffer<u8> = ByteBuffer::new(usize::from(SEARCH_WINDOW_SIZE));
-    let mut buffered_writer = BufWriter::new(writer);
+/// Checks a reference against the history, the last `search_window_size` bytes of the
+/// dictionary followed by the `written` bytes decoded so far, and returns where its bytes come
+/// from: a range of the dictionary, then a range of the decoded bytes. Either may be empty.
+fn reference_source(
+    dictionary_len: usize,
+    written: usize,
+    search_window_size: usize,
+    offset: u16,
+    length: u16,
+) -> Result<(Range<usize>, Range<usize>)> {
+    let available = cmp::min(dictionary_len + written, search_window_size);
+    let (offset_len, length_len) = (usize::from(offset), usize::from(length));
+    if offset_len > available || length_len > offset_len {
+        return Err(DeserialiseError::InvalidReference {
+            offset,
+            length,
+            available,
+        }
+        .into());
+    }
+    let copy_start = dictionary_len + written - offset_len;
+    let copy_end = copy_start + length_len;
+    Ok((
+        cmp::min(copy_start, dictionary_len)..cmp::min(copy_end, dictionary_len),
+        copy_start.saturating_sub(dictionary_len)..copy_end.saturating_sub(dictionary_len),
+    ))
+}
 
-    for node in nodes {
-        let mut bytes_to_write = Vec::new();
+/// The number of bytes `nodes` decode to, failing with `InvalidLength` rather than overflowing.
+pub fn decoded_len(nodes: &[NodeType]) -> core::result::Result<usize, DeserialiseError> {
+    nodes.iter().try_fold(0usize, |total, node| {
+        total
+            .checked_add(node.decoded_len())
+            .ok_or(DeserialiseError::InvalidLength)
+    })
+}
 
-        // TODO: might be nicer to have a slice returned and just append in a single location.
-        match node {
+/// Like `decompress_nodes`, but decodes into the start of `output`, returning the number of
+/// bytes written. Fails with `LengthMismatch` if `output` is too short to hold them all.
+pub fn decompress_nodes_into(
+    nodes: &[NodeType],
+    dictionary: &[u8],
+    search_window_size: u16,
+    output: &mut [u8],
+) -> Result<usize> {
+    let search_window_size = usize::from(search_window_size);
+    let dictionary = &dictionary[dictionary.len().saturating_sub(search_window_size)..];
+
+    let mut written = 0;
+    for node in nodes {
+        match *node {
             NodeType::ByteLiteral { lit } => {
-                bytes_to_write.push(lit);
+                *output
+                    .get_mut(written)
+                    .ok_or(DeserialiseError::LengthMismatch)? = lit;
+                written += 1;
             }
             NodeType::Reference { offset, length } => {
-                // copy from the search buffer
-                let search_start_index = search_buffer.vec.len() - usize::from(offset);
-                let search_stop_index = search_start_index + usize::from(length);
-                let b = &search_buffer.vec[search_start_index..search_stop_index];
-                bytes_to_write.extend(b);
+                let (from_dictionary, from_output) = reference_source(
+                    dictionary.len(),
+                    written,
+                    search_window_size,
+                    offset,
+                    length,
+                )?;
+                let end = written + usize::from(length);
+                if end > output.len() {
+                    return Err(DeserialiseError::LengthMismatch.into());
+                }
+                let copied = written + from_dictionary.len();
+                output[written..copied].copy_from_slice(&dictionary[from_dictionary]);
+                output.copy_within(from_output, copied);
+                written = end;
             }
-            NodeType::EndOfStream => {
-                // Might not be needed here - might just be a serialisation thing
+            NodeType::Run { byte, count } => {
+                let end = written + count as usize;
+                output
+                    .get_mut(written..end)
+                    .ok_or(DeserialiseError::LengthMismatch)?
+                    .fill(byte);
+                written = end;
             }
         };
+    }
+    Ok(written)
+}
 
-        buffered_writer
-            .write_all(&bytes_to_write)
-            .expect("Error during decompression");
-
-        search_buffer.push_all(&bytes_to_write);
+/// The runs of at least `min_run_length` copies of one byte in `bytes[start..]`, as
+/// `(position, length)` pairs in order.
+pub fn find_runs(bytes: &[u8], start: usize, min_run_length: usize) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut position = start;
+    while position < bytes.len() {
+        let byte = bytes[position];
+        let length = bytes[position..].iter().take_while(|&&b| b == byte).count();
+        if length >= min_run_length {
+            runs.push((position, length));
+        }
+        position += length;
     }
+    runs
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::Error;
+    use crate::lz77::serialisation::OffsetCodec;
+    use crate::test_util::pseudo_random;
 
     #[test]
     fn builds_a_node_list() {
@@ -188,6 +576,44 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn larger_search_window_reaches_further_back() {
+        let mut bytes = vec![9, 8, 7, 6];
+        bytes.resize(3000, 0);
+        bytes.extend_from_slice(&[9, 8, 7, 6]);
+
+        let mut nodes = Vec::new();
+        build_lz77_node_list_from(
+            &bytes,
+            3000,
+            &MatchOptions {
+                search_window_size: 4096,
+                ..MatchOptions::default()
+            },
+            &mut PrefixTables::default(),
+            |node| nodes.push(node),
+            |_, _| {},
+        );
+        assert_eq!(
+            vec![NodeType::Reference {
+                offset: 3000,
+                length: 4
+            }],
+            nodes
+        );
+
+        let mut nodes = Vec::new();
+        build_lz77_node_list_from(
+            &bytes,
+            3000,
+            &MatchOptions::default(),
+            &mut PrefixTables::default(),
+            |node| nodes.push(node),
+            |_, _| {},
+        );
+        assert_eq!(4, nodes.len());
+    }
+
     #[test]
     fn node_list_test_no_trailing_chars() {
         let bytes = vec![b'a', b'b', b'a', b'b', b'b']; // D:
@@ -206,4 +632,505 @@ mod tests {
         build_lz77_node_list(&bytes, |node| nodes.push(node));
         assert_eq!(expected, nodes);
     }
+
+    #[test]
+    fn node_list_references_bytes_before_start() {
+        let bytes = vec![b'a', b'b', b'c', b'a', b'b', b'c'];
+
+        let mut nodes = Vec::new();
+        build_lz77_node_list_from(
+            &bytes,
+            3,
+            &MatchOptions::default(),
+            &mut PrefixTables::default(),
+            |node| nodes.push(node),
+            |_, _| {},
+        );
+
+        assert_eq!(
+            vec![NodeType::Reference {
+                offset: 3,
+                length: 3,
+            }],
+            nodes
+        );
+    }
+
+    #[test]
+    fn decompress_rejects_offset_before_start_of_data() {
+        let nodes = vec![
+            NodeType::ByteLiteral { lit: b'a' },
+            NodeType::Reference {
+                offset: 2,
+                length: 2,
+            },
+        ];
+        let mut write_vec: Vec<u8> = Vec::new();
+        let err = decompress_nodes(&nodes, &[], SEARCH_WINDOW_SIZE, &mut write_vec).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Deserialise(DeserialiseError::InvalidReference {
+                offset: 2,
+                length: 2,
+                available: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn decompress_history_is_the_dictionary_and_this_output_only() {
+        let nodes = vec![
+            NodeType::reference(3, 2),
+            NodeType::literal(b'!'),
+            NodeType::reference(3, 3),
+        ];
+        let mut write_vec = b"earlier block".to_vec();
+        decompress_nodes(&nodes, b"xxabc", 3, &mut write_vec).unwrap();
+        assert_eq!(b"earlier blockab!ab!".to_vec(), write_vec);
+
+        let mut write_vec = b"earlier block".to_vec();
+        let err = decompress_nodes(&[NodeType::reference(1, 1)], &[], 3, &mut write_vec);
+        assert!(matches!(
+            err,
+            Err(Error::Deserialise(DeserialiseError::InvalidReference {
+                available: 0,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn decompress_rejects_copy_past_end_of_buffer() {
+        let nodes = vec![
+            NodeType::ByteLiteral { lit: b'a' },
+            NodeType::ByteLiteral { lit: b'b' },
+            NodeType::Reference {
+                offset: 2,
+                length: 3,
+            },
+        ];
+        let mut write_vec: Vec<u8> = Vec::new();
+        let err = decompress_nodes(&nodes, &[], SEARCH_WINDOW_SIZE, &mut write_vec).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Deserialise(DeserialiseError::InvalidReference {
+                offset: 2,
+                length: 3,
+                available: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn progress_is_reported_every_interval() {
+        let to_compress: Vec<u8> = (0..PROGRESS_INTERVAL * 7 / 2).map(|i| i as u8).collect();
+        let mut reports = Vec::new();
+        build_lz77_node_list_from(
+            &to_compress,
+            0,
+            &MatchOptions::default(),
+            &mut PrefixTables::default(),
+            |_| {},
+            |done, total| reports.push((done, total)),
+        );
+
+        assert_eq!(4, reports.len());
+        assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (i, (done, total)) in reports[..3].iter().enumerate() {
+            let boundary = (i + 1) * PROGRESS_INTERVAL;
+            assert!(*done >= boundary && *done <= boundary + usize::from(MAX_MATCH_LENGTH));
+            assert_eq!(to_compress.len(), *total);
+        }
+        assert_eq!(
+            Some(&(to_compress.len(), to_compress.len())),
+            reports.last()
+        );
+    }
+
+    #[test]
+    fn matches_an_exhaustive_greedy_search() {
+        let mut random = pseudo_random(7);
+        let mut next = || random.next().unwrap();
+        let small_alphabet: Vec<u8> = (0..6000).map(|_| b'a' + next() % 4).collect();
+        let skewed: Vec<u8> = (0..6000)
+            .map(|_| if next() < 240 { b'a' } else { next() })
+            .collect();
+        let text = b"she sells sea shells on the sea shore, the shells she sells are sea shells"
+            .repeat(40);
+
+        for bytes in [&small_alphabet, &skewed, &text] {
+            for (start, window) in [(0, SEARCH_WINDOW_SIZE), (0, 5), (1000, 300), (0, 4096)] {
+                let mut nodes = Vec::new();
+                build_lz77_node_list_from(
+                    bytes,
+                    start,
+                    &MatchOptions {
+                        search_window_size: window,
+                        ..MatchOptions::default()
+                    },
+                    &mut PrefixTables::default(),
+                    |n| nodes.push(n),
+                    |_, _| {},
+                );
+                assert_eq!(exhaustive_greedy_node_list(bytes, start, window), nodes);
+            }
+        }
+    }
+
+    #[test]
+    fn optimal_parse_is_never_larger_than_greedy() {
+        let mut random = pseudo_random(11);
+        let mut next = || random.next().unwrap();
+        let small_alphabet: Vec<u8> = (0..6000).map(|_| b'a' + next() % 4).collect();
+        let skewed: Vec<u8> = (0..6000)
+            .map(|_| if next() < 240 { b'a' } else { next() })
+            .collect();
+        let text = b"she sells sea shells on the sea shore, the shells she sells are sea shells"
+            .repeat(40);
+
+        for bytes in [&small_alphabet, &skewed, &text] {
+            for (start, window) in [(0, SEARCH_WINDOW_SIZE), (0, 5), (1000, 300), (0, 4096)] {
+                let mut greedy = Vec::new();
+                build_lz77_node_list_from(
+                    bytes,
+                    start,
+                    &MatchOptions {
+                        search_window_size: window,
+                        ..MatchOptions::default()
+                    },
+                    &mut PrefixTables::default(),
+                    |n| greedy.push(n),
+                    |_, _| {},
+                );
+                let mut optimal = Vec::new();
+                build_optimal_node_list_from(
+                    bytes,
+                    start,
+                    window,
+                    MAX_MATCH_LENGTH,
+                    &mut PrefixTables::default(),
+                    |n| optimal.push(n),
+                    |_, _| {},
+                );
+
+                assert!(encoded_bits(&optimal) <= encoded_bits(&greedy));
+                let mut output = Vec::new();
+                decompress_nodes(&optimal, &bytes[..start], window, &mut output).unwrap();
+                assert_eq!(&bytes[start..], &output[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn taking_long_matches_outright_costs_the_optimal_parse_little() {
+        let bytes = include_bytes!("compress.rs");
+        let parse = |skip_length| {
+            let mut nodes = Vec::new();
+            build_optimal_node_list_skipping(
+                bytes,
+                0,
+                4096,
+                MAX_MATCH_LENGTH,
+                skip_length,
+                &mut PrefixTables::default(),
+                |n| nodes.push(n),
+                |_, _| {},
+            );
+            encoded_bits(&nodes)
+        };
+
+        let shortcut = parse(OPTIMAL_SKIP_LENGTH);
+        let full = parse(u16::MAX);
+        assert!(full <= shortcut);
+        assert!(
+            shortcut - full <= full / 1000,
+            "{} against {}",
+            shortcut,
+            full
+        );
+    }
+
+    #[test]
+    fn optimal_parse_takes_a_literal_to_reach_a_longer_match() {
+        // greedy takes "ab" and then "cdefghij", where a literal 'a' then "bcdefghij" is cheaper
+        let bytes = b"ab--bcdefghij--abcdefghij";
+        let start = 15;
+
+        let mut greedy = Vec::new();
+        build_lz77_node_list_from(
+            bytes,
+            start,
+            &MatchOptions::default(),
+            &mut PrefixTables::default(),
+            |n| greedy.push(n),
+            |_, _| {},
+        );
+        let mut optimal = Vec::new();
+        build_optimal_node_list_from(
+            bytes,
+            start,
+            SEARCH_WINDOW_SIZE,
+            MAX_MATCH_LENGTH,
+            &mut PrefixTables::default(),
+            |n| optimal.push(n),
+            |_, _| {},
+        );
+
+        assert_eq!(
+            vec![NodeType::reference(15, 2), NodeType::reference(12, 8)],
+            greedy
+        );
+        assert_eq!(
+            vec![NodeType::literal(b'a'), NodeType::reference(12, 9)],
+            optimal
+        );
+        assert!(encoded_bits(&optimal) < encoded_bits(&greedy));
+    }
+
+    #[test]
+    fn equal_length_matches_take_the_smallest_offset() {
+        // "wxyz" both 218 and 14 bytes back, the nearer one fits the 7 bit offset encoding
+        let far_filler: Vec<u8> = (0..200).map(|i| 255 - i).collect();
+        let bytes = [&b"wxyz"[..], &far_filler, b"wxyz", b"0123456789", b"wxyz"].concat();
+
+        let mut nodes = Vec::new();
+        build_lz77_node_list(&bytes, |node| nodes.push(node));
+
+        assert_eq!(Some(&NodeType::reference(14, 4)), nodes.last());
+        assert!(serialisation::reference_bits(14, 4) < serialisation::reference_bits(218, 4));
+    }
+
+    #[test]
+    fn long_runs_are_split_at_the_max_match_length() {
+        // a block that repeats for far longer than any one reference can cover
+        let block: Vec<u8> = pseudo_random(3).take(SEARCH_WINDOW_SIZE.into()).collect();
+        let bytes = block.repeat(5);
+
+        for max_match_length in [MAX_MATCH_LENGTH, 16, 2] {
+            let mut greedy = Vec::new();
+            build_lz77_node_list_from(
+                &bytes,
+                0,
+                &MatchOptions {
+                    max_match_length,
+                    ..MatchOptions::default()
+                },
+                &mut PrefixTables::default(),
+                |n| greedy.push(n),
+                |_, _| {},
+            );
+            let mut optimal = Vec::new();
+            build_optimal_node_list_from(
+                &bytes,
+                0,
+                SEARCH_WINDOW_SIZE,
+                max_match_length,
+                &mut PrefixTables::default(),
+                |n| optimal.push(n),
+                |_, _| {},
+            );
+
+            let longest = greedy.iter().map(NodeType::decoded_len).max();
+            assert_eq!(Some(usize::from(max_match_length)), longest);
+            for nodes in [greedy, optimal] {
+                assert!(nodes
+                    .iter()
+                    .all(|n| n.decoded_len() <= usize::from(max_match_length)));
+
+                // every length survives the bitstream
+                let encoded = serialisation::serialise_stream(&nodes, OffsetCodec::Split);
+                let (decoded, _) =
+                    serialisation::deserialise_nodes(&encoded, OffsetCodec::Split).unwrap();
+                assert_eq!(nodes, decoded);
+
+                let mut output = Vec::new();
+                decompress_nodes(&decoded, &[], SEARCH_WINDOW_SIZE, &mut output).unwrap();
+                assert_eq!(bytes, output);
+            }
+        }
+    }
+
+    // write_length panics on a length of 1, so the matchers must never emit one, however
+    // awkward the input
+    #[test]
+    fn references_are_never_shorter_than_two_bytes() {
+        let two_letters: Vec<u8> = pseudo_random(5)
+            .take(600)
+            .map(|random| b'a' + random % 2)
+            .collect();
+        let inputs = [
+            b"a".repeat(600),
+            b"ab".repeat(300),
+            b"aab".repeat(200),
+            b"abcabd".repeat(100),
+            (0..=255).chain(0..=255).collect(),
+            two_letters,
+        ];
+
+        for bytes in &inputs {
+            for window in [1, 2, 3, SEARCH_WINDOW_SIZE] {
+                for max_match_length in [MAX_MATCH_LENGTH, 3, 2] {
+                    let mut greedy = Vec::new();
+                    build_lz77_node_list_from(
+                        bytes,
+                        0,
+                        &MatchOptions {
+                            search_window_size: window,
+                            max_match_length,
+                            ..MatchOptions::default()
+                        },
+                        &mut PrefixTables::default(),
+                        |n| greedy.push(n),
+                        |_, _| {},
+                    );
+                    let mut optimal = Vec::new();
+                    build_optimal_node_list_from(
+                        bytes,
+                        0,
+                        window,
+                        max_match_length,
+                        &mut PrefixTables::default(),
+                        |n| optimal.push(n),
+                        |_, _| {},
+                    );
+
+                    for nodes in [greedy, optimal] {
+                        for node in &nodes {
+                            if let NodeType::Reference { offset, length } = *node {
+                                assert!(
+                                    length >= 2 && length <= max_match_length,
+                                    "length {} with window {} and max length {}",
+                                    length,
+                                    window,
+                                    max_match_length
+                                );
+                                assert!(offset >= 1 && offset <= window);
+                            }
+                        }
+                        let mut output = Vec::new();
+                        decompress_nodes(&nodes, &[], window, &mut output).unwrap();
+                        assert_eq!(bytes, &output);
+                    }
+                }
+            }
+        }
+    }
+
+    fn encoded_bits(nodes: &[NodeType]) -> usize {
+        nodes
+            .iter()
+            .map(|node| match *node {
+                NodeType::ByteLiteral { .. } => serialisation::LITERAL_BITS,
+                NodeType::Reference { offset, length } => {
+                    serialisation::reference_bits(offset, length)
+                }
+                NodeType::Run { .. } => unreachable!("the parsers don't emit runs"),
+            })
+            .sum()
+    }
+
+    // the matcher before the prefix index: try every earlier position in the window, only
+    // replacing a match with one at least 2 bytes longer
+    fn exhaustive_greedy_node_list(bytes: &[u8], start: usize, window: u16) -> Vec<NodeType> {
+        let mut nodes = Vec::new();
+        build_linear_node_list_from(
+            bytes,
+            start,
+            window,
+            MAX_MATCH_LENGTH,
+            |node| nodes.push(node),
+            |_, _| {},
+        );
+        nodes
+    }
+
+    #[test]
+    fn finds_runs_of_at_least_the_minimum_length() {
+        let bytes = b"aaaabccccccdddaaaaa";
+        assert_eq!(vec![(0, 4), (5, 6), (14, 5)], find_runs(bytes, 0, 4));
+        assert_eq!(vec![(5, 6)], find_runs(bytes, 2, 6));
+        assert!(find_runs(bytes, 0, 7).is_empty());
+        assert!(find_runs(&[], 0, 1).is_empty());
+    }
+
+    #[test]
+    fn runs_decode_to_their_bytes() {
+        let nodes = [
+            NodeType::literal(b'a'),
+            NodeType::run(b'b', 5),
+            NodeType::reference(3, 3),
+        ];
+        let mut output = [0; 9];
+        let written = decompress_nodes_into(&nodes, &[], SEARCH_WINDOW_SIZE, &mut output).unwrap();
+        assert_eq!(9, written);
+        assert_eq!(b"abbbbbbbb", &output);
+
+        assert!(matches!(
+            decompress_nodes_into(&nodes, &[], SEARCH_WINDOW_SIZE, &mut [0; 4]),
+            Err(Error::Deserialise(DeserialiseError::LengthMismatch))
+        ));
+    }
+
+    #[test]
+    fn matches_reach_the_last_byte() {
+        let unique: Vec<u8> = (0..50).collect();
+        for tail_len in 2..=50 {
+            let bytes = [&unique[..], &unique[..tail_len]].concat();
+            for start in [0, 10] {
+                let mut greedy = Vec::new();
+                build_lz77_node_list_from(
+                    &bytes,
+                    start,
+                    &MatchOptions::default(),
+                    &mut PrefixTables::default(),
+                    |n| greedy.push(n),
+                    |_, _| {},
+                );
+                let mut optimal = Vec::new();
+                build_optimal_node_list_from(
+                    &bytes,
+                    start,
+                    SEARCH_WINDOW_SIZE,
+                    MAX_MATCH_LENGTH,
+                    &mut PrefixTables::default(),
+                    |n| optimal.push(n),
+                    |_, _| {},
+                );
+                for nodes in [greedy, optimal] {
+                    assert_eq!(
+                        Some(&NodeType::reference(50, tail_len as u16)),
+                        nodes.last(),
+                        "tail of {} from {}",
+                        tail_len,
+                        start
+                    );
+                }
+            }
+        }
+
+        // the final match is as long as the exhaustive search finds, whatever the tail
+        let bytes: Vec<u8> = pseudo_random(3)
+            .take(3000)
+            .map(|random| b"abc"[usize::from(random) % 3])
+            .collect();
+        for end in 2990..=bytes.len() {
+            let mut nodes = Vec::new();
+            build_lz77_node_list(&bytes[..end], |n| nodes.push(n));
+            let exhaustive = exhaustive_greedy_node_list(&bytes[..end], 0, SEARCH_WINDOW_SIZE);
+            let covered = |nodes: &[NodeType]| nodes.iter().map(NodeType::decoded_len).sum();
+            assert_eq!(end, covered(&nodes));
+            assert_eq!(end, covered(&exhaustive));
+            assert_eq!(
+                exhaustive.last().map(NodeType::decoded_len),
+                nodes.last().map(NodeType::decoded_len)
+            );
+        }
+    }
+
+    #[test]
+    fn empty_input_builds_no_nodes() {
+        let mut nodes = Vec::new();
+        build_lz77_node_list(&[], |node| nodes.push(node));
+        assert!(nodes.is_empty());
+    }
 }
@@ -0,0 +1,95 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use super::Lz77Compression;
+use crate::error::{Error, Result};
+
+/// Bytes known to hold one or more whole lz77 streams, as written by `encode`.
+///
+/// Construction parses every header and node through to the end marker, so anything holding a
+/// `CompressedLz77` can't have been handed arbitrary bytes. Decoding can still fail if a
+/// reference reaches outside the history or the stream was written with a dictionary.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompressedLz77(Vec<u8>);
+
+impl CompressedLz77 {
+    /// Wraps bytes `encode` just wrote, which need no checking.
+    pub(super) fn new_unchecked(bytes: Vec<u8>) -> Self {
+        CompressedLz77(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl AsRef<[u8]> for CompressedLz77 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for CompressedLz77 {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        Lz77Compression::parse_streams(bytes)?;
+        Ok(CompressedLz77(bytes.to_vec()))
+    }
+}
+
+impl TryFrom<Vec<u8>> for CompressedLz77 {
+    type Error = Error;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self> {
+        Lz77Compression::parse_streams(&bytes)?;
+        Ok(CompressedLz77(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DeserialiseError;
+
+    #[test]
+    fn accepts_whole_streams() {
+        let compressor = Lz77Compression::new();
+        let compressed = compressor.compress_stream(b"abcabcabcabc");
+        let bytes = compressor.encode(b"abcabcabcabc");
+        assert_eq!(bytes, compressed.as_bytes());
+        assert_eq!(compressed, CompressedLz77::try_from(&bytes[..]).unwrap());
+
+        let appended = [&bytes[..], &compressor.encode(b"")].concat();
+        let appended = CompressedLz77::try_from(appended).unwrap();
+        assert_eq!(
+            b"abcabcabcabc".to_vec(),
+            compressor.decompress_stream(&appended).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_anything_but_whole_streams() {
+        let bytes = Lz77Compression::new()
+            .literals_only()
+            .encode(b"abcabcabcabc");
+        let rejected = |bytes: &[u8]| match CompressedLz77::try_from(bytes) {
+            Err(Error::Deserialise(err)) => err,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(DeserialiseError::BadMagic, rejected(b""));
+        assert_eq!(DeserialiseError::BadMagic, rejected(b"abcabcabcabc"));
+        // the end marker is missing
+        assert_eq!(
+            DeserialiseError::Truncated,
+            rejected(&bytes[..bytes.len() - 1])
+        );
+        // trailing bytes have to be another stream
+        let trailing = [&bytes[..], b"x"].concat();
+        assert_eq!(DeserialiseError::BadMagic, rejected(&trailing));
+    }
+}
@@ -0,0 +1,515 @@
+use std::cmp;
+use std::io::{self, prelude::*};
+
+use super::compress;
+use super::nodes::NodeType;
+use super::serialisation::{self, Mode};
+use super::Lz77Compression;
+use crate::error::{DeserialiseError, Error, Result};
+use crate::frame;
+
+/// How many bytes `Lz77Decoder` asks `inner` for at least, when it needs more of a stream.
+const READ_SIZE: usize = 8 * 1024;
+
+/// Decompresses the lz77 streams read from `inner`, like flate2's `GzDecoder`. Streams appended
+/// one after another are joined, as `decompress_concatenated` does, including those written
+/// by `Lz77Encoder` that continue the stream before them.
+///
+/// Compressed bytes are only read as they're needed, so a stream can be decoded before the
+/// next has arrived on a pipe or socket. The nodes are parsed from a slice, so a stream's
+/// nodes are read in full before any of them is expanded, while a stored stream's bytes are
+/// read as they're asked for. The decompressed bytes are only expanded as they're read, and
+/// only the search window is kept as history, not everything decoded so far.
+pub struct Lz77Decoder<R: Read> {
+    inner: R,
+    compressor: Lz77Compression,
+    /// Compressed bytes read from `inner`, starting with the current stream's header.
+    buffered: Vec<u8>,
+    /// How much of `buffered` has been parsed or read.
+    consumed: usize,
+    /// Whether `inner` has run out.
+    eof: bool,
+    stream: Option<Stream>,
+    /// The history and window of the last stream finished, for one that continues it.
+    previous: Option<(Vec<u8>, usize)>,
+    /// The original lengths of the streams started so far, for `with_max_output_bytes`.
+    started_len: usize,
+}
+
+/// The stream currently being decoded.
+struct Stream {
+    body: Body,
+    /// At least the last `window` bytes decoded, starting out as the dictionary.
+    history: Vec<u8>,
+    window: usize,
+    /// The md5 recorded in the header, and the bytes decoded so far.
+    content_hash: Option<([u8; 16], md5::Context)>,
+}
+
+enum Body {
+    /// How many stored bytes are still to be read, from what's buffered and then `inner`.
+    Stored(usize),
+    /// The nodes, the index of the next to expand and how many of its bytes have been already.
+    Nodes {
+        nodes: Vec<NodeType>,
+        next: usize,
+        done: usize,
+    },
+}
+
+impl<R: Read> Lz77Decoder<R> {
+    /// Decodes with `compressor`'s dictionary and output limit.
+    pub fn new(inner: R, compressor: Lz77Compression) -> Self {
+        Lz77Decoder {
+            inner,
+            compressor,
+            buffered: Vec::new(),
+            consumed: 0,
+            eof: false,
+            stream: None,
+            previous: None,
+            started_len: 0,
+        }
+    }
+
+    /// Reads the header and nodes of the next stream, returning false once there are none left.
+    fn start_stream(&mut self) -> Result<bool> {
+        self.buffered.drain(..self.consumed);
+        self.consumed = 0;
+        while self.buffered.is_empty() && !self.eof {
+            self.fill()?;
+        }
+        // an empty input fails to parse, like `decompress_concatenated`, so it only ends once
+        // a stream has been finished
+        if self.buffered.is_empty() && self.previous.is_some() {
+            return Ok(false);
+        }
+
+        let (header, body_start) = self.parse(|bytes| {
+            let (header, body) = serialisation::read_header(bytes)?;
+            Ok((header, bytes.len() - body.len()))
+        })?;
+        self.compressor
+            .check_output_limit(self.started_len, header.original_len)?;
+        let body = match header.mode {
+            Mode::Stored => {
+                self.consumed = body_start;
+                Body::Stored(header.original_len)
+            }
+            Mode::Lz77 | Mode::Tokens | Mode::FixedOffsets { .. } | Mode::RepeatOffsets => {
+                let (nodes, body_len) = self.parse(|bytes| {
+                    let body = &bytes[body_start..];
+                    let (nodes, rest) = Lz77Compression::decode_nodes(&header, body)?;
+                    Ok((nodes, body.len() - rest.len()))
+                })?;
+                if compress::decoded_len(&nodes)? != header.original_len {
+                    return Err(DeserialiseError::LengthMismatch.into());
+                }
+                self.consumed = body_start + body_len;
+                Body::Nodes {
+                    nodes,
+                    next: 0,
+                    done: 0,
+                }
+            }
+        };
+        self.started_len += header.original_len;
+
+        let window = usize::from(header.search_window_size.get());
+        let history = match self.previous.take() {
+            Some((history, previous_window)) if header.continues => {
+                if previous_window != window {
+                    return Err(DeserialiseError::WindowChanged.into());
+                }
+                history
+            }
+            _ => {
+                let dictionary = &self.compressor.dictionary;
+                // `remember` never lets the history past twice the window, so it never
+                // reallocates
+                let mut history = Vec::with_capacity(2 * window);
+                history.extend_from_slice(&dictionary[dictionary.len().saturating_sub(window)..]);
+                history
+            }
+        };
+        self.stream = Some(Stream {
+            body,
+            history,
+            window,
+            content_hash: header.content_hash.map(|hash| (hash, md5::Context::new())),
+        });
+        Ok(true)
+    }
+
+    /// Runs `parse` over the buffered bytes, reading more from `inner` for as long as they end
+    /// too soon.
+    fn parse<T>(&mut self, parse: impl Fn(&[u8]) -> Result<T>) -> Result<T> {
+        loop {
+            // the magic bytes can't be recognised from part of them
+            let short = self.buffered.len() < frame::HEADER_SIZE;
+            match parse(&self.buffered) {
+                Err(Error::Deserialise(DeserialiseError::Truncated)) if !self.eof => self.fill()?,
+                Err(Error::Deserialise(DeserialiseError::BadMagic)) if short && !self.eof => {
+                    self.fill()?
+                }
+                parsed => return parsed,
+            }
+        }
+    }
+
+    /// Reads once from `inner` onto the end of the buffered bytes, asking for at least
+    /// `READ_SIZE` bytes and as many as are buffered already, so parsing again as the bytes
+    /// arrive doesn't take quadratic time.
+    fn fill(&mut self) -> io::Result<()> {
+        let len = self.buffered.len();
+        self.buffered.resize(len + cmp::max(len, READ_SIZE), 0);
+        let read = self.inner.read(&mut self.buffered[len..]);
+        self.buffered.truncate(len + *read.as_ref().unwrap_or(&0));
+        self.eof = read? == 0;
+        Ok(())
+    }
+
+    /// Decodes as much of the current stream as fits in `buf`, returning 0 once it's all read.
+    fn decode(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let stream = self.stream.as_mut().expect("a stream has been started");
+        let written = match &mut stream.body {
+            Body::Stored(remaining) => {
+                let len = cmp::min(*remaining, buf.len());
+                let buffered = &self.buffered[self.consumed..];
+                let len = if buffered.is_empty() && len > 0 {
+                    match self.inner.read(&mut buf[..len])? {
+                        0 => return Err(DeserialiseError::Truncated.into()),
+                        read => read,
+                    }
+                } else {
+                    let len = cmp::min(len, buffered.len());
+                    buf[..len].copy_from_slice(&buffered[..len]);
+                    self.consumed += len;
+                    len
+                };
+                *remaining -= len;
+                remember(&mut stream.history, &buf[..len], stream.window);
+                len
+            }
+            Body::Nodes { nodes, next, done } => {
+                expand(nodes, next, done, &mut stream.history, stream.window, buf)?
+            }
+        };
+        if let Some((_, context)) = &mut stream.content_hash {
+            context.consume(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    /// Checks the finished stream against its content hash, if it has one, and keeps its
+    /// history for the next.
+    fn finish_stream(&mut self) -> Result<()> {
+        let stream = self.stream.take().expect("a stream has been started");
+        if let Some((hash, context)) = stream.content_hash {
+            if context.compute().0 != hash {
+                return Err(DeserialiseError::ChecksumMismatch.into());
+            }
+        }
+        self.previous = Some((stream.history, stream.window));
+        Ok(())
+    }
+}
+
+/// Expands nodes from `nodes[*next]`, `*done` bytes in, into `buf`, returning how many bytes
+/// were written, and remembers each node's bytes in `history`.
+fn expand(
+    nodes: &[NodeType],
+    next: &mut usize,
+    done: &mut usize,
+    history: &mut Vec<u8>,
+    window: usize,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let mut written = 0;
+    while written < buf.len() && *next < nodes.len() {
+        let node = nodes[*next];
+        let out = &mut buf[written..];
+        let len = cmp::min(node.decoded_len() - *done, out.len());
+        match node {
+            NodeType::ByteLiteral { lit } => out[0] = lit,
+            NodeType::Run { byte, .. } => out[..len].fill(byte),
+            NodeType::Reference { offset, length } => {
+                let available = cmp::min(history.len(), window);
+                if *done == 0 && (usize::from(offset) > available || length > offset) {
+                    return Err(DeserialiseError::InvalidReference {
+                        offset,
+                        length,
+                        available,
+                    }
+                    .into());
+                }
+                // the rest of the reference starts `offset` bytes back from the end of the
+                // history, however much of it has been copied already
+                let start = history.len() - usize::from(offset);
+                out[..len].copy_from_slice(&history[start..start + len]);
+            }
+        }
+        remember(history, &out[..len], window);
+        written += len;
+        *done += len;
+        if *done == node.decoded_len() {
+            *next += 1;
+            *done = 0;
+        }
+    }
+    Ok(written)
+}
+
+/// Appends the last `window` of `bytes` to `history`, trimming it first if they'd take it past
+/// twice the window, however many bytes there are.
+fn remember(history: &mut Vec<u8>, bytes: &[u8], window: usize) {
+    let kept = &bytes[bytes.len().saturating_sub(window)..];
+    if history.len() + kept.len() > 2 * window {
+        history.drain(..history.len() + kept.len() - window);
+    }
+    history.extend_from_slice(kept);
+}
+
+impl<R: Read> Read for Lz77Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.stream.is_none() && !self.start_stream()? {
+                return Ok(0);
+            }
+            let written = self.decode(buf)?;
+            if written > 0 {
+                return Ok(written);
+            }
+            self.finish_stream()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::lz77::{Lz77Encoder, OriginalFile, WindowSize};
+    use crate::test_util::pseudo_random;
+
+    fn read_in_chunks(decoder: &mut impl Read, chunk_size: usize) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        let mut buf = vec![0; chunk_size];
+        loop {
+            match decoder.read(&mut buf)? {
+                0 => return Ok(decoded),
+                len => decoded.extend_from_slice(&buf[..len]),
+            }
+        }
+    }
+
+    /// Hands out at most 3 bytes a read, and only the first `available`, like a socket whose
+    /// peer hasn't sent the rest yet.
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        position: usize,
+        available: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let end = cmp::min(self.available, self.bytes.len());
+            if self.position == end && end < self.bytes.len() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = cmp::min(cmp::min(3, buf.len()), end - self.position);
+            buf[..len].copy_from_slice(&self.bytes[self.position..self.position + len]);
+            self.position += len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn streams_decode_before_the_next_has_arrived() {
+        let mut input: Vec<u8> = pseudo_random(9).take(1000).collect();
+        input.extend(b"records sent a chunk at a time, ".repeat(60));
+        for compressor in [
+            Lz77Compression::new,
+            || Lz77Compression::new().with_sync_interval(300),
+            || Lz77Compression::new().with_token_stream(),
+            || Lz77Compression::new().with_adaptive_offsets(),
+            || Lz77Compression::new().with_repeat_offsets(),
+            || Lz77Compression::new().with_run_length_encoding(8),
+            || {
+                Lz77Compression::new()
+                    .with_content_hash()
+                    .with_original_file(OriginalFile::new("records", 0).unwrap())
+            },
+        ] {
+            let mut encoder = Lz77Encoder::new(Vec::new(), compressor()).with_chunk_size(1000);
+            encoder.write_all(&input).unwrap();
+            let compressed = encoder.finish().unwrap();
+            // the first chunk is random, so stored
+            let first_len = compressor().encode(&input[..1000]).len();
+
+            let trickle = Trickle {
+                bytes: &compressed,
+                position: 0,
+                available: first_len,
+            };
+            let mut decoder = Lz77Decoder::new(trickle, compressor());
+            let mut first = vec![0; 1000];
+            decoder.read_exact(&mut first).unwrap();
+            assert_eq!(input[..1000], first[..]);
+            let err = decoder.read(&mut [0; 100]).unwrap_err();
+            assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+            // the rest is parsed a few bytes at a time as it arrives
+            decoder.inner.available = usize::MAX;
+            let mut rest = Vec::new();
+            decoder.read_to_end(&mut rest).unwrap();
+            assert_eq!(input[1000..], rest[..]);
+        }
+    }
+
+    #[test]
+    fn awkward_read_sizes_decode_the_original() {
+        let mut random = pseudo_random(7);
+        let mut input = Vec::new();
+        for i in 0..2000 {
+            input
+                .extend_from_slice(format!("record {} of a fairly repetitive log, ", i).as_bytes());
+            if i % 100 == 0 {
+                input.extend(vec![b'-'; 3000]);
+                input.extend(random.by_ref().take(500));
+            }
+        }
+        for compressor in [
+            Lz77Compression::new,
+            || Lz77Compression::new().with_run_length_encoding(32),
+            || {
+                Lz77Compression::new()
+                    .with_token_stream()
+                    .with_content_hash()
+            },
+            || Lz77Compression::new().with_search_window_size(WindowSize::new(300).unwrap()),
+        ] {
+            let compressed = compressor().encode(&input);
+            for chunk_size in [1, 7, 1 << 20] {
+                let mut decoder = Lz77Decoder::new(&compressed[..], compressor());
+                assert_eq!(input, read_in_chunks(&mut decoder, chunk_size).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn history_stays_within_twice_the_window_in_one_read() {
+        let window = WindowSize::new(300).unwrap();
+        let compressor = || {
+            Lz77Compression::new()
+                .with_search_window_size(window)
+                .with_run_length_encoding(32)
+        };
+        let mut input = b"a line repeated within the window. ".repeat(200);
+        input.extend(vec![b'-'; 5000]);
+        input.extend(pseudo_random(3).take(1000));
+        let compressed = compressor().encode(&input);
+
+        let mut decoder = Lz77Decoder::new(&compressed[..], compressor());
+        let mut buf = vec![0; input.len()];
+        assert_eq!(input.len(), decoder.read(&mut buf).unwrap());
+        assert_eq!(input, buf);
+        let history = &decoder.stream.as_ref().unwrap().history;
+        assert!(history.capacity() <= 2 * usize::from(window.get()));
+        assert_eq!(history[..], input[input.len() - history.len()..]);
+    }
+
+    #[test]
+    fn concatenated_stored_and_dictionary_streams_decode() {
+        let dictionary = b"a dictionary of shared boilerplate";
+        let compressor = Lz77Compression::new().with_dictionary(dictionary);
+        let mut compressed = compressor.encode(b"shared boilerplate, shared boilerplate");
+        compressed.extend(compressor.encode(b""));
+        compressed.extend(compressor.encode(b"xyz"));
+        let expected = compressor.decompress_concatenated(&compressed).unwrap();
+
+        let mut decoder = Lz77Decoder::new(&compressed[..], compressor);
+        let mut decoded = Vec::new();
+        io::copy(&mut decoder, &mut decoded).unwrap();
+        assert_eq!(expected, decoded);
+
+        let empty: &[u8] = &[];
+        let mut decoder = Lz77Decoder::new(empty, Lz77Compression::new());
+        assert!(decoder.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn streams_continuing_the_one_before_decode() {
+        let window = WindowSize::new(300).unwrap();
+        let compressor = || {
+            Lz77Compression::new()
+                .with_dictionary(b"a dictionary")
+                .with_search_window_size(window)
+        };
+        // the random chunk is stored, and the next starts by reaching back into it
+        let mut input: Vec<u8> = pseudo_random(5).take(1000).collect();
+        input.extend_from_within(800..);
+        input.extend(b"a dictionary entry, ".repeat(40));
+        let mut encoder = Lz77Encoder::new(Vec::new(), compressor()).with_chunk_size(1000);
+        encoder.write_all(&input).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(
+            input,
+            compressor().decompress_concatenated(&compressed).unwrap()
+        );
+        for chunk_size in [1, 7, 1 << 20] {
+            let mut decoder = Lz77Decoder::new(&compressed[..], compressor());
+            assert_eq!(input, read_in_chunks(&mut decoder, chunk_size).unwrap());
+        }
+
+        // a stream can't continue one with a different window
+        let wider = Lz77Compression::new().with_search_window_size(WindowSize::new(400).unwrap());
+        let mut encoder = Lz77Encoder::new(Vec::new(), wider).with_chunk_size(1000);
+        encoder.write_all(&input).unwrap();
+        let wider_compressed = encoder.finish().unwrap();
+        let first_len = compressor().encode(&input[..1000]).len();
+        let wider_first_len = Lz77Compression::new()
+            .with_search_window_size(WindowSize::new(400).unwrap())
+            .encode(&input[..1000])
+            .len();
+        let mixed = [
+            &compressed[..first_len],
+            &wider_compressed[wider_first_len..],
+        ]
+        .concat();
+        assert!(matches!(
+            compressor().decompress_concatenated(&mixed),
+            Err(Error::Deserialise(DeserialiseError::WindowChanged))
+        ));
+        let mut decoder = Lz77Decoder::new(&mixed[..], compressor());
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            err.into_inner().unwrap().downcast::<Error>().as_deref(),
+            Ok(Error::Deserialise(DeserialiseError::WindowChanged))
+        ));
+    }
+
+    #[test]
+    fn corrupt_streams_fail_the_read() {
+        let input = b"checked against its md5 ".repeat(50);
+        let mut compressed = Lz77Compression::new().with_content_hash().encode(&input);
+        let last = compressed.len() - 1;
+        compressed[last - 20] ^= 0x40;
+        let mut decoder = Lz77Decoder::new(&compressed[..], Lz77Compression::new());
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let limited = Lz77Compression::new().with_max_output_bytes(10);
+        let compressed = Lz77Compression::new().encode(&input);
+        let mut decoder = Lz77Decoder::new(&compressed[..], limited);
+        let err = decoder.read(&mut [0; 4]).unwrap_err();
+        assert!(matches!(
+            err.into_inner().unwrap().downcast::<Error>().as_deref(),
+            Ok(Error::OutputLimitExceeded { limit: 10 })
+        ));
+    }
+}
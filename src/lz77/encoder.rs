@@ -0,0 +1,323 @@
+use std::cmp;
+use std::io::{self, prelude::*};
+
+use super::Lz77Compression;
+use crate::compression::CompressionStats;
+use crate::error::Result;
+
+/// How many input bytes `Lz77Encoder` keeps before compressing them, unless changed with
+/// `with_chunk_size`.
+pub(super) const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Compresses everything written to it into `inner`, like flate2's `GzEncoder`.
+///
+/// The header records the original length and how the nodes are stored before any of them, so
+/// input is kept until a chunk of it has been written, which is compressed into `inner` as a
+/// stream of its own by the next write. `finish` compresses whatever is left. Every stream
+/// after the first continues the one before it, with the last search window of the bytes
+/// already compressed as its history, so references reach back across chunk boundaries and
+/// only the input and a window are kept in memory. The output is a series of appended streams,
+/// which `decompress_concatenated` and `Lz77Decoder` join back together. It's the same as
+/// compressing in one go when the input fits in a single chunk, and otherwise only differs
+/// by a header per chunk and matches cut short at the chunk boundaries.
+pub struct Lz77Encoder<W: Write> {
+    compressor: Lz77Compression,
+    chunks: Chunks,
+    /// Taken by `finish`, so dropping an encoder that wasn't finished can finish it instead.
+    inner: Option<W>,
+}
+
+impl<W: Write> Lz77Encoder<W> {
+    pub fn new(inner: W, compressor: Lz77Compression) -> Self {
+        Lz77Encoder {
+            chunks: Chunks::new(&compressor, DEFAULT_CHUNK_SIZE),
+            compressor,
+            inner: Some(inner),
+        }
+    }
+
+    /// Compresses every `chunk_size` bytes written into a stream of their own, at least 1.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunks.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Compresses the bytes not yet written into the inner writer, flushes it and returns it.
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut inner = self
+            .inner
+            .take()
+            .expect("only finish takes the inner writer");
+        self.chunks.finish(&self.compressor, &mut inner)?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for Lz77Encoder<W> {
+    /// Compresses a full chunk left by the last write before taking any more of `buf`, so an
+    /// error from the inner writer leaves `buf` unwritten.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunks = &mut self.chunks;
+        if chunks.input.len() == chunks.chunk_size {
+            let inner = self
+                .inner
+                .as_mut()
+                .expect("only finish takes the inner writer");
+            chunks.compress(&self.compressor, inner)?;
+        }
+        let taken = cmp::min(buf.len(), chunks.chunk_size - chunks.input.len());
+        chunks.input.extend_from_slice(&buf[..taken]);
+        Ok(taken)
+    }
+
+    /// Only flushes the inner writer, as the bytes of a chunk that isn't full yet can't be
+    /// compressed before the rest of it is written or `finish` is called.
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for Lz77Encoder<W> {
+    /// Finishes an encoder that was dropped without calling `finish`, ignoring any error.
+    fn drop(&mut self) {
+        if let Some(mut inner) = self.inner.take() {
+            let _ = self.chunks.finish(&self.compressor, &mut inner);
+        }
+    }
+}
+
+/// Compresses everything `reader` yields into `output` as an `Lz77Encoder` with chunks of
+/// `chunk_size` would, returning the stats of all the chunks together. The content hash is only given for a single chunk, as
+/// each stream records the hash of its own bytes.
+pub(super) fn compress_chunks(
+    compressor: &Lz77Compression,
+    reader: &mut dyn Read,
+    output: &mut dyn Write,
+    chunk_size: usize,
+) -> Result<CompressionStats> {
+    let mut chunks = Chunks::new(compressor, chunk_size.max(1));
+    let mut total: Option<CompressionStats> = None;
+    loop {
+        let chunk_size = chunks.chunk_size;
+        reader
+            .take(chunk_size as u64)
+            .read_to_end(&mut chunks.input)?;
+        if chunks.input.len() < chunk_size {
+            let stats = chunks.finish(compressor, output)?;
+            return Ok(match (total, stats) {
+                (Some(total), Some(stats)) => add(total, stats),
+                (total, stats) => total.or(stats).expect("at least one stream is written"),
+            });
+        }
+        let stats = chunks.compress(compressor, output)?;
+        total = Some(match total {
+            Some(total) => add(total, stats),
+            None => stats,
+        });
+    }
+}
+
+/// The stats of two chunks compressed one after the other.
+fn add(first: CompressionStats, second: CompressionStats) -> CompressionStats {
+    let mut histogram = first.histogram.unwrap_or_default();
+    histogram += second.histogram.unwrap_or_default();
+    CompressionStats {
+        original_bytes: first.original_bytes + second.original_bytes,
+        compressed_bytes: first.compressed_bytes + second.compressed_bytes,
+        histogram: Some(histogram),
+        ..CompressionStats::default()
+    }
+}
+
+/// The input not yet compressed and the history the next chunk continues from, kept apart
+/// from the compressor so `compress_chunks` can work with a borrowed one.
+struct Chunks {
+    input: Vec<u8>,
+    chunk_size: usize,
+    /// The last window of the dictionary and the bytes compressed so far.
+    history: Vec<u8>,
+    /// Whether a chunk has been compressed, so the next continues it and `finish` needn't
+    /// write an empty stream.
+    wrote_chunk: bool,
+}
+
+impl Chunks {
+    fn new(compressor: &Lz77Compression, chunk_size: usize) -> Self {
+        let window = usize::from(compressor.search_window_size.get());
+        let dictionary = &compressor.dictionary;
+        Chunks {
+            input: Vec::new(),
+            chunk_size,
+            history: dictionary[dictionary.len().saturating_sub(window)..].to_vec(),
+            wrote_chunk: false,
+        }
+    }
+
+    /// Compresses the input into `inner`, continuing the last chunk if there was one, then
+    /// keeps its last window as the history for the next.
+    fn compress(
+        &mut self,
+        compressor: &Lz77Compression,
+        inner: &mut dyn Write,
+    ) -> Result<CompressionStats> {
+        let history = if self.wrote_chunk {
+            Some(&self.history[..])
+        } else {
+            None
+        };
+        let stats = compressor.compress_continuing(history, &self.input, inner, |_, _| {})?;
+
+        let window = usize::from(compressor.search_window_size.get());
+        let input = &self.input[self.input.len().saturating_sub(window)..];
+        self.history.extend_from_slice(input);
+        self.history
+            .drain(..self.history.len().saturating_sub(window));
+        self.input.clear();
+        self.wrote_chunk = true;
+        Ok(stats)
+    }
+
+    /// Compresses what's left of the input, or an empty stream if nothing was written at all,
+    /// returning `None` if there was nothing to write.
+    fn finish(
+        &mut self,
+        compressor: &Lz77Compression,
+        inner: &mut dyn Write,
+    ) -> Result<Option<CompressionStats>> {
+        if self.input.is_empty() && self.wrote_chunk {
+            inner.flush()?;
+            return Ok(None);
+        }
+        self.compress(compressor, inner).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Algorithm;
+    use crate::error::{DeserialiseError, Error};
+
+    #[test]
+    fn small_writes_match_compressing_in_one_go() {
+        let input = b"written a few bytes at a time, written a few bytes at a time. ".repeat(100);
+        for compressor in [
+            Lz77Compression::new,
+            || Lz77Compression::new().with_optimal_parse(),
+            || Lz77Compression::new().with_token_stream(),
+        ] {
+            let one_shot = compressor().compress_to_vec(&input).unwrap();
+            for chunk_size in [1, 7, 2048, input.len()] {
+                let mut encoder = Lz77Encoder::new(Vec::new(), compressor());
+                for chunk in input.chunks(chunk_size) {
+                    encoder.write_all(chunk).unwrap();
+                    encoder.flush().unwrap();
+                }
+                let compressed = encoder.finish().unwrap();
+                assert_eq!(one_shot, compressed, "chunks of {}", chunk_size);
+                assert_eq!(input, compressor().decode(&compressed).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn full_chunks_are_compressed_as_they_are_written() {
+        let input = b"long enough to fill several chunks of the encoder. ".repeat(100);
+        let compressor = Lz77Compression::new;
+        let chunked = |input: &[u8], write_size: usize| {
+            let mut encoder = Lz77Encoder::new(Vec::new(), compressor()).with_chunk_size(1000);
+            for bytes in input.chunks(write_size) {
+                encoder.write_all(bytes).unwrap();
+                assert!(encoder.chunks.input.len() <= 1000);
+            }
+            encoder.finish().unwrap()
+        };
+        let expected = chunked(&input, input.len());
+        for write_size in [1, 999, 1000, 3333] {
+            assert_eq!(
+                expected,
+                chunked(&input, write_size),
+                "writes of {}",
+                write_size
+            );
+        }
+        assert_eq!(
+            input,
+            compressor().decompress_concatenated(&expected).unwrap()
+        );
+
+        // the first chunk is compressed as if on its own, and the next reaches back into it
+        let first = compressor().encode(&input[..1000]);
+        assert!(expected.starts_with(&first));
+        assert!(matches!(
+            compressor().decode(&expected[first.len()..]),
+            Err(Error::Deserialise(
+                DeserialiseError::InvalidReference { .. }
+            ))
+        ));
+        let one_shot = compressor().encode(&input);
+        // a header and a few nodes either side of each boundary
+        assert!(expected.len() < one_shot.len() + 6 * 16);
+
+        // input ending on a chunk boundary doesn't leave an empty stream behind
+        let two_chunks = chunked(&input[..2000], 2000);
+        assert!(expected.starts_with(&two_chunks));
+        assert_eq!(
+            input[..2000].to_vec(),
+            compressor().decompress_concatenated(&two_chunks).unwrap()
+        );
+    }
+
+    #[test]
+    fn reading_chunks_matches_writing_them_to_an_encoder() {
+        let input = b"read from a reader a chunk at a time, read from a reader. ".repeat(100);
+        let compressor = || Lz77Compression::new().with_content_hash();
+        for length in [0, 999, 1000, 1001, input.len()] {
+            let input = &input[..length];
+            let mut encoder = Lz77Encoder::new(Vec::new(), compressor()).with_chunk_size(1000);
+            encoder.write_all(input).unwrap();
+            let expected = encoder.finish().unwrap();
+
+            let mut compressed = Vec::new();
+            let stats =
+                compress_chunks(&compressor(), &mut &input[..], &mut compressed, 1000).unwrap();
+            assert_eq!(expected, compressed, "{} bytes", length);
+            assert_eq!(length, stats.original_bytes);
+            assert_eq!(compressed.len(), stats.compressed_bytes);
+            assert_eq!(length <= 1000, stats.content_hash.is_some());
+            assert_eq!(
+                input,
+                &compressor().decompress_concatenated(&compressed).unwrap()[..]
+            );
+        }
+
+        // the byte after the first chunk is a stream of one literal, counted with the rest
+        let (_, first) = compressor().compress_with_stats(&input[..1000]).unwrap();
+        let mut expected = first.histogram.unwrap();
+        expected.literals += 1;
+        let stats =
+            compress_chunks(&compressor(), &mut &input[..1001], &mut io::sink(), 1000).unwrap();
+        assert_eq!(Some(expected), stats.histogram);
+    }
+
+    #[test]
+    fn dropping_an_unfinished_encoder_finishes_it() {
+        let mut output = Vec::new();
+        {
+            let mut encoder = Lz77Encoder::new(&mut output, Lz77Compression::new());
+            encoder.write_all(b"dropped, not finished").unwrap();
+        }
+        assert_eq!(
+            b"dropped, not finished".to_vec(),
+            Lz77Compression::new().decode(&output).unwrap()
+        );
+
+        let empty = Lz77Encoder::new(Vec::new(), Lz77Compression::new())
+            .finish()
+            .unwrap();
+        assert_eq!(Lz77Compression::new().encode(b""), empty);
+    }
+}
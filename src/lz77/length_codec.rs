@@ -0,0 +1,182 @@
+use core::convert::TryFrom;
+
+use super::bit_reader::BitReader;
+use super::bit_writer::BitWriter;
+use crate::error::DeserialiseError;
+
+// Reference lengths use the LZS variable length code. 2 to 7 have a code of their own from
+// `SHORT_CODES`. Anything longer is N blocks of 1111, where N = (length + 7) / 15, followed by
+// 4 bits holding length - (N * 15 - 7), which is always 0 to 14.
+
+/// The (code, bit count) of each length from 2 to 7, indexed by length - 2.
+const SHORT_CODES: [(u8, u32); 6] = [
+    (0b00, 2),
+    (0b01, 2),
+    (0b10, 2),
+    (0b1100, 4),
+    (0b1101, 4),
+    (0b1110, 4),
+];
+const SHORTEST_LENGTH: u16 = 2;
+const LONGEST_SHORT_LENGTH: u16 = SHORTEST_LENGTH + SHORT_CODES.len() as u16 - 1;
+
+const BLOCK_BITS: u32 = 4;
+/// What each block of 1111 adds to the length.
+const BLOCK_LENGTH: usize = 15;
+/// Taken off the blocks' total, so a single block with nothing left over is a length of 8.
+const BLOCK_BIAS: usize = 7;
+
+fn block_count(length: u16) -> usize {
+    (usize::from(length) + BLOCK_BIAS) / BLOCK_LENGTH
+}
+
+/// Bits `write_length` spends on `length`.
+pub fn encoded_bits(length: u16) -> usize {
+    match length {
+        SHORTEST_LENGTH..=LONGEST_SHORT_LENGTH => {
+            SHORT_CODES[usize::from(length - SHORTEST_LENGTH)].1 as usize
+        }
+        _ => BLOCK_BITS as usize * (block_count(length) + 1),
+    }
+}
+
+/// Panics on a length below 2, which has no code. The matchers emit a literal instead, as
+/// `references_are_never_shorter_than_two_bytes` checks.
+pub fn write_length<W: Extend<u8>>(writer: &mut BitWriter<W>, length: u16) {
+    match length {
+        0 | 1 => panic!("References must cover at least 2 bytes, not {}", length),
+        SHORTEST_LENGTH..=LONGEST_SHORT_LENGTH => {
+            let (code, bits) = SHORT_CODES[usize::from(length - SHORTEST_LENGTH)];
+            writer.write_bits(u32::from(code), bits);
+        }
+        _ => {
+            let blocks = block_count(length);
+            for _ in 0..blocks {
+                writer.write_bits(0b1111, BLOCK_BITS);
+            }
+            let left_over = usize::from(length) - (blocks * BLOCK_LENGTH - BLOCK_BIAS);
+            writer.write_bits(left_over as u32, BLOCK_BITS);
+        }
+    }
+}
+
+/// Reads a length written by `write_length`, leaving `reader` just after it.
+pub fn deserialise_length(reader: &mut BitReader) -> Result<u16, DeserialiseError> {
+    // the 2 bit codes come first, so only a 11 prefix needs the next 2 bits
+    for (length, &(code, bits)) in (SHORTEST_LENGTH..).zip(SHORT_CODES.iter()) {
+        if reader.peek_bits(bits as usize)? == u16::from(code) {
+            reader.read_bits(bits as usize)?;
+            return Ok(length);
+        }
+    }
+
+    let mut blocks = 0;
+    loop {
+        let block = reader.read_bits(BLOCK_BITS as usize)?;
+        if block == 0b1111 {
+            blocks += 1;
+            continue;
+        }
+
+        let length = blocks * BLOCK_LENGTH - BLOCK_BIAS + usize::from(block);
+        return u16::try_from(length).map_err(|_| DeserialiseError::InvalidLength);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lz77::compress::MAX_MATCH_LENGTH;
+    use alloc::vec::Vec;
+    use bitvec::prelude::*;
+
+    fn serialise_length(length: u16) -> BitVec<Msb0, u8> {
+        let mut writer = BitWriter::new(Vec::new());
+        write_length(&mut writer, length);
+        writer.into_bitvec()
+    }
+
+    /// The length and the number of bits it took up.
+    fn deserialise(encoded: &BitSlice<Msb0, u8>) -> Result<(u16, usize), DeserialiseError> {
+        let encoded = encoded.to_bitvec();
+        let mut reader = BitReader::with_len(encoded.as_raw_slice(), encoded.len());
+        let length = deserialise_length(&mut reader)?;
+        Ok((length, reader.position()))
+    }
+
+    #[test]
+    fn every_length_round_trips() {
+        for length in SHORTEST_LENGTH..=MAX_MATCH_LENGTH {
+            let mut encoded = serialise_length(length);
+            assert_eq!(encoded_bits(length), encoded.len(), "length {}", length);
+
+            // decoding stops at the end of the code, whatever follows it
+            let bits = encoded.len();
+            for trailing in [false, true] {
+                encoded.resize(bits + 8, trailing);
+                assert_eq!(
+                    Ok((length, bits)),
+                    deserialise(&encoded),
+                    "length {}",
+                    length
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn deserialises_length() {
+        assert_eq!(Ok((2, 2)), deserialise(bits![Msb0, u8; 0,0,0,0]));
+        assert_eq!(Ok((3, 2)), deserialise(bits![Msb0, u8; 0,1,0,0]));
+        assert_eq!(Ok((4, 2)), deserialise(bits![Msb0, u8; 1,0,0,0]));
+        assert_eq!(Ok((5, 4)), deserialise(bits![Msb0, u8; 1,1,0,0]));
+        assert_eq!(Ok((6, 4)), deserialise(bits![Msb0, u8; 1,1,0,1]));
+        assert_eq!(Ok((7, 4)), deserialise(bits![Msb0, u8; 1,1,1,0]));
+        assert_eq!(Ok((8, 8)), deserialise(bits![Msb0, u8; 1,1,1,1,0,0,0,0]));
+        assert_eq!(Ok((9, 8)), deserialise(bits![Msb0, u8; 1,1,1,1,0,0,0,1]));
+        assert_eq!(
+            Ok((23, 12)),
+            deserialise(bits![Msb0, u8; 1,1,1,1,1,1,1,1,0,0,0,0])
+        );
+        assert_eq!(
+            Ok((37, 12)),
+            deserialise(bits![Msb0, u8; 1,1,1,1,1,1,1,1,1,1,1,0])
+        );
+        assert_eq!(
+            Ok((38, 16)),
+            deserialise(bits![Msb0, u8; 1,1,1,1,1,1,1,1,1,1,1,1,0,0,0,0])
+        );
+
+        let mut max_val = bitvec![Msb0, u8;];
+        let four_bit_blocks_for_max_size = ((2047 + 7) / 15) + 1; // +1 for final 4 bits;
+        assert_eq!(137, four_bit_blocks_for_max_size);
+
+        max_val.resize(137 * 4, true);
+        max_val.set(544, true);
+        max_val.set(545, true);
+        max_val.set(546, true);
+        max_val.set(547, false);
+        assert_eq!(Ok((2047, 548)), deserialise(&max_val));
+    }
+
+    #[test]
+    fn truncated_lengths_are_rejected() {
+        for encoded in [
+            bits![Msb0, u8;],
+            bits![Msb0, u8; 0],
+            bits![Msb0, u8; 1, 1],
+            bits![Msb0, u8; 1, 1, 1, 1],
+            bits![Msb0, u8; 1, 1, 1, 1, 0, 0],
+        ] {
+            assert_eq!(Err(DeserialiseError::Truncated), deserialise(encoded));
+        }
+    }
+
+    #[test]
+    fn overlong_lengths_are_rejected() {
+        let mut too_long = bitvec![Msb0, u8;];
+        too_long.resize(5000 * 4, true);
+        too_long.extend_from_bitslice(bits![Msb0, u8; 0, 0, 0, 0]);
+        assert_eq!(Err(DeserialiseError::InvalidLength), deserialise(&too_long));
+    }
+}
@@ -0,0 +1,14 @@
+/// Presets trading compression speed against size, set with `Lz77Compression::with_level`,
+/// like gzip's `-1` to `-9`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Level {
+    /// Stops at the first 16 byte match and compares at most 4 candidates at each position.
+    Fastest,
+    /// The settings `Lz77Compression::new` starts with: a greedy parse over the default
+    /// window, comparing every candidate.
+    #[default]
+    Default,
+    /// The optimal parse over a 16383 byte window, with repeat and adaptive offsets. Around
+    /// twenty times slower than `Default`.
+    Best,
+}
@@ -1,65 +1,1364 @@
-use std::fs::File;
-use std::io::{self, prelude::*};
+use alloc::{string::String, vec, vec::Vec};
+use core::cmp;
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+#[cfg(feature = "std")]
+use std::fs::{self, File};
+#[cfg(feature = "std")]
+use std::io::{self, prelude::*, SeekFrom};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::thread;
 
-use crate::compression;
+#[cfg(feature = "std")]
+use crate::compression::{self, CompressionStats};
+use crate::error::{DeserialiseError, Error, Result};
+use nodes::NodeType;
+use serialisation::{Header, Mode, OffsetCodec};
+use window_byte_container::PrefixTables;
 
+#[cfg(feature = "std")]
+mod archive;
+mod bit_reader;
+mod bit_writer;
 mod compress;
-mod nodes;
+mod compressed;
+#[cfg(feature = "std")]
+mod decoder;
+#[cfg(feature = "std")]
+mod encoder;
+mod length_codec;
+mod level;
+pub mod nodes;
+mod original_file;
+#[cfg(feature = "std")]
+mod parallel;
+#[cfg(feature = "std")]
+mod seekable;
 mod serialisation;
+mod suffix_array;
+mod tokens;
 pub mod window_byte_container;
+mod window_size;
 
-pub struct Lz77Compression {}
+pub use compressed::CompressedLz77;
+#[cfg(feature = "std")]
+pub use decoder::Lz77Decoder;
+#[cfg(feature = "std")]
+pub use encoder::Lz77Encoder;
+pub use level::Level;
+pub use original_file::OriginalFile;
+pub use window_size::WindowSize;
 
+/// How many literals per reference or run `Lz77Compression::with_adaptive_literal_runs` needs
+/// before it writes a token stream. Each literal saves a bit there, and each reference costs
+/// about a byte more.
+const LITERALS_PER_REFERENCE: usize = 12;
+
+pub struct Lz77Compression {
+    dictionary: Vec<u8>,
+    search_window_size: WindowSize,
+    max_match_length: u16,
+    nice_match_length: u16,
+    /// How many earlier positions the greedy matcher compares at each position, at most.
+    max_candidates: usize,
+    /// Whether the greedy matcher passes over references that cost more than their literals.
+    profitable_matches: bool,
+    parse: Parse,
+    /// How nodes are serialised, `Lz77` or `Tokens`.
+    node_mode: Mode,
+    /// Whether a bitstream may instead write every offset in one width chosen per stream.
+    adaptive_offsets: bool,
+    /// Whether mostly literal nodes are written as a token stream instead of a bitstream.
+    adaptive_literal_runs: bool,
+    /// Whether a bitstream may instead write references that reuse a recent offset as a
+    /// short code.
+    repeat_offsets: bool,
+    max_output_bytes: Option<usize>,
+    min_run_length: Option<usize>,
+    sync_interval: Option<usize>,
+    /// Whether the header records the original bytes' md5.
+    content_hash: bool,
+    original_file: Option<OriginalFile>,
+    /// Match tables left over from earlier inputs, one per compression that was running at
+    /// once, so compressing again doesn't allocate them afresh.
+    #[cfg(feature = "std")]
+    spare_tables: Mutex<Vec<PrefixTables>>,
+}
+
+/// How the matcher picks between the matches it finds.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Parse {
+    /// Takes the longest match at each position.
+    Greedy,
+    /// Takes the sequence of nodes that serialises to the fewest bits.
+    Optimal,
+    /// Takes the same matches as `Greedy`, searching the whole window for each.
+    Linear,
+    /// Takes the match costing the fewest bits per byte, found through a suffix array.
+    Suffix,
+    /// Takes no matches at all.
+    LiteralsOnly,
+}
+
+impl Default for Lz77Compression {
+    fn default() -> Self {
+        Lz77Compression {
+            dictionary: Vec::new(),
+            search_window_size: WindowSize::DEFAULT,
+            max_match_length: compress::MAX_MATCH_LENGTH,
+            nice_match_length: compress::MAX_MATCH_LENGTH,
+            max_candidates: usize::MAX,
+            profitable_matches: false,
+            parse: Parse::Greedy,
+            node_mode: Mode::Lz77,
+            adaptive_offsets: false,
+            adaptive_literal_runs: false,
+            repeat_offsets: false,
+            max_output_bytes: None,
+            min_run_length: None,
+            sync_interval: None,
+            content_hash: false,
+            original_file: None,
+            #[cfg(feature = "std")]
+            spare_tables: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl compression::Algorithm for Lz77Compression {
-    fn compress(&self, mut file: File, output_file_path: &str) -> io::Result<()> {
-        let mut file_bytes = Vec::new();
-        file.read_to_end(&mut file_bytes)
-            .expect("Error on file read");
+    fn name(&self) -> &'static str {
+        crate::frame::Format::Lz77.name()
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        crate::frame::Format::Lz77.magic()
+    }
 
-        self.compress_bytes(&file_bytes, output_file_path)
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<CompressionStats> {
+        self.compress_with_progress(input, output, |_, _| {})
     }
 
-    fn decompress(&self, mut compressed_file: File, output_file_path: &str) -> io::Result<()> {
+    /// Streams `reader` through the chunks `Lz77Encoder` would write, so only a chunk of the
+    /// input and a search window are held at once rather than the whole input.
+    fn compress_from_reader(
+        &self,
+        reader: &mut dyn Read,
+        output_file_path: &str,
+        force: bool,
+    ) -> Result<CompressionStats> {
+        compression::write_atomically(output_file_path, force, |out_file| {
+            encoder::compress_chunks(self, reader, out_file, encoder::DEFAULT_CHUNK_SIZE)
+        })
+    }
+
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
         let mut file_bytes: Vec<u8> = vec![];
-        compressed_file.read_to_end(&mut file_bytes)?;
+        input.read_to_end(&mut file_bytes)?;
+
+        output.write_all(&self.decompress_concatenated(&file_bytes)?)?;
+        Ok(output.flush()?)
+    }
 
-        let nodes = serialisation::deserialise_nodes(&file_bytes);
+    fn decompressed_size(&self, input: &mut dyn Read) -> Result<u64> {
+        let mut file_bytes: Vec<u8> = vec![];
+        input.read_to_end(&mut file_bytes)?;
 
-        let mut file = File::create(output_file_path)?;
-        compress::decompress_nodes(nodes, &mut file);
-        Ok(())
+        Ok(self.decompressed_len(&file_bytes)? as u64)
     }
 }
 
 impl Lz77Compression {
-    pub fn compress_bytes(&self, file_bytes: &[u8], output_file_path: &str) -> io::Result<()> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pre-fills the search window with a preset dictionary, like zlib's.
+    ///
+    /// Lets small inputs back-reference boilerplate they share with the dictionary. The
+    /// dictionary isn't stored in the output, so decompression must be configured with the
+    /// same one.
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.dictionary = dictionary.to_vec();
+        self
+    }
+
+    /// Sets how far back, in bytes, the matcher may look for a reference.
+    ///
+    /// Larger windows find more matches on big files at the cost of compression speed.
+    /// Offsets of 2048 and above take 16 bits rather than 11 to encode. The window size is
+    /// recorded in the stream header so the decoder keeps enough history.
+    pub fn with_search_window_size(mut self, search_window_size: WindowSize) -> Self {
+        self.search_window_size = search_window_size;
+        self
+    }
+
+    /// Caps how many bytes a single reference may cover, between 2 and the 2047 the length
+    /// encoding supports, which is also the default. Values outside that range are clamped.
+    ///
+    /// Longer runs are split across several references. Decompression is unaffected.
+    pub fn with_max_match_length(mut self, max_match_length: u16) -> Self {
+        self.max_match_length = max_match_length.clamp(2, compress::MAX_MATCH_LENGTH);
+        self
+    }
+
+    /// Stops looking for a longer match as soon as one of at least `nice_match_length` bytes
+    /// is found, rather than trying every candidate in the window, like DEFLATE's
+    /// `nice_length`.
+    ///
+    /// Speeds up compressing highly redundant input, where every position has many long
+    /// candidates, for a slightly larger output. The default of 2047 always finds the longest
+    /// match. Values below 2 are treated as 2. Only the default greedy parse is affected, and
+    /// decompression isn't.
+    pub fn with_nice_match_length(mut self, nice_match_length: u16) -> Self {
+        self.nice_match_length = nice_match_length.max(2);
+        self
+    }
+
+    /// Compares at most `max_candidates` earlier positions when looking for a match, taking
+    /// the longest of those, like DEFLATE's `max_chain`.
+    ///
+    /// Bounds the time spent at each position on input where a common prefix turns up
+    /// thousands of times in the window, for a slightly larger output. The default compares
+    /// every candidate. Values below 1 are treated as 1. Only the default greedy parse is
+    /// affected, and decompression isn't.
+    pub fn with_max_candidates(mut self, max_candidates: usize) -> Self {
+        self.max_candidates = max_candidates.max(1);
+        self
+    }
+
+    /// Only emits a reference when it takes fewer bits than the literals it replaces, judged
+    /// by the bitstream's costs: 9 bits a literal, against the flag, offset and length bits.
+    ///
+    /// By default every match of 2 or more bytes is taken, but with a search window over 2047
+    /// bytes a 2 or 3 byte match at an escaped 16 bit offset costs more than its literals.
+    /// Only the default greedy parse is affected, the optimal parse already weighs every
+    /// match by its cost. Decompression is unaffected.
+    pub fn with_profitable_matches(mut self) -> Self {
+        self.profitable_matches = true;
+        self
+    }
+
+    /// Sets the search window, matcher and offset coding from a preset, so they needn't be
+    /// tuned one by one. Settings made afterwards override the preset's.
+    ///
+    /// Resets the window size, nice match length, max candidates, profitable matches, parse,
+    /// adaptive offsets and repeat offsets, leaving everything else as it was.
+    pub fn with_level(mut self, level: Level) -> Self {
+        let defaults = Lz77Compression::default();
+        self.search_window_size = defaults.search_window_size;
+        self.nice_match_length = defaults.nice_match_length;
+        self.max_candidates = defaults.max_candidates;
+        self.profitable_matches = defaults.profitable_matches;
+        self.parse = defaults.parse;
+        self.adaptive_offsets = defaults.adaptive_offsets;
+        self.repeat_offsets = defaults.repeat_offsets;
+        match level {
+            Level::Fastest => self.with_nice_match_length(16).with_max_candidates(4),
+            Level::Default => self,
+            Level::Best => self
+                .with_search_window_size(WindowSize::new(16383).expect("a valid window size"))
+                .with_optimal_parse()
+                .with_adaptive_offsets()
+                .with_repeat_offsets(),
+        }
+    }
+
+    /// Picks matches by their encoded cost over the whole input, rather than greedily taking
+    /// the longest match at each position.
+    ///
+    /// Gives smaller output than the default, but compresses several times slower and uses
+    /// around 16 bytes of memory per input byte. Worth it for archival, where a file is
+    /// compressed once and decompressed many times. Decompression is unaffected.
+    ///
+    /// The parse isn't quite minimal: a match of 128 bytes or more is taken outright, without
+    /// looking for a cheaper path through the bytes it covers. Otherwise every position of a
+    /// long repeat is weighed against every offset in the window. On source code the shortcut
+    /// costs well under a tenth of a percent.
+    pub fn with_optimal_parse(mut self) -> Self {
+        self.parse = Parse::Optimal;
+        self
+    }
+
+    /// Finds matches by comparing against every position in the search window, rather than
+    /// only those sharing a prefix with the next bytes.
+    ///
+    /// The output is identical to the default's, but compression is hundreds of times slower.
+    /// For benchmarking and checking the indexed matcher against. Ignores the nice match
+    /// length. Decompression is unaffected.
+    pub fn with_linear_matcher(mut self) -> Self {
+        self.parse = Parse::Linear;
+        self
+    }
+
+    /// Finds matches through a suffix array built over the whole input, rather than the
+    /// prefix index, and takes whichever costs the fewest bits per byte rather than the longest.
+    ///
+    /// Each position's matches are among the earlier suffixes that sort next to it, and a
+    /// slightly shorter match at a much smaller offset can be preferred, which pays off most
+    /// with a large window. Matches are still greedy, stay within the search window and are
+    /// capped at the max match length. Several times slower than the prefix index, and uses
+    /// up to around 40 bytes of memory per input byte while sorting the suffixes. Ignores the
+    /// nice match length and max candidates. Decompression is unaffected.
+    pub fn with_suffix_matcher(mut self) -> Self {
+        self.parse = Parse::Suffix;
+        self
+    }
+
+    /// Never emits a reference, so every byte is encoded as a literal.
+    ///
+    /// For debugging the serialiser and as a worst-case baseline. The node stream is always
+    /// written, even though it's larger than storing the input as-is, so the output is a
+    /// plain sequence of flag and byte pairs. Decompression is unaffected.
+    pub fn literals_only(mut self) -> Self {
+        self.parse = Parse::LiteralsOnly;
+        self
+    }
+
+    /// Writes nodes as LZ4 style byte aligned sequences, each a run of literals followed by a
+    /// reference, rather than the LZS bitstream.
+    ///
+    /// Runs of literals cost a byte or two rather than a bit per literal, and decoding needs
+    /// no bit shifting, though short references cost more. The mode is recorded in the
+    /// header, so decompression is unaffected.
+    pub fn with_token_stream(mut self) -> Self {
+        self.node_mode = Mode::Tokens;
+        self
+    }
+
+    /// Chooses the offset encoding per stream: once the nodes are built, every offset may be
+    /// written in the fewest bits that hold the largest one, rather than split between 7, 11
+    /// and 16 bits, whichever is smaller.
+    ///
+    /// Small inputs, whose offsets are all short, and large windows, whose offsets overflow
+    /// 11 bits, gain the most. The width is recorded in the header, so decompression is
+    /// unaffected. Has no effect with `with_token_stream`.
+    pub fn with_adaptive_offsets(mut self) -> Self {
+        self.adaptive_offsets = true;
+        self
+    }
+
+    /// Chooses the node format per stream: once the nodes are built, a stream of more than
+    /// `LITERALS_PER_REFERENCE` literals for each reference or run is written as a token
+    /// stream, see `with_token_stream`, rather than a bitstream.
+    ///
+    /// Literals then cost a byte each rather than nine bits, for a couple of bytes per run of
+    /// them, which suits data that barely compresses. The format is recorded in the header,
+    /// so decompression is unaffected. Has no effect with `with_sync_interval`, as token
+    /// streams have no sync markers.
+    pub fn with_adaptive_literal_runs(mut self) -> Self {
+        self.adaptive_literal_runs = true;
+        self
+    }
+
+    /// Also tries writing the bitstream with repeat offsets, keeping it if it comes out
+    /// smaller: a reference whose offset is one of the last four used costs 3 bits for the
+    /// offset rather than 8 to 28, and every other reference a bit more.
+    ///
+    /// Structured data, whose fields line up at the same distance record after record, gains
+    /// the most. The mode is recorded in the header, so decompression is unaffected. Has no
+    /// effect with `with_token_stream`.
+    pub fn with_repeat_offsets(mut self) -> Self {
+        self.repeat_offsets = true;
+        self
+    }
+
+    /// Refuses to decompress more than `max_output_bytes` bytes, failing with
+    /// `Error::OutputLimitExceeded` instead.
+    ///
+    /// A few bytes of references can decode to megabytes, so set this when decompressing
+    /// untrusted input. The limit is checked against each stream's header before anything is
+    /// decoded or allocated, and covers the total of concatenated or parallel streams.
+    pub fn with_max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.max_output_bytes = Some(max_output_bytes);
+        self
+    }
+
+    /// Encodes every run of at least `min_run_length` copies of one byte as a single run
+    /// node, the byte and a varint count, before the matcher sees the rest of the input.
+    ///
+    /// Without it a long run costs a chain of references, each capped at the maximum match
+    /// length and unable to overlap the bytes it copies. A run node costs around 6 bytes
+    /// however long the run, so lengths below about 32 are unlikely to pay off. Values below
+    /// 2 are treated as 2. Runs are decoded whether or not this is set.
+    pub fn with_run_length_encoding(mut self, min_run_length: usize) -> Self {
+        self.min_run_length = Some(min_run_length.max(2));
+        self
+    }
+
+    /// Writes a byte aligned sync marker into the bitstream every `sync_interval` input bytes,
+    /// so a damaged or truncated stream can still be partly decoded with `recover`.
+    ///
+    /// Each marker records how many bytes were decoded before it, and references never reach
+    /// back past one, so decoding can resume at any marker without what came before it. That
+    /// costs some compression, as does the 14 or so bytes per marker, so intervals below a few
+    /// KiB aren't worth it. Values below 1 are treated as 1. The token stream has no markers.
+    /// Decompression is unaffected.
+    pub fn with_sync_interval(mut self, sync_interval: usize) -> Self {
+        self.sync_interval = Some(sync_interval.max(1));
+        self
+    }
+
+    /// Records the md5 of the original bytes in the header, for use as a content address.
+    /// Decoding checks the output against it, failing with `ChecksumMismatch`.
+    ///
+    /// Costs 16 bytes. Read it back without decompressing with `compression::inspect`, or
+    /// from `CompressionStats::content_hash` when compressing.
+    ///
+    /// Compression is deterministic: the same input and settings always give the same bytes
+    /// for a given format version, so the output can be stored under the hash.
+    pub fn with_content_hash(mut self) -> Self {
+        self.content_hash = true;
+        self
+    }
+
+    /// Records the name and modification time of the file being compressed in the header,
+    /// as gzip does, costing 9 bytes plus the name. See `OriginalFile::of`.
+    ///
+    /// Decompression is unaffected. Read it back with `Lz77Compression::original_file`, e.g.
+    /// to restore the file when decompressing into a directory.
+    pub fn with_original_file(mut self, original_file: OriginalFile) -> Self {
+        self.original_file = Some(original_file);
+        self
+    }
+
+    /// Runs the matcher over `input` and returns the node stream it would serialise.
+    ///
+    /// Useful for gathering match statistics without producing a compressed file.
+    /// ```
+    /// use sloppycomp::lz77::{nodes::NodeType, Lz77Compression};
+    /// let references = Lz77Compression::new()
+    ///     .nodes(b"abcabcabc")
+    ///     .filter(NodeType::is_reference)
+    ///     .count();
+    /// assert_eq!(2, references);
+    /// ```
+    pub fn nodes(&self, input: &[u8]) -> impl Iterator<Item = NodeType> {
+        self.nodes_with_progress(&self.dictionary, input, |_, _| {})
+            .into_iter()
+    }
+
+    /// Turns nodes, e.g. from `nodes`, back into bytes without going through the bitstream.
+    ///
+    /// The output is allocated once, from the nodes' lengths. References are checked against
+    /// the history exactly as `decode` checks them.
+    /// ```
+    /// use sloppycomp::lz77::{nodes::NodeType, Lz77Compression};
+    /// let compressor = Lz77Compression::new();
+    /// let nodes: Vec<NodeType> = compressor.nodes(b"abcabcabc").collect();
+    /// assert_eq!(b"abcabcabc".to_vec(), compressor.decode_nodes_to_vec(&nodes).unwrap());
+    /// ```
+    pub fn decode_nodes_to_vec(&self, nodes: &[NodeType]) -> Result<Vec<u8>> {
+        self.check_output_limit(0, compress::decoded_len(nodes)?)?;
+        let mut decoded = Vec::new();
+        compress::decompress_nodes(
+            nodes,
+            &self.dictionary,
+            self.search_window_size.get(),
+            &mut decoded,
+        )?;
+        Ok(decoded)
+    }
+
+    /// The nodes for `input`, with references reaching back into `history` as well, which is
+    /// the dictionary unless the stream continues another.
+    fn nodes_with_progress<P>(&self, history: &[u8], input: &[u8], mut progress: P) -> Vec<NodeType>
+    where
+        P: FnMut(usize, usize),
+    {
         let mut nodes = Vec::new();
-        compress::build_lz77_node_list(file_bytes, |node| nodes.push(node));
+        match self.sync_interval {
+            None => self.extend_nodes(history, input, &mut nodes, progress),
+            Some(sync_interval) => {
+                // each interval is matched on its own so decoding can resume at its start
+                let total = input.len();
+                for (i, chunk) in input.chunks(sync_interval).enumerate() {
+                    let history: &[u8] = if i == 0 { history } else { &[] };
+                    let done = i * sync_interval;
+                    self.extend_nodes(history, chunk, &mut nodes, |processed, _| {
+                        if done + processed < total {
+                            progress(done + processed, total)
+                        }
+                    });
+                }
+                progress(total, total);
+            }
+        }
+        nodes
+    }
 
-        let mut encoded_nodes = serialisation::serailise_nodes(&nodes);
-        serialisation::append_end_marker(&mut encoded_nodes);
-        let bv: Vec<u8> = encoded_nodes.into();
+    /// Appends the nodes for `input` to `nodes`, with references reaching back into
+    /// `history` as well.
+    fn extend_nodes<P>(&self, history: &[u8], input: &[u8], nodes: &mut Vec<NodeType>, progress: P)
+    where
+        P: FnMut(usize, usize),
+    {
+        let primed;
+        let to_compress = if history.is_empty() {
+            input
+        } else {
+            primed = [history, input].concat();
+            &primed[..]
+        };
+        let start = history.len();
+
+        match self.min_run_length {
+            None => self.build_nodes(to_compress, start, nodes, progress),
+            Some(min_run_length) => {
+                self.build_nodes_around_runs(to_compress, start, min_run_length, nodes, progress)
+            }
+        }
+    }
+
+    /// Emits a run node for each run of at least `min_run_length` bytes in
+    /// `to_compress[start..]`, and matches the bytes between them as usual.
+    fn build_nodes_around_runs<P>(
+        &self,
+        to_compress: &[u8],
+        start: usize,
+        min_run_length: usize,
+        nodes: &mut Vec<NodeType>,
+        mut progress: P,
+    ) where
+        P: FnMut(usize, usize),
+    {
+        let total = to_compress.len() - start;
+        let window = usize::from(self.search_window_size.get());
+        let mut runs = compress::find_runs(to_compress, start, min_run_length);
+        // a final empty run takes care of the bytes after the last one
+        runs.push((to_compress.len(), 0));
+
+        let mut segment_start = start;
+        for (run_start, run_len) in runs {
+            if segment_start < run_start {
+                // the bytes before the segment only pre-fill the search window
+                let history_start = segment_start.saturating_sub(window);
+                let done = segment_start - start;
+                self.build_nodes(
+                    &to_compress[history_start..run_start],
+                    segment_start - history_start,
+                    nodes,
+                    |processed, _| {
+                        if done + processed < total {
+                            progress(done + processed, total)
+                        }
+                    },
+                );
+            }
+            let mut remaining = run_len;
+            while remaining > 0 {
+                let count = cmp::min(remaining, u32::MAX as usize);
+                nodes.push(NodeType::run(to_compress[run_start], count as u32));
+                remaining -= count;
+            }
+            segment_start = run_start + run_len;
+        }
+        progress(total, total);
+    }
+
+    fn build_nodes<P>(
+        &self,
+        to_compress: &[u8],
+        start: usize,
+        nodes: &mut Vec<NodeType>,
+        progress: P,
+    ) where
+        P: FnMut(usize, usize),
+    {
+        let callback = |node| nodes.push(node);
+        match self.parse {
+            Parse::Greedy => self.with_spare_tables(|tables| {
+                compress::build_lz77_node_list_from(
+                    to_compress,
+                    start,
+                    &compress::MatchOptions {
+                        search_window_size: self.search_window_size.get(),
+                        max_match_length: self.max_match_length,
+                        nice_match_length: self.nice_match_length,
+                        max_candidates: self.max_candidates,
+                        profitable_only: self.profitable_matches,
+                    },
+                    tables,
+                    callback,
+                    progress,
+                )
+            }),
+            Parse::Optimal => self.with_spare_tables(|tables| {
+                compress::build_optimal_node_list_from(
+                    to_compress,
+                    start,
+                    self.search_window_size.get(),
+                    self.max_match_length,
+                    tables,
+                    callback,
+                    progress,
+                )
+            }),
+            Parse::Linear => compress::build_linear_node_list_from(
+                to_compress,
+                start,
+                self.search_window_size.get(),
+                self.max_match_length,
+                callback,
+                progress,
+            ),
+            Parse::Suffix => compress::build_suffix_node_list_from(
+                to_compress,
+                start,
+                self.search_window_size.get(),
+                self.max_match_length,
+                callback,
+                progress,
+            ),
+            Parse::LiteralsOnly => {
+                compress::build_literal_node_list_from(to_compress, start, callback, progress)
+            }
+        }
+    }
+
+    /// Runs `build` with match tables from an earlier input if there are any spare, keeping
+    /// them for the next one afterwards. Without std they're always new.
+    fn with_spare_tables<T>(&self, build: impl FnOnce(&mut PrefixTables) -> T) -> T {
+        #[cfg(feature = "std")]
+        {
+            let spare = || {
+                self.spare_tables
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+            };
+            let mut tables = spare().pop().unwrap_or_default();
+            let built = build(&mut tables);
+            spare().push(tables);
+            built
+        }
+        #[cfg(not(feature = "std"))]
+        build(&mut PrefixTables::default())
+    }
+
+    /// Encodes `file_bytes` behind a header, falling back to storing them as-is when the
+    /// node encoding would be larger than the input. Output never exceeds the input by more
+    /// than the header, unless `literals_only` is set.
+    pub fn encode(&self, file_bytes: &[u8]) -> Vec<u8> {
+        self.encode_with_progress(file_bytes, |_, _| {})
+    }
+
+    fn encode_with_progress<P>(&self, file_bytes: &[u8], progress: P) -> Vec<u8>
+    where
+        P: FnMut(usize, usize),
+    {
+        let nodes = self.nodes_with_progress(&self.dictionary, file_bytes, progress);
+        self.encode_from_nodes(file_bytes, nodes, self.content_hash_of(file_bytes))
+    }
+
+    /// The md5 to record in the header for `file_bytes`, if `with_content_hash` is set.
+    fn content_hash_of(&self, file_bytes: &[u8]) -> Option<[u8; 16]> {
+        if self.content_hash {
+            Some(md5::compute(file_bytes).0)
+        } else {
+            None
+        }
+    }
+
+    fn encode_from_nodes(
+        &self,
+        file_bytes: &[u8],
+        nodes: Vec<NodeType>,
+        content_hash: Option<[u8; 16]>,
+    ) -> Vec<u8> {
+        let (mut encoded, body) = self.encode_parts(file_bytes, nodes, content_hash, false);
+        encoded.extend_from_slice(body.as_deref().unwrap_or(file_bytes));
+        encoded
+    }
+
+    /// Serialises `nodes`, returning the header along with the body that follows it: the
+    /// encoded nodes, or `None` when `file_bytes` is to be stored as-is. `continues` marks
+    /// nodes that reach back into the streams before this one.
+    ///
+    /// They're kept apart so `compress` can write each straight to the output rather than
+    /// copying the body in after the header.
+    fn encode_parts(
+        &self,
+        file_bytes: &[u8],
+        nodes: Vec<NodeType>,
+        content_hash: Option<[u8; 16]>,
+        continues: bool,
+    ) -> (Vec<u8>, Option<Vec<u8>>) {
+        let (node_mode, encoded_nodes) = self.encode_nodes(nodes);
+        let store = encoded_nodes.len() > file_bytes.len() && self.parse != Parse::LiteralsOnly;
+        let (mode, body) = if store {
+            (Mode::Stored, None)
+        } else {
+            (node_mode, Some(encoded_nodes))
+        };
+
+        let mut header = Vec::with_capacity(serialisation::MAX_HEADER_SIZE);
+        serialisation::write_header(
+            &mut header,
+            &Header {
+                mode,
+                search_window_size: self.search_window_size,
+                original_len: file_bytes.len(),
+                content_hash,
+                original_file: self.original_file.clone(),
+                continues,
+            },
+        );
+        (header, body)
+    }
+
+    /// Serialises `nodes`, returning the mode they were written in along with the bytes.
+    fn encode_nodes(&self, nodes: Vec<NodeType>) -> (Mode, Vec<u8>) {
+        let mostly_literals = || {
+            let literals = nodes
+                .iter()
+                .filter(|node| matches!(node, NodeType::ByteLiteral { .. }))
+                .count();
+            literals > (nodes.len() - literals) * LITERALS_PER_REFERENCE
+        };
+        if self.node_mode == Mode::Tokens
+            || (self.adaptive_literal_runs && self.sync_interval.is_none() && mostly_literals())
+        {
+            return (Mode::Tokens, tokens::serialise_tokens(&nodes));
+        }
+        let serialise = |codec| match self.sync_interval {
+            Some(sync_interval) => {
+                serialisation::serialise_stream_with_sync_points(&nodes, codec, sync_interval)
+            }
+            None => serialisation::serialise_stream(&nodes, codec),
+        };
+        let mut smallest = (Mode::Lz77, serialise(OffsetCodec::Split));
+        if self.adaptive_offsets {
+            let fixed_codec = OffsetCodec::fixed_for(&nodes);
+            let fixed = serialise(fixed_codec);
+            match fixed_codec {
+                // the width costs a byte in the header
+                OffsetCodec::Fixed(bits) if fixed.len() + 1 < smallest.1.len() => {
+                    smallest = (Mode::FixedOffsets { bits }, fixed)
+                }
+                _ => {}
+            }
+        }
+        if self.repeat_offsets {
+            let repeat = serialise(OffsetCodec::Repeat);
+            if repeat.len() < smallest.1.len() {
+                smallest = (Mode::RepeatOffsets, repeat);
+            }
+        }
+        smallest
+    }
+
+    /// Reads the nodes of a body in any node mode, returning them along with whatever follows
+    /// the body.
+    fn decode_nodes<'a>(header: &Header, body: &'a [u8]) -> Result<(Vec<NodeType>, &'a [u8])> {
+        let decoded = match header.mode.offset_codec() {
+            Some(codec) => serialisation::deserialise_nodes(body, codec)?,
+            None => tokens::deserialise_tokens(body, header.original_len)?,
+        };
+        Ok(decoded)
+    }
 
-        compression::write_to_new_file(&bv, output_file_path)
+    /// The file recorded in the header of the stream at the start of `compressed`, if it was
+    /// written `with_original_file`. Only the header needs to be there, which is at most
+    /// `MAX_HEADER_SIZE` bytes.
+    pub fn original_file(compressed: &[u8]) -> Result<Option<OriginalFile>> {
+        let (header, _) = serialisation::read_header(compressed)?;
+        Ok(header.original_file)
     }
 
+    /// The nodes of the stream at the start of `compressed`, as written, without expanding
+    /// them. A stored stream's bytes come back as literals.
+    pub fn disassemble(compressed: &[u8]) -> Result<Vec<NodeType>> {
+        let nodes = Self::disassemble_with_positions(compressed)?;
+        Ok(nodes.into_iter().map(|(_, node)| node).collect())
+    }
+
+    /// Like `disassemble`, along with the bit each node's encoding starts at, counted from the
+    /// start of `compressed`. Token stream nodes start on their literal or offset bytes.
+    pub fn disassemble_with_positions(compressed: &[u8]) -> Result<Vec<(usize, NodeType)>> {
+        let (header, body) = serialisation::read_header(compressed)?;
+        let body_start = (compressed.len() - body.len()) * 8;
+        let mut nodes = Vec::new();
+        let mut on_node = |bit: usize, node| nodes.push((body_start + bit, node));
+        match (header.mode, header.mode.offset_codec()) {
+            (Mode::Stored, _) => {
+                let (stored, _) = serialisation::read_stored(body, header.original_len)?;
+                for (i, &lit) in stored.iter().enumerate() {
+                    on_node(i * 8, NodeType::literal(lit));
+                }
+            }
+            (_, Some(codec)) => {
+                serialisation::deserialise_nodes_with(body, codec, on_node)?;
+            }
+            (_, None) => {
+                tokens::deserialise_tokens_with(body, header.original_len, on_node)?;
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Decodes a stream produced by `encode` back into the original bytes. Anything after
+    /// the end of the stream is ignored, see `decompress_concatenated`.
+    pub fn decode(&self, compressed_bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        self.decode_stream(compressed_bytes, &mut decoded, 0)?;
+        Ok(decoded)
+    }
+
+    /// Decodes a stream produced by `encode` into the start of `output`, without allocating
+    /// for the decoded bytes, returning how many were written.
+    ///
+    /// The header records the original length, so this fails with `Error::OutputTooSmall`
+    /// before decoding anything if `output` can't hold it all.
+    pub fn decode_into(&self, compressed_bytes: &[u8], output: &mut [u8]) -> Result<usize> {
+        let (header, body) = serialisation::read_header(compressed_bytes)?;
+        self.check_output_limit(0, header.original_len)?;
+        if output.len() < header.original_len {
+            return Err(Error::OutputTooSmall {
+                needed: header.original_len,
+                available: output.len(),
+            });
+        }
+        let output = &mut output[..header.original_len];
+        match header.mode {
+            Mode::Stored => {
+                let (stored, _) = serialisation::read_stored(body, header.original_len)?;
+                output.copy_from_slice(stored);
+            }
+            Mode::Lz77 | Mode::Tokens | Mode::FixedOffsets { .. } | Mode::RepeatOffsets => {
+                let (nodes, _) = Self::decode_nodes(&header, body)?;
+                let written = compress::decompress_nodes_into(
+                    &nodes,
+                    &self.dictionary,
+                    header.search_window_size.get(),
+                    output,
+                )?;
+                if written != header.original_len {
+                    return Err(DeserialiseError::LengthMismatch.into());
+                }
+            }
+        }
+        check_content_hash(&header, output)?;
+        Ok(header.original_len)
+    }
+
+    /// Decodes several streams from `encode` appended one after another, e.g. a log that is
+    /// compressed a chunk at a time, into the concatenation of their original bytes.
+    ///
+    /// A stream compressed on its own starts with a fresh search window: its references never
+    /// reach back into an earlier stream, and the dictionary, if any, is used again for it.
+    /// One that continues the stream before it, as `Lz77Encoder` writes them, may reach back
+    /// into the last window of the bytes decoded so far, and must have the same window size.
+    pub fn decompress_concatenated(&self, compressed_bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        let mut remaining = compressed_bytes;
+        let mut window = None;
+        loop {
+            let (header, _) = serialisation::read_header(remaining)?;
+            if header.continues && window.is_some_and(|window| window != header.search_window_size)
+            {
+                return Err(DeserialiseError::WindowChanged.into());
+            }
+            window = Some(header.search_window_size);
+            remaining = self.decode_stream(remaining, &mut decoded, 0)?;
+            if remaining.is_empty() {
+                return Ok(decoded);
+            }
+        }
+    }
+
+    /// The number of bytes `decompress_concatenated` would decode `compressed_bytes` to, from
+    /// the original length in each stream's header. Nodes are only parsed to find where the
+    /// next stream starts, nothing is decoded or allocated for the output.
+    pub fn decompressed_len(&self, compressed_bytes: &[u8]) -> Result<usize> {
+        Self::parse_streams(compressed_bytes)
+    }
+
+    /// Parses the headers and nodes of every stream in `compressed_bytes` without decoding
+    /// them, returning the total of their original lengths.
+    fn parse_streams(compressed_bytes: &[u8]) -> Result<usize> {
+        let mut total: usize = 0;
+        let mut remaining = compressed_bytes;
+        loop {
+            let (header, body) = serialisation::read_header(remaining)?;
+            remaining = match header.mode {
+                Mode::Stored => serialisation::read_stored(body, header.original_len)?.1,
+                Mode::Lz77 | Mode::Tokens | Mode::FixedOffsets { .. } | Mode::RepeatOffsets => {
+                    Self::decode_nodes(&header, body)?.1
+                }
+            };
+            total = total
+                .checked_add(header.original_len)
+                .ok_or(DeserialiseError::InvalidLength)?;
+            if remaining.is_empty() {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Decodes whatever can still be decoded of a damaged or truncated stream written with
+    /// `with_sync_interval`, returning each stretch of the original bytes that decoded along
+    /// with its offset in the original.
+    ///
+    /// Decoding starts again at the next sync marker after anything that fails to decode, so
+    /// at most the interval around each damaged byte is lost. Only the header has to be
+    /// intact. Without sync markers everything up to the first damage is lost too.
+    pub fn recover(&self, compressed_bytes: &[u8]) -> Result<Vec<(usize, Vec<u8>)>> {
+        let (header, body) = serialisation::read_header(compressed_bytes)?;
+        self.check_output_limit(0, header.original_len)?;
+        let codec = match header.mode.offset_codec() {
+            Some(codec) => codec,
+            None if header.mode == Mode::Stored => {
+                let stored = &body[..cmp::min(body.len(), header.original_len)];
+                return Ok(vec![(0, stored.to_vec())]);
+            }
+            None => return Ok(vec![(0, self.decode(compressed_bytes)?)]),
+        };
+
+        let mut recovered: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut decoded_to = 0;
+        let starts = core::iter::once((0, body)).chain(serialisation::find_sync_points(body));
+        for (position, segment) in starts {
+            let position = match usize::try_from(position) {
+                Ok(position) if position >= decoded_to => position,
+                _ => continue,
+            };
+            // only the first interval was matched against the dictionary
+            let dictionary: &[u8] = if position == 0 { &self.dictionary } else { &[] };
+            let decoded = serialisation::deserialise_segment(segment, codec)
+                .map_err(Error::from)
+                .and_then(|(nodes, end)| {
+                    // checked before decoding, so a corrupt segment can't size the output
+                    let decoded_len = compress::decoded_len(&nodes)?;
+                    if decoded_len > header.original_len.saturating_sub(position) {
+                        return Err(DeserialiseError::BadSyncMarker.into());
+                    }
+                    let decoded_end = position + decoded_len;
+                    let consistent = match end {
+                        serialisation::SegmentEnd::Sync { position, .. } => {
+                            position == decoded_end as u64
+                        }
+                        serialisation::SegmentEnd::End(_) => decoded_end == header.original_len,
+                    };
+                    if !consistent {
+                        return Err(DeserialiseError::BadSyncMarker.into());
+                    }
+                    let mut decoded = Vec::new();
+                    compress::decompress_nodes(
+                        &nodes,
+                        dictionary,
+                        header.search_window_size.get(),
+                        &mut decoded,
+                    )?;
+                    Ok(decoded)
+                });
+            let decoded = match decoded {
+                Ok(decoded) => decoded,
+                Err(_) => continue,
+            };
+            decoded_to = position + decoded.len();
+            match recovered.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() == position => {
+                    bytes.extend_from_slice(&decoded)
+                }
+                _ => recovered.push((position, decoded)),
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Encodes `file_bytes` like `encode`, returning them as a `CompressedLz77` so they can't
+    /// be mixed up with uncompressed bytes.
+    pub fn compress_stream(&self, file_bytes: &[u8]) -> CompressedLz77 {
+        CompressedLz77::new_unchecked(self.encode(file_bytes))
+    }
+
+    /// Decodes every stream in `compressed` back into the concatenation of the original bytes.
+    pub fn decompress_stream(&self, compressed: &CompressedLz77) -> Result<Vec<u8>> {
+        self.decompress_concatenated(compressed.as_bytes())
+    }
+
+    /// Encodes text, e.g. JSON or logs, like `encode`.
+    pub fn compress_str(&self, text: &str) -> Vec<u8> {
+        self.encode(text.as_bytes())
+    }
+
+    /// Decodes a stream produced by `compress_str`, failing with `Error::Utf8` rather than
+    /// returning a `String` that isn't valid UTF-8.
+    pub fn decompress_str(&self, compressed_bytes: &[u8]) -> Result<String> {
+        let decoded = self.decode(compressed_bytes)?;
+        String::from_utf8(decoded).map_err(|err| err.utf8_error().into())
+    }
+
+    /// Appends one stream's bytes to `output`, returning whatever follows the stream. If it
+    /// continues the streams before it, they're the ones decoded into `output[history_start..]`.
+    fn decode_stream<'a>(
+        &self,
+        compressed_bytes: &'a [u8],
+        output: &mut Vec<u8>,
+        history_start: usize,
+    ) -> Result<&'a [u8]> {
+        let (header, body) = serialisation::read_header(compressed_bytes)?;
+        self.check_output_limit(output.len(), header.original_len)?;
+        let start = output.len();
+        let rest = match header.mode {
+            Mode::Stored => {
+                let (stored, rest) = serialisation::read_stored(body, header.original_len)?;
+                output.extend_from_slice(stored);
+                rest
+            }
+            Mode::Lz77 | Mode::Tokens | Mode::FixedOffsets { .. } | Mode::RepeatOffsets => {
+                let (nodes, rest) = Self::decode_nodes(&header, body)?;
+                // checked before decoding, so a corrupt header can't size the output
+                if compress::decoded_len(&nodes)? != header.original_len {
+                    return Err(DeserialiseError::LengthMismatch.into());
+                }
+                let continued;
+                let history = if header.continues {
+                    continued = continued_history(
+                        &self.dictionary,
+                        &output[history_start..],
+                        header.search_window_size,
+                    );
+                    &continued
+                } else {
+                    &self.dictionary
+                };
+                compress::decompress_nodes(
+                    &nodes,
+                    history,
+                    header.search_window_size.get(),
+                    output,
+                )?;
+                rest
+            }
+        };
+        check_content_hash(&header, &output[start..])?;
+        Ok(rest)
+    }
+
+    /// Fails if decoding `len` more bytes after `already_decoded` would pass the limit set
+    /// by `with_max_output_bytes`.
+    fn check_output_limit(&self, already_decoded: usize, len: usize) -> Result<()> {
+        match self.max_output_bytes {
+            Some(limit) if already_decoded.saturating_add(len) > limit => {
+                Err(Error::OutputLimitExceeded { limit })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Lz77Compression {
+    /// Encodes `file_bytes` into a new file, only replacing an existing one if `force` is set.
+    pub fn compress_bytes(
+        &self,
+        file_bytes: &[u8],
+        output_file_path: &str,
+        force: bool,
+    ) -> Result<()> {
+        let bv = self.encode(file_bytes);
+        Ok(compression::write_to_new_file(
+            &bv,
+            output_file_path,
+            force,
+        )?)
+    }
+
+    /// Compresses like `Algorithm::compress`, calling `progress` with
+    /// `(bytes_processed, total_bytes)` every 64 KB of input and once when matching is done.
+    ///
+    /// The output is identical to `compress`. The stats include a histogram of the nodes the
+    /// matcher found, even when the input ends up stored as-is.
+    pub fn compress_with_progress<P>(
+        &self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        progress: P,
+    ) -> Result<CompressionStats>
+    where
+        P: FnMut(usize, usize),
+    {
+        let mut file_bytes = Vec::new();
+        input.read_to_end(&mut file_bytes)?;
+        self.compress_continuing(None, &file_bytes, output, progress)
+    }
+
+    /// Compresses `file_bytes` like `compress_with_progress`. Given the `history` before them,
+    /// at most the last window of the dictionary and the bytes compressed so far, the stream
+    /// continues from it rather than starting again from the dictionary.
+    pub(super) fn compress_continuing<P>(
+        &self,
+        history: Option<&[u8]>,
+        file_bytes: &[u8],
+        output: &mut dyn Write,
+        progress: P,
+    ) -> Result<CompressionStats>
+    where
+        P: FnMut(usize, usize),
+    {
+        let nodes =
+            self.nodes_with_progress(history.unwrap_or(&self.dictionary), file_bytes, progress);
+        let histogram = nodes.iter().copied().collect();
+        let content_hash = self.content_hash_of(file_bytes);
+        let (header, body) = self.encode_parts(file_bytes, nodes, content_hash, history.is_some());
+        let body = body.as_deref().unwrap_or(file_bytes);
+        output.write_all(&header)?;
+        output.write_all(body)?;
+        output.flush()?;
+        Ok(CompressionStats {
+            original_bytes: file_bytes.len(),
+            compressed_bytes: header.len() + body.len(),
+            histogram: Some(histogram),
+            content_hash,
+            ..CompressionStats::default()
+        })
+    }
+
+    /// Decodes `compressed_bytes` into a new file, only replacing an existing one if `force`
+    /// is set.
     pub fn decompress_bytes(
         &self,
-        compressed_bytes: &Vec<u8>,
+        compressed_bytes: &[u8],
         output_file_path: &str,
-    ) -> io::Result<()> {
-        let nodes = serialisation::deserialise_nodes(compressed_bytes);
+        force: bool,
+    ) -> Result<()> {
+        Ok(compression::write_to_new_file(
+            &self.decode(compressed_bytes)?,
+            output_file_path,
+            force,
+        )?)
+    }
+
+    /// Compresses several files into a single archive stream.
+    ///
+    /// The files are stored by file name, with their directories dropped, and are compressed
+    /// together so later files can back-reference earlier ones. Read the archive back with
+    /// `decompress_files`.
+    pub fn compress_files(&self, paths: &[PathBuf], output: &mut dyn Write) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} has no UTF-8 file name", path.display()),
+                    )
+                })?;
+            if !names.insert(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("more than one input is named {}", name),
+                )
+                .into());
+            }
+            files.push((name.to_string(), fs::read(path)?));
+        }
+
+        Ok(output.write_all(&self.encode(&archive::write_archive(&files)))?)
+    }
+
+    /// Recreates the files in an archive made by `compress_files` inside `output_dir`,
+    /// overwriting any with the same name. Returns the paths written, in archive order.
+    ///
+    /// Entries named with a relative path, such as `dir/file`, are written into
+    /// subdirectories, which are created as needed. An archive with any entry that would land
+    /// outside `output_dir`, such as `../escape`, is refused before anything is written.
+    pub fn decompress_files(
+        &self,
+        input: &mut dyn Read,
+        output_dir: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let mut compressed_bytes = Vec::new();
+        input.read_to_end(&mut compressed_bytes)?;
+        let archive_bytes = self.decode(&compressed_bytes)?;
+
+        let mut written = Vec::new();
+        for (name, contents) in archive::read_archive(&archive_bytes)? {
+            let path = name
+                .split('/')
+                .fold(output_dir.to_path_buf(), |path, part| path.join(part));
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, contents)?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// `decompress_files` from an archive file.
+    pub fn decompress_archive(&self, compressed: &File, out_dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut compressed = compressed;
+        self.decompress_files(&mut compressed, out_dir)
+    }
+
+    /// Splits `input` into one block per thread and compresses the blocks concurrently.
+    ///
+    /// Every block starts with a cold search window, so matches can't cross a block boundary
+    /// and the ratio drops slightly in exchange for throughput. The blocks are written as a
+    /// framed stream which must be read back with `decompress_parallel`.
+    pub fn compress_parallel(&self, input: &[u8], threads: usize) -> Vec<u8> {
+        let threads = threads.max(1);
+        let block_size = input.len().div_ceil(threads).max(1);
+
+        let blocks: Vec<Vec<u8>> = thread::scope(|scope| {
+            let handles: Vec<_> = input
+                .chunks(block_size)
+                .map(|block| scope.spawn(move || self.encode(block)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Compression thread panicked"))
+                .collect()
+        });
+
+        parallel::write_frames(&blocks)
+    }
+
+    /// Decompresses a framed stream produced by `compress_parallel`.
+    pub fn decompress_parallel(&self, compressed_bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        for block in parallel::read_frames(compressed_bytes)? {
+            let history_start = decompressed.len();
+            self.decode_stream(block, &mut decompressed, history_start)?;
+        }
+        Ok(decompressed)
+    }
+
+    /// Compresses `input` as independent streams of `block_size` original bytes each,
+    /// `SEEKABLE_BLOCK_SIZE` being a reasonable choice, followed by an index of where each
+    /// starts, so `decompress_range` can decode part of the input without the rest.
+    ///
+    /// Every block starts with a cold search window, costing some compression, more so the
+    /// smaller the blocks. Values below 1 are treated as 1. Read the whole stream back with
+    /// `decompress_seekable`.
+    pub fn compress_seekable(&self, input: &[u8], block_size: usize) -> Vec<u8> {
+        let block_size = block_size.max(1);
+        let mut compressed = Vec::new();
+        let mut block_lens = Vec::new();
+        for block in input.chunks(block_size) {
+            let encoded = self.encode(block);
+            block_lens.push(encoded.len());
+            compressed.extend_from_slice(&encoded);
+        }
+        seekable::Index::write(&mut compressed, &block_lens, block_size, input.len());
+        compressed
+    }
+
+    /// Decompresses the `len` original bytes from `start` of a stream made by
+    /// `compress_seekable`, reading only its index and the blocks that hold them.
+    ///
+    /// Fails with `RangeOutOfBounds` if the range runs past the end of the original input.
+    pub fn decompress_range<R: Read + Seek>(
+        &self,
+        input: &mut R,
+        start: u64,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let index = seekable::Index::read(input)?;
+        let range = start..start.saturating_add(len as u64);
+        if range.end > index.original_len {
+            return Err(Error::RangeOutOfBounds {
+                end: range.end,
+                len: index.original_len,
+            });
+        }
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let blocks = index.blocks_covering(&range);
+        let skip = (range.start - blocks.start as u64 * index.block_size) as usize;
+        let mut decompressed = self.decode_seekable_blocks(input, &index, blocks)?;
+        decompressed.drain(..skip);
+        decompressed.truncate(len);
+        Ok(decompressed)
+    }
+
+    /// Decompresses the whole of a stream made by `compress_seekable`.
+    pub fn decompress_seekable(&self, compressed_bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut input = io::Cursor::new(compressed_bytes);
+        let index = seekable::Index::read(&mut input)?;
+        let blocks = 0..index.offsets.len() - 1;
+        self.decode_seekable_blocks(&mut input, &index, blocks)
+    }
 
-        let mut file = File::create(output_file_path)?;
-        compress::decompress_nodes(nodes, &mut file);
-        Ok(())
+    /// Reads and decodes `blocks` of a seekable stream, checking each decodes to the length
+    /// the index expects.
+    fn decode_seekable_blocks<R: Read + Seek>(
+        &self,
+        input: &mut R,
+        index: &seekable::Index,
+        blocks: core::ops::Range<usize>,
+    ) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        let mut block_bytes = Vec::new();
+        for block in blocks {
+            let (offset, compressed_len) = index.block_extent(block);
+            block_bytes.resize(compressed_len as usize, 0);
+            input.seek(SeekFrom::Start(offset))?;
+            input.read_exact(&mut block_bytes)?;
+            let decoded_start = decompressed.len();
+            self.decode_stream(&block_bytes, &mut decompressed, decoded_start)?;
+            if (decompressed.len() - decoded_start) as u64 != index.block_len(block) {
+                return Err(DeserialiseError::InvalidIndex.into());
+            }
+        }
+        Ok(decompressed)
     }
 }
 
+/// The original bytes per block that suit `Lz77Compression::compress_seekable` in most cases.
+#[cfg(feature = "std")]
+pub const SEEKABLE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// The most bytes a stream header can take.
+#[cfg(feature = "std")]
+pub const MAX_HEADER_SIZE: usize = serialisation::MAX_HEADER_SIZE;
+
+/// The most bytes a single reference can cover, see `Lz77Compression::with_max_match_length`.
+pub const MAX_MATCH_LENGTH: u16 = compress::MAX_MATCH_LENGTH;
+
+/// The history of a stream continuing the ones that decoded to `decoded`: the last `window`
+/// bytes of the dictionary followed by them.
+fn continued_history(dictionary: &[u8], decoded: &[u8], window: WindowSize) -> Vec<u8> {
+    let window = usize::from(window.get());
+    let decoded = &decoded[decoded.len().saturating_sub(window)..];
+    let dictionary = &dictionary[dictionary.len().saturating_sub(window - decoded.len())..];
+    [dictionary, decoded].concat()
+}
+
+/// Fails with `ChecksumMismatch` if the header records a content hash `decoded` doesn't match.
+fn check_content_hash(header: &Header, decoded: &[u8]) -> Result<()> {
+    match header.content_hash {
+        Some(hash) if md5::compute(decoded).0 != hash => {
+            Err(DeserialiseError::ChecksumMismatch.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The original length and content hash recorded in the header at the start of
+/// `compressed_bytes`.
+#[cfg(feature = "std")]
+pub(crate) fn header_summary(compressed_bytes: &[u8]) -> Result<(usize, Option<[u8; 16]>)> {
+    let (header, _) = serialisation::read_header(compressed_bytes)?;
+    Ok((header.original_len, header.content_hash))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::lz77::nodes::NodeType;
+    use crate::test_util::{pseudo_random_bytes, ScratchDir};
 
     #[test]
     fn decompresses_to_original_bytes() {
@@ -92,7 +1391,1237 @@ mod tests {
         assert_eq!(expected, nodes);
 
         let mut write_vec: Vec<u8> = Vec::new();
-        compress::decompress_nodes(nodes, &mut write_vec);
+        compress::decompress_nodes(&nodes, &[], compress::SEARCH_WINDOW_SIZE, &mut write_vec)
+            .unwrap();
         assert_eq!(write_vec, bytes);
     }
+
+    #[test]
+    fn parallel_compression_round_trips_across_blocks() {
+        let bytes: Vec<u8> = b"the quick brown fox jumps over the lazy dog. "
+            .iter()
+            .cycle()
+            .take(10_000)
+            .copied()
+            .collect();
+
+        let compressor = Lz77Compression::new();
+        let framed = compressor.compress_parallel(&bytes, 4);
+        assert_eq!(4, parallel::read_frames(&framed).unwrap().len());
+        assert!(framed.len() < bytes.len());
+
+        assert_eq!(bytes, compressor.decompress_parallel(&framed).unwrap());
+    }
+
+    #[test]
+    fn dictionary_shrinks_similar_payloads() {
+        let dictionary = br#"{"id": 0, "name": "", "email": "@example.com", "active": true}"#;
+        let payload = br#"{"id": 17, "name": "neil", "email": "neil@example.com", "active": true}"#;
+
+        let cold = Lz77Compression::new().encode(payload);
+        let primed = Lz77Compression::new()
+            .with_dictionary(dictionary)
+            .encode(payload);
+        assert!(primed.len() * 2 < cold.len());
+
+        let mut write_vec: Vec<u8> = Vec::new();
+        Lz77Compression::new()
+            .with_dictionary(dictionary)
+            .decode_stream(&primed, &mut write_vec, 0)
+            .unwrap();
+        assert_eq!(payload.to_vec(), write_vec);
+    }
+
+    #[test]
+    fn larger_search_window_round_trips() {
+        // pseudo-random block that only repeats beyond the default window
+        let mut bytes = pseudo_random_bytes(12_000);
+        bytes.extend_from_slice(&bytes.clone());
+
+        let compressor =
+            Lz77Compression::new().with_search_window_size(WindowSize::new(30_000).unwrap());
+        let encoded = compressor.encode(&bytes);
+        assert!(encoded.len() < Lz77Compression::new().encode(&bytes).len());
+
+        let mut write_vec: Vec<u8> = Vec::new();
+        Lz77Compression::new()
+            .decode_stream(&encoded, &mut write_vec, 0)
+            .unwrap();
+        assert_eq!(bytes, write_vec);
+    }
+
+    #[test]
+    fn incompressible_input_expands_by_at_most_a_literal_flag_per_byte() {
+        let bytes = pseudo_random_bytes(64 * 1024);
+        let compressor = Lz77Compression::new();
+        let (_, encoded) = compressor.encode_nodes(compressor.nodes(&bytes).collect());
+
+        // every literal costs 9 bits, plus up to 2 bytes of end marker
+        let bound = bytes.len() * 9 / 8 + 2;
+        assert!(
+            encoded.len() <= bound,
+            "{} bytes encoded to {}, expected at most {}",
+            bytes.len(),
+            encoded.len(),
+            bound
+        );
+    }
+
+    #[test]
+    fn adaptive_literal_runs_shrink_mostly_literal_streams() {
+        let bytes = pseudo_random_bytes(64 * 1024);
+        let compressor = Lz77Compression::new().with_adaptive_literal_runs();
+        let nodes: Vec<NodeType> = compressor.nodes(&bytes).collect();
+        let (_, bitstream) = Lz77Compression::new().encode_nodes(nodes.clone());
+        let (mode, runs) = compressor.encode_nodes(nodes);
+        assert_eq!(Mode::Tokens, mode);
+        // the short matches random bytes happen to contain cost a little more than they save
+        // as tokens, but far less than a flag per literal
+        assert!(
+            runs.len() * 100 < bytes.len() * 105 && runs.len() * 100 < bitstream.len() * 95,
+            "{} bytes encoded to {} as literal runs, {} as a bitstream",
+            bytes.len(),
+            runs.len(),
+            bitstream.len()
+        );
+
+        // random bytes that repeat once don't get stored, but are still nearly all literals
+        let repeated = [&bytes[..1500], &bytes[..1500]].concat();
+        let encoded = compressor.encode(&repeated);
+        let (header, _) = serialisation::read_header(&encoded).unwrap();
+        assert_eq!(Mode::Tokens, header.mode);
+        assert!(encoded.len() < Lz77Compression::new().encode(&repeated).len());
+        assert_eq!(repeated, Lz77Compression::new().decode(&encoded).unwrap());
+
+        // text keeps the bitstream
+        let text = b"the cat sat on the mat, the cat sat on the hat".repeat(20);
+        let encoded = compressor.encode(&text);
+        let (header, _) = serialisation::read_header(&encoded).unwrap();
+        assert_eq!(Mode::Lz77, header.mode);
+        assert_eq!(encoded, Lz77Compression::new().encode(&text));
+    }
+
+    #[test]
+    fn incompressible_input_is_stored() {
+        let bytes = pseudo_random_bytes(64 * 1024);
+        let encoded = Lz77Compression::new().encode(&bytes);
+
+        let (header, body) = serialisation::read_header(&encoded).unwrap();
+        assert_eq!(Mode::Stored, header.mode);
+        assert_eq!(bytes, body);
+
+        let mut write_vec: Vec<u8> = Vec::new();
+        Lz77Compression::new()
+            .decode_stream(&encoded, &mut write_vec, 0)
+            .unwrap();
+        assert_eq!(bytes, write_vec);
+    }
+
+    #[test]
+    fn compressible_input_is_lz77_encoded() {
+        let bytes = b"abcabcabcabcabcabcabcabcabcabc".to_vec();
+        let encoded = Lz77Compression::new().encode(&bytes);
+        assert!(encoded.len() < bytes.len());
+
+        let (header, _) = serialisation::read_header(&encoded).unwrap();
+        assert_eq!(Mode::Lz77, header.mode);
+
+        let mut write_vec: Vec<u8> = Vec::new();
+        Lz77Compression::new()
+            .decode_stream(&encoded, &mut write_vec, 0)
+            .unwrap();
+        assert_eq!(bytes, write_vec);
+    }
+
+    #[test]
+    fn appended_streams_decompress_as_one() {
+        let compressor = Lz77Compression::new().with_dictionary(b"log line ");
+        let chunks: [&[u8]; 4] = [
+            b"log line one, log line two\n",
+            b"",
+            &pseudo_random_bytes(300),
+            b"log line three, log line three again\n",
+        ];
+
+        let mut appended = Vec::new();
+        for chunk in &chunks {
+            appended.extend_from_slice(&compressor.encode(chunk));
+        }
+        assert_eq!(
+            chunks.concat(),
+            compressor.decompress_concatenated(&appended).unwrap()
+        );
+        assert_eq!(chunks[0], &compressor.decode(&appended).unwrap()[..]);
+        // the Algorithm trait reads every stream, not just the first
+        let algorithm: &dyn compression::Algorithm = &compressor;
+        assert_eq!(
+            chunks.concat(),
+            algorithm.decompress_to_vec(&appended).unwrap()
+        );
+
+        appended.extend_from_slice(b"not a stream");
+        assert!(compressor.decompress_concatenated(&appended).is_err());
+    }
+
+    #[test]
+    fn decode_into_fills_a_preallocated_buffer() {
+        let compressor = Lz77Compression::new().with_dictionary(b"the cat");
+        for bytes in [
+            b"the cat sat on the mat, the cat sat on the hat".to_vec(),
+            pseudo_random_bytes(100),
+            Vec::new(),
+        ] {
+            let encoded = compressor.encode(&bytes);
+
+            let mut exact = vec![0; bytes.len()];
+            assert_eq!(
+                bytes.len(),
+                compressor.decode_into(&encoded, &mut exact).unwrap()
+            );
+            assert_eq!(bytes, exact);
+
+            // only the start of a larger buffer is written
+            let mut larger = vec![0xaa; bytes.len() + 4];
+            assert_eq!(
+                bytes.len(),
+                compressor.decode_into(&encoded, &mut larger).unwrap()
+            );
+            assert_eq!(bytes, larger[..bytes.len()]);
+            assert_eq!([0xaa; 4], larger[bytes.len()..]);
+
+            if !bytes.is_empty() {
+                let mut too_small = vec![0; bytes.len() - 1];
+                assert!(matches!(
+                    compressor.decode_into(&encoded, &mut too_small),
+                    Err(Error::OutputTooSmall { needed, available })
+                        if needed == bytes.len() && available == bytes.len() - 1
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn streams_that_disagree_with_their_header_length_are_rejected() {
+        let bytes = b"abcabcabcabcabcabcabcabcabcabc".to_vec();
+        let compressor = Lz77Compression::new();
+        let mut encoded = compressor.encode(&bytes);
+        let (header, body) = serialisation::read_header(&encoded).unwrap();
+        assert_eq!(Mode::Lz77, header.mode);
+        // the length is a single byte varint, just before the body
+        let length_byte = encoded.len() - body.len() - 1;
+        encoded[length_byte] -= 1;
+
+        assert!(matches!(
+            compressor.decode(&encoded),
+            Err(Error::Deserialise(DeserialiseError::LengthMismatch))
+        ));
+        let mut output = vec![0; bytes.len()];
+        assert!(matches!(
+            compressor.decode_into(&encoded, &mut output),
+            Err(Error::Deserialise(DeserialiseError::LengthMismatch))
+        ));
+    }
+
+    #[test]
+    fn nodes_decode_straight_to_a_vec() {
+        let bytes = b"the cat sat on the mat, the cat sat on the hat".to_vec();
+        for compressor in [
+            Lz77Compression::new(),
+            Lz77Compression::new().with_dictionary(b"the cat sat"),
+        ] {
+            let nodes: Vec<NodeType> = compressor.nodes(&bytes).collect();
+            assert_eq!(bytes, compressor.decode_nodes_to_vec(&nodes).unwrap());
+        }
+
+        let reaches_before_the_start = [NodeType::literal(b'a'), NodeType::reference(2, 2)];
+        assert!(matches!(
+            Lz77Compression::new().decode_nodes_to_vec(&reaches_before_the_start),
+            Err(Error::Deserialise(
+                DeserialiseError::InvalidReference { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn output_limit_stops_decompression_bombs() {
+        // maximum length references decode to about 27 times their encoded size
+        let mut nodes: Vec<NodeType> = (0..2047).map(|i| NodeType::literal(i as u8)).collect();
+        nodes.extend((0..512).map(|_| NodeType::reference(2047, 2047)));
+        let decoded_len: usize = nodes.iter().map(NodeType::decoded_len).sum();
+        let mut bomb = Vec::new();
+        serialisation::write_header(
+            &mut bomb,
+            &Header {
+                mode: Mode::Lz77,
+                search_window_size: WindowSize::DEFAULT,
+                original_len: decoded_len,
+                content_hash: None,
+                original_file: None,
+                continues: false,
+            },
+        );
+        bomb.extend_from_slice(&serialisation::serialise_stream(&nodes, OffsetCodec::Split));
+        assert!(bomb.len() * 20 < decoded_len);
+
+        let limited = Lz77Compression::new().with_max_output_bytes(4096);
+        assert!(matches!(
+            limited.decode(&bomb),
+            Err(Error::OutputLimitExceeded { limit: 4096 })
+        ));
+        assert!(matches!(
+            limited.decode_into(&bomb, &mut vec![0; decoded_len]),
+            Err(Error::OutputLimitExceeded { limit: 4096 })
+        ));
+        assert!(matches!(
+            limited.decode_nodes_to_vec(&nodes),
+            Err(Error::OutputLimitExceeded { limit: 4096 })
+        ));
+        assert_eq!(
+            decoded_len,
+            Lz77Compression::new().decode(&bomb).unwrap().len()
+        );
+
+        // the limit covers the total of appended streams, and is inclusive
+        let chunk = Lz77Compression::new().encode(&[b'x'; 3000]);
+        let appended = [&chunk[..], &chunk[..]].concat();
+        assert!(matches!(
+            limited.decompress_concatenated(&appended),
+            Err(Error::OutputLimitExceeded { limit: 4096 })
+        ));
+        let exact = Lz77Compression::new().with_max_output_bytes(6000);
+        assert_eq!(
+            6000,
+            exact.decompress_concatenated(&appended).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn run_node_bombs_are_checked_before_allocating() {
+        // a few bytes of runs claim 16 GiB
+        let nodes = [NodeType::run(b'a', u32::MAX); 4];
+        let bomb_with_len = |original_len| {
+            let mut bomb = Vec::new();
+            serialisation::write_header(
+                &mut bomb,
+                &Header {
+                    mode: Mode::Lz77,
+                    search_window_size: WindowSize::DEFAULT,
+                    original_len,
+                    content_hash: None,
+                    original_file: None,
+                    continues: false,
+                },
+            );
+            bomb.extend_from_slice(&serialisation::serialise_stream(&nodes, OffsetCodec::Split));
+            bomb
+        };
+        let claimed = compress::decoded_len(&nodes).unwrap();
+        assert!(bomb_with_len(claimed).len() < 64);
+
+        // a header that disagrees with the nodes is caught before any output is reserved
+        let understated = bomb_with_len(10);
+        assert!(matches!(
+            Lz77Compression::new().decode(&understated),
+            Err(Error::Deserialise(DeserialiseError::LengthMismatch))
+        ));
+        assert!(Lz77Compression::new()
+            .decode_into(&understated, &mut [0; 10])
+            .is_err());
+
+        let limited = Lz77Compression::new().with_max_output_bytes(1 << 20);
+        assert!(matches!(
+            limited.decode(&bomb_with_len(claimed)),
+            Err(Error::OutputLimitExceeded { .. })
+        ));
+        assert!(matches!(
+            limited.decode_nodes_to_vec(&nodes),
+            Err(Error::OutputLimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn token_streams_round_trip() {
+        let compressor = Lz77Compression::new().with_token_stream();
+        let text = b"the cat sat on the mat, the cat sat on the hat".repeat(20);
+        for bytes in [text.clone(), pseudo_random_bytes(1000), Vec::new()] {
+            let encoded = compressor.encode(&bytes);
+            let (header, _) = serialisation::read_header(&encoded).unwrap();
+            if bytes == text {
+                assert_eq!(Mode::Tokens, header.mode);
+            }
+
+            // decoding doesn't need to be told the node mode
+            let decoder = Lz77Compression::new();
+            assert_eq!(bytes, decoder.decode(&encoded).unwrap());
+            let mut output = vec![0; bytes.len()];
+            decoder.decode_into(&encoded, &mut output).unwrap();
+            assert_eq!(bytes, output);
+        }
+
+        let appended = [compressor.encode(&text), compressor.encode(b"tail")].concat();
+        assert_eq!(
+            [&text[..], b"tail"].concat(),
+            compressor.decompress_concatenated(&appended).unwrap()
+        );
+    }
+
+    #[test]
+    fn repeat_offsets_shrink_structured_records() {
+        // 16 byte records whose fixed fields line up 16 bytes apart, split by fields that vary
+        let noise = pseudo_random_bytes(4000);
+        let records: Vec<u8> = (0..1000u32)
+            .flat_map(|i| {
+                let mut record = i.to_le_bytes().to_vec();
+                record.extend_from_slice(b"RECORD");
+                record.extend_from_slice(&noise[i as usize * 4..i as usize * 4 + 4]);
+                record.extend_from_slice(&[0, 1]);
+                record
+            })
+            .collect();
+        let plain = Lz77Compression::new().encode(&records);
+        let compressor = Lz77Compression::new().with_repeat_offsets();
+        let repeat = compressor.encode(&records);
+        let (header, _) = serialisation::read_header(&repeat).unwrap();
+        assert_eq!(Mode::RepeatOffsets, header.mode);
+        assert!(
+            repeat.len() * 100 < plain.len() * 95,
+            "repeat offsets {} bytes, split {}",
+            repeat.len(),
+            plain.len()
+        );
+
+        // decoding doesn't need to be told about the repeat offsets
+        let decoder = Lz77Compression::new();
+        assert_eq!(records, decoder.decode(&repeat).unwrap());
+        let mut output = vec![0; records.len()];
+        decoder.decode_into(&repeat, &mut output).unwrap();
+        assert_eq!(records, output);
+
+        // every stretch between sync markers starts over, so each still recovers on its own
+        let synced = Lz77Compression::new()
+            .with_repeat_offsets()
+            .with_sync_interval(1000)
+            .encode(&records);
+        let (header, _) = serialisation::read_header(&synced).unwrap();
+        assert_eq!(Mode::RepeatOffsets, header.mode);
+        let mut damaged = synced.clone();
+        damaged[100] ^= 0xff;
+        let recovered = decoder.recover(&damaged).unwrap();
+        let (start, bytes) = recovered.last().unwrap();
+        assert_eq!(records.len(), start + bytes.len());
+        assert_eq!(&records[*start..], &bytes[..]);
+        assert!(*start <= 2000);
+
+        // only kept when smaller
+        for bytes in [pseudo_random_bytes(300), b"abcabcabcabd".repeat(20)] {
+            assert!(compressor.encode(&bytes).len() <= Lz77Compression::new().encode(&bytes).len());
+        }
+    }
+
+    #[test]
+    fn adaptive_offsets_shrink_small_files() {
+        let small = b"the cat sat on the mat, the cat sat on the hat".repeat(3);
+        let split = Lz77Compression::new().encode(&small);
+        let compressor = Lz77Compression::new().with_adaptive_offsets();
+        let adaptive = compressor.encode(&small);
+        assert!(
+            adaptive.len() < split.len(),
+            "adaptive {} bytes, split {}",
+            adaptive.len(),
+            split.len()
+        );
+        let (header, _) = serialisation::read_header(&adaptive).unwrap();
+        assert!(matches!(header.mode, Mode::FixedOffsets { bits } if bits < 7));
+
+        // decoding doesn't need to be told the offsets were adaptive
+        let decoder = Lz77Compression::new();
+        assert_eq!(small, decoder.decode(&adaptive).unwrap());
+        assert_eq!(small.len(), decoder.decompressed_len(&adaptive).unwrap());
+        let mut output = vec![0; small.len()];
+        decoder.decode_into(&adaptive, &mut output).unwrap();
+        assert_eq!(small, output);
+    }
+
+    #[test]
+    fn adaptive_offsets_round_trip() {
+        let compressor = Lz77Compression::new()
+            .with_adaptive_offsets()
+            .with_run_length_encoding(32);
+        let mut text = b"the cat sat on the mat, the cat sat on the hat".repeat(200);
+        text.extend_from_slice(&[0; 1000]);
+        for bytes in [
+            text.clone(),
+            pseudo_random_bytes(1000),
+            Vec::new(),
+            b"x".to_vec(),
+        ] {
+            assert_eq!(
+                bytes,
+                compressor.decode(&compressor.encode(&bytes)).unwrap()
+            );
+        }
+
+        let appended = [compressor.encode(&text), compressor.encode(b"tail")].concat();
+        assert_eq!(
+            [&text[..], b"tail"].concat(),
+            compressor.decompress_concatenated(&appended).unwrap()
+        );
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip_in_memory() {
+        let bytes = b"the cat sat on the mat, the cat sat on the hat".to_vec();
+        let compressor = Lz77Compression::new();
+
+        let encoded = compressor.encode(&bytes);
+        assert!(encoded.len() < bytes.len());
+        assert_eq!(bytes, compressor.decode(&encoded).unwrap());
+        assert!(compressor.decode(&encoded[..4]).is_err());
+    }
+
+    #[test]
+    fn strings_round_trip_and_invalid_utf8_is_refused() {
+        let text = "naïve café, 東京, 🦀 and crème brûlée. ".repeat(8);
+        let compressor = Lz77Compression::new();
+
+        let compressed = compressor.compress_str(&text);
+        assert!(compressed.len() < text.len());
+        assert_eq!(text, compressor.decompress_str(&compressed).unwrap());
+
+        // a multi-byte character cut in half
+        let cut = compressor.encode(&"🦀".as_bytes()[..2]);
+        assert!(matches!(
+            compressor.decompress_str(&cut),
+            Err(crate::error::Error::Utf8(_))
+        ));
+    }
+
+    #[test]
+    fn optimal_parse_round_trips_and_is_no_larger() {
+        let bytes = b"she sells sea shells on the sea shore, the shells she sells are sea shells"
+            .repeat(20);
+        let dictionary = b"sea shells";
+        let greedy = Lz77Compression::new().with_dictionary(dictionary);
+        let optimal = Lz77Compression::new()
+            .with_dictionary(dictionary)
+            .with_optimal_parse();
+
+        let encoded = optimal.encode(&bytes);
+        assert!(encoded.len() <= greedy.encode(&bytes).len());
+        // the parse is a compression choice only, any decoder reads it
+        assert_eq!(bytes, greedy.decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn suffix_matcher_round_trips_and_is_no_larger() {
+        // log lines recurring both inside and well outside the default window, noise, runs
+        // and a stretch of English
+        let mut bytes = Vec::new();
+        for (i, noise) in pseudo_random_bytes(30000).chunks(6).enumerate() {
+            bytes.extend_from_slice(format!("{} GET /item/", i % 97).as_bytes());
+            bytes.extend_from_slice(&noise[..usize::from(noise[0] % 6)]);
+            bytes.extend_from_slice(if i % 3 == 0 { b" 200\n" } else { b" 404\n" });
+            if i % 700 == 0 {
+                bytes.extend(vec![noise[1]; 300]);
+            }
+        }
+        bytes.extend(
+            b"she sells sea shells on the sea shore, the shells she sells are sea shells"
+                .repeat(20),
+        );
+
+        for window in [
+            WindowSize::DEFAULT,
+            WindowSize::new(16383).unwrap(),
+            WindowSize::MAX,
+        ] {
+            let greedy = Lz77Compression::new().with_search_window_size(window);
+            let suffix = Lz77Compression::new()
+                .with_search_window_size(window)
+                .with_suffix_matcher();
+            let encoded = suffix.encode(&bytes);
+            assert!(encoded.len() <= greedy.encode(&bytes).len(), "{:?}", window);
+            assert_eq!(bytes, greedy.decode(&encoded).unwrap());
+        }
+        // and with the settings that change what a match may reach back to
+        let suffix = Lz77Compression::new()
+            .with_dictionary(b"GET /item/")
+            .with_sync_interval(5000)
+            .with_suffix_matcher();
+        assert_eq!(bytes, suffix.decode(&suffix.encode(&bytes)).unwrap());
+        assert_eq!(b"".to_vec(), suffix.decode(&suffix.encode(b"")).unwrap());
+    }
+
+    #[test]
+    fn nice_match_length_trades_a_little_ratio() {
+        let phrases: [&[u8]; 4] = [
+            b"GET /index.html 200 ",
+            b"GET /style.css 304 ",
+            b"GET /index.html 304 ",
+            b"POST /login 200 ",
+        ];
+        let bytes: Vec<u8> = pseudo_random_bytes(4000)
+            .iter()
+            .flat_map(|&choice| phrases[usize::from(choice % 4)].iter().copied())
+            .collect();
+
+        let longest = Lz77Compression::new().encode(&bytes);
+        assert_eq!(
+            longest,
+            Lz77Compression::new()
+                .with_nice_match_length(compress::MAX_MATCH_LENGTH)
+                .encode(&bytes)
+        );
+        let nice = Lz77Compression::new().with_nice_match_length(32);
+        let encoded = nice.encode(&bytes);
+        assert_ne!(longest, encoded);
+        assert!(
+            encoded.len() * 10 <= longest.len() * 12,
+            "{} bytes against {}",
+            encoded.len(),
+            longest.len()
+        );
+        assert_eq!(bytes, nice.decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn levels_trade_speed_for_size() {
+        // records sharing a prefix, so every position has many candidates, and lines that
+        // recur further back than the default window
+        let mut bytes = Vec::new();
+        for (i, noise) in pseudo_random_bytes(24000).chunks(3).enumerate() {
+            bytes.extend_from_slice(b"id=");
+            bytes.extend_from_slice(noise);
+            if i % 500 == 0 {
+                bytes.extend_from_slice(b"a log line that turns up every few kilobytes\n");
+            }
+        }
+        let fastest = Lz77Compression::new().with_level(Level::Fastest);
+        let default = Lz77Compression::new().with_level(Level::Default);
+        let best = Lz77Compression::new().with_level(Level::Best);
+        // the candidates the greedy search compares at the start of each node, rather than
+        // the time taken, which is left to the benchmarks
+        let compared = |compressor: &Lz77Compression| {
+            let window = usize::from(compressor.search_window_size.get());
+            let mut index =
+                window_byte_container::PrefixIndex::with_max_window_size(&bytes, window);
+            let mut position = 0;
+            let mut compared = 0;
+            for node in compressor.nodes(&bytes) {
+                index.advance_to_pointer(position);
+                compared += index.candidates_compared(
+                    usize::from(compressor.max_match_length),
+                    usize::from(compressor.nice_match_length),
+                    compressor.max_candidates,
+                );
+                position += node.decoded_len();
+            }
+            compared
+        };
+        assert!(compared(&fastest) * 10 < compared(&default));
+
+        let encoded = [&fastest, &default, &best].map(|compressor| compressor.encode(&bytes));
+        assert_eq!(Lz77Compression::new().encode(&bytes), encoded[1]);
+        assert!(encoded[2].len() < encoded[1].len());
+        for (compressor, encoded) in [&fastest, &default, &best].iter().zip(&encoded) {
+            assert_eq!(bytes, compressor.decode(encoded).unwrap());
+        }
+
+        // settings made after the level override it, and the level resets earlier ones
+        assert_eq!(
+            Lz77Compression::new().with_optimal_parse().encode(&bytes),
+            best.with_search_window_size(WindowSize::DEFAULT)
+                .with_level(Level::Default)
+                .with_optimal_parse()
+                .encode(&bytes)
+        );
+    }
+
+    #[test]
+    fn max_candidates_caps_the_search_for_a_valid_stream() {
+        // every record starts with the same trigram, so each position has hundreds of candidates
+        let bytes: Vec<u8> = pseudo_random_bytes(6000)
+            .chunks(3)
+            .flat_map(|noise| [&b"id="[..], noise].concat())
+            .collect();
+
+        let longest = Lz77Compression::new().encode(&bytes);
+        assert_eq!(
+            longest,
+            Lz77Compression::new()
+                .with_max_candidates(usize::MAX)
+                .encode(&bytes)
+        );
+        let capped = Lz77Compression::new().with_max_candidates(4);
+        let encoded = capped.encode(&bytes);
+        assert_ne!(longest, encoded);
+        assert!(
+            encoded.len() > longest.len() && encoded.len() * 10 <= longest.len() * 12,
+            "{} bytes against {}",
+            encoded.len(),
+            longest.len()
+        );
+        assert_eq!(bytes, capped.decode(&encoded).unwrap());
+        assert_eq!(
+            bytes,
+            Lz77Compression::new()
+                .with_max_candidates(0)
+                .decode(&Lz77Compression::new().with_max_candidates(0).encode(&bytes))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn profitable_matches_pass_over_short_far_references() {
+        let mut dictionary = b"qzxy".to_vec();
+        dictionary.extend_from_slice(&[b'.'; 3000]);
+        let window = WindowSize::new(4096).unwrap();
+        let greedy = Lz77Compression::new()
+            .with_dictionary(&dictionary)
+            .with_search_window_size(window);
+        let profitable = Lz77Compression::new()
+            .with_dictionary(&dictionary)
+            .with_search_window_size(window)
+            .with_profitable_matches();
+
+        // an escaped offset costs 31 bits, more than 2 or 3 literals at 9 bits each
+        for input in [&b"qz"[..], b"qzx"] {
+            assert_eq!(
+                vec![NodeType::reference(3004, input.len() as u16)],
+                greedy.nodes(input).collect::<Vec<_>>()
+            );
+            let literals: Vec<_> = input.iter().copied().map(NodeType::literal).collect();
+            assert_eq!(literals, profitable.nodes(input).collect::<Vec<_>>());
+            let encoded = profitable.encode(input);
+            assert!(encoded.len() <= greedy.encode(input).len());
+            assert_eq!(input, &greedy.decode(&encoded).unwrap()[..]);
+        }
+        // but not 4
+        assert_eq!(
+            vec![NodeType::reference(3004, 4)],
+            profitable.nodes(b"qzxy").collect::<Vec<_>>()
+        );
+
+        // near references always pay for themselves
+        let text = b"profitable or not, profitable or not".repeat(10);
+        assert_eq!(
+            Lz77Compression::new().encode(&text),
+            Lz77Compression::new()
+                .with_profitable_matches()
+                .encode(&text)
+        );
+    }
+
+    #[test]
+    fn run_length_encoding_collapses_long_runs() {
+        let zeros = vec![0u8; 100_000];
+        let chained = Lz77Compression::new().encode(&zeros);
+        let compressor = Lz77Compression::new().with_run_length_encoding(32);
+        let encoded = compressor.encode(&zeros);
+        assert!(
+            encoded.len() * 100 < chained.len(),
+            "{} bytes against {}",
+            encoded.len(),
+            chained.len()
+        );
+        assert_eq!(
+            vec![NodeType::run(0, 100_000)],
+            compressor.nodes(&zeros).collect::<Vec<_>>()
+        );
+        assert_eq!(zeros, compressor.decode(&encoded).unwrap());
+        // runs decode without the option set
+        assert_eq!(zeros, Lz77Compression::new().decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn sync_points_round_trip_in_every_mode() {
+        let line = b"2026-10-15 12:00:00 INFO request served in 3ms\n";
+        let log = line.repeat(400);
+        let compressors = [
+            Lz77Compression::new(),
+            Lz77Compression::new().with_adaptive_offsets(),
+            Lz77Compression::new().with_run_length_encoding(32),
+            Lz77Compression::new().with_dictionary(b"INFO request served"),
+            Lz77Compression::new().with_token_stream(),
+        ];
+        for compressor in compressors {
+            let compressor = compressor.with_sync_interval(4096);
+            for bytes in [&log[..], b"", b"x"] {
+                let encoded = compressor.encode(bytes);
+                assert_eq!(bytes, &compressor.decode(&encoded).unwrap()[..]);
+                assert_eq!(
+                    vec![(0, bytes.to_vec())],
+                    compressor.recover(&encoded).unwrap()
+                );
+            }
+        }
+        // references don't cross the markers, so they cost a little
+        let plain = Lz77Compression::new().encode(&log);
+        let synced = Lz77Compression::new().with_sync_interval(4096).encode(&log);
+        assert!(plain.len() < synced.len());
+    }
+
+    #[test]
+    fn decoding_resumes_at_the_sync_point_after_corruption() {
+        let line = b"2026-10-15 12:00:00 INFO request served in 3ms\n";
+        let log = line.repeat(400);
+        let compressor = Lz77Compression::new().with_sync_interval(4096);
+        let encoded = compressor.encode(&log);
+        let (header, body) = serialisation::read_header(&encoded).unwrap();
+        assert_eq!(Mode::Lz77, header.mode);
+        let header_len = encoded.len() - body.len();
+        let sync_points: Vec<usize> = serialisation::find_sync_points(body)
+            .map(|(position, rest)| {
+                assert_eq!(0, position as usize % 4096);
+                body.len() - rest.len()
+            })
+            .collect();
+        assert_eq!(log.len() / 4096, sync_points.len());
+
+        // damage the nodes between the first and second markers
+        let mut corrupt = encoded.clone();
+        corrupt[header_len + sync_points[0] + 10] ^= 0xff;
+        assert!(compressor.decode(&corrupt).is_err());
+        let recovered = compressor.recover(&corrupt).unwrap();
+        assert_eq!(
+            vec![(0, log[..4096].to_vec()), (8192, log[8192..].to_vec())],
+            recovered
+        );
+
+        // a truncated stream decodes up to its last marker
+        let truncated = &encoded[..header_len + sync_points[2] + 20];
+        assert_eq!(
+            vec![(0, log[..3 * 4096].to_vec())],
+            compressor.recover(truncated).unwrap()
+        );
+    }
+
+    #[test]
+    fn recovery_checks_a_segment_length_before_decoding_it() {
+        let nodes = [NodeType::literal(b'a'), NodeType::run(b'a', u32::MAX)];
+        let mut bomb = Vec::new();
+        serialisation::write_header(
+            &mut bomb,
+            &Header {
+                mode: Mode::Lz77,
+                search_window_size: WindowSize::DEFAULT,
+                original_len: 10,
+                content_hash: None,
+                original_file: None,
+                continues: false,
+            },
+        );
+        bomb.extend_from_slice(&serialisation::serialise_stream(&nodes, OffsetCodec::Split));
+        assert!(Lz77Compression::new().recover(&bomb).unwrap().is_empty());
+    }
+
+    #[test]
+    fn linear_matcher_takes_the_same_matches() {
+        let text = b"the cat sat on the mat, the cat sat on the hat. ".repeat(50);
+        let mut bytes = pseudo_random_bytes(3000);
+        bytes.extend_from_slice(&text);
+        for window in [64, 2047, 4096] {
+            let window = WindowSize::new(window).unwrap();
+            let indexed = Lz77Compression::new().with_search_window_size(window);
+            let linear = Lz77Compression::new()
+                .with_search_window_size(window)
+                .with_linear_matcher();
+            assert_eq!(indexed.encode(&bytes), linear.encode(&bytes));
+        }
+        let dictionary = Lz77Compression::new().with_dictionary(b"the cat ");
+        assert_eq!(
+            dictionary.encode(&text),
+            Lz77Compression::new()
+                .with_dictionary(b"the cat ")
+                .with_linear_matcher()
+                .encode(&text)
+        );
+    }
+
+    #[test]
+    fn runs_mix_with_matches_in_every_mode() {
+        let mut bytes = b"header: abcabcabc ".to_vec();
+        bytes.extend(vec![b' '; 500]);
+        bytes.extend_from_slice(b"abcabc header: ");
+        bytes.extend(vec![0xff; 40]);
+        bytes.extend(vec![b'x'; 10]);
+        bytes.extend_from_slice(b"header:");
+
+        let compressors = [
+            Lz77Compression::new(),
+            Lz77Compression::new().with_optimal_parse(),
+            Lz77Compression::new().with_token_stream(),
+            Lz77Compression::new().with_dictionary(b"header: "),
+        ];
+        for compressor in compressors {
+            let compressor = compressor.with_run_length_encoding(32);
+            let nodes: Vec<NodeType> = compressor.nodes(&bytes).collect();
+            assert_eq!(
+                vec![NodeType::run(b' ', 501), NodeType::run(0xff, 40)],
+                nodes
+                    .iter()
+                    .copied()
+                    .filter(|node| matches!(node, NodeType::Run { .. }))
+                    .collect::<Vec<_>>()
+            );
+            // matches still reach back across a run
+            assert!(nodes.iter().any(|node| matches!(
+                node,
+                NodeType::Reference { offset, .. } if *offset > 500
+            )));
+            assert_eq!(
+                bytes,
+                compressor.decode(&compressor.encode(&bytes)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn max_match_length_caps_references() {
+        let bytes = b"abcabcabcabcabcabcabcabcabcabc".repeat(10);
+        let capped = Lz77Compression::new().with_max_match_length(6);
+
+        assert!(capped.nodes(&bytes).all(|node| node.decoded_len() <= 6));
+        assert_eq!(bytes, capped.decode(&capped.encode(&bytes)).unwrap());
+
+        assert_eq!(
+            2,
+            Lz77Compression::new()
+                .with_max_match_length(0)
+                .max_match_length
+        );
+        assert_eq!(
+            compress::MAX_MATCH_LENGTH,
+            Lz77Compression::new()
+                .with_max_match_length(u16::MAX)
+                .max_match_length
+        );
+    }
+
+    #[test]
+    fn literals_only_round_trips_without_references() {
+        let bytes = b"the cat sat on the mat, the cat sat on the hat".repeat(4);
+        let compressor = Lz77Compression::new().literals_only();
+
+        let nodes: Vec<NodeType> = compressor.nodes(&bytes).collect();
+        assert_eq!(bytes.len(), nodes.len());
+        assert!(!nodes.iter().any(NodeType::is_reference));
+
+        // 9 bits per literal plus the end marker, rather than falling back to storing
+        let encoded = compressor.encode(&bytes);
+        let (_, body) = serialisation::read_header(&encoded).unwrap();
+        assert_eq!((bytes.len() * 9 + 9).div_ceil(8), body.len());
+        assert_eq!(bytes, Lz77Compression::new().decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn disassembly_lists_the_nodes_where_they_start() {
+        let encoded = Lz77Compression::new().encode(b"abababab!");
+        // after the 9 byte header, literals take 9 bits and these references 11
+        let expected = vec![
+            (72, NodeType::literal(b'a')),
+            (81, NodeType::literal(b'b')),
+            (
+                90,
+                NodeType::Reference {
+                    offset: 2,
+                    length: 2,
+                },
+            ),
+            (
+                101,
+                NodeType::Reference {
+                    offset: 4,
+                    length: 4,
+                },
+            ),
+            (112, NodeType::literal(b'!')),
+        ];
+        assert_eq!(
+            expected,
+            Lz77Compression::disassemble_with_positions(&encoded).unwrap()
+        );
+
+        let log = b"2026-10-15 12:00:00 INFO request served in 3ms\n".repeat(200);
+        for compressor in [
+            Lz77Compression::new().with_sync_interval(4096),
+            Lz77Compression::new().with_token_stream(),
+            Lz77Compression::new().with_run_length_encoding(16),
+        ] {
+            let disassembled = Lz77Compression::disassemble(&compressor.encode(&log)).unwrap();
+            assert_eq!(compressor.nodes(&log).collect::<Vec<_>>(), disassembled);
+        }
+        let stored = Lz77Compression::disassemble(&Lz77Compression::new().encode(b"xy")).unwrap();
+        assert_eq!(
+            vec![NodeType::literal(b'x'), NodeType::literal(b'y')],
+            stored
+        );
+    }
+
+    // a cheap stand-in for the fuzz target: corrupt valid streams and make sure decoding
+    // only ever fails with an error
+    #[test]
+    fn decode_does_not_panic_on_corrupt_streams() {
+        let compressor = Lz77Compression::new();
+        let inputs = [
+            b"abababababababababab".to_vec(),
+            b"the cat sat on the mat, the cat sat on the hat".repeat(4),
+            [vec![b'z'; 3000], b"abc".repeat(50)].concat(),
+        ];
+        for input in inputs.iter() {
+            let encoded = compressor.encode(input);
+            for len in 0..encoded.len() {
+                let _ = compressor.decode(&encoded[..len]);
+            }
+            for index in 0..encoded.len() {
+                for mask in [0x01, 0x10, 0x80, 0xff] {
+                    let mut corrupt = encoded.clone();
+                    corrupt[index] ^= mask;
+                    let _ = compressor.decode(&corrupt);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn content_hash_is_deterministic_and_checked() {
+        let bytes = b"content addressed content, addressed by content. ".repeat(40);
+        let hash = md5::compute(&bytes).0;
+        let compressor: &dyn compression::Algorithm = &Lz77Compression::new().with_content_hash();
+
+        let mut first = Vec::new();
+        let first_stats = compressor.compress(&mut &bytes[..], &mut first).unwrap();
+        let mut second = Vec::new();
+        let second_stats = compressor.compress(&mut &bytes[..], &mut second).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(Some(hash), first_stats.content_hash);
+        assert_eq!(first_stats, second_stats);
+        let (header, _) = serialisation::read_header(&first).unwrap();
+        assert_eq!(Some(hash), header.content_hash);
+
+        let mut decompressed = Vec::new();
+        compressor
+            .decompress(&mut &first[..], &mut decompressed)
+            .unwrap();
+        assert_eq!(bytes, decompressed);
+
+        let at = first.windows(16).position(|window| window == hash).unwrap();
+        let mut corrupt = first.clone();
+        corrupt[at] ^= 1;
+        assert!(matches!(
+            Lz77Compression::new().decode(&corrupt),
+            Err(Error::Deserialise(DeserialiseError::ChecksumMismatch))
+        ));
+        let mut buffer = vec![0; bytes.len()];
+        assert!(Lz77Compression::new()
+            .decode_into(&corrupt, &mut buffer)
+            .is_err());
+        assert!(matches!(
+            Lz77Compression::new().decode(&first[..at + 8]),
+            Err(Error::Deserialise(DeserialiseError::Truncated))
+        ));
+
+        // stored input is hashed too
+        let stored = Lz77Compression::new().with_content_hash().encode(b"xyz");
+        assert_eq!(
+            b"xyz".to_vec(),
+            Lz77Compression::new().decode(&stored).unwrap()
+        );
+        assert!(stored.len() > Lz77Compression::new().encode(b"xyz").len());
+    }
+
+    #[test]
+    fn round_trips_through_the_algorithm_trait() {
+        let bytes = b"abcabcabcabcabcabcabcabcabcabc".to_vec();
+        let compressor: &dyn compression::Algorithm = &Lz77Compression::new();
+
+        let mut compressed = Vec::new();
+        compressor
+            .compress(&mut &bytes[..], &mut compressed)
+            .unwrap();
+
+        let mut decompressed = Vec::new();
+        compressor
+            .decompress(&mut &compressed[..], &mut decompressed)
+            .unwrap();
+        assert_eq!(bytes, decompressed);
+    }
+
+    #[test]
+    fn progress_does_not_change_the_output() {
+        let bytes = pseudo_random_bytes(200 * 1024);
+        let compressor = Lz77Compression::new();
+
+        let mut reports = 0;
+        let mut compressed = Vec::new();
+        compressor
+            .compress_with_progress(&mut &bytes[..], &mut compressed, |_, _| reports += 1)
+            .unwrap();
+
+        assert_eq!(4, reports);
+        assert_eq!(compressor.encode(&bytes), compressed);
+    }
+
+    #[test]
+    fn archives_round_trip_multiple_files() {
+        let dir = ScratchDir::new("archive");
+        let input_dir = dir.join("in");
+        let output_dir = dir.join("out");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::create_dir_all(&output_dir).unwrap();
+        let files: [(&str, &[u8]); 3] = [
+            ("first.txt", b"the quick brown fox jumps over the lazy dog"),
+            ("empty.txt", b""),
+            ("third.txt", b"the quick brown fox jumps over the lazy cat"),
+        ];
+        let paths: Vec<PathBuf> = files
+            .iter()
+            .map(|(name, contents)| {
+                let path = input_dir.join(name);
+                fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect();
+
+        let compressor = Lz77Compression::new();
+        let mut compressed = Vec::new();
+        compressor.compress_files(&paths, &mut compressed).unwrap();
+        let written = compressor
+            .decompress_files(&mut &compressed[..], &output_dir)
+            .unwrap();
+
+        let expected: Vec<PathBuf> = files
+            .iter()
+            .map(|(name, _)| output_dir.join(name))
+            .collect();
+        assert_eq!(expected, written);
+        for (name, contents) in files.iter() {
+            assert_eq!(*contents, &fs::read(output_dir.join(name)).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn archives_recreate_nested_paths_and_refuse_escapes() {
+        let dir = ScratchDir::new("nested");
+        let output_dir = dir.join("out");
+        let compressor = Lz77Compression::new();
+        let archive_file = |name: &str, files: &[(String, Vec<u8>)]| {
+            let path = dir.join(name);
+            fs::write(&path, compressor.encode(&archive::write_archive(files))).unwrap();
+            File::open(path).unwrap()
+        };
+
+        let nested = archive_file(
+            "nested",
+            &[
+                ("top.txt".to_string(), b"top".to_vec()),
+                ("a/b/deep.txt".to_string(), b"deep".to_vec()),
+                ("a/side.txt".to_string(), b"side".to_vec()),
+            ],
+        );
+        let written = compressor.decompress_archive(&nested, &output_dir).unwrap();
+        assert_eq!(
+            vec![
+                output_dir.join("top.txt"),
+                output_dir.join("a").join("b").join("deep.txt"),
+                output_dir.join("a").join("side.txt"),
+            ],
+            written
+        );
+        assert_eq!(b"deep".to_vec(), fs::read(&written[1]).unwrap());
+        assert_eq!(b"side".to_vec(), fs::read(&written[2]).unwrap());
+
+        let malicious = archive_file(
+            "malicious",
+            &[
+                ("fine.txt".to_string(), b"fine".to_vec()),
+                ("../escape".to_string(), b"escaped".to_vec()),
+            ],
+        );
+        let escape_dir = dir.join("escape-out");
+        assert!(matches!(
+            compressor.decompress_archive(&malicious, &escape_dir),
+            Err(Error::Deserialise(DeserialiseError::InvalidArchiveEntry))
+        ));
+        assert!(!escape_dir.exists());
+        assert!(!dir.join("escape").exists());
+    }
+
+    #[test]
+    fn seekable_streams_decode_ranges_from_the_blocks_holding_them() {
+        /// Counts the bytes read through it.
+        struct CountingReads<R> {
+            inner: R,
+            read: usize,
+        }
+        impl<R: Read> Read for CountingReads<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.inner.read(buf)?;
+                self.read += n;
+                Ok(n)
+            }
+        }
+        impl<R: Seek> Seek for CountingReads<R> {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.inner.seek(pos)
+            }
+        }
+
+        let text = b"a seekable stream of not very random text, block after block. ";
+        let input: Vec<u8> = text
+            .iter()
+            .cycle()
+            .zip(pseudo_random_bytes(10_500))
+            .map(|(&byte, noise)| if noise < 32 { noise } else { byte })
+            .collect();
+        let compressor = Lz77Compression::new();
+        let compressed = compressor.compress_seekable(&input, 1000);
+        assert_eq!(input, compressor.decompress_seekable(&compressed).unwrap());
+
+        let mut reader = CountingReads {
+            inner: io::Cursor::new(&compressed),
+            read: 0,
+        };
+        let middle = compressor
+            .decompress_range(&mut reader, 4321, 1500)
+            .unwrap();
+        assert_eq!(&input[4321..5821], &middle[..]);
+        // the index and blocks 4 and 5 of the 11
+        assert!(reader.read < compressed.len() / 3, "{}", reader.read);
+
+        for (start, len) in [(0, 1000), (999, 2), (10_000, 500), (10_499, 1), (500, 0)] {
+            assert_eq!(
+                &input[start..start + len],
+                &compressor
+                    .decompress_range(&mut io::Cursor::new(&compressed), start as u64, len)
+                    .unwrap()[..]
+            );
+        }
+        assert!(matches!(
+            compressor.decompress_range(&mut io::Cursor::new(&compressed), 10_000, 501),
+            Err(Error::RangeOutOfBounds {
+                end: 10_501,
+                len: 10_500
+            })
+        ));
+
+        let dir = ScratchDir::new("seekable");
+        let path = dir.join("seekable");
+        fs::write(&path, &compressed).unwrap();
+        let from_file = compressor
+            .decompress_range(&mut File::open(&path).unwrap(), 7000, 2500)
+            .unwrap();
+        assert_eq!(&input[7000..9500], &from_file[..]);
+
+        // a plain stream has no index
+        assert!(matches!(
+            compressor.decompress_seekable(&compressor.encode(&input)),
+            Err(Error::Deserialise(DeserialiseError::InvalidIndex))
+        ));
+        let empty = compressor.compress_seekable(b"", 1000);
+        assert_eq!(
+            Vec::<u8>::new(),
+            compressor.decompress_seekable(&empty).unwrap()
+        );
+    }
 }
@@ -1,13 +1,212 @@
-#[derive(PartialEq, Debug)]
-pub struct Node {
-    pub offset: u16,
-    pub length: u16,
-    pub char: u8,
-}
+use core::fmt;
+use core::iter::FromIterator;
+use core::ops::AddAssign;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum NodeType {
-    ByteLiteral { lit: u8 },
-    Reference { offset: u16, length: u16 },
-    EndOfStream,
+    ByteLiteral {
+        lit: u8,
+    },
+    Reference {
+        offset: u16,
+        length: u16,
+    },
+    /// `count` copies of `byte`, from the run-length pre-pass.
+    Run {
+        byte: u8,
+        count: u32,
+    },
+}
+
+impl NodeType {
+    pub fn literal(lit: u8) -> Self {
+        NodeType::ByteLiteral { lit }
+    }
+
+    /// A back-reference to `length` bytes starting `offset` bytes before the current position.
+    pub fn reference(offset: u16, length: u16) -> Self {
+        NodeType::Reference { offset, length }
+    }
+
+    pub fn run(byte: u8, count: u32) -> Self {
+        NodeType::Run { byte, count }
+    }
+
+    pub fn is_reference(&self) -> bool {
+        matches!(self, NodeType::Reference { .. })
+    }
+
+    /// How many bytes the node expands to when decompressed.
+    pub fn decoded_len(&self) -> usize {
+        match self {
+            NodeType::ByteLiteral { .. } => 1,
+            NodeType::Reference { length, .. } => usize::from(*length),
+            NodeType::Run { count, .. } => *count as usize,
+        }
+    }
+}
+
+/// The largest offset counted in each bucket of `NodeHistogram::offsets`, one bucket per
+/// offset encoding size.
+pub const OFFSET_BUCKETS: [u16; 3] = [127, 2047, u16::MAX];
+/// The largest length counted in each bucket of `NodeHistogram::lengths`. The first three
+/// match the 2, 4 and 8 bit length encodings.
+pub const LENGTH_BUCKETS: [u16; 5] = [4, 7, 22, 127, u16::MAX];
+
+/// Counts of the nodes a matcher emitted, for tuning the window size and the encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NodeHistogram {
+    pub literals: usize,
+    /// References by offset, bucketed by `OFFSET_BUCKETS`.
+    pub offsets: [usize; 3],
+    /// References by length, bucketed by `LENGTH_BUCKETS`.
+    pub lengths: [usize; 5],
+    pub runs: usize,
+}
+
+impl NodeHistogram {
+    pub fn record(&mut self, node: NodeType) {
+        match node {
+            NodeType::ByteLiteral { .. } => self.literals += 1,
+            NodeType::Reference { offset, length } => {
+                self.offsets[bucket(&OFFSET_BUCKETS, offset)] += 1;
+                self.lengths[bucket(&LENGTH_BUCKETS, length)] += 1;
+            }
+            NodeType::Run { .. } => self.runs += 1,
+        }
+    }
+
+    pub fn references(&self) -> usize {
+        self.offsets.iter().sum()
+    }
+
+    /// Every node recorded, of any kind.
+    pub fn nodes(&self) -> usize {
+        self.literals + self.references() + self.runs
+    }
+}
+
+impl FromIterator<NodeType> for NodeHistogram {
+    fn from_iter<I: IntoIterator<Item = NodeType>>(nodes: I) -> Self {
+        let mut histogram = NodeHistogram::default();
+        nodes.into_iter().for_each(|node| histogram.record(node));
+        histogram
+    }
+}
+
+/// Counts the nodes of another histogram too, e.g. those of the next chunk.
+impl AddAssign for NodeHistogram {
+    fn add_assign(&mut self, other: Self) {
+        self.literals += other.literals;
+        for (count, other) in self.offsets.iter_mut().zip(other.offsets.iter()) {
+            *count += other;
+        }
+        for (count, other) in self.lengths.iter_mut().zip(other.lengths.iter()) {
+            *count += other;
+        }
+        self.runs += other.runs;
+    }
+}
+
+impl fmt::Display for NodeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "literals: {}, references: {}",
+            self.literals,
+            self.references()
+        )?;
+        if self.runs > 0 {
+            writeln!(f, "runs: {}", self.runs)?;
+        }
+        write!(f, "offsets:")?;
+        write_buckets(f, 1, &OFFSET_BUCKETS, &self.offsets)?;
+        write!(f, "\nlengths:")?;
+        write_buckets(f, 2, &LENGTH_BUCKETS, &self.lengths)
+    }
+}
+
+fn bucket(bucket_ends: &[u16], value: u16) -> usize {
+    bucket_ends.iter().position(|end| value <= *end).unwrap()
+}
+
+// e.g. " 1-127: 3, 128-2047: 0, 2048+: 1"
+fn write_buckets(
+    f: &mut fmt::Formatter<'_>,
+    first: u16,
+    bucket_ends: &[u16],
+    counts: &[usize],
+) -> fmt::Result {
+    let mut start = first;
+    for (i, (end, count)) in bucket_ends.iter().zip(counts).enumerate() {
+        let separator = if i == 0 { " " } else { ", " };
+        if *end == u16::MAX {
+            write!(f, "{}{}+: {}", separator, start, count)?;
+        } else {
+            write!(f, "{}{}-{}: {}", separator, start, end, count)?;
+            start = end + 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_kind_and_decoded_length() {
+        let literal = NodeType::literal(b'a');
+        let reference = NodeType::reference(12, 300);
+
+        assert_eq!(NodeType::ByteLiteral { lit: b'a' }, literal);
+        assert!(!literal.is_reference());
+        assert_eq!(1, literal.decoded_len());
+
+        assert!(reference.is_reference());
+        assert_eq!(300, reference.decoded_len());
+
+        let run = NodeType::run(0, 100_000);
+        assert!(!run.is_reference());
+        assert_eq!(100_000, run.decoded_len());
+    }
+
+    #[test]
+    fn histogram_buckets_by_encoding_size() {
+        let histogram: NodeHistogram = [
+            NodeType::literal(b'a'),
+            NodeType::literal(b'b'),
+            NodeType::reference(1, 2),
+            NodeType::reference(127, 7),
+            NodeType::reference(128, 8),
+            NodeType::reference(2047, 22),
+            NodeType::reference(2048, 2047),
+        ]
+        .iter()
+        .copied()
+        .collect();
+
+        assert_eq!(2, histogram.literals);
+        assert_eq!(5, histogram.references());
+        assert_eq!([2, 2, 1], histogram.offsets);
+        assert_eq!([1, 1, 2, 0, 1], histogram.lengths);
+        assert_eq!(
+            "literals: 2, references: 5\n\
+             offsets: 1-127: 2, 128-2047: 2, 2048+: 1\n\
+             lengths: 2-4: 1, 5-7: 1, 8-22: 2, 23-127: 0, 128+: 1",
+            histogram.to_string()
+        );
+    }
+
+    #[test]
+    fn histogram_lists_runs_only_when_there_are_some() {
+        let histogram: NodeHistogram = [NodeType::literal(b'a'), NodeType::run(b'a', 500)]
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(1, histogram.runs);
+        assert!(histogram
+            .to_string()
+            .starts_with("literals: 1, references: 0\nruns: 1\noffsets:"));
+    }
 }
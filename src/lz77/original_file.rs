@@ -0,0 +1,171 @@
+use alloc::string::{String, ToString};
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::path::{Component, Path, PathBuf};
+#[cfg(feature = "std")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
+
+/// The longest name recorded, the most common file systems allow.
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+/// The name and modification time of the file a stream was compressed from, recorded in the
+/// header like gzip's FNAME and MTIME so decompressing into a directory can restore them.
+///
+/// The name is always a plain file name, never a path, so it can't be restored outside the
+/// directory decompressed into.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OriginalFile {
+    name: String,
+    /// Seconds since the Unix epoch.
+    modified: u64,
+}
+
+impl OriginalFile {
+    /// Fails unless `name` is a plain file name of at most `MAX_FILE_NAME_LEN` bytes: not
+    /// empty, `.` or `..`, and without `/`, `\`, `:` or a nul byte.
+    pub fn new(name: &str, modified: u64) -> Result<Self> {
+        if !is_plain_file_name(name) || name.len() > MAX_FILE_NAME_LEN {
+            return Err(Error::InvalidFileName(name.to_string()));
+        }
+        Ok(OriginalFile {
+            name: name.to_string(),
+            modified,
+        })
+    }
+
+    /// The name and modification time of the file at `path`, keeping only the last part of
+    /// the path. Times before the epoch are recorded as the epoch.
+    #[cfg(feature = "std")]
+    pub fn of(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Error::InvalidFileName(path.display().to_string()))?;
+        let modified = path
+            .metadata()?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        OriginalFile::new(name, modified)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn modified(&self) -> u64 {
+        self.modified
+    }
+
+    /// Where to restore the file when decompressing into `dir`.
+    #[cfg(feature = "std")]
+    pub fn path_in(&self, dir: &Path) -> PathBuf {
+        dir.join(&self.name)
+    }
+
+    /// The modification time, failing when it's past what the platform can represent.
+    #[cfg(feature = "std")]
+    pub fn modified_time(&self) -> Result<SystemTime> {
+        UNIX_EPOCH
+            .checked_add(Duration::from_secs(self.modified))
+            .ok_or(Error::InvalidModifiedTime(self.modified))
+    }
+
+    /// Sets the modification time of the restored file at `path`.
+    #[cfg(feature = "std")]
+    pub fn restore_modified(&self, path: &Path) -> Result<()> {
+        let modified = self.modified_time()?;
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(modified)?;
+        Ok(())
+    }
+}
+
+/// Not empty, `.` or `..`, and without a directory separator, a drive or stream separator
+/// (`:` on Windows) or a nul byte.
+pub(crate) fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', ':', '\0'])
+        && is_one_normal_component(name)
+}
+
+/// Whether the platform parses `name` as a single normal path component, so nothing it
+/// treats as a separator or prefix slipped past the checks above.
+#[cfg(feature = "std")]
+fn is_one_normal_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+#[cfg(not(feature = "std"))]
+fn is_one_normal_component(_: &str) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_plain_file_names() {
+        let original = OriginalFile::new("notes.txt", 1_700_000_000).unwrap();
+        assert_eq!("notes.txt", original.name());
+        assert_eq!(1_700_000_000, original.modified());
+        assert!(OriginalFile::new(&"n".repeat(MAX_FILE_NAME_LEN), 0).is_ok());
+
+        let too_long = "n".repeat(MAX_FILE_NAME_LEN + 1);
+        for name in [
+            "",
+            ".",
+            "..",
+            "../notes.txt",
+            "/etc/passwd",
+            "a\\b",
+            "C:notes.txt",
+            "notes.txt:hidden",
+            "notes\0.txt",
+            &too_long,
+        ] {
+            assert!(matches!(
+                OriginalFile::new(name, 0),
+                Err(Error::InvalidFileName(n)) if n == name
+            ));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn times_the_platform_cannot_represent_fail_to_restore() {
+        let dir = crate::test_util::ScratchDir::new("modified");
+        let path = dir.join("notes.txt");
+        std::fs::write(&path, b"notes").unwrap();
+
+        let original = OriginalFile::new("notes.txt", 1_500_000_000).unwrap();
+        original.restore_modified(&path).unwrap();
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_500_000_000),
+            path.metadata().unwrap().modified().unwrap()
+        );
+
+        let far_off = OriginalFile::new("notes.txt", u64::MAX).unwrap();
+        assert!(matches!(
+            far_off.modified_time(),
+            Err(Error::InvalidModifiedTime(u64::MAX))
+        ));
+        assert!(matches!(
+            far_off.restore_modified(&path),
+            Err(Error::InvalidModifiedTime(u64::MAX))
+        ));
+    }
+}
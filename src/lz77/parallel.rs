@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+
+use crate::error::DeserialiseError;
+
+/// Frames independently compressed blocks into a single stream.
+///
+/// Layout: a big-endian `u32` block count, the compressed length of each block as a
+/// big-endian `u32`, then the concatenated blocks.
+pub fn write_frames(blocks: &[Vec<u8>]) -> Vec<u8> {
+    let body_len: usize = blocks.iter().map(|b| b.len()).sum();
+    let mut framed = Vec::with_capacity(4 + blocks.len() * 4 + body_len);
+    framed.extend_from_slice(&u32::try_from(blocks.len()).unwrap().to_be_bytes());
+    for block in blocks {
+        framed.extend_from_slice(&u32::try_from(block.len()).unwrap().to_be_bytes());
+    }
+    for block in blocks {
+        framed.extend_from_slice(block);
+    }
+    framed
+}
+
+/// Splits a framed stream back into its compressed blocks.
+pub fn read_frames(framed: &[u8]) -> Result<Vec<&[u8]>, DeserialiseError> {
+    let block_count = read_u32(framed, 0)? as usize;
+    let mut block_ptr = 4 + block_count * 4;
+    if framed.len() < block_ptr {
+        return Err(DeserialiseError::Truncated);
+    }
+
+    let mut blocks = Vec::with_capacity(block_count);
+    for i in 0..block_count {
+        let block_len = read_u32(framed, 4 + i * 4)? as usize;
+        let block_end = block_ptr + block_len;
+        if block_end > framed.len() {
+            return Err(DeserialiseError::Truncated);
+        }
+        blocks.push(&framed[block_ptr..block_end]);
+        block_ptr = block_end;
+    }
+    Ok(blocks)
+}
+
+fn read_u32(bytes: &[u8], index: usize) -> Result<u32, DeserialiseError> {
+    bytes
+        .get(index..index + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(DeserialiseError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let blocks = vec![vec![1, 2, 3], vec![], vec![4]];
+        let framed = write_frames(&blocks);
+        assert_eq!(
+            vec![0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4],
+            framed
+        );
+
+        let read = read_frames(&framed).unwrap();
+        assert_eq!(vec![&[1, 2, 3][..], &[][..], &[4][..]], read);
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let framed = write_frames(&[vec![1, 2, 3]]);
+        for len in 0..framed.len() {
+            assert_eq!(
+                Err(DeserialiseError::Truncated),
+                read_frames(&framed[..len])
+            );
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::convert::TryFrom;
+use std::io::{prelude::*, SeekFrom};
+use std::ops::Range;
+
+use crate::error::{DeserialiseError, Result};
+
+/// Marks the end of a seekable stream, so a stream without an index is refused.
+const INDEX_MAGIC: [u8; 4] = *b"SLZX";
+/// The block size, block count and original length, each a big-endian `u64`, then the magic.
+const TRAILER_SIZE: usize = 28;
+
+/// Where each block of a seekable stream starts, as stored at the end of the stream.
+///
+/// Layout: the compressed blocks back to back, then each block's offset from the start of the
+/// stream as a big-endian `u64`, then the trailer.
+#[derive(Debug, PartialEq)]
+pub struct Index {
+    /// Original bytes per block, only the last block may hold fewer.
+    pub block_size: u64,
+    pub original_len: u64,
+    /// The offset of each block followed by the offset of the index, which ends the last.
+    pub offsets: Vec<u64>,
+}
+
+impl Index {
+    /// Appends the index for blocks of `block_size` bytes, compressed into `blocks`, to
+    /// `output`, which already holds the blocks.
+    pub fn write(output: &mut Vec<u8>, blocks: &[usize], block_size: usize, original_len: usize) {
+        let mut offset = output.len() as u64 - blocks.iter().sum::<usize>() as u64;
+        for &block_len in blocks {
+            output.extend_from_slice(&offset.to_be_bytes());
+            offset += block_len as u64;
+        }
+        output.extend_from_slice(&(block_size as u64).to_be_bytes());
+        output.extend_from_slice(&(blocks.len() as u64).to_be_bytes());
+        output.extend_from_slice(&(original_len as u64).to_be_bytes());
+        output.extend_from_slice(&INDEX_MAGIC);
+    }
+
+    /// Reads the index from the end of `input`, leaving it positioned somewhere past the blocks.
+    pub fn read<R: Read + Seek>(input: &mut R) -> Result<Index> {
+        let stream_len = input.seek(SeekFrom::End(0))?;
+        if stream_len < TRAILER_SIZE as u64 {
+            return Err(DeserialiseError::InvalidIndex.into());
+        }
+        let mut trailer = [0; TRAILER_SIZE];
+        input.seek(SeekFrom::End(-(TRAILER_SIZE as i64)))?;
+        input.read_exact(&mut trailer)?;
+        if trailer[24..] != INDEX_MAGIC {
+            return Err(DeserialiseError::InvalidIndex.into());
+        }
+        let field = |i: usize| u64::from_be_bytes(<[u8; 8]>::try_from(&trailer[i..i + 8]).unwrap());
+        let (block_size, block_count, original_len) = (field(0), field(8), field(16));
+        let expected_count = original_len
+            .checked_div(block_size)
+            .map(|whole_blocks| whole_blocks + u64::from(!original_len.is_multiple_of(block_size)));
+        let index_start = block_count
+            .checked_mul(8)
+            .and_then(|offsets_len| (stream_len - TRAILER_SIZE as u64).checked_sub(offsets_len))
+            .filter(|_| expected_count == Some(block_count))
+            .ok_or(DeserialiseError::InvalidIndex)?;
+
+        let mut offsets_bytes = vec![0; block_count as usize * 8];
+        input.seek(SeekFrom::Start(index_start))?;
+        input.read_exact(&mut offsets_bytes)?;
+        let mut offsets: Vec<u64> = offsets_bytes
+            .chunks(8)
+            .map(|offset| u64::from_be_bytes(<[u8; 8]>::try_from(offset).unwrap()))
+            .collect();
+        offsets.push(index_start);
+        if offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(DeserialiseError::InvalidIndex.into());
+        }
+        Ok(Index {
+            block_size,
+            original_len,
+            offsets,
+        })
+    }
+
+    /// The indexes of the blocks holding the original bytes `range`, which must be non-empty.
+    pub fn blocks_covering(&self, range: &Range<u64>) -> Range<usize> {
+        let first = range.start / self.block_size;
+        let last = (range.end - 1) / self.block_size;
+        first as usize..last as usize + 1
+    }
+
+    /// Where block `block` starts and how many compressed bytes it takes.
+    pub fn block_extent(&self, block: usize) -> (u64, u64) {
+        (
+            self.offsets[block],
+            self.offsets[block + 1] - self.offsets[block],
+        )
+    }
+
+    /// The original bytes in block `block`.
+    pub fn block_len(&self, block: usize) -> u64 {
+        let start = block as u64 * self.block_size;
+        self.block_size.min(self.original_len - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::io::Cursor;
+
+    #[test]
+    fn index_round_trips() {
+        let mut stream = b"0123456789".to_vec();
+        Index::write(&mut stream, &[4, 0, 6], 100, 250);
+
+        let index = Index::read(&mut Cursor::new(&stream)).unwrap();
+        assert_eq!(
+            Index {
+                block_size: 100,
+                original_len: 250,
+                offsets: vec![0, 4, 4, 10],
+            },
+            index
+        );
+        assert_eq!(1..3, index.blocks_covering(&(150..201)));
+        assert_eq!(0..1, index.blocks_covering(&(0..1)));
+        assert_eq!((4, 6), index.block_extent(2));
+        assert_eq!(50, index.block_len(2));
+    }
+
+    #[test]
+    fn damaged_indexes_are_rejected() {
+        let mut stream = b"0123456789".to_vec();
+        Index::write(&mut stream, &[4, 6], 100, 150);
+        let invalid = |stream: &[u8]| {
+            matches!(
+                Index::read(&mut Cursor::new(stream)),
+                Err(Error::Deserialise(DeserialiseError::InvalidIndex))
+            )
+        };
+        assert!(!invalid(&stream));
+
+        // no magic, or too short to hold a trailer
+        assert!(invalid(&stream[..stream.len() - 1]));
+        assert!(invalid(&stream[stream.len() - 10..]));
+
+        // a block count that doesn't match the length
+        let mut wrong_count = stream.clone();
+        let at = wrong_count.len() - 13;
+        wrong_count[at] = 3;
+        assert!(invalid(&wrong_count));
+
+        // offsets that go backwards
+        let mut backwards = stream.clone();
+        backwards[10 + 7] = 20;
+        assert!(invalid(&backwards));
+    }
+}
@@ -1,231 +1,713 @@
-use std::mem::size_of;
+//! The lz77 stream format. Its byte order is fixed rather than the machine's, so a stream reads
+//! back the same on any architecture: the search window size and sync marker positions are
+//! big-endian, varints hold 7 bits per byte least significant first, and the node bitstream is
+//! packed most significant bit first, each offset and length high bits first.
 
-use bitvec::prelude::*;
-
-use std::convert::TryFrom;
+use alloc::{vec, vec::Vec};
+use core::convert::{TryFrom, TryInto};
+use core::str;
 
+use super::bit_reader::BitReader;
+use super::bit_writer::BitWriter;
+use super::length_codec::{self, deserialise_length};
 use super::nodes::NodeType;
+use super::original_file::{OriginalFile, MAX_FILE_NAME_LEN};
+use super::window_size::WindowSize;
+use crate::error::DeserialiseError;
+use crate::frame::{self, Format};
 
-const U16_BIT_SIZE: usize = size_of::<u16>() * 8;
+/// The frame header, mode and search window size, before the original length.
+const FIXED_HEADER_SIZE: usize = frame::HEADER_SIZE + 3;
+const MAX_VARINT_SIZE: usize = 10;
+/// The fixed header, the offset width a `FixedOffsets` stream records, the original length,
+/// the content hash and the original file.
+pub const MAX_HEADER_SIZE: usize =
+    FIXED_HEADER_SIZE + 1 + MAX_VARINT_SIZE + CONTENT_HASH_SIZE + 1 + MAX_FILE_NAME_LEN + 8;
 
-pub fn serailise_nodes(nodes: &Vec<NodeType>) -> BitVec<Msb0, u8> {
-    let mut vec = bitvec![Msb0, u8;];
-    // Don't reserve here as a bug in bit-vec results in slower extend/append ops.
+/// Set in the mode byte when the header has the original bytes' md5.
+const CONTENT_HASH_FLAG: u8 = 0x80;
+const CONTENT_HASH_SIZE: usize = 16;
+/// Set in the mode byte when the header ends with the original file's name, as a length byte
+/// then UTF-8, and modification time, as big-endian u64 seconds since the Unix epoch.
+const ORIGINAL_FILE_FLAG: u8 = 0x40;
+/// Set in the mode byte when references may reach back into the streams before this one.
+const CONTINUES_FLAG: u8 = 0x20;
 
-    for node in nodes {
-        match node {
-            NodeType::ByteLiteral { lit } => {
-                // literal byte - push '0' followed by 8 bits for the byte val
-                vec.push(false);
-                let literal = BitSlice::<Msb0, u8>::from_element(lit);
-                append_bitvecs(&mut vec, &literal.to_bitvec());
-            }
-            NodeType::Reference { offset, length } => {
-                // offset / length reference
-                vec.push(true);
-                let x = offset.view_bits::<Msb0>();
-                if *offset < 128 {
-                    vec.push(true);
-                    for b in x[U16_BIT_SIZE - 7..].iter() {
-                        vec.push(*b);
-                    }
-                } else {
-                    vec.push(false);
-                    for b in x[U16_BIT_SIZE - 11..].iter() {
-                        vec.push(*b);
-                    }
-                }
-                let length_encoded = serialise_length(*length);
-                append_bitvecs(&mut vec, &length_encoded);
-            }
-            NodeType::EndOfStream => {}
+/// Follows a sync marker's padding, so a decoder that has lost its place can find the next
+/// one by scanning the bytes.
+pub const SYNC_PATTERN: [u8; 4] = *b"\xffSYN";
+/// The pattern then the bytes decoded before the marker, as a big-endian u64.
+const SYNC_POINT_SIZE: usize = SYNC_PATTERN.len() + 8;
+
+/// The widest offset a fixed width can need, for the largest search window.
+pub const MAX_FIXED_OFFSET_BITS: u8 = 16;
+
+/// How the bytes following the header are stored.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Mode {
+    /// An lz77 node bitstream.
+    Lz77,
+    /// The original bytes, written as-is because encoding them would have expanded them.
+    Stored,
+    /// LZ4 style byte aligned sequences of literals and references, see `tokens`.
+    Tokens,
+    /// An lz77 node bitstream with every offset written in the same number of bits, recorded
+    /// in the header after the mode.
+    FixedOffsets { bits: u8 },
+    /// An lz77 node bitstream where a reference may reuse one of the last few offsets, see
+    /// `OffsetCodec::Repeat`.
+    RepeatOffsets,
+}
+
+impl Mode {
+    fn id(self) -> u8 {
+        match self {
+            Mode::Lz77 => 0,
+            Mode::Stored => 1,
+            Mode::Tokens => 2,
+            Mode::FixedOffsets { .. } => 3,
+            Mode::RepeatOffsets => 4,
         }
     }
 
-    vec
+    /// The offset codec a node bitstream in this mode was written with.
+    pub fn offset_codec(self) -> Option<OffsetCodec> {
+        match self {
+            Mode::Lz77 => Some(OffsetCodec::Split),
+            Mode::FixedOffsets { bits } => Some(OffsetCodec::Fixed(bits)),
+            Mode::RepeatOffsets => Some(OffsetCodec::Repeat),
+            Mode::Stored | Mode::Tokens => None,
+        }
+    }
 }
 
-fn serialise_length(length: u16) -> BitVec<Msb0, u8> {
-    return match length {
-        1 => panic!("Nodes should not have a size of 1"),
-        2 => bitvec![Msb0, u8;0, 0],
-        3 => bitvec![Msb0, u8;0, 1],
-        4 => bitvec![Msb0, u8;1, 0],
-        5 => bitvec![Msb0, u8;1, 1, 0, 0],
-        6 => bitvec![Msb0, u8;1, 1, 0, 1],
-        7 => bitvec![Msb0, u8;1, 1, 1, 0],
-        _ => {
-            let mut encoded = bitvec![Msb0, u8;];
-            let padding_one_blocks = (length + 7) / 15;
-
-            for _ in 0..padding_one_blocks {
-                let mut padding_block = bitvec![1, 1, 1, 1];
-                encoded.append(&mut padding_block);
-            }
+#[derive(PartialEq, Debug)]
+pub struct Header {
+    pub mode: Mode,
+    /// Bytes of history the decoder needs to keep in memory.
+    pub search_window_size: WindowSize,
+    /// Bytes the stream decodes to, so the output can be allocated up front.
+    pub original_len: usize,
+    /// md5 of the original bytes, if the stream was written with one.
+    pub content_hash: Option<[u8; CONTENT_HASH_SIZE]>,
+    /// The file the stream was compressed from, if it was written with one.
+    pub original_file: Option<OriginalFile>,
+    /// Whether the history starts with the last window of the streams before this one, after
+    /// the dictionary, rather than the dictionary alone.
+    pub continues: bool,
+}
 
-            let adjusted = length - (padding_one_blocks * 15 - 7);
-            let adjusted = u8::try_from(adjusted).unwrap();
-            let bits = adjusted.view_bits::<Msb0>();
-            encoded.extend_from_bitslice(&bits[4..]);
-            encoded
-        }
+/// Writes the stream header: magic bytes, format version, storage mode, search window size,
+/// original length and, if any, content hash and original file.
+pub fn write_header(output: &mut Vec<u8>, header: &Header) {
+    frame::write_header(output, frame::Header::current(Format::Lz77));
+    let hash_flag = match header.content_hash {
+        Some(_) => CONTENT_HASH_FLAG,
+        None => 0,
     };
+    let file_flag = match header.original_file {
+        Some(_) => ORIGINAL_FILE_FLAG,
+        None => 0,
+    };
+    let continues_flag = if header.continues { CONTINUES_FLAG } else { 0 };
+    output.push(header.mode.id() | hash_flag | file_flag | continues_flag);
+    if let Mode::FixedOffsets { bits } = header.mode {
+        output.push(bits);
+    }
+    output.extend_from_slice(&header.search_window_size.get().to_be_bytes());
+    write_varint(output, header.original_len as u64);
+    if let Some(content_hash) = &header.content_hash {
+        output.extend_from_slice(content_hash);
+    }
+    if let Some(original_file) = &header.original_file {
+        let name = original_file.name().as_bytes();
+        output.push(u8::try_from(name.len()).unwrap());
+        output.extend_from_slice(name);
+        output.extend_from_slice(&original_file.modified().to_be_bytes());
+    }
 }
 
-pub fn deserialise_nodes(file_bytes: &Vec<u8>) -> Vec<NodeType> {
-    let end_of_stream_marker = bits![Msb0, u8; 1, 1, 0, 0, 0, 0, 0, 0, 0];
+/// Validates the stream header, returning it along with the bytes that follow it.
+pub fn read_header(file_bytes: &[u8]) -> Result<(Header, &[u8]), DeserialiseError> {
+    let (frame_header, rest) = frame::read_header(file_bytes)?;
+    frame_header.expect(Format::Lz77)?;
 
-    let mut nodes: Vec<NodeType> = vec![];
-    let bit_view = file_bytes.view_bits::<Msb0>();
+    let (&mode, rest) = rest.split_first().ok_or(DeserialiseError::Truncated)?;
+    let has_content_hash = mode & CONTENT_HASH_FLAG != 0;
+    let has_original_file = mode & ORIGINAL_FILE_FLAG != 0;
+    let continues = mode & CONTINUES_FLAG != 0;
+    let (mode, rest) = match mode & !(CONTENT_HASH_FLAG | ORIGINAL_FILE_FLAG | CONTINUES_FLAG) {
+        0 => (Mode::Lz77, rest),
+        1 => (Mode::Stored, rest),
+        2 => (Mode::Tokens, rest),
+        3 => {
+            let (&bits, rest) = rest.split_first().ok_or(DeserialiseError::Truncated)?;
+            if bits == 0 || bits > MAX_FIXED_OFFSET_BITS {
+                return Err(DeserialiseError::InvalidOffsetBits(bits));
+            }
+            (Mode::FixedOffsets { bits }, rest)
+        }
+        4 => (Mode::RepeatOffsets, rest),
+        unknown => return Err(DeserialiseError::UnknownMode(unknown)),
+    };
+    if rest.len() < 2 {
+        return Err(DeserialiseError::Truncated);
+    }
 
-    let mut bitstream_offset = 0;
-    while bitstream_offset < bit_view.len() {
-        let is_reference_node = bit_view[bitstream_offset];
-        bitstream_offset += 1;
+    let search_window_size = u16::from_be_bytes([rest[0], rest[1]]);
+    let (original_len, mut body) = read_varint(&rest[2..])?;
+    let mut content_hash = None;
+    if has_content_hash {
+        if body.len() < CONTENT_HASH_SIZE {
+            return Err(DeserialiseError::Truncated);
+        }
+        let (hash, rest) = body.split_at(CONTENT_HASH_SIZE);
+        let mut bytes = [0; CONTENT_HASH_SIZE];
+        bytes.copy_from_slice(hash);
+        content_hash = Some(bytes);
+        body = rest;
+    }
+    let mut original_file = None;
+    if has_original_file {
+        let (&name_len, rest) = body.split_first().ok_or(DeserialiseError::Truncated)?;
+        let name_len = usize::from(name_len);
+        if rest.len() < name_len + 8 {
+            return Err(DeserialiseError::Truncated);
+        }
+        let (name, rest) = rest.split_at(name_len);
+        let (modified, rest) = rest.split_at(8);
+        let name = str::from_utf8(name).map_err(|_| DeserialiseError::InvalidFileName)?;
+        let modified = u64::from_be_bytes(modified.try_into().unwrap());
+        original_file =
+            Some(OriginalFile::new(name, modified).map_err(|_| DeserialiseError::InvalidFileName)?);
+        body = rest;
+    }
+    let header = Header {
+        mode,
+        search_window_size: WindowSize::new(usize::from(search_window_size))
+            .map_err(|_| DeserialiseError::InvalidWindowSize)?,
+        original_len: usize::try_from(original_len).map_err(|_| DeserialiseError::InvalidLength)?,
+        content_hash,
+        original_file,
+        continues,
+    };
+    Ok((header, body))
+}
 
-        if !is_reference_node {
-            // next 8 bits will be a literal byte node
-            let byte_literal = &bit_view[bitstream_offset..bitstream_offset + 8];
-            nodes.push(NodeType::ByteLiteral {
-                lit: slice_to_byte(&byte_literal),
-            });
-            bitstream_offset += 8;
-        } else {
-            // flag 1: this is a node reference
-            let offset_sub_128 = bit_view[bitstream_offset];
-            bitstream_offset += 1;
-
-            let offset: u16;
-            if offset_sub_128 {
-                // 7 bits for the offset size
-                offset = slice_to_offset(&bit_view[bitstream_offset..bitstream_offset + 7]);
-                bitstream_offset += 7;
-            } else {
-                // 11 bits for the offset
-                offset = slice_to_offset(&bit_view[bitstream_offset..bitstream_offset + 11]);
-                bitstream_offset += 11;
-            }
+/// Splits a stored body of `original_len` bytes from the bytes that follow it.
+pub fn read_stored(body: &[u8], original_len: usize) -> Result<(&[u8], &[u8]), DeserialiseError> {
+    if body.len() < original_len {
+        return Err(DeserialiseError::Truncated);
+    }
+    Ok(body.split_at(original_len))
+}
 
-            let (length, bits_read) = deserialise_length(&bit_view[bitstream_offset..]);
-            bitstream_offset += usize::from(bits_read);
+/// Writes `value` as an unsigned LEB128 varint: 7 bits per byte, least significant first, with
+/// the top bit set on every byte but the last.
+pub fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
 
-            nodes.push(NodeType::Reference { length, offset });
+/// Reads a varint written by `write_varint`, returning it along with the bytes that follow it.
+pub fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8]), DeserialiseError> {
+    let mut value = 0;
+    for (i, &byte) in bytes.iter().take(MAX_VARINT_SIZE).enumerate() {
+        let bits = u64::from(byte & 0x7f);
+        let shift = 7 * i;
+        if (bits << shift) >> shift != bits {
+            return Err(DeserialiseError::InvalidLength);
         }
-
-        if bit_view[bitstream_offset..bitstream_offset + 9] == end_of_stream_marker {
-            break;
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, &bytes[i + 1..]));
         }
     }
-    nodes
+    if bytes.len() < MAX_VARINT_SIZE {
+        Err(DeserialiseError::Truncated)
+    } else {
+        Err(DeserialiseError::InvalidLength)
+    }
 }
 
-/// Extract the length from the encoded bit array
-///
-/// Expectation is the slice starts at the first bit of the encoded length, to the end of the stream.
+/// How a reference's offset is written after its flag bit. Each codec reserves an offset of 0,
+/// which can't otherwise occur, to escape to a run or the end-of-stream marker.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OffsetCodec {
+    /// 1 then a 7 bit offset, or 0 then an 11 bit offset, an 11 bit 0 escaping to a full 16
+    /// bit offset. A 7 bit 0 is the end marker and a 16 bit 0 a run.
+    Split,
+    /// Every offset in the same number of bits, then a bit after a 0 offset saying whether it
+    /// is a run (1) or the end marker (0).
+    Fixed(u8),
+    /// 0 then the 2 bit index of an offset in `RecentOffsets`, or 1 then anything `Split`
+    /// writes, so a reference reusing a recent offset costs 3 bits rather than 8 or more.
+    Repeat,
+}
+
+/// The last `RECENT_OFFSETS` distinct offsets written or read, most recent first, for
+/// `OffsetCodec::Repeat`.
 ///
-/// Returns a tuple in the form (length, num bits consumed)
-fn deserialise_length(slice: &BitSlice<Msb0, u8>) -> (u16, u16) {
-    let two_bit_size = &slice[0..2];
-    if two_bit_size != bits![Msb0, u8; 1, 1] {
-        if two_bit_size == bits![Msb0, u8; 0, 0] {
-            return (2, 2);
+/// Starts out as 1 to 4 and goes back to that at every sync marker, so each stretch between
+/// markers decodes on its own.
+#[derive(Debug, PartialEq)]
+pub struct RecentOffsets([u16; RECENT_OFFSETS]);
+
+const RECENT_OFFSETS: usize = 4;
+
+impl Default for RecentOffsets {
+    fn default() -> Self {
+        RecentOffsets([1, 2, 3, 4])
+    }
+}
+
+impl RecentOffsets {
+    /// Moves the offset at `index` to the front, returning it.
+    fn reuse(&mut self, index: usize) -> u16 {
+        self.0[..=index].rotate_right(1);
+        self.0[0]
+    }
+
+    /// Puts `offset` at the front, forgetting the oldest.
+    fn push(&mut self, offset: u16) {
+        self.0.rotate_right(1);
+        self.0[0] = offset;
+    }
+}
+
+/// What the bits after a reference's flag turned out to be.
+enum Escape {
+    Offset(u16),
+    Run,
+    End,
+}
+
+impl OffsetCodec {
+    /// The narrowest fixed width that can hold every offset in `nodes`.
+    pub fn fixed_for(nodes: &[NodeType]) -> OffsetCodec {
+        let max_offset = nodes
+            .iter()
+            .map(|node| match *node {
+                NodeType::Reference { offset, .. } => offset,
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0);
+        // even with no references, an escape needs a bit to be 0
+        OffsetCodec::Fixed((16 - max_offset.leading_zeros() as u8).max(1))
+    }
+
+    fn write_offset<W: Extend<u8>>(
+        self,
+        writer: &mut BitWriter<W>,
+        offset: u16,
+        recent: &mut RecentOffsets,
+    ) {
+        match self {
+            OffsetCodec::Split => {
+                if offset < 128 {
+                    writer.write_bit(true);
+                    writer.write_bits(u32::from(offset), 7);
+                } else if offset < 2048 {
+                    writer.write_bit(false);
+                    writer.write_bits(u32::from(offset), 11);
+                } else {
+                    writer.write_bit(false);
+                    writer.write_bits(0, 11);
+                    writer.write_bits(u32::from(offset), 16);
+                }
+            }
+            OffsetCodec::Fixed(bits) => {
+                // cut down to the width, it would read back as another offset or an escape
+                debug_assert!(
+                    u32::from(offset) >> bits == 0,
+                    "offset wider than the codec"
+                );
+                writer.write_bits(u32::from(offset), u32::from(bits))
+            }
+            OffsetCodec::Repeat => match recent.0.iter().position(|&o| o == offset) {
+                Some(index) => {
+                    writer.write_bit(false);
+                    writer.write_bits(index as u32, 2);
+                    recent.reuse(index);
+                }
+                None => {
+                    writer.write_bit(true);
+                    OffsetCodec::Split.write_offset(writer, offset, recent);
+                    recent.push(offset);
+                }
+            },
+        }
+    }
+
+    fn write_run_escape<W: Extend<u8>>(self, writer: &mut BitWriter<W>) {
+        match self {
+            OffsetCodec::Split => {
+                writer.write_bit(false);
+                writer.write_bits(0, 11 + 16);
+            }
+            OffsetCodec::Fixed(bits) => {
+                writer.write_bits(0, u32::from(bits));
+                writer.write_bit(true);
+            }
+            OffsetCodec::Repeat => {
+                writer.write_bit(true);
+                OffsetCodec::Split.write_run_escape(writer);
+            }
         }
+    }
 
-        if two_bit_size == bits![Msb0, u8; 0, 1] {
-            return (3, 2);
+    fn write_end_escape<W: Extend<u8>>(self, writer: &mut BitWriter<W>) {
+        match self {
+            OffsetCodec::Split => writer.write_bits(0b1000_0000, 8),
+            OffsetCodec::Fixed(bits) => writer.write_bits(0, u32::from(bits) + 1),
+            OffsetCodec::Repeat => {
+                writer.write_bit(true);
+                OffsetCodec::Split.write_end_escape(writer);
+            }
         }
+    }
+
+    fn read(
+        self,
+        reader: &mut BitReader,
+        recent: &mut RecentOffsets,
+    ) -> Result<Escape, DeserialiseError> {
+        let offset = match self {
+            OffsetCodec::Split => {
+                if reader.read_bit()? {
+                    match reader.read_bits(7)? {
+                        0 => return Ok(Escape::End),
+                        offset => offset,
+                    }
+                } else {
+                    match reader.read_bits(11)? {
+                        0 => match reader.read_bits(16)? {
+                            0 => return Ok(Escape::Run),
+                            offset => offset,
+                        },
+                        offset => offset,
+                    }
+                }
+            }
+            OffsetCodec::Fixed(bits) => match reader.read_bits(bits.into())? {
+                0 if reader.read_bit()? => return Ok(Escape::Run),
+                0 => return Ok(Escape::End),
+                offset => offset,
+            },
+            OffsetCodec::Repeat if reader.read_bit()? => {
+                let escape = OffsetCodec::Split.read(reader, recent)?;
+                if let Escape::Offset(offset) = escape {
+                    recent.push(offset);
+                }
+                return Ok(escape);
+            }
+            OffsetCodec::Repeat => recent.reuse(usize::from(reader.read_bits(2)?)),
+        };
+        Ok(Escape::Offset(offset))
+    }
+}
 
-        return (4, 2); // 1, 1
+/// Writes each node to `writer`: a literal as 0 then its byte, a reference as 1 then its offset
+/// and length, and a run as 1 then the codec's run escape followed by its byte and count.
+pub fn serailise_nodes<W: Extend<u8>>(
+    writer: &mut BitWriter<W>,
+    nodes: &[NodeType],
+    codec: OffsetCodec,
+) {
+    let mut recent = RecentOffsets::default();
+    for node in nodes {
+        serialise_node(writer, *node, codec, &mut recent);
     }
+}
 
-    let four_bit_size = &slice[0..4];
-    if four_bit_size != bits![Msb0, u8; 1, 1, 1, 1] {
-        if four_bit_size == bits![Msb0, u8; 1, 1, 0, 0] {
-            return (5, 4);
+fn serialise_node<W: Extend<u8>>(
+    writer: &mut BitWriter<W>,
+    node: NodeType,
+    codec: OffsetCodec,
+    recent: &mut RecentOffsets,
+) {
+    match node {
+        NodeType::ByteLiteral { lit } => {
+            writer.write_bit(false);
+            writer.write_bits(u32::from(lit), 8);
+        }
+        NodeType::Reference { offset, length } => {
+            // offset 0 is how every codec writes its run and end escapes
+            debug_assert_ne!(offset, 0, "references can't have an offset of 0");
+            writer.write_bit(true);
+            codec.write_offset(writer, offset, recent);
+            length_codec::write_length(writer, length);
+        }
+        NodeType::Run { byte, count } => {
+            writer.write_bit(true);
+            codec.write_run_escape(writer);
+            writer.write_bits(u32::from(byte), 8);
+            write_run_count(writer, count);
         }
+    }
+}
 
-        if four_bit_size == bits![Msb0, u8; 1, 1, 0, 1] {
-            return (6, 4);
+/// Like `serialise_stream`, but with a sync marker before the first node at or after every
+/// `sync_interval` decoded bytes.
+pub fn serialise_stream_with_sync_points(
+    nodes: &[NodeType],
+    codec: OffsetCodec,
+    sync_interval: usize,
+) -> Vec<u8> {
+    let mut writer = BitWriter::new(Vec::new());
+    serailise_nodes_with_sync_points(&mut writer, nodes, codec, sync_interval);
+    write_end_marker(&mut writer, codec);
+    writer.finish()
+}
+
+fn serailise_nodes_with_sync_points<W: Extend<u8>>(
+    writer: &mut BitWriter<W>,
+    nodes: &[NodeType],
+    codec: OffsetCodec,
+    sync_interval: usize,
+) {
+    let mut position = 0;
+    let mut next_sync = sync_interval;
+    let mut recent = RecentOffsets::default();
+    for node in nodes {
+        if position >= next_sync {
+            write_sync_marker(writer, codec, position as u64);
+            recent = RecentOffsets::default();
+            while next_sync <= position {
+                next_sync += sync_interval;
+            }
         }
+        serialise_node(writer, *node, codec, &mut recent);
+        position += node.decoded_len();
+    }
+}
 
-        return (7, 4);
+/// Writes a sync marker: a run escape with a count of 0, which a run can't have, padded to a
+/// whole byte and followed by `SYNC_PATTERN` and the number of bytes decoded before it.
+fn write_sync_marker<W: Extend<u8>>(writer: &mut BitWriter<W>, codec: OffsetCodec, position: u64) {
+    writer.write_bit(true);
+    codec.write_run_escape(writer);
+    writer.write_bits(0, 8 + 8);
+    writer.pad_to_byte();
+    for byte in SYNC_PATTERN.iter().chain(&position.to_be_bytes()) {
+        writer.write_bits(u32::from(*byte), 8);
     }
+}
 
-    let four_bits_all_set = bits![Msb0, u8; 1,1,1,1];
-    let mut four_bit_block_count = 0;
-    loop {
-        // iterate through the bit slice, find the first non 1,1,1,1 block then reverse the encoding.
-        let block_index = four_bit_block_count * 4;
-        let block_bits = &slice[block_index..block_index + 4];
-        if block_bits == four_bits_all_set {
-            four_bit_block_count += 1;
-        } else {
-            // non 1,1,1,1 sequence found - read the next for bits then invert the enoding formula:
-            // (1111 repeated N times) xxxx, where  is integer result of (length + 7) / 15, and xxxx is length - (N*15 − 7)
-            let trailing_bit_value = slice_to_offset(block_bits);
-            let length = (four_bit_block_count * 15 - 7) + trailing_bit_value as usize;
-
-            // +4 to account for the non: 1,1,1,1 block at the end of the encoded length
-            let next_read_offset = u16::try_from(block_index + 4).unwrap();
-            return (u16::try_from(length).unwrap(), next_read_offset);
-        }
+/// Reads the pattern and position of a sync marker whose padding ends at the start of
+/// `bytes`, returning the position along with the bytes that follow it.
+fn read_sync_point(bytes: &[u8]) -> Result<(u64, &[u8]), DeserialiseError> {
+    if bytes.len() < SYNC_POINT_SIZE {
+        return Err(DeserialiseError::Truncated);
+    }
+    let (point, rest) = bytes.split_at(SYNC_POINT_SIZE);
+    if point[..SYNC_PATTERN.len()] != SYNC_PATTERN {
+        return Err(DeserialiseError::BadSyncMarker);
     }
+    let mut position = [0; 8];
+    position.copy_from_slice(&point[SYNC_PATTERN.len()..]);
+    Ok((u64::from_be_bytes(position), rest))
 }
 
-fn slice_to_byte<T>(slice: &BitSlice<Msb0, T>) -> u8
-where
-    T: BitStore,
-{
-    let mut as_byte: u8 = 0;
-    for (i, flag) in slice.iter().rev().enumerate() {
-        if *flag {
-            as_byte = as_byte | (1 << i);
+/// Every sync point in `body` that a scan can find, as the position it records and the bytes
+/// that follow it. Nodes that happen to contain the pattern are found too, so a caller has to
+/// check that what follows decodes.
+pub fn find_sync_points(body: &[u8]) -> impl Iterator<Item = (u64, &[u8])> {
+    (0..body.len())
+        .filter(move |&i| body[i..].starts_with(&SYNC_PATTERN))
+        .filter_map(move |i| read_sync_point(&body[i..]).ok())
+}
+
+/// Writes a run's count 7 bits at a time, least significant first, each group after a bit
+/// saying whether another follows.
+fn write_run_count<W: Extend<u8>>(writer: &mut BitWriter<W>, mut count: u32) {
+    while count >= 0x80 {
+        writer.write_bits(0x80 | (count & 0x7f), 8);
+        count >>= 7;
+    }
+    writer.write_bits(count, 8);
+}
+
+/// Reads a count written by `write_run_count`, which is 0 only for a sync marker.
+fn read_run_count(reader: &mut BitReader) -> Result<u32, DeserialiseError> {
+    let mut count: u32 = 0;
+    for shift in (0..32).step_by(7) {
+        let group = u32::from(reader.read_bits(8)?);
+        let bits = group & 0x7f;
+        if (bits << shift) >> shift != bits {
+            return Err(DeserialiseError::InvalidLength);
+        }
+        count |= bits << shift;
+        if group & 0x80 == 0 {
+            // a zero count must be a single group, a run always covers at least one byte
+            return if count == 0 && shift > 0 {
+                Err(DeserialiseError::InvalidLength)
+            } else {
+                Ok(count)
+            };
         }
     }
+    Err(DeserialiseError::InvalidLength)
+}
+
+/// Serialises `nodes` followed by the end-of-stream marker, padded to a whole byte.
+pub fn serialise_stream(nodes: &[NodeType], codec: OffsetCodec) -> Vec<u8> {
+    let mut writer = BitWriter::new(Vec::new());
+    serailise_nodes(&mut writer, nodes, codec);
+    write_end_marker(&mut writer, codec);
+    writer.finish()
+}
 
-    as_byte
+/// Bits `serailise_nodes` spends on a literal.
+pub const LITERAL_BITS: usize = 9;
+
+/// Bits `serailise_nodes` spends on a reference with the split codec, worked out without
+/// serialising it.
+pub fn reference_bits(offset: u16, length: u16) -> usize {
+    let offset_bits = if offset < 128 {
+        8
+    } else if offset < 2048 {
+        12
+    } else {
+        28
+    };
+    1 + offset_bits + length_codec::encoded_bits(length)
+}
+
+/// Reads nodes up to the end-of-stream marker, returning them along with the bytes that follow
+/// the marker's padding, so a stream appended after this one can be read in turn. Sync markers
+/// are checked against the bytes decoded so far and skipped.
+pub fn deserialise_nodes(
+    file_bytes: &[u8],
+    codec: OffsetCodec,
+) -> Result<(Vec<NodeType>, &[u8]), DeserialiseError> {
+    let mut nodes: Vec<NodeType> = vec![];
+    let rest = deserialise_nodes_with(file_bytes, codec, |_, node| nodes.push(node))?;
+    Ok((nodes, rest))
 }
 
-fn slice_to_offset<T>(slice: &BitSlice<Msb0, T>) -> u16
-where
-    T: BitStore,
-{
-    let mut as_byte: u16 = 0;
-    for (i, flag) in slice.iter().rev().enumerate() {
-        if *flag {
-            as_byte = as_byte | (1 << i);
+/// Like `deserialise_nodes`, but hands each node to `on_node` along with the bit its encoding
+/// starts at, counted from the start of `file_bytes`.
+pub fn deserialise_nodes_with<F: FnMut(usize, NodeType)>(
+    file_bytes: &[u8],
+    codec: OffsetCodec,
+    mut on_node: F,
+) -> Result<&[u8], DeserialiseError> {
+    // a u64, as the sync markers record, so a long run of runs can't overflow it
+    let mut decoded_len = 0u64;
+    let mut remaining = file_bytes;
+    loop {
+        let segment_start = (file_bytes.len() - remaining.len()) * 8;
+        let end = deserialise_segment_with(remaining, codec, |bit, node| {
+            decoded_len += node.decoded_len() as u64;
+            on_node(segment_start + bit, node);
+        })?;
+        match end {
+            SegmentEnd::Sync { position, rest } => {
+                if position != decoded_len {
+                    return Err(DeserialiseError::BadSyncMarker);
+                }
+                remaining = rest;
+            }
+            SegmentEnd::End(rest) => return Ok(rest),
         }
     }
+}
+
+/// What stopped `deserialise_segment`.
+pub enum SegmentEnd<'a> {
+    /// A sync marker recording `position` bytes decoded before it, followed by `rest`.
+    Sync { position: u64, rest: &'a [u8] },
+    /// The end-of-stream marker, followed by the given bytes.
+    End(&'a [u8]),
+}
 
-    as_byte
+/// Reads nodes up to the next sync marker or the end-of-stream marker, whichever comes first.
+pub fn deserialise_segment(
+    file_bytes: &[u8],
+    codec: OffsetCodec,
+) -> Result<(Vec<NodeType>, SegmentEnd<'_>), DeserialiseError> {
+    let mut nodes: Vec<NodeType> = vec![];
+    let end = deserialise_segment_with(file_bytes, codec, |_, node| nodes.push(node))?;
+    Ok((nodes, end))
 }
 
-/// Adds the end-of-stream bit sequence and pads the vector to a whole byte
-pub fn append_end_marker<O, T>(encoding: &mut BitVec<O, T>)
-where
-    O: BitOrder,
-    T: BitStore,
-{
-    encoding.append(&mut bitvec![Msb0, u8; 1, 1, 0, 0, 0, 0, 0, 0, 0]);
-    let trailing_bits = encoding.len() % 8;
-    if trailing_bits > 0 {
-        for _ in 0..(8 - trailing_bits) {
-            encoding.push(false);
+/// Like `deserialise_segment`, but hands each node to `on_node` along with the bit its encoding
+/// starts at, counted from the start of `file_bytes`.
+pub fn deserialise_segment_with<F: FnMut(usize, NodeType)>(
+    file_bytes: &[u8],
+    codec: OffsetCodec,
+    mut on_node: F,
+) -> Result<SegmentEnd<'_>, DeserialiseError> {
+    let mut reader = BitReader::new(file_bytes);
+    let mut recent = RecentOffsets::default();
+
+    loop {
+        let start = reader.position();
+        if !reader.read_bit()? {
+            // next 8 bits will be a literal byte node
+            let lit = reader.read_bits(8)? as u8;
+            on_node(start, NodeType::ByteLiteral { lit });
+            continue;
+        }
+        // flag 1: this is a node reference, a run or the end marker
+        match codec.read(&mut reader, &mut recent)? {
+            Escape::Offset(offset) => {
+                let length = deserialise_length(&mut reader)?;
+                on_node(start, NodeType::Reference { length, offset });
+            }
+            Escape::Run => {
+                let byte = reader.read_bits(8)? as u8;
+                match read_run_count(&mut reader)? {
+                    // both markers are padded out to a whole byte
+                    0 => {
+                        reader.read_padding()?;
+                        let (position, rest) = read_sync_point(reader.remaining_bytes())?;
+                        return Ok(SegmentEnd::Sync { position, rest });
+                    }
+                    count => on_node(start, NodeType::Run { byte, count }),
+                }
+            }
+            Escape::End => {
+                reader.read_padding()?;
+                return Ok(SegmentEnd::End(reader.remaining_bytes()));
+            }
         }
     }
 }
 
-fn append_bitvecs<O, T>(original: &mut BitVec<O, T>, to_add: &BitVec<O, T>)
-where
-    O: BitOrder,
-    T: BitStore,
-{
-    original.extend_from_bitslice(to_add);
+/// Writes the end-of-stream bit sequence, which `BitWriter::finish` pads to a whole byte.
+pub fn write_end_marker<W: Extend<u8>>(writer: &mut BitWriter<W>, codec: OffsetCodec) {
+    writer.write_bit(true);
+    codec.write_end_escape(writer);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::pseudo_random;
+    use bitvec::prelude::*;
+
+    fn serialised_bits(nodes: &[NodeType]) -> BitVec<Msb0, u8> {
+        let mut writer = BitWriter::new(Vec::new());
+        serailise_nodes(&mut writer, nodes, OffsetCodec::Split);
+        writer.into_bitvec()
+    }
+
+    #[test]
+    fn reference_bits_match_the_serialised_size() {
+        for offset in [1, 127, 128, 2047, 2048, 65535] {
+            for length in (2..=300).chain([1000, 2049]) {
+                let serialised = serialised_bits(&[NodeType::reference(offset, length)]);
+                assert_eq!(serialised.len(), reference_bits(offset, length));
+            }
+        }
+        assert_eq!(LITERAL_BITS, serialised_bits(&[NodeType::literal(0)]).len());
+    }
 
     #[test]
     fn serailise_nodes_handles_literals_and_refs() {
@@ -250,7 +732,7 @@ mod tests {
             0, 0, 1, 1, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, 0, 1, 0, 1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0,
             0, 0, 1, 1, 0, 0, 0, 1, 0,
         ];
-        assert_eq!(expected, serailise_nodes(&nodes));
+        assert_eq!(expected, serialised_bits(&nodes));
     }
 
     #[test]
@@ -264,7 +746,7 @@ mod tests {
         ];
         assert_eq!(
             bitvec![1, 1, 0, 0, 1, 0, 0, 0, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 1],
-            serailise_nodes(&nodes)
+            serialised_bits(&nodes)
         );
 
         let nodes = vec![
@@ -279,7 +761,516 @@ mod tests {
                 1, 1, 0, 0, 1, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, 0,
                 0, 1
             ],
-            serailise_nodes(&nodes)
+            serialised_bits(&nodes)
+        );
+    }
+
+    #[test]
+    fn serailise_nodes_escapes_large_offsets() {
+        let nodes = vec![NodeType::Reference {
+            offset: 2048,
+            length: 2,
+        }];
+        // 1 0 - reference with an offset >= 128
+        // 00000000000 - escape to a 16 bit offset
+        // 0000100000000000 - 2048
+        // 00 - length 2
+        assert_eq!(
+            bitvec![
+                1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0
+            ],
+            serialised_bits(&nodes)
+        );
+    }
+
+    #[test]
+    fn runs_escape_through_a_16_bit_offset_of_0() {
+        // 10 + 27 0s - a 16 bit offset of 0
+        // 01100001 - 'a'
+        // 1 0000011 0 0000001 - 3 + (1 << 7)
+        let mut expected = bitvec![Msb0, u8; 1, 0];
+        expected.resize(2 + 27, false);
+        expected.extend_from_bitslice(bits![Msb0, u8; 0, 1, 1, 0, 0, 0, 0, 1]);
+        expected.extend_from_bitslice(bits![Msb0, u8; 1, 0, 0, 0, 0, 0, 1, 1]);
+        expected.extend_from_bitslice(bits![Msb0, u8; 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(expected, serialised_bits(&[NodeType::run(b'a', 131)]));
+
+        for count in [1, 127, 128, 100_000, u32::MAX] {
+            let nodes = vec![
+                NodeType::literal(b'a'),
+                NodeType::run(b'b', count),
+                NodeType::reference(2, 2),
+            ];
+            let serialised = serialise_stream(&nodes, OffsetCodec::Split);
+            assert_eq!(
+                nodes,
+                deserialise_nodes(&serialised, OffsetCodec::Split)
+                    .unwrap()
+                    .0
+            );
+        }
+    }
+
+    #[test]
+    fn runs_of_nothing_or_past_u32_are_rejected() {
+        let run_of = |count: &[u8]| {
+            let mut writer = BitWriter::new(Vec::new());
+            writer.write_bits(0b10, 2);
+            writer.write_bits(0, 27);
+            writer.write_bits(u32::from(b'a'), 8);
+            for &group in count {
+                writer.write_bits(u32::from(group), 8);
+            }
+            write_end_marker(&mut writer, OffsetCodec::Split);
+            deserialise_nodes(&writer.finish(), OffsetCodec::Split).map(|(nodes, _)| nodes)
+        };
+        assert_eq!(Ok(vec![NodeType::run(b'a', 5)]), run_of(&[5]));
+        // a zero count is a sync marker, which needs zero padding and then its pattern and
+        // position, not the end marker
+        assert_eq!(Err(DeserialiseError::NonZeroPadding), run_of(&[0]));
+        assert_eq!(Err(DeserialiseError::InvalidLength), run_of(&[0x80, 0]));
+        assert_eq!(
+            Err(DeserialiseError::InvalidLength),
+            run_of(&[0xff, 0xff, 0xff, 0xff, 0x1f])
+        );
+        assert_eq!(
+            Err(DeserialiseError::InvalidLength),
+            run_of(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01])
+        );
+    }
+
+    #[test]
+    fn serialise_and_deserialise_large_offsets() {
+        for offset in [127, 128, 2047, 2048, 10000, 65535] {
+            let nodes = vec![
+                NodeType::Reference { offset, length: 9 },
+                NodeType::ByteLiteral { lit: b'a' },
+            ];
+            let serialised = serialise_stream(&nodes, OffsetCodec::Split);
+            assert_eq!(
+                nodes,
+                deserialise_nodes(&serialised, OffsetCodec::Split)
+                    .unwrap()
+                    .0
+            );
+        }
+    }
+
+    #[test]
+    fn fixed_offsets_round_trip_and_resume_after_the_end_marker() {
+        let nodes = vec![
+            NodeType::literal(b'a'),
+            NodeType::reference(1, 4),
+            NodeType::run(b'b', 300),
+            NodeType::reference(13, 2),
+        ];
+        let codec = OffsetCodec::fixed_for(&nodes);
+        assert_eq!(OffsetCodec::Fixed(4), codec);
+
+        let mut appended = serialise_stream(&nodes, codec);
+        appended.extend_from_slice(&serialise_stream(&[], codec));
+        let (deserialised, rest) = deserialise_nodes(&appended, codec).unwrap();
+        assert_eq!(nodes, deserialised);
+        let (deserialised, rest) = deserialise_nodes(rest, codec).unwrap();
+        assert!(deserialised.is_empty());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn repeat_offsets_code_recent_offsets_in_3_bits() {
+        let nodes = vec![
+            NodeType::literal(b'a'),
+            NodeType::reference(100, 2),
+            NodeType::literal(b'b'),
+            NodeType::reference(100, 2),
+            NodeType::reference(1, 2),
+            NodeType::run(b'c', 3),
+        ];
+        let mut writer = BitWriter::new(Vec::new());
+        serailise_nodes(&mut writer, &nodes, OffsetCodec::Repeat);
+        let expected = [
+            "0 01100001",
+            // not recent yet: 1 then the split codec's 7 bit offset, then length 2
+            "1 1 1 1100100 00",
+            "0 01100010",
+            // the most recent offset
+            "1 0 00 00",
+            // 1 starts out recent, and is second now
+            "1 0 01 00",
+            // a run escapes through the split codec
+            "1 1 0 00000000000 0000000000000000 01100011 00000011",
+        ]
+        .concat();
+        let expected: BitVec<Msb0, u8> = expected
+            .chars()
+            .filter(|&c| c != ' ')
+            .map(|c| c == '1')
+            .collect();
+        assert_eq!(expected, writer.into_bitvec());
+
+        let mut appended = serialise_stream(&nodes, OffsetCodec::Repeat);
+        appended.extend_from_slice(&serialise_stream(&nodes, OffsetCodec::Repeat));
+        let (deserialised, rest) = deserialise_nodes(&appended, OffsetCodec::Repeat).unwrap();
+        assert_eq!(nodes, deserialised);
+        // the next stream starts with the initial offsets again
+        assert_eq!(
+            nodes,
+            deserialise_nodes(rest, OffsetCodec::Repeat).unwrap().0
+        );
+
+        let header = Header {
+            mode: Mode::RepeatOffsets,
+            search_window_size: WindowSize::DEFAULT,
+            original_len: 9,
+            content_hash: None,
+            original_file: None,
+            continues: false,
+        };
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header);
+        assert_eq!(header, read_header(&bytes).unwrap().0);
+    }
+
+    #[test]
+    fn end_marker_bits_inside_nodes_do_not_end_the_stream() {
+        // bytes and offsets heavy in the zeros and leading ones the end escapes are made of
+        let mut nodes = Vec::new();
+        for lit in [0xc0, 0x80, 0x00, 0x01, 0xc0, 0x00] {
+            nodes.push(NodeType::literal(lit));
+        }
+        for offset in [128, 1, 2048, 256, 64, 4096, 3] {
+            nodes.push(NodeType::reference(offset, 3));
+            nodes.push(NodeType::literal(0x80));
+        }
+        nodes.push(NodeType::run(0x00, 300));
+        nodes.push(NodeType::reference(2, 258));
+        nodes.push(NodeType::literal(0x00));
+
+        for codec in [
+            OffsetCodec::Split,
+            OffsetCodec::fixed_for(&nodes),
+            OffsetCodec::Repeat,
+        ] {
+            let serialised = serialise_stream(&nodes, codec);
+            let bits = serialised.view_bits::<Msb0>();
+            let mut end_marker = BitWriter::new(Vec::new());
+            write_end_marker(&mut end_marker, codec);
+            let end_marker = end_marker.into_bitvec();
+
+            // where each node starts, then where the end marker does
+            let boundaries: Vec<usize> = (0..=nodes.len())
+                .map(|i| {
+                    let mut writer = BitWriter::new(Vec::new());
+                    serailise_nodes(&mut writer, &nodes[..i], codec);
+                    writer.into_bitvec().len()
+                })
+                .collect();
+            let end = *boundaries.last().unwrap();
+            let lookalikes = (0..end)
+                .filter(|&bit| bits[bit..].starts_with(&end_marker))
+                .count();
+            assert!(lookalikes > 0, "{:?} never writes the pattern", codec);
+
+            let mut deserialised = Vec::new();
+            let rest = deserialise_nodes_with(&serialised, codec, |bit, node| {
+                deserialised.push((bit, node))
+            })
+            .unwrap();
+            assert!(rest.is_empty());
+            let expected: Vec<_> = boundaries.iter().copied().zip(nodes.clone()).collect();
+            assert_eq!(expected, deserialised, "{:?}", codec);
+
+            // nor does running out of bytes part way through the last node
+            for len in 0..serialised.len() {
+                assert_eq!(
+                    Err(DeserialiseError::Truncated),
+                    deserialise_nodes(&serialised[..len], codec).map(|(nodes, _)| nodes)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_offset_width_fits_the_largest_offset() {
+        let width = |offset| OffsetCodec::fixed_for(&[NodeType::reference(offset, 2)]);
+        assert_eq!(OffsetCodec::Fixed(1), OffsetCodec::fixed_for(&[]));
+        assert_eq!(OffsetCodec::Fixed(1), width(1));
+        assert_eq!(OffsetCodec::Fixed(7), width(127));
+        assert_eq!(OffsetCodec::Fixed(8), width(128));
+        assert_eq!(OffsetCodec::Fixed(16), width(65535));
+        for offset in [1, 127, 128, 2048, 65535] {
+            let nodes = vec![NodeType::reference(offset, 9)];
+            let codec = OffsetCodec::fixed_for(&nodes);
+            assert_eq!(
+                nodes,
+                deserialise_nodes(&serialise_stream(&nodes, codec), codec)
+                    .unwrap()
+                    .0
+            );
+        }
+    }
+
+    #[test]
+    fn fixed_offset_headers_record_the_width() {
+        let header = Header {
+            mode: Mode::FixedOffsets { bits: 9 },
+            search_window_size: WindowSize::DEFAULT,
+            original_len: 5,
+            content_hash: None,
+            original_file: None,
+            continues: false,
+        };
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header);
+        assert_eq!(&[3, 9], &bytes[5..7]);
+        let (read, body) = read_header(&bytes).unwrap();
+        assert_eq!(header, read);
+        assert!(body.is_empty());
+
+        for bits in [0, MAX_FIXED_OFFSET_BITS + 1] {
+            bytes[6] = bits;
+            assert_eq!(
+                Err(DeserialiseError::InvalidOffsetBits(bits)),
+                read_header(&bytes)
+            );
+        }
+        assert_eq!(Err(DeserialiseError::Truncated), read_header(&bytes[..6]));
+    }
+
+    #[test]
+    fn sync_markers_are_skipped_and_found_by_scanning() {
+        let nodes = vec![
+            NodeType::literal(b'a'),
+            NodeType::reference(1, 4),
+            NodeType::run(b'b', 300),
+            NodeType::literal(b'c'),
+            NodeType::reference(1, 2),
+        ];
+        for codec in [
+            OffsetCodec::Split,
+            OffsetCodec::Fixed(3),
+            OffsetCodec::Repeat,
+        ] {
+            let serialised = serialise_stream_with_sync_points(&nodes, codec, 5);
+            assert_eq!(nodes, deserialise_nodes(&serialised, codec).unwrap().0);
+
+            // markers before the run and the literal after it, not in the middle of the run
+            let points: Vec<u64> = find_sync_points(&serialised).map(|(at, _)| at).collect();
+            assert_eq!(vec![5, 305], points);
+
+            let (_, after) = find_sync_points(&serialised).nth(1).unwrap();
+            let (segment, end) = deserialise_segment(after, codec).unwrap();
+            assert_eq!(&nodes[3..], &segment[..]);
+            assert!(matches!(end, SegmentEnd::End(rest) if rest.is_empty()));
+        }
+    }
+
+    #[test]
+    fn sync_markers_must_record_the_bytes_before_them() {
+        let mut writer = BitWriter::new(Vec::new());
+        serailise_nodes(&mut writer, &[NodeType::literal(b'a')], OffsetCodec::Split);
+        write_sync_marker(&mut writer, OffsetCodec::Split, 2);
+        write_end_marker(&mut writer, OffsetCodec::Split);
+        assert_eq!(
+            Err(DeserialiseError::BadSyncMarker),
+            deserialise_nodes(&writer.finish(), OffsetCodec::Split)
+        );
+
+        let mut serialised =
+            serialise_stream_with_sync_points(&[NodeType::literal(b'a'); 2], OffsetCodec::Split, 1);
+        let pattern = serialised
+            .windows(SYNC_PATTERN.len())
+            .position(|bytes| bytes == SYNC_PATTERN)
+            .unwrap();
+        serialised[pattern] = 0;
+        assert_eq!(
+            Err(DeserialiseError::BadSyncMarker),
+            deserialise_nodes(&serialised, OffsetCodec::Split)
+        );
+    }
+
+    #[test]
+    fn multi_byte_fields_are_big_endian() {
+        let header = Header {
+            mode: Mode::Lz77,
+            search_window_size: WindowSize::new(0x1234).unwrap(),
+            original_len: 300,
+            content_hash: None,
+            original_file: None,
+            continues: false,
+        };
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header);
+        // the window size high byte first, then the length's low 7 bits first
+        assert_eq!(&[0, 0x12, 0x34, 0xac, 0x02], &bytes[frame::HEADER_SIZE..]);
+        assert_eq!(header, read_header(&bytes).unwrap().0);
+
+        let nodes = [NodeType::reference(0x1234, 2)];
+        // 1 for a reference, then 0001 0010 0011 0100 and the 2 bit length
+        let fixed = serialise_stream(&nodes, OffsetCodec::Fixed(16));
+        assert_eq!(&[0b1000_1001, 0b0001_1010], &fixed[..2]);
+        assert_eq!(
+            &nodes[..],
+            &deserialise_nodes(&fixed, OffsetCodec::Fixed(16)).unwrap().0[..]
+        );
+
+        // the split codec escapes to the same 16 bits after an all-zero 11 bit offset
+        let mut writer = BitWriter::new(Vec::new());
+        serailise_nodes(&mut writer, &nodes, OffsetCodec::Split);
+        let expected: BitVec<Msb0, u8> = "1 0 00000000000 0001001000110100 00"
+            .chars()
+            .filter(|&c| c != ' ')
+            .map(|c| c == '1')
+            .collect();
+        assert_eq!(expected, writer.into_bitvec());
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = Header {
+            mode: Mode::Stored,
+            search_window_size: WindowSize::new(4096).unwrap(),
+            original_len: 300,
+            content_hash: Some(*b"0123456789abcdef"),
+            original_file: None,
+            continues: true,
+        };
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header);
+        bytes.push(0xff);
+
+        let (read, body) = read_header(&bytes).unwrap();
+        assert_eq!(header, read);
+        assert_eq!(&[0xff], body);
+    }
+
+    #[test]
+    fn original_file_headers_round_trip_and_reject_paths() {
+        let header = Header {
+            mode: Mode::Lz77,
+            search_window_size: WindowSize::DEFAULT,
+            original_len: 3,
+            content_hash: Some(*b"0123456789abcdef"),
+            original_file: Some(OriginalFile::new("notes.txt", 1_700_000_000).unwrap()),
+            continues: false,
+        };
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header);
+        assert!(bytes.len() <= MAX_HEADER_SIZE);
+        let (read, body) = read_header(&bytes).unwrap();
+        assert_eq!(header, read);
+        assert!(body.is_empty());
+        for len in frame::HEADER_SIZE..bytes.len() {
+            assert_eq!(Err(DeserialiseError::Truncated), read_header(&bytes[..len]));
+        }
+
+        // a name written by something else is checked the same way as one about to be written
+        let name_at = bytes.len() - 8 - "notes.txt".len();
+        for bad_name in [b"../../txt", b"/etc/txts", b"..\\notesx", b"notes\xfftxt"] {
+            let mut bad = bytes.clone();
+            bad[name_at..name_at + bad_name.len()].copy_from_slice(bad_name);
+            assert_eq!(
+                Err(DeserialiseError::InvalidFileName),
+                read_header(&bad),
+                "{:?}",
+                bad_name
+            );
+        }
+    }
+
+    #[test]
+    fn header_rejects_bad_magic_version_mode_and_window() {
+        assert_eq!(
+            Err(DeserialiseError::BadMagic),
+            read_header(b"not a stream")
+        );
+        assert_eq!(Err(DeserialiseError::Truncated), read_header(b"SLZ7"));
+        let mut block = Vec::new();
+        frame::write_header(&mut block, frame::Header::current(Format::Block));
+        assert_eq!(
+            Err(DeserialiseError::WrongAlgorithm {
+                expected: "lz77",
+                found: "block",
+            }),
+            read_header(&block)
+        );
+
+        let mut bytes = Vec::new();
+        write_header(
+            &mut bytes,
+            &Header {
+                mode: Mode::Lz77,
+                search_window_size: WindowSize::DEFAULT,
+                original_len: 0,
+                content_hash: None,
+                original_file: None,
+                continues: false,
+            },
+        );
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] += 1;
+        assert_eq!(
+            Err(DeserialiseError::UnsupportedVersion(
+                Format::Lz77.version() + 1
+            )),
+            read_header(&bad_version)
+        );
+
+        let mut bad_mode = bytes.clone();
+        bad_mode[5] = 7;
+        assert_eq!(
+            Err(DeserialiseError::UnknownMode(7)),
+            read_header(&bad_mode)
+        );
+
+        let mut no_window = bytes.clone();
+        no_window[6..8].copy_from_slice(&[0, 0]);
+        assert_eq!(
+            Err(DeserialiseError::InvalidWindowSize),
+            read_header(&no_window)
+        );
+
+        let no_length = &bytes[..bytes.len() - 1];
+        assert_eq!(Err(DeserialiseError::Truncated), read_header(no_length));
+    }
+
+    #[test]
+    fn varints_round_trip_and_reject_overflow() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16383,
+            16384,
+            u64::from(u32::MAX),
+            u64::MAX,
+        ] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            assert!(bytes.len() <= MAX_VARINT_SIZE);
+            bytes.push(0xff);
+            assert_eq!(Ok((value, &[0xff][..])), read_varint(&bytes));
+        }
+
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 300);
+        assert_eq!(vec![0xac, 0x02], bytes);
+        assert_eq!(Err(DeserialiseError::Truncated), read_varint(&bytes[..1]));
+
+        // 11 bytes, or a tenth byte with more than the top bit of a u64
+        assert_eq!(
+            Err(DeserialiseError::InvalidLength),
+            read_varint(&[0xff; 11])
+        );
+        let mut too_large = vec![0xff; 9];
+        too_large.push(0x02);
+        assert_eq!(
+            Err(DeserialiseError::InvalidLength),
+            read_varint(&too_large)
         );
     }
 
@@ -296,7 +1287,7 @@ mod tests {
             bitvec![
                 0, 0, 1, 1, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, 0, 0, 1,
             ],
-            serailise_nodes(&three_raw_bytes)
+            serialised_bits(&three_raw_bytes)
         );
 
         let two_length_node_ref = vec![
@@ -308,94 +1299,183 @@ mod tests {
         ];
         assert_eq!(
             bitvec![1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 1,],
-            serailise_nodes(&two_length_node_ref)
+            serialised_bits(&two_length_node_ref)
         );
 
         // A: the reference is always smaller, even for larger offset values that require extra bits
     }
 
     #[test]
-    fn append_end_marker_adds_byte_padding() {
-        let mut vec = bitvec![Msb0, u8;];
-        append_end_marker(&mut vec);
-        assert_eq!(
-            bitvec![1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,],
-            vec
-        );
+    fn end_marker_is_padded_to_a_byte() {
+        let mut writer = BitWriter::new(Vec::new());
+        write_end_marker(&mut writer, OffsetCodec::Split);
+        assert_eq!(vec![0b1100_0000, 0], writer.finish());
 
-        vec = bitvec![Msb0, u8;1, 1, 1];
-        append_end_marker(&mut vec);
-        assert_eq!(
-            bitvec![1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,],
-            vec
-        );
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(0b111, 3);
+        write_end_marker(&mut writer, OffsetCodec::Split);
+        assert_eq!(vec![0b1111_1000, 0], writer.finish());
     }
 
     #[test]
-    fn converts_slice_to_byte() {
-        let slice = BitSlice::<Msb0, u8>::from_element(&b'w');
-        let byte = slice_to_byte(slice);
-        assert_eq!(byte, b'w');
+    fn serialise_and_deserialise_nodes() {
+        let nodes: Vec<NodeType> = vec![
+            NodeType::ByteLiteral { lit: b'a' },
+            NodeType::ByteLiteral { lit: b'b' },
+            NodeType::Reference {
+                offset: 2,
+                length: 2,
+            },
+        ];
+        let serialised = serialise_stream(&nodes, OffsetCodec::Split);
+        let deserialised = deserialise_nodes(&serialised, OffsetCodec::Split).unwrap();
+        assert_eq!((nodes, &[][..]), deserialised);
     }
 
     #[test]
-    fn deserialises_length() {
-        assert_eq!((2, 2), deserialise_length(bits![Msb0, u8; 0,0,0,0]));
-        assert_eq!((3, 2), deserialise_length(bits![Msb0, u8; 0,1,0,0]));
-        assert_eq!((4, 2), deserialise_length(bits![Msb0, u8; 1,0,0,0]));
-        assert_eq!((5, 4), deserialise_length(bits![Msb0, u8; 1,1,0,0]));
-        assert_eq!((6, 4), deserialise_length(bits![Msb0, u8; 1,1,0,1]));
-        assert_eq!((7, 4), deserialise_length(bits![Msb0, u8; 1,1,1,0]));
-        assert_eq!((8, 8), deserialise_length(bits![Msb0, u8; 1,1,1,1,0,0,0,0]));
-        assert_eq!((9, 8), deserialise_length(bits![Msb0, u8; 1,1,1,1,0,0,0,1]));
-        assert_eq!(
-            (23, 12),
-            deserialise_length(bits![Msb0, u8; 1,1,1,1,1,1,1,1,0,0,0,0])
-        );
-        assert_eq!(
-            (37, 12),
-            deserialise_length(bits![Msb0, u8; 1,1,1,1,1,1,1,1,1,1,1,0])
-        );
-        assert_eq!(
-            (38, 16),
-            deserialise_length(bits![Msb0, u8; 1,1,1,1,1,1,1,1,1,1,1,1,0,0,0,0])
-        );
-
-        let mut max_val = bitvec![Msb0, u8;];
-        let four_bit_blocks_for_max_size = ((2047 + 7) / 15) + 1; // +1 for final 4 bits;
-        assert_eq!(137, four_bit_blocks_for_max_size);
+    fn deserialise_nodes_resumes_after_the_end_marker() {
+        let first = vec![NodeType::ByteLiteral { lit: b'a' }];
+        let second = vec![
+            NodeType::ByteLiteral { lit: b'b' },
+            NodeType::Reference {
+                offset: 1,
+                length: 3,
+            },
+        ];
+        let mut appended = Vec::new();
+        for nodes in [&first, &second] {
+            appended.extend_from_slice(&serialise_stream(nodes, OffsetCodec::Split));
+        }
 
-        max_val.resize(137 * 4, true);
-        max_val.set(544, true);
-        max_val.set(545, true);
-        max_val.set(546, true);
-        max_val.set(547, false);
-        assert_eq!((2047, 548), deserialise_length(&max_val));
+        let (deserialised, rest) = deserialise_nodes(&appended, OffsetCodec::Split).unwrap();
+        assert_eq!(first, deserialised);
+        let (deserialised, rest) = deserialise_nodes(rest, OffsetCodec::Split).unwrap();
+        assert_eq!(second, deserialised);
+        assert!(rest.is_empty());
     }
 
     #[test]
-    fn length_encode_decode_in_harmony() {
-        assert_eq!((2, 2), deserialise_length(&serialise_length(2)));
-        assert_eq!((7, 4), deserialise_length(&serialise_length(7)));
-        assert_eq!((8, 8), deserialise_length(&serialise_length(8)));
-        assert_eq!((23, 12), deserialise_length(&serialise_length(23)));
-        assert_eq!((77, 24), deserialise_length(&serialise_length(77)));
-        assert_eq!((1024, 276), deserialise_length(&serialise_length(1024)));
+    fn padding_after_the_end_marker_must_be_zero() {
+        let nodes = vec![NodeType::ByteLiteral { lit: b'a' }];
+        // 9 bits of literal and 9 of end marker leave 6 bits of padding
+        let mut serialised = serialise_stream(&nodes, OffsetCodec::Split);
+        assert_eq!(
+            Ok((nodes, &[][..])),
+            deserialise_nodes(&serialised, OffsetCodec::Split)
+        );
+
+        *serialised.last_mut().unwrap() |= 1;
+        assert_eq!(
+            Err(DeserialiseError::NonZeroPadding),
+            deserialise_nodes(&serialised, OffsetCodec::Split)
+        );
     }
 
     #[test]
-    fn serialise_and_deserialise_nodes() {
-        let nodes: Vec<NodeType> = vec![
+    fn truncated_streams_are_rejected() {
+        let nodes = vec![
             NodeType::ByteLiteral { lit: b'a' },
-            NodeType::ByteLiteral { lit: b'b' },
             NodeType::Reference {
-                offset: 2,
-                length: 2,
+                offset: 1000,
+                length: 40,
             },
         ];
-        let mut serialised = serailise_nodes(&nodes);
-        append_end_marker(&mut serialised);
-        let deserialised = deserialise_nodes(&serialised.into());
-        assert_eq!(nodes, deserialised);
+        let serialised = serialise_stream(&nodes, OffsetCodec::Split);
+
+        for len in 0..serialised.len() {
+            assert_eq!(
+                Err(DeserialiseError::Truncated),
+                deserialise_nodes(&serialised[..len], OffsetCodec::Split).map(|(nodes, _)| nodes)
+            );
+        }
+    }
+
+    /// The `BitVec` based serialiser `BitWriter` replaced, kept to check the bytes haven't
+    /// changed.
+    fn bitvec_serialise(nodes: &[NodeType]) -> Vec<u8> {
+        let mut vec = bitvec![Msb0, u8;];
+        for node in nodes {
+            match *node {
+                NodeType::ByteLiteral { lit } => {
+                    vec.push(false);
+                    vec.extend_from_bitslice(lit.view_bits::<Msb0>());
+                }
+                NodeType::Reference { offset, length } => {
+                    vec.push(true);
+                    let x = offset.view_bits::<Msb0>();
+                    if offset < 128 {
+                        vec.push(true);
+                        vec.extend_from_bitslice(&x[16 - 7..]);
+                    } else if offset < 2048 {
+                        vec.push(false);
+                        vec.extend_from_bitslice(&x[16 - 11..]);
+                    } else {
+                        vec.resize(vec.len() + 12, false);
+                        vec.extend_from_bitslice(x);
+                    }
+                    vec.extend_from_bitslice(&bitvec_serialise_length(length));
+                }
+                NodeType::Run { .. } => unreachable!("runs were added after this serialiser"),
+            }
+        }
+        vec.extend_from_bitslice(bits![Msb0, u8; 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+        vec.into()
+    }
+
+    fn bitvec_serialise_length(length: u16) -> BitVec<Msb0, u8> {
+        let code = |value: u8, bits: usize| value.view_bits::<Msb0>()[8 - bits..].to_bitvec();
+        match length {
+            2..=4 => code(length as u8 - 2, 2),
+            5..=7 => code(0b1100 + (length as u8 - 5), 4),
+            _ => {
+                let blocks = (usize::from(length) + 7) / 15;
+                let mut encoded = bitvec![Msb0, u8; 1; blocks * 4];
+                encoded
+                    .extend_from_bitslice(&code((usize::from(length) + 7 - blocks * 15) as u8, 4));
+                encoded
+            }
+        }
+    }
+
+    #[test]
+    fn bit_writer_output_matches_the_bitvec_serialiser() {
+        let text = include_bytes!("../../README.md");
+        let random: Vec<u8> = pseudo_random(7).take(20_000).collect();
+        // repeats far enough apart to need 16 bit offsets
+        let mut far_repeats = random[..5000].to_vec();
+        far_repeats.extend_from_within(..3000);
+        far_repeats.extend_from_slice(&random[..40]);
+
+        for fixture in [&text[..], &random, &far_repeats] {
+            let mut nodes = Vec::new();
+            crate::lz77::compress::build_lz77_node_list_from(
+                fixture,
+                0,
+                &crate::lz77::compress::MatchOptions {
+                    search_window_size: u16::MAX,
+                    ..crate::lz77::compress::MatchOptions::default()
+                },
+                &mut crate::lz77::window_byte_container::PrefixTables::default(),
+                |node| nodes.push(node),
+                |_, _| {},
+            );
+            assert_eq!(
+                bitvec_serialise(&nodes),
+                serialise_stream(&nodes, OffsetCodec::Split)
+            );
+        }
+
+        let every_length: Vec<NodeType> = (2..=2047)
+            .flat_map(|length| {
+                [127, 128, 2047, 2048, 65535]
+                    .iter()
+                    .map(move |&offset| NodeType::reference(offset, length))
+            })
+            .chain((0..=255).map(NodeType::literal))
+            .collect();
+        assert_eq!(
+            bitvec_serialise(&every_length),
+            serialise_stream(&every_length, OffsetCodec::Split)
+        );
     }
 }
@@ -0,0 +1,267 @@
+use alloc::{collections::BTreeSet, vec, vec::Vec};
+use core::convert::TryFrom;
+use core::{cmp, mem};
+
+use super::serialisation;
+use super::window_byte_container::find_length_of_series_match;
+
+/// How many suffixes in the window either side of a position are compared before giving up
+/// on that side, so a long run of the same bytes doesn't compare the whole window.
+const MAX_NEIGHBOURS: usize = 256;
+
+/// The suffixes of a byte slice in sorted order, so the matches for any position are found
+/// among the earlier suffixes sorting next to it rather than by following a candidate chain.
+///
+/// Takes around 16 bytes of memory per input byte, plus the ordered set of the suffixes in
+/// the search window. Building it needs more, see `sort_suffixes`.
+pub struct SuffixArray<'a> {
+    bytes: &'a [u8],
+    /// The start of every suffix, in sorted order.
+    suffixes: Vec<usize>,
+    /// Where the suffix starting at each position comes in `suffixes`.
+    ranks: Vec<usize>,
+    /// The ranks of the suffixes starting in `window_start..window_end`.
+    window: BTreeSet<usize>,
+    window_start: usize,
+    window_end: usize,
+    /// The matches found for the position being matched, kept to save allocating for each.
+    candidates: Vec<(usize, usize)>,
+}
+
+impl<'a> SuffixArray<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        let suffixes = sort_suffixes(bytes);
+        let mut ranks = vec![0; bytes.len()];
+        for (rank, &suffix) in suffixes.iter().enumerate() {
+            ranks[suffix] = rank;
+        }
+        SuffixArray {
+            bytes,
+            suffixes,
+            ranks,
+            window: BTreeSet::new(),
+            window_start: 0,
+            window_end: 0,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Finds the back-reference for the bytes at `position` starting in the `window` bytes
+    /// before it that takes the fewest bits for each byte it covers, as `(offset, length)`.
+    ///
+    /// Matches can't run on past `position` and are no longer than `max_length`. Bits are
+    /// counted with the split codec, and the nearest of equally cheap matches is taken. Any
+    /// match of 2 bytes or more is returned even if literals would be cheaper, as the greedy
+    /// matcher does. Cheapest when positions are asked for in increasing order.
+    pub fn best_match(
+        &mut self,
+        position: usize,
+        window: usize,
+        max_length: usize,
+    ) -> Option<(usize, usize)> {
+        let reach = cmp::min(max_length, self.bytes.len() - position);
+        let earliest = position.saturating_sub(window);
+        self.move_window(earliest, position);
+
+        let rank = self.ranks[position];
+        let SuffixArray {
+            bytes,
+            suffixes,
+            window,
+            candidates,
+            ..
+        } = self;
+        let matching = &bytes[position..position + reach];
+        candidates.clear();
+        let before = window.range(..rank).rev().map(|&r| suffixes[r]);
+        collect_matches(before, bytes, matching, position, candidates);
+        let after = window.range(rank + 1..).map(|&r| suffixes[r]);
+        collect_matches(after, bytes, matching, position, candidates);
+
+        // nearest first, so it's kept over equally cheap matches further back
+        candidates.sort_unstable();
+        let mut best: Option<((usize, usize), usize)> = None;
+        for &(offset, length) in candidates.iter() {
+            let bits = reference_bits(offset, length);
+            let cheaper = match best {
+                Some(((_, best_length), best_bits)) => bits * best_length < best_bits * length,
+                None => true,
+            };
+            if cheaper {
+                best = Some(((offset, length), bits));
+            }
+        }
+        best.map(|(found, _)| found)
+    }
+
+    /// Makes `window` hold the suffixes starting in `start..end`, starting over if either
+    /// has moved back.
+    fn move_window(&mut self, start: usize, end: usize) {
+        if start < self.window_start || end < self.window_end {
+            self.window.clear();
+            self.window_start = start;
+            self.window_end = start;
+        }
+        while self.window_start < start {
+            if self.window_start < self.window_end {
+                self.window.remove(&self.ranks[self.window_start]);
+            }
+            self.window_start += 1;
+        }
+        self.window_end = cmp::max(self.window_end, start);
+        while self.window_end < end {
+            self.window.insert(self.ranks[self.window_end]);
+            self.window_end += 1;
+        }
+    }
+}
+
+/// Adds a match to `candidates`, as `(offset, length)`, for each of the `neighbours` sharing
+/// at least 2 bytes of `matching`, the bytes at `position`. The neighbours are suffix starts
+/// before `position`, in sorted order moving away from it, so the prefix each shares with
+/// `matching` only shrinks.
+fn collect_matches(
+    neighbours: impl Iterator<Item = usize>,
+    bytes: &[u8],
+    matching: &[u8],
+    position: usize,
+    candidates: &mut Vec<(usize, usize)>,
+) {
+    let mut prefix = matching.len();
+    for suffix in neighbours.take(MAX_NEIGHBOURS) {
+        let end = cmp::min(suffix + prefix, bytes.len());
+        prefix = find_length_of_series_match(&matching[..prefix], &bytes[suffix..end]);
+        if prefix < 2 {
+            return;
+        }
+        let length = cmp::min(prefix, position - suffix);
+        if length >= 2 {
+            candidates.push((position - suffix, length));
+        }
+    }
+}
+
+/// Bits a reference takes with the split codec.
+fn reference_bits(offset: usize, length: usize) -> usize {
+    serialisation::reference_bits(
+        u16::try_from(offset).unwrap(),
+        u16::try_from(length).unwrap(),
+    )
+}
+
+/// The start of every suffix of `bytes`, in sorted order, by prefix doubling: suffixes
+/// sorted by their first `k` bytes are sorted by their first `2k` by radix sorting on the
+/// ranks of the two halves.
+///
+/// The suffixes, their ranks, the next round's ranks and the suffixes ordered by their second
+/// halves are all held at once, a `usize` each per byte: around 32 bytes of memory per input
+/// byte on 64 bit targets, which with the input and nodes comes to around 40.
+fn sort_suffixes(bytes: &[u8]) -> Vec<usize> {
+    let len = bytes.len();
+    let mut suffixes: Vec<usize> = (0..len).collect();
+    suffixes.sort_unstable_by_key(|&suffix| bytes[suffix]);
+    let mut ranks: Vec<usize> = bytes.iter().map(|&byte| usize::from(byte)).collect();
+    let mut next_ranks = vec![0; len];
+    let mut by_second_half = Vec::with_capacity(len);
+    let mut counts = Vec::new();
+    let mut classes = 256;
+
+    let mut k = 1;
+    while k < len {
+        // ordered by the rank of the bytes k on, suffixes too short to have any first
+        by_second_half.clear();
+        by_second_half.extend(len - k..len);
+        by_second_half.extend(
+            suffixes
+                .iter()
+                .filter(|&&suffix| suffix >= k)
+                .map(|&suffix| suffix - k),
+        );
+
+        // then stably by the rank of their first k bytes
+        counts.clear();
+        counts.resize(classes + 1, 0);
+        for &suffix in &by_second_half {
+            counts[ranks[suffix] + 1] += 1;
+        }
+        for class in 1..counts.len() {
+            counts[class] += counts[class - 1];
+        }
+        for &suffix in &by_second_half {
+            suffixes[counts[ranks[suffix]]] = suffix;
+            counts[ranks[suffix]] += 1;
+        }
+
+        let second_half = |ranks: &[usize], suffix: usize| ranks.get(suffix + k).copied();
+        let mut class = 0;
+        next_ranks[suffixes[0]] = 0;
+        for pair in suffixes.windows(2) {
+            if ranks[pair[0]] != ranks[pair[1]]
+                || second_half(&ranks, pair[0]) != second_half(&ranks, pair[1])
+            {
+                class += 1;
+            }
+            next_ranks[pair[1]] = class;
+        }
+        mem::swap(&mut ranks, &mut next_ranks);
+        classes = class + 1;
+        if classes == len {
+            break;
+        }
+        k *= 2;
+    }
+    suffixes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_every_suffix() {
+        let inputs: [&[u8]; 5] = [
+            b"",
+            b"a",
+            b"banana",
+            b"aaaaaaaaaaaaaaaaa",
+            b"abracadabra abracadabra, mississippi",
+        ];
+        for bytes in inputs.iter() {
+            let mut expected: Vec<usize> = (0..bytes.len()).collect();
+            expected.sort_by_key(|&suffix| &bytes[suffix..]);
+            assert_eq!(expected, sort_suffixes(bytes));
+        }
+    }
+
+    #[test]
+    fn prefers_the_match_saving_the_most_bits() {
+        // "abcdefgh" 200 bytes back, and "abcdefg" only 10 back
+        let mut bytes = b"abcdefgh".to_vec();
+        bytes.extend(b"-".repeat(192));
+        bytes.extend(b"abcdefg...");
+        let position = bytes.len();
+        bytes.extend(b"abcdefgh");
+
+        let mut suffix_array = SuffixArray::new(&bytes);
+        assert_eq!(Some((10, 7)), suffix_array.best_match(position, 2047, 255));
+        // the nearer match is a byte short, but 13 bits for 7 bytes beats 21 for 8
+        assert_eq!(
+            (13, 21),
+            (reference_bits(10, 7), reference_bits(position, 8))
+        );
+        // and the window and max length are kept to
+        assert_eq!(Some((10, 5)), suffix_array.best_match(position, 2047, 5));
+        assert_eq!(None, suffix_array.best_match(position, 9, 255));
+    }
+
+    #[test]
+    fn finds_matches_after_moving_back() {
+        let bytes = b"abcabcabc";
+        let mut suffix_array = SuffixArray::new(bytes);
+        assert_eq!(Some((3, 3)), suffix_array.best_match(6, 100, 255));
+        assert_eq!(Some((3, 3)), suffix_array.best_match(3, 100, 255));
+        assert_eq!(None, suffix_array.best_match(1, 100, 255));
+        assert_eq!(None, suffix_array.best_match(6, 2, 255));
+        assert_eq!(Some((3, 3)), suffix_array.best_match(6, 3, 255));
+    }
+}
@@ -0,0 +1,282 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use super::nodes::NodeType;
+use super::serialisation::{read_varint, write_varint};
+use crate::error::DeserialiseError;
+
+// An LZ4 style alternative to the bitstream, byte aligned so it decodes without any bit
+// shuffling. Nodes are grouped into sequences of a run of literals followed by a reference:
+//
+//   token | [literal run varint] | literals | offset | [length varint]
+//
+// The token's high nibble is the number of literals and its low nibble the reference length
+// minus 2. A nibble of 15 means the rest of the count follows as a varint. The offset is a
+// big-endian u16. There's no end marker: the last sequence stops after its literals, with a
+// length nibble of 0, once the header's original length has been decoded.
+//
+// An offset of 0 marks a run instead of a reference, with a length nibble of 0:
+//
+//   token | [literal run varint] | literals | 0u16 | byte | count varint
+
+const NIBBLE_MAX: usize = 15;
+const MIN_LENGTH: usize = 2;
+
+pub fn serialise_tokens(nodes: &[NodeType]) -> Vec<u8> {
+    let mut tokens = Vec::new();
+    let mut literals = Vec::new();
+    for node in nodes {
+        match *node {
+            NodeType::ByteLiteral { lit } => literals.push(lit),
+            NodeType::Reference { offset, length } => {
+                write_sequence(&mut tokens, &literals, Some((offset, length)));
+                literals.clear();
+            }
+            NodeType::Run { byte, count } => {
+                write_sequence(&mut tokens, &literals, None);
+                tokens.extend_from_slice(&[0, 0, byte]);
+                write_varint(&mut tokens, u64::from(count));
+                literals.clear();
+            }
+        }
+    }
+    if !literals.is_empty() {
+        write_sequence(&mut tokens, &literals, None);
+    }
+    tokens
+}
+
+fn write_sequence(tokens: &mut Vec<u8>, literals: &[u8], reference: Option<(u16, u16)>) {
+    let length = reference.map_or(0, |(_, length)| usize::from(length) - MIN_LENGTH);
+    let nibble = |count: usize| count.min(NIBBLE_MAX) as u8;
+    tokens.push(nibble(literals.len()) << 4 | nibble(length));
+
+    if literals.len() >= NIBBLE_MAX {
+        write_varint(tokens, (literals.len() - NIBBLE_MAX) as u64);
+    }
+    tokens.extend_from_slice(literals);
+    if let Some((offset, _)) = reference {
+        tokens.extend_from_slice(&offset.to_be_bytes());
+        if length >= NIBBLE_MAX {
+            write_varint(tokens, (length - NIBBLE_MAX) as u64);
+        }
+    }
+}
+
+/// Reads sequences until `original_len` bytes' worth of nodes have been read, returning them
+/// along with the bytes that follow.
+pub fn deserialise_tokens(
+    tokens: &[u8],
+    original_len: usize,
+) -> Result<(Vec<NodeType>, &[u8]), DeserialiseError> {
+    let mut nodes = Vec::new();
+    let rest = deserialise_tokens_with(tokens, original_len, |_, node| nodes.push(node))?;
+    Ok((nodes, rest))
+}
+
+/// Like `deserialise_tokens`, but hands each node to `on_node` along with the bit it starts at,
+/// counted from the start of `tokens`: a literal's own byte, or a reference's or run's offset.
+pub fn deserialise_tokens_with<F: FnMut(usize, NodeType)>(
+    tokens: &[u8],
+    original_len: usize,
+    mut on_node: F,
+) -> Result<&[u8], DeserialiseError> {
+    let bit_position = |rest: &[u8]| (tokens.len() - rest.len()) * 8;
+    let mut decoded = 0;
+    let mut rest = tokens;
+    while decoded < original_len {
+        let (&token, after_token) = rest.split_first().ok_or(DeserialiseError::Truncated)?;
+        rest = after_token;
+
+        let (literal_count, after_count) = read_count(rest, usize::from(token >> 4))?;
+        if literal_count > after_count.len() {
+            return Err(DeserialiseError::Truncated);
+        }
+        let (literals, after_literals) = after_count.split_at(literal_count);
+        let literals_start = bit_position(after_count);
+        for (i, &lit) in literals.iter().enumerate() {
+            on_node(literals_start + i * 8, NodeType::literal(lit));
+        }
+        decoded += literal_count;
+        rest = after_literals;
+
+        let length_nibble = usize::from(token & 0x0f);
+        if decoded >= original_len {
+            // the last sequence, which has no reference
+            if decoded > original_len || length_nibble != 0 {
+                return Err(DeserialiseError::LengthMismatch);
+            }
+            break;
+        }
+
+        let start = bit_position(rest);
+        let offset = rest.get(..2).ok_or(DeserialiseError::Truncated)?;
+        let offset = u16::from_be_bytes([offset[0], offset[1]]);
+        if offset == 0 {
+            let (run, after_run) = read_run(&rest[2..], length_nibble)?;
+            on_node(start, run);
+            decoded = decoded
+                .checked_add(run.decoded_len())
+                .ok_or(DeserialiseError::InvalidLength)?;
+            rest = after_run;
+            continue;
+        }
+        let (length, after_length) = read_count(&rest[2..], length_nibble)?;
+        let length =
+            u16::try_from(length + MIN_LENGTH).map_err(|_| DeserialiseError::InvalidLength)?;
+        on_node(start, NodeType::Reference { offset, length });
+        decoded += usize::from(length);
+        rest = after_length;
+    }
+    if decoded != original_len {
+        return Err(DeserialiseError::LengthMismatch);
+    }
+    Ok(rest)
+}
+
+/// Reads the byte and count of a run whose token had a length nibble of `length_nibble`,
+/// returning it along with the bytes that follow.
+fn read_run(bytes: &[u8], length_nibble: usize) -> Result<(NodeType, &[u8]), DeserialiseError> {
+    let (&byte, after_byte) = bytes.split_first().ok_or(DeserialiseError::Truncated)?;
+    let (count, rest) = read_varint(after_byte)?;
+    let count = u32::try_from(count).map_err(|_| DeserialiseError::InvalidLength)?;
+    if length_nibble != 0 || count == 0 {
+        return Err(DeserialiseError::InvalidLength);
+    }
+    Ok((NodeType::run(byte, count), rest))
+}
+
+/// Reads the rest of a count whose nibble is `nibble`, returning it along with the bytes that
+/// follow.
+fn read_count(bytes: &[u8], nibble: usize) -> Result<(usize, &[u8]), DeserialiseError> {
+    if nibble < NIBBLE_MAX {
+        return Ok((nibble, bytes));
+    }
+    let (extra, rest) = read_varint(bytes)?;
+    let count = usize::try_from(extra)
+        .ok()
+        .and_then(|extra| extra.checked_add(NIBBLE_MAX))
+        .ok_or(DeserialiseError::InvalidLength)?;
+    Ok((count, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn decoded_len(nodes: &[NodeType]) -> usize {
+        nodes.iter().map(NodeType::decoded_len).sum()
+    }
+
+    #[test]
+    fn sequences_are_laid_out_like_lz4() {
+        let nodes = vec![
+            NodeType::literal(b'a'),
+            NodeType::literal(b'b'),
+            NodeType::reference(2, 4),
+            NodeType::literal(b'c'),
+        ];
+        assert_eq!(
+            vec![0x22, b'a', b'b', 0, 2, 0x10, b'c'],
+            serialise_tokens(&nodes)
+        );
+    }
+
+    #[test]
+    fn runs_are_sequences_with_an_offset_of_0() {
+        let nodes = vec![NodeType::literal(b'a'), NodeType::run(b'b', 300)];
+        let tokens = serialise_tokens(&nodes);
+        assert_eq!(vec![0x10, b'a', 0, 0, b'b', 0xac, 0x02], tokens);
+        assert_eq!(Ok((nodes, &[][..])), deserialise_tokens(&tokens, 301));
+
+        // a run must have a length nibble of 0 and cover at least one byte
+        assert_eq!(
+            Err(DeserialiseError::InvalidLength),
+            deserialise_tokens(&[0x01, 0, 0, b'b', 3], 3)
+        );
+        assert_eq!(
+            Err(DeserialiseError::InvalidLength),
+            deserialise_tokens(&[0x00, 0, 0, b'b', 0], 3)
+        );
+    }
+
+    #[test]
+    fn long_runs_and_lengths_use_varint_extensions() {
+        let literals: Vec<NodeType> = (0..15 + 300).map(|i| NodeType::literal(i as u8)).collect();
+        let mut nodes = literals.clone();
+        nodes.push(NodeType::reference(300, 2 + 15 + 130));
+
+        let tokens = serialise_tokens(&nodes);
+        assert_eq!(0xff, tokens[0]);
+        // 300 = 0xac 0x02 as a varint
+        assert_eq!([0xac, 0x02], tokens[1..3]);
+        // offset, then 130 = 0x82 0x01
+        assert_eq!([0x01, 0x2c, 0x82, 0x01], tokens[tokens.len() - 4..]);
+
+        assert_eq!(
+            Ok((nodes.clone(), &[][..])),
+            deserialise_tokens(&tokens, decoded_len(&nodes))
+        );
+    }
+
+    #[test]
+    fn nodes_round_trip() {
+        let cases = vec![
+            vec![],
+            vec![NodeType::literal(b'x')],
+            vec![NodeType::literal(b'x'), NodeType::reference(1, 2)],
+            vec![
+                NodeType::literal(b'x'),
+                NodeType::reference(1, 17),
+                NodeType::reference(5, 2047),
+                NodeType::literal(b'y'),
+                NodeType::literal(b'z'),
+            ],
+            (0..40).map(|i| NodeType::literal(i as u8)).collect(),
+            vec![NodeType::run(0, 100_000)],
+            vec![
+                NodeType::literal(b'x'),
+                NodeType::run(b'x', 300),
+                NodeType::run(b'y', 5),
+                NodeType::reference(2, 2),
+            ],
+        ];
+        for nodes in cases {
+            let mut tokens = serialise_tokens(&nodes);
+            tokens.push(0xee);
+            assert_eq!(
+                Ok((nodes.clone(), &[0xee][..])),
+                deserialise_tokens(&tokens, decoded_len(&nodes))
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_and_mislengthed_tokens_are_rejected() {
+        let nodes = vec![
+            NodeType::literal(b'x'),
+            NodeType::reference(1, 40),
+            NodeType::literal(b'y'),
+        ];
+        let tokens = serialise_tokens(&nodes);
+        let original_len = decoded_len(&nodes);
+
+        for len in 0..tokens.len() {
+            assert_eq!(
+                Err(DeserialiseError::Truncated),
+                deserialise_tokens(&tokens[..len], original_len)
+            );
+        }
+        // the header promising fewer bytes than the sequences hold
+        assert_eq!(
+            Err(DeserialiseError::LengthMismatch),
+            deserialise_tokens(&tokens, 10)
+        );
+        // a reference length past a u16
+        assert_eq!(
+            Err(DeserialiseError::InvalidLength),
+            deserialise_tokens(&[0x0f, 0, 1, 0xff, 0xff, 0x03], 70000)
+        );
+    }
+}
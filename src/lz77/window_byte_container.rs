@@ -1,6 +1,10 @@
+#[cfg(not(feature = "std"))]
+use alloc::collections::{btree_map::Entry, BTreeMap};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{cmp, convert::TryFrom};
+#[cfg(feature = "std")]
 use std::{
-    cmp,
-    collections::{HashMap, VecDeque},
+    collections::{hash_map::Entry, HashMap},
     hash::{BuildHasherDefault, Hasher},
 };
 
@@ -11,7 +15,7 @@ pub struct ByteBuffer<T> {
     limit: usize,
 }
 
-impl<T: std::marker::Copy> ByteBuffer<T> {
+impl<T: Copy> ByteBuffer<T> {
     // Potential improvements: Use a circular buffer instead, and use a chained iterator when
     // we need to return wrapped segments. Would save on allocations and moves.
     // Performance of swapping direct slice loops with an iter would have to be checked.
@@ -23,6 +27,8 @@ impl<T: std::marker::Copy> ByteBuffer<T> {
     }
 
     pub fn push_all(&mut self, elements: &[T]) {
+        // anything beyond the limit would be dropped straight away
+        let elements = &elements[elements.len().saturating_sub(self.limit)..];
         if self.vec.len() + elements.len() > self.limit {
             let count_to_drop = (self.vec.len() + elements.len()) - self.limit;
             self.vec.drain(0..count_to_drop);
@@ -100,7 +106,20 @@ impl<'a> ByteWindow<'a> {
         self.advance_to_pointer(new_pointer)
     }
 
+    /// Moves the end of the window to `pointer`.
+    ///
+    /// Moving back to an earlier pointer starts the window afresh there, so nothing is
+    /// evicted and the whole new window is admitted.
     pub fn advance_to_pointer(&mut self, pointer: usize) -> ByteWindowAdvance<'a> {
+        if pointer < self.current_index {
+            self.current_index = pointer;
+            let window = self.window();
+            return ByteWindowAdvance {
+                evicted: &[],
+                admitted: window,
+                window,
+            };
+        }
         let new_start_index = pointer.saturating_sub(self.max_window_size);
         let old_start_index = self.current_index.saturating_sub(self.max_window_size);
         let end_index = cmp::min(self.bytes.len(), pointer);
@@ -118,7 +137,7 @@ impl<'a> ByteWindow<'a> {
         };
 
         let evicted = if old_start_index < new_start_index && old_start_index < self.bytes.len() {
-            &self.bytes[old_start_index..new_start_index]
+            &self.bytes[old_start_index..cmp::min(self.bytes.len(), new_start_index)]
         } else {
             &[]
         };
@@ -132,6 +151,12 @@ impl<'a> ByteWindow<'a> {
         }
     }
 
+    /// Whether every byte has been admitted and then evicted, so advancing further changes
+    /// nothing.
+    pub fn is_exhausted(&self) -> bool {
+        self.current_index >= self.bytes.len() + self.max_window_size
+    }
+
     pub fn window(&self) -> &'a [u8] {
         let start_index = self.current_index.saturating_sub(self.max_window_size);
         let end_index = cmp::min(self.bytes.len(), self.current_index);
@@ -149,12 +174,14 @@ impl<'a> ByteWindow<'a> {
 /// is updated. Note that the indexes stored in this location are not restricted to the
 /// visible byte window, they are global across the whole original byte slice.
 /// This means we don't have to update values in the collection as we slide the window.
+#[cfg(feature = "std")]
 #[derive(PartialEq, Debug)]
 pub struct IndexableByteWindow<'a> {
     window: ByteWindow<'a>,
     byte_locations: HashMap<u8, VecDeque<usize>, U8HasherBuilder>,
 }
 
+#[cfg(feature = "std")]
 impl<'a> IndexableByteWindow<'a> {
     pub fn with_max_window_size(bytes: &'a [u8], max_window_size: usize) -> Self {
         IndexableByteWindow {
@@ -168,17 +195,44 @@ impl<'a> IndexableByteWindow<'a> {
     }
 
     pub fn advance(&mut self, count: usize) -> ByteWindowAdvance<'a> {
-        let result = self.window.advance(count);
-        self.update_byte_location_table(
-            result.admitted,
-            self.window.current_index - count,
-            result.evicted,
-        );
-        result
+        self.advance_to_pointer(self.window.current_index + count)
     }
 
+    /// Moves the end of the window to `pointer`. Moving back to an earlier pointer rebuilds
+    /// the byte locations from the new window, see `ByteWindow::advance_to_pointer`.
     pub fn advance_to_pointer(&mut self, pointer: usize) -> ByteWindowAdvance<'a> {
-        self.advance(pointer - self.window.current_index)
+        let admission_offset = if pointer < self.window.current_index {
+            self.byte_locations.clear();
+            pointer.saturating_sub(self.window.max_window_size)
+        } else {
+            self.window.current_index
+        };
+        let result = self.window.advance_to_pointer(pointer);
+        self.update_byte_location_table(result.admitted, admission_offset, result.evicted);
+        result
+    }
+
+    /// Advances by `step` bytes at a time until every byte has been admitted and evicted
+    /// again, yielding each advance.
+    ///
+    /// Panics if `step` is 0.
+    /// ```
+    /// use sloppycomp::lz77::window_byte_container::IndexableByteWindow;
+    /// let windows: Vec<_> = IndexableByteWindow::with_max_window_size(b"bcde", 2)
+    ///     .steps(2)
+    ///     .map(|advance| advance.window)
+    ///     .collect();
+    /// assert_eq!(vec![&b"bc"[..], b"de", b""], windows);
+    /// ```
+    pub fn steps(mut self, step: usize) -> impl Iterator<Item = ByteWindowAdvance<'a>> {
+        assert!(step != 0, "a window can't advance in steps of 0");
+        core::iter::from_fn(move || {
+            if self.window.is_exhausted() {
+                None
+            } else {
+                Some(self.advance(step))
+            }
+        })
     }
 
     pub fn window(&self) -> &'a [u8] {
@@ -229,24 +283,326 @@ impl<'a> IndexableByteWindow<'a> {
     }
 }
 
+/// Tracks where each 3 byte sequence starts within a sliding window over a byte slice.
+///
+/// Lets the matcher jump straight to positions that share the next three bytes rather than
+/// extending a match from every earlier occurrence of a single byte. The most recent start of
+/// each 2 byte sequence is kept too, for the short matches the 3 byte index can't see.
+///
+/// A sequence is only indexed once all of its bytes are inside the window, and like
+/// `IndexableByteWindow` the positions are global across the whole byte slice.
+#[derive(PartialEq, Debug)]
+pub struct PrefixIndex<'a> {
+    bytes: &'a [u8],
+    max_window_size: usize,
+    pointer: usize,
+    // positions before these have been indexed, or skipped for falling outside the window
+    next_triple: usize,
+    next_pair: usize,
+    tables: PrefixTables,
+}
+
+/// The positions a `PrefixIndex` has indexed, kept apart from the bytes so the memory can be
+/// reused for the next input, see `PrefixIndex::with_tables`.
+#[derive(PartialEq, Debug, Default)]
+pub struct PrefixTables {
+    triples: TripleMap,
+    // last position + 1 for each pair, indexed by the pair as a big-endian u16. 0 if unseen.
+    // Empty until the tables are first used.
+    last_pairs: Vec<usize>,
+}
+
+impl PrefixTables {
+    /// Forgets every position, keeping the memory allocated.
+    pub fn reset(&mut self) {
+        self.triples.clear();
+        self.last_pairs.clear();
+        self.last_pairs.resize(1 << 16, 0);
+    }
+}
+
+impl<'a> PrefixIndex<'a> {
+    pub fn with_max_window_size(bytes: &'a [u8], max_window_size: usize) -> Self {
+        Self::with_tables(bytes, max_window_size, PrefixTables::default())
+    }
+
+    /// An index over `bytes` that reuses `tables` from an earlier one, resetting them first.
+    pub fn with_tables(bytes: &'a [u8], max_window_size: usize, mut tables: PrefixTables) -> Self {
+        tables.reset();
+        PrefixIndex {
+            bytes,
+            max_window_size,
+            pointer: 0,
+            next_triple: 0,
+            next_pair: 0,
+            tables,
+        }
+    }
+
+    /// Gives up the tables for the next index to reuse.
+    pub fn into_tables(self) -> PrefixTables {
+        self.tables
+    }
+
+    /// Moves the end of the window to `pointer`. Moving back to an earlier pointer re-indexes
+    /// the new window from scratch, which the matcher never needs.
+    pub fn advance_to_pointer(&mut self, pointer: usize) {
+        if pointer < self.pointer {
+            self.tables.reset();
+            self.next_triple = 0;
+            self.next_pair = 0;
+        }
+        let old_start = self.window_start();
+        self.pointer = pointer;
+        let new_start = self.window_start();
+
+        // positions leave the window in order, so each one is at the front of its deque
+        for position in old_start..cmp::min(new_start, self.next_triple) {
+            if let Entry::Occupied(mut entry) =
+                self.tables.triples.entry(triple_at(self.bytes, position))
+            {
+                entry.get_mut().pop_front();
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+
+        let last_triple = cmp::min(pointer, self.bytes.len()).saturating_sub(2);
+        for position in cmp::max(self.next_triple, new_start)..last_triple {
+            self.tables
+                .triples
+                .entry(triple_at(self.bytes, position))
+                .or_default()
+                .push_back(position);
+        }
+        self.next_triple = cmp::max(self.next_triple, last_triple);
+
+        let last_pair = cmp::min(pointer, self.bytes.len()).saturating_sub(1);
+        for position in cmp::max(self.next_pair, new_start)..last_pair {
+            self.tables.last_pairs[pair_at(self.bytes, position)] = position + 1;
+        }
+        self.next_pair = cmp::max(self.next_pair, last_pair);
+    }
+
+    /// Positions in the window where the 3 bytes at `position` also start, oldest first.
+    pub fn triple_locations(&self, position: usize) -> impl DoubleEndedIterator<Item = &usize> {
+        self.bytes
+            .get(position..position + 3)
+            .and_then(|triple| self.tables.triples.get(triple))
+            .into_iter()
+            .flatten()
+    }
+
+    /// The most recent position in the window where the 2 bytes at `position` also start.
+    pub fn last_pair_location(&self, position: usize) -> Option<usize> {
+        if position + 2 > self.bytes.len() {
+            return None;
+        }
+        let location = self.tables.last_pairs[pair_at(self.bytes, position)].checked_sub(1)?;
+        if location >= self.window_start() {
+            Some(location)
+        } else {
+            None
+        }
+    }
+
+    /// Finds the best back-reference for the bytes at the pointer, as `(offset, length)`.
+    ///
+    /// Matches can't run on past the pointer into the bytes being matched, and are no longer
+    /// than `max_length`. Candidates are tried from the most recent back, so of two matches
+    /// the same length the one with the smallest offset is always taken.
+    ///
+    /// The search stops at the first match of `nice_length` or more, like DEFLATE's
+    /// `nice_length`, even if an older candidate would be longer, and after `max_candidates`
+    /// candidates, like its `max_chain`, taking the best of those.
+    pub fn longest_match(
+        &self,
+        max_length: usize,
+        nice_length: usize,
+        max_candidates: usize,
+    ) -> Option<(usize, usize)> {
+        self.search(max_length, nice_length, max_candidates).0
+    }
+
+    /// How many candidates `longest_match` compares against the bytes at the pointer.
+    #[cfg(test)]
+    pub fn candidates_compared(
+        &self,
+        max_length: usize,
+        nice_length: usize,
+        max_candidates: usize,
+    ) -> usize {
+        self.search(max_length, nice_length, max_candidates).1
+    }
+
+    /// `longest_match`, along with the number of candidates compared to find it.
+    fn search(
+        &self,
+        max_length: usize,
+        nice_length: usize,
+        max_candidates: usize,
+    ) -> (Option<(usize, usize)>, usize) {
+        let lookahead = self.lookahead(max_length);
+        let good_enough = cmp::min(nice_length, lookahead.len());
+        let mut best: Option<(usize, usize)> = None;
+        let mut compared = 0;
+        for location in self.match_candidates().take(max_candidates) {
+            compared += 1;
+            let length =
+                find_length_of_series_match(&self.bytes[location..self.pointer], lookahead);
+            if replaces_match(length, best) {
+                best = Some((self.pointer - location, length));
+                if length >= good_enough {
+                    break;
+                }
+            }
+        }
+        (best, compared)
+    }
+
+    /// Calls `option(offset, length)` for every length a match for the bytes at the pointer
+    /// could have, from 2 up to `max_length`, with the smallest offset that reaches it.
+    pub fn for_each_match<F>(&self, max_length: usize, mut option: F)
+    where
+        F: FnMut(usize, usize),
+    {
+        let lookahead = self.lookahead(max_length);
+        let mut reach = 1;
+        for location in self.match_candidates() {
+            let history = &self.bytes[location..self.pointer];
+            // can't reach any further unless it matches the byte after the current reach
+            if history.get(reach).is_none() || history.get(reach) != lookahead.get(reach) {
+                continue;
+            }
+            let length = find_length_of_series_match(history, lookahead);
+            for option_length in reach + 1..=length {
+                option(self.pointer - location, option_length);
+            }
+            reach = length;
+            if reach == lookahead.len() {
+                break;
+            }
+        }
+    }
+
+    /// Where a match for the bytes at the pointer can start, most recent first.
+    ///
+    /// Every match starts at a position sharing the next 2 bytes, and the most recent of those
+    /// comes first. Any longer match after that starts at a position sharing the next 3 bytes.
+    fn match_candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        let most_recent_pair = self.last_pair_location(self.pointer);
+        let older_triples = self
+            .triple_locations(self.pointer)
+            .rev()
+            .copied()
+            .skip_while(move |location| Some(*location) >= most_recent_pair);
+        most_recent_pair
+            .into_iter()
+            .chain(older_triples.filter(move |_| most_recent_pair.is_some()))
+    }
+
+    /// The bytes a match at the pointer can cover.
+    fn lookahead(&self, max_length: usize) -> &'a [u8] {
+        let end = cmp::min(self.bytes.len(), self.pointer + max_length);
+        &self.bytes[cmp::min(self.pointer, end)..end]
+    }
+
+    fn window_start(&self) -> usize {
+        self.pointer.saturating_sub(self.max_window_size)
+    }
+}
+
+/// Whether a match `length` bytes long, found further back than the `best` match so far,
+/// should replace it.
+///
+/// A match the same length never does, as the nearer one's smaller offset takes fewer bits to
+/// encode. Nor does one only a byte longer, which is how the original scan over every
+/// matching byte behaved and keeps the output unchanged.
+fn replaces_match(length: usize, best: Option<(usize, usize)>) -> bool {
+    match best {
+        None => length >= 2,
+        Some((_, best_length)) => length >= best_length + 2,
+    }
+}
+
+/// How many bytes from the start of `left` and `right` are equal.
+pub fn find_length_of_series_match(left: &[u8], right: &[u8]) -> usize {
+    let max_count = cmp::min(left.len(), right.len());
+    for i in 0..max_count {
+        if left[i] != right[i] {
+            return i;
+        }
+    }
+    max_count
+}
+
+// Without std there's no HashMap, so fall back to the slower but equivalent BTreeMap.
+#[cfg(feature = "std")]
+type TripleMap = HashMap<[u8; 3], VecDeque<usize>, PrefixHasherBuilder>;
+#[cfg(not(feature = "std"))]
+type TripleMap = BTreeMap<[u8; 3], VecDeque<usize>>;
+
+fn triple_at(bytes: &[u8], position: usize) -> [u8; 3] {
+    <[u8; 3]>::try_from(&bytes[position..position + 3]).unwrap()
+}
+
+fn pair_at(bytes: &[u8], position: usize) -> usize {
+    usize::from(u16::from_be_bytes([bytes[position], bytes[position + 1]]))
+}
+
 // Custom hasher optimised for the u8 keys of the IndexableByteWindow's map.
+//
+// A single byte key hashes to itself. Anything longer is folded into the one byte of state
+// so the hasher stays usable (if weak) rather than silently keeping only the first byte.
+#[cfg(feature = "std")]
 #[derive(Default)]
 pub struct U8Hasher {
     hash: u8,
 }
 
+#[cfg(feature = "std")]
 impl Hasher for U8Hasher {
     fn finish(&self) -> u64 {
         u64::from(self.hash)
     }
 
     fn write(&mut self, bytes: &[u8]) {
-        self.hash = bytes[0]
+        for byte in bytes {
+            self.hash = self.hash.rotate_left(3) ^ byte;
+        }
     }
 }
 
+#[cfg(feature = "std")]
 type U8HasherBuilder = BuildHasherDefault<U8Hasher>;
 
+// The same idea as U8Hasher for the short byte sequence keys of the PrefixIndex: the bytes are
+// packed into the state and spread with a multiply so every bit of the key reaches the low
+// bits used to pick a bucket.
+#[cfg(feature = "std")]
+#[derive(Default)]
+pub struct PrefixHasher {
+    hash: u64,
+}
+
+#[cfg(feature = "std")]
+impl Hasher for PrefixHasher {
+    fn finish(&self) -> u64 {
+        let spread = self.hash.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        spread ^ (spread >> 32)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash = (self.hash << 8) | u64::from(*byte);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+type PrefixHasherBuilder = BuildHasherDefault<PrefixHasher>;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -254,13 +610,155 @@ mod tests {
     #[test]
     fn push_all() {
         let mut search_buffer: ByteBuffer<u8> = ByteBuffer::new(4);
-        search_buffer.push_all(&[b'a']);
-        search_buffer.push_all(&[b'b', b'c', b'd', b'e']);
+        search_buffer.push_all(b"a");
+        search_buffer.push_all(b"bcde");
 
         assert_eq!(search_buffer.vec, vec![b'b', b'c', b'd', b'e']);
-        search_buffer.push_all(&[b'z']);
+        search_buffer.push_all(b"z");
         assert_eq!(search_buffer.vec, vec![b'c', b'd', b'e', b'z']);
     }
+
+    #[test]
+    fn u8_hasher_gives_each_byte_its_own_hash() {
+        let hashes: std::collections::HashSet<u64> = (0..=255u8)
+            .map(|byte| {
+                let mut hasher = U8Hasher::default();
+                hasher.write_u8(byte);
+                hasher.finish()
+            })
+            .collect();
+        assert_eq!(256, hashes.len());
+    }
+
+    #[test]
+    fn u8_hasher_handles_empty_and_multi_byte_writes() {
+        let mut hasher = U8Hasher::default();
+        hasher.write(&[]);
+        assert_eq!(0, hasher.finish());
+
+        let mut first = U8Hasher::default();
+        first.write(&[1, 2]);
+        let mut second = U8Hasher::default();
+        second.write(&[1, 3]);
+        assert_ne!(first.finish(), second.finish());
+    }
+
+    #[test]
+    fn prefix_index_finds_triples_inside_the_window() {
+        let bytes = b"abcXabcYabcZabc";
+        let mut index = PrefixIndex::with_max_window_size(bytes, 8);
+
+        index.advance_to_pointer(11);
+        let locations: Vec<usize> = index.triple_locations(12).copied().collect();
+        assert_eq!(vec![4, 8], locations);
+        assert_eq!(Some(8), index.last_pair_location(12));
+
+        // 'abc' at 8 doesn't fit before the pointer yet, and 0 has left the window
+        let mut index = PrefixIndex::with_max_window_size(bytes, 8);
+        index.advance_to_pointer(10);
+        let locations: Vec<usize> = index.triple_locations(12).copied().collect();
+        assert_eq!(vec![4], locations);
+    }
+
+    #[test]
+    fn reused_tables_forget_the_earlier_bytes() {
+        let mut index = PrefixIndex::with_max_window_size(b"xyzxyzxyz", 16);
+        index.advance_to_pointer(9);
+        let tables = index.into_tables();
+        assert!(!tables.triples.is_empty());
+
+        // the same positions hold other bytes now
+        let bytes = b"abcXabcYabcZabc";
+        let mut reused = PrefixIndex::with_tables(bytes, 8, tables);
+        reused.advance_to_pointer(11);
+        let mut fresh = PrefixIndex::with_max_window_size(bytes, 8);
+        fresh.advance_to_pointer(11);
+        assert_eq!(fresh, reused);
+        assert_eq!(Some(8), reused.last_pair_location(12));
+    }
+
+    #[test]
+    fn prefix_index_forgets_positions_that_leave_the_window() {
+        let bytes = b"abcdefabc";
+        let mut index = PrefixIndex::with_max_window_size(bytes, 5);
+        index.advance_to_pointer(3);
+        assert_eq!(Some(&0), index.triple_locations(6).next());
+
+        index.advance_to_pointer(6);
+        assert_eq!(None, index.triple_locations(6).next());
+        assert_eq!(None, index.last_pair_location(6));
+        assert_eq!(Some(4), index.last_pair_location(4));
+        assert_eq!(None, index.last_pair_location(8));
+    }
+
+    #[test]
+    fn longest_match_prefers_the_most_recent_of_equal_matches() {
+        let bytes = b"abcXabcYabcZabc";
+        let mut index = PrefixIndex::with_max_window_size(bytes, 16);
+        index.advance_to_pointer(12);
+        assert_eq!(Some((4, 3)), index.longest_match(2048, 2048, usize::MAX));
+        assert_eq!(Some((4, 2)), index.longest_match(2, 2048, usize::MAX));
+    }
+
+    #[test]
+    fn longest_match_takes_an_older_match_only_when_2_bytes_longer() {
+        // "abcd" at 8 is the most recent, "abcdef" at 0 is 2 longer
+        let bytes = b"abcdef--abcd--abcdef";
+        let mut index = PrefixIndex::with_max_window_size(bytes, 16);
+        index.advance_to_pointer(14);
+        assert_eq!(Some((14, 6)), index.longest_match(2048, 2048, usize::MAX));
+
+        // with the older one outside the window only the shorter match is left
+        let mut index = PrefixIndex::with_max_window_size(bytes, 8);
+        index.advance_to_pointer(14);
+        assert_eq!(Some((6, 4)), index.longest_match(2048, 2048, usize::MAX));
+
+        // a nice length stops the search at the most recent match that reaches it
+        let mut index = PrefixIndex::with_max_window_size(bytes, 16);
+        index.advance_to_pointer(14);
+        assert_eq!(Some((6, 4)), index.longest_match(2048, 4, usize::MAX));
+        assert_eq!(Some((14, 6)), index.longest_match(2048, 5, usize::MAX));
+
+        // as does running out of candidates, after the most recent here
+        assert_eq!(Some((6, 4)), index.longest_match(2048, 2048, 1));
+        assert_eq!(Some((14, 6)), index.longest_match(2048, 2048, 2));
+
+        // "abc-" at 5 is the most recent, "abcd" at 0 is only 1 longer
+        let bytes = b"abcd-abc-abcd";
+        let mut index = PrefixIndex::with_max_window_size(bytes, 16);
+        index.advance_to_pointer(9);
+        assert_eq!(Some((4, 3)), index.longest_match(2048, 2048, usize::MAX));
+    }
+
+    #[test]
+    fn longest_match_needs_2_matching_bytes() {
+        let bytes = b"abcdefaXcbZ";
+        let mut index = PrefixIndex::with_max_window_size(bytes, 16);
+        for pointer in 0..bytes.len() {
+            index.advance_to_pointer(pointer);
+            assert_eq!(None, index.longest_match(2048, 2048, usize::MAX));
+        }
+    }
+
+    #[test]
+    fn for_each_match_offers_every_length_at_its_smallest_offset() {
+        let bytes = b"abcdef--abcd--abcdef";
+        let mut index = PrefixIndex::with_max_window_size(bytes, 16);
+        index.advance_to_pointer(14);
+
+        let mut options = Vec::new();
+        index.for_each_match(2048, |offset, length| options.push((offset, length)));
+        assert_eq!(vec![(6, 2), (6, 3), (6, 4), (14, 5), (14, 6)], options);
+    }
+
+    #[test]
+    fn push_all_larger_than_limit() {
+        let mut search_buffer: ByteBuffer<u8> = ByteBuffer::new(2);
+        search_buffer.push_all(b"a");
+        search_buffer.push_all(b"bcde");
+
+        assert_eq!(search_buffer.vec, vec![b'd', b'e']);
+    }
 }
 
 #[cfg(test)]
@@ -273,12 +771,12 @@ mod byte_window_tests {
         let mut byte_window = ByteWindow::with_max_window_size(&bytes, 2);
 
         assert_eq!([] as [u8; 0], byte_window.window());
-        assert_window_advance(&mut byte_window, &[], &[b'b'], &[b'b']);
-        assert_window_advance(&mut byte_window, &[], &[b'c'], &[b'b', b'c']);
-        assert_window_advance(&mut byte_window, &[b'b'], &[b'd'], &[b'c', b'd']);
-        assert_window_advance(&mut byte_window, &[b'c'], &[b'e'], &[b'd', b'e']);
-        assert_window_advance(&mut byte_window, &[b'd'], &[], &[b'e']);
-        assert_window_advance(&mut byte_window, &[b'e'], &[], &[]);
+        assert_window_advance(&mut byte_window, &[], b"b", b"b");
+        assert_window_advance(&mut byte_window, &[], b"c", b"bc");
+        assert_window_advance(&mut byte_window, b"b", b"d", b"cd");
+        assert_window_advance(&mut byte_window, b"c", b"e", b"de");
+        assert_window_advance(&mut byte_window, b"d", &[], b"e");
+        assert_window_advance(&mut byte_window, b"e", &[], &[]);
         assert_window_advance(&mut byte_window, &[], &[], &[]);
     }
 
@@ -290,9 +788,9 @@ mod byte_window_tests {
         let door = byte_window.advance(5);
         assert_eq!(
             ByteWindowAdvance {
-                evicted: &[b'b', b'c', b'd'],
-                admitted: &[b'b', b'c', b'd', b'e'],
-                window: &[b'e']
+                evicted: b"bcd",
+                admitted: b"bcde",
+                window: b"e"
             },
             door
         );
@@ -333,15 +831,119 @@ mod byte_window_tests {
         let door = byte_window.advance_to_pointer(3);
         assert_eq!(
             ByteWindowAdvance {
-                evicted: &[b'b'],
-                admitted: &[b'b', b'c', b'd'],
-                window: &[b'c', b'd']
+                evicted: b"b",
+                admitted: b"bcd",
+                window: b"cd"
             },
             door
         );
         assert_eq!([b'c', b'd'], byte_window.window());
     }
 
+    #[test]
+    fn advancing_to_the_same_or_an_earlier_pointer() {
+        let bytes = b"abracadabra";
+        let mut byte_window = IndexableByteWindow::with_max_window_size(bytes, 4);
+        byte_window.advance_to_pointer(7);
+        let before = byte_window.byte_locations().clone();
+        assert_eq!(
+            ByteWindowAdvance {
+                evicted: b"",
+                admitted: b"",
+                window: b"acad"
+            },
+            byte_window.advance_to_pointer(7)
+        );
+        assert_eq!(&before, byte_window.byte_locations());
+
+        // rewinding starts afresh, as if the window had only ever advanced to the new pointer
+        assert_eq!(
+            ByteWindowAdvance {
+                evicted: b"",
+                admitted: b"brac",
+                window: b"brac"
+            },
+            byte_window.advance_to_pointer(5)
+        );
+        let mut fresh = IndexableByteWindow::with_max_window_size(bytes, 4);
+        fresh.advance_to_pointer(5);
+        assert_eq!(fresh, byte_window);
+        byte_window.advance_to_pointer(11);
+        fresh.advance_to_pointer(11);
+        assert_eq!(fresh, byte_window);
+
+        let mut index = PrefixIndex::with_max_window_size(bytes, 8);
+        index.advance_to_pointer(11);
+        index.advance_to_pointer(11);
+        index.advance_to_pointer(4);
+        let mut fresh = PrefixIndex::with_max_window_size(bytes, 8);
+        fresh.advance_to_pointer(4);
+        assert_eq!(fresh, index);
+        // not 7, which was indexed before rewinding
+        let locations: Vec<usize> = index.triple_locations(7).copied().collect();
+        assert_eq!(vec![0], locations);
+    }
+
+    #[test]
+    fn steps_match_advancing_by_hand() {
+        let bytes = b"abracadabra";
+        for max_window_size in [1, 2, 4, 20] {
+            for step in [1, 2, 3, 5, 30] {
+                let mut by_hand = IndexableByteWindow::with_max_window_size(bytes, max_window_size);
+                let mut steps = IndexableByteWindow::with_max_window_size(bytes, max_window_size)
+                    .steps(step)
+                    .peekable();
+                while steps.peek().is_some() {
+                    assert_eq!(by_hand.advance(step), steps.next().unwrap());
+                }
+                // stepping stops once the last byte has left the window
+                assert!(by_hand.window().is_empty());
+                assert_eq!(
+                    ByteWindowAdvance {
+                        evicted: &[],
+                        admitted: &[],
+                        window: &[]
+                    },
+                    by_hand.advance(step)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn steps_of_one_admit_then_evict_every_byte() {
+        let bytes = [b'b', b'c', b'd', b'e'];
+        let advances: Vec<_> = IndexableByteWindow::with_max_window_size(&bytes, 2)
+            .steps(1)
+            .map(|advance| (advance.evicted, advance.admitted, advance.window))
+            .collect();
+        let expected: [(&[u8], &[u8], &[u8]); 6] = [
+            (&[], b"b", b"b"),
+            (&[], b"c", b"bc"),
+            (b"b", b"d", b"cd"),
+            (b"c", b"e", b"de"),
+            (b"d", &[], b"e"),
+            (b"e", &[], &[]),
+        ];
+        assert_eq!(&expected[..], &advances[..]);
+        assert_eq!(
+            0,
+            IndexableByteWindow::with_max_window_size(&[], 0)
+                .steps(1)
+                .count()
+        );
+    }
+
+    #[test]
+    fn advancing_far_past_the_end_evicts_only_real_bytes() {
+        let bytes = [b'b', b'c', b'd', b'e'];
+        let mut byte_window = ByteWindow::with_max_window_size(&bytes, 2);
+        byte_window.advance(5);
+        let door = byte_window.advance(5);
+        assert_eq!(b"e", door.evicted);
+        assert!(door.window.is_empty());
+    }
+
     fn assert_window_advance(
         byte_window: &mut ByteWindow,
         evicted: &[u8],
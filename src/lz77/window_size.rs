@@ -0,0 +1,60 @@
+use core::convert::TryFrom;
+
+use crate::error::{Error, Result};
+
+/// How far back, in bytes, references may reach, and so how much history the decoder keeps.
+///
+/// Always between 1 and 65535, the largest offset the escaped 16 bit offset encoding can
+/// hold, so any `WindowSize` can be written to the stream header and decoded.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct WindowSize(u16);
+
+impl WindowSize {
+    /// The largest offset that still fits the 11 bit offset encoding.
+    pub const DEFAULT: WindowSize = WindowSize(2047);
+    pub const MAX: WindowSize = WindowSize(u16::MAX);
+
+    pub fn new(size: usize) -> Result<Self> {
+        match u16::try_from(size) {
+            Ok(size) if size > 0 => Ok(WindowSize(size)),
+            _ => Err(Error::InvalidWindowSize(size)),
+        }
+    }
+
+    pub const fn get(self) -> u16 {
+        self.0
+    }
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        WindowSize::DEFAULT
+    }
+}
+
+impl TryFrom<usize> for WindowSize {
+    type Error = Error;
+
+    fn try_from(size: usize) -> Result<Self> {
+        WindowSize::new(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_encodable_window_sizes() {
+        assert_eq!(1, WindowSize::new(1).unwrap().get());
+        assert_eq!(2047, WindowSize::default().get());
+        assert_eq!(WindowSize::MAX, WindowSize::new(65535).unwrap());
+
+        for size in [0, 65536, usize::MAX] {
+            assert!(matches!(
+                WindowSize::new(size),
+                Err(Error::InvalidWindowSize(s)) if s == size
+            ));
+        }
+    }
+}
@@ -1,8 +1,15 @@
-use std::{env, fs::File, io};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::Path;
+use std::time::Instant;
+use std::{fs::File, panic, process};
 
-use sloppycomp::block_compress;
-use sloppycomp::compression;
-use sloppycomp::lz77;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use sloppycomp::compression::{self, Algorithm, CompressionStats};
+use sloppycomp::error::{DeserialiseError, Error, Result};
+use sloppycomp::frame;
+use sloppycomp::lz77::{self, nodes::NodeType, Level, OriginalFile, WindowSize};
 
 /// a really rubbish file compressor.
 ///
@@ -10,34 +17,589 @@ use sloppycomp::lz77;
 ///
 /// To decompress - `./sloppy-compressor lz77 decompress ~/file/input.name ~/file/output.name`
 ///
-/// The program ignores most error checking and will overwrite files without warning.
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let algo = &args[1];
-    let compress_mode = &args[2] == "compress";
-    let path = &args[3];
-    let output_path = &args[4];
-    println!("{:?}", args);
-
-    // I wonder what the compiler outputs for this?
-    // We could avoid the heap alloc if we just had if/else blocks.
-    let compressor: Box<dyn compression::Algorithm> = match algo.as_str() {
-        "block" => Box::new(block_compress::BlockCompression {}),
-        "lz77" => Box::new(lz77::Lz77Compression {}),
-        _ => panic!("Unknown compression algorithm"),
+/// Round-trip every algorithm over some built-in inputs: `./sloppy-compressor selftest`
+///
+/// List the available algorithms: `./sloppy-compressor list`
+///
+/// Time compressing a file in memory, without writing anything:
+/// `./sloppy-compressor lz77 benchmark ~/file/input.name`
+///
+/// Show which algorithm wrote a compressed file and how large it decompresses to, without
+/// writing anything: `./sloppy-compressor info ~/file/input.name`
+///
+/// Print each node of an lz77 file along with the bit it starts at, to debug the format:
+/// `./sloppy-compressor disasm ~/file/input.name`
+///
+/// `--help`, on its own or after an algorithm, lists the options.
+///
+/// lz77 can split the work across threads with `--threads N`. The output is a framed
+/// multi-block stream, so pass `--threads` again (any count) when decompressing it. It also
+/// takes `--window`, `--nice-length`, `--max-candidates`, `--profitable-matches`,
+/// `--run-length`, `--sync-interval`, `--optimal`, `--suffix-matcher`, `--token-stream`,
+/// `--adaptive-offsets`, `--adaptive-literal-runs` and `--repeat-offsets` to tune compression. `--content-hash`
+/// records the input's md5 in the header, which `info` and the compression report print.
+/// `-N` (`--name`) records the input's file name and modification time, and decompressing
+/// into a directory then restores both, like gzip.
+/// `-0` (`--fastest`), `-6` and `-9` (`--best`) pick a preset for the window, matcher and
+/// offset coding, which the options above then adjust.
+///
+/// After compressing, the sizes are printed. With `--verbose` (or `--stats`), lz77 also prints
+/// how many literals and references it emitted, with the references bucketed by offset and
+/// length.
+///
+/// Decompressing a file that was written by a different algorithm is refused rather than
+/// producing garbage. lz77 takes `--max-output BYTES` to refuse files that would decompress
+/// to more than that, e.g. a small malicious file that expands to fill the disk.
+///
+/// An existing output file is never overwritten unless `--force` is given, the program fails
+/// instead. Failures are reported on stderr with a non-zero exit code: 1 for bad arguments and
+/// I/O errors, including an output file that already exists, and 2 for input that isn't valid
+/// compressed data.
+fn main() {
+    let matches = cli().get_matches();
+    match matches.subcommand() {
+        ("selftest", _) => {
+            if !self_test() {
+                process::exit(1);
+            }
+        }
+        ("list", _) => {
+            for name in compression::registry().keys() {
+                println!("{}", name);
+            }
+        }
+        ("info", Some(args)) => exit_on_error(info(args.value_of("file").unwrap())),
+        ("disasm", Some(args)) => exit_on_error(disasm(args.value_of("file").unwrap())),
+        (algo, Some(args)) => exit_on_error(run(algo, args)),
+        // a subcommand is required, so clap has already printed the help
+        (_, None) => unreachable!(),
+    }
+}
+
+/// The command line: `list`, `selftest`, and a subcommand per algorithm taking a mode and the
+/// input and output paths.
+fn cli() -> App<'static, 'static> {
+    let mut app = App::new("sloppy-compressor")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("a really rubbish file compressor")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(SubCommand::with_name("list").about("Prints the available algorithms"))
+        .subcommand(
+            SubCommand::with_name("selftest")
+                .about("Round-trips every algorithm over some built-in inputs"),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Prints how a compressed file was written and its decompressed size")
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Prints the nodes of an lz77 file and the bit each starts at")
+                .arg(Arg::with_name("file").required(true)),
+        );
+    for name in compression::registry().keys() {
+        let command = algorithm_command(name);
+        app = app.subcommand(if *name == "lz77" {
+            with_lz77_options(command)
+        } else {
+            command
+        });
+    }
+    app
+}
+
+fn algorithm_command(name: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about("Compresses, decompresses or benchmarks a file with this algorithm")
+        .arg(Arg::with_name("mode").required(true).possible_values(&[
+            "compress",
+            "decompress",
+            "benchmark",
+        ]))
+        .arg(Arg::with_name("input").required(true))
+        .arg(
+            Arg::with_name("output")
+                .required_ifs(&[("mode", "compress"), ("mode", "decompress")])
+                .help("Not needed to benchmark, which writes nothing"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("Replaces the output file if it already exists"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .visible_alias("stats")
+                .help("Prints more detail about the compressed file, where there is any"),
+        )
+}
+
+fn with_lz77_options(command: App<'static, 'static>) -> App<'static, 'static> {
+    let number = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("N")
+            .validator(|value| {
+                value
+                    .parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| "expects a number".to_string())
+            })
+            .help(help)
     };
+    command
+        .arg(number(
+            "threads",
+            "Compresses in a block per thread, the output must be decompressed with --threads too",
+        ))
+        .arg(
+            number(
+                "window",
+                "How far back, in bytes, matches may reach, 1 to 65535",
+            )
+            .validator(|value| match value.parse().map(WindowSize::new) {
+                Ok(Ok(_)) => Ok(()),
+                _ => Err("expects a window size from 1 to 65535".to_string()),
+            }),
+        )
+        .arg(
+            Arg::with_name("fastest")
+                .short("0")
+                .long("fastest")
+                .conflicts_with_all(&["default-level", "best"])
+                .help("Sets the matcher up for speed, before any options that tune it"),
+        )
+        .arg(
+            Arg::with_name("default-level")
+                .short("6")
+                .conflicts_with("best")
+                .help("Sets the matcher back to its defaults, before any options that tune it"),
+        )
+        .arg(
+            Arg::with_name("best")
+                .short("9")
+                .long("best")
+                .help("Sets the matcher up for size, before any options that tune it"),
+        )
+        .arg(number(
+            "nice-length",
+            "Stops looking for a longer match once one this long is found",
+        ))
+        .arg(number(
+            "max-candidates",
+            "Compares at most this many earlier positions when looking for a match",
+        ))
+        .arg(number(
+            "run-length",
+            "Encodes runs of at least this many copies of a byte as a single node",
+        ))
+        .arg(number(
+            "sync-interval",
+            "Writes a sync marker every this many bytes, so damaged files can be partly recovered",
+        ))
+        .arg(number(
+            "max-output",
+            "Refuses to decompress to more than this many bytes",
+        ))
+        .arg(
+            Arg::with_name("optimal")
+                .long("optimal")
+                .help("Picks matches by their encoded cost, slower but smaller"),
+        )
+        .arg(
+            Arg::with_name("suffix-matcher")
+                .long("suffix-matcher")
+                .conflicts_with("optimal")
+                .help(
+                    "Finds the cheapest match per byte through a suffix array, slower but smaller",
+                ),
+        )
+        .arg(
+            Arg::with_name("token-stream")
+                .long("token-stream")
+                .help("Writes the LZ4 style byte aligned format"),
+        )
+        .arg(
+            Arg::with_name("profitable-matches")
+                .long("profitable-matches")
+                .help("Only takes matches that encode smaller than their literals"),
+        )
+        .arg(
+            Arg::with_name("adaptive-offsets")
+                .long("adaptive-offsets")
+                .help("Writes offsets in one width chosen per file, if that's smaller"),
+        )
+        .arg(
+            Arg::with_name("adaptive-literal-runs")
+                .long("adaptive-literal-runs")
+                .help("Writes the byte aligned format for files that are mostly literals"),
+        )
+        .arg(
+            Arg::with_name("repeat-offsets")
+                .long("repeat-offsets")
+                .help("Gives recently used offsets a short code, if that's smaller"),
+        )
+        .arg(
+            Arg::with_name("content-hash")
+                .long("content-hash")
+                .help("Records the input's md5 in the header and prints it"),
+        )
+        .arg(
+            Arg::with_name("name")
+                .short("N")
+                .long("name")
+                .conflicts_with("threads")
+                .help(
+                    "Records the input's file name and modification time, restored when \
+                     decompressing into a directory",
+                ),
+        )
+}
 
-    let file = File::open(path)?;
+fn exit_on_error(result: Result<()>) {
+    if let Err(err) = result {
+        eprintln!("sloppy-compressor: {}", err);
+        process::exit(exit_code(&err));
+    }
+}
 
-    if compress_mode {
-        compressor
-            .compress(file, output_path)
-            .expect("Error on compression");
+/// Prints what the header of the compressed file at `path` says about it, and how many bytes
+/// it decompresses to.
+fn info(path: &str) -> Result<()> {
+    let mut file = File::open(path)?;
+    let info = compression::inspect(&mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    let compressor = compression::registry()[info.algorithm]();
+    let size = compressor.decompressed_size(&mut file)?;
+
+    println!("algorithm: {}", info.algorithm);
+    println!("format version: {}", info.version);
+    println!("checksum: {}", if info.has_checksum { "yes" } else { "no" });
+    if let Some(hash) = info.content_hash {
+        println!("content hash: {}", compression::hex(&hash));
+    }
+    println!("decompressed size: {}", size);
+    Ok(())
+}
+
+/// Prints each node of the lz77 file at `path` on a line of its own, e.g.
+/// `@90: Reference { offset: 2, length: 2 }` or `@72: Literal 'a'`.
+fn disasm(path: &str) -> Result<()> {
+    let compressed = std::fs::read(path)?;
+    for (bit, node) in lz77::Lz77Compression::disassemble_with_positions(&compressed)? {
+        match node {
+            NodeType::ByteLiteral { lit } => {
+                println!("@{}: Literal '{}'", bit, char::from(lit).escape_default())
+            }
+            _ => println!("@{}: {:?}", bit, node),
+        }
+    }
+    Ok(())
+}
+
+/// The lz77 compressor the options on the command line describe.
+fn lz77_compressor(args: &ArgMatches) -> lz77::Lz77Compression {
+    let mut compressor = lz77::Lz77Compression::new();
+    if args.is_present("fastest") {
+        compressor = compressor.with_level(Level::Fastest);
+    } else if args.is_present("best") {
+        compressor = compressor.with_level(Level::Best);
+    } else if args.is_present("default-level") {
+        compressor = compressor.with_level(Level::Default);
+    }
+    if let Some(window) = number(args, "window") {
+        compressor = compressor.with_search_window_size(WindowSize::new(window).unwrap());
+    }
+    if let Some(nice_length) = number(args, "nice-length") {
+        compressor =
+            compressor.with_nice_match_length(u16::try_from(nice_length).unwrap_or(u16::MAX));
+    }
+    if let Some(max_candidates) = number(args, "max-candidates") {
+        compressor = compressor.with_max_candidates(max_candidates);
+    }
+    if let Some(min_run_length) = number(args, "run-length") {
+        compressor = compressor.with_run_length_encoding(min_run_length);
+    }
+    if let Some(sync_interval) = number(args, "sync-interval") {
+        compressor = compressor.with_sync_interval(sync_interval);
+    }
+    if let Some(limit) = number(args, "max-output") {
+        compressor = compressor.with_max_output_bytes(limit);
+    }
+    if args.is_present("profitable-matches") {
+        compressor = compressor.with_profitable_matches();
+    }
+    if args.is_present("optimal") {
+        compressor = compressor.with_optimal_parse();
+    }
+    if args.is_present("suffix-matcher") {
+        compressor = compressor.with_suffix_matcher();
+    }
+    if args.is_present("token-stream") {
+        compressor = compressor.with_token_stream();
+    }
+    if args.is_present("adaptive-offsets") {
+        compressor = compressor.with_adaptive_offsets();
+    }
+    if args.is_present("adaptive-literal-runs") {
+        compressor = compressor.with_adaptive_literal_runs();
+    }
+    if args.is_present("repeat-offsets") {
+        compressor = compressor.with_repeat_offsets();
+    }
+    if args.is_present("content-hash") {
+        compressor = compressor.with_content_hash();
+    }
+    compressor
+}
+
+/// The value of a numeric option, which clap has already validated, if it was given.
+fn number(args: &ArgMatches, name: &str) -> Option<usize> {
+    args.value_of(name).map(|value| value.parse().unwrap())
+}
+
+/// The compressor for `algo` with the options on the command line.
+fn compressor(algo: &str, args: &ArgMatches) -> Box<dyn Algorithm> {
+    if algo == "lz77" {
+        Box::new(lz77_compressor(args))
     } else {
-        compressor
-            .decompress(file, output_path)
-            .expect("Error on decompression");
+        compression::registry()[algo]()
     }
+}
+
+/// The sizes of a `--threads` compression, which has no node histogram.
+fn parallel_stats(input: &[u8], compressed: &[u8]) -> CompressionStats {
+    CompressionStats {
+        original_bytes: input.len(),
+        compressed_bytes: compressed.len(),
+        ..CompressionStats::default()
+    }
+}
+
+/// Compresses the file at `path` in memory and prints the sizes along with how long it took.
+fn benchmark(algo: &str, path: &str, args: &ArgMatches) -> Result<()> {
+    let mut input = Vec::new();
+    File::open(path)?.read_to_end(&mut input)?;
+
+    let start = Instant::now();
+    let stats = match number(args, "threads") {
+        Some(threads) => {
+            let compressed = lz77_compressor(args).compress_parallel(&input, threads);
+            parallel_stats(&input, &compressed)
+        }
+        None => compressor(algo, args).compress(&mut &input[..], &mut Vec::new())?,
+    };
+    let seconds = start.elapsed().as_secs_f64();
 
+    println!("{}", stats);
+    println!("time: {:.3}s", seconds);
+    println!(
+        "throughput: {:.2} MB/s",
+        stats.original_bytes as f64 / 1e6 / seconds.max(1e-9)
+    );
     Ok(())
 }
+
+fn run(algo: &str, args: &ArgMatches) -> Result<()> {
+    let path = args.value_of("input").unwrap();
+    if args.value_of("mode") == Some("benchmark") {
+        return benchmark(algo, path, args);
+    }
+    let compress_mode = args.value_of("mode") == Some("compress");
+    let output_path = args.value_of("output").unwrap();
+    let verbose = args.is_present("verbose");
+    let force = args.is_present("force");
+
+    if let Some(threads) = number(args, "threads") {
+        let compressor = lz77_compressor(args);
+        let mut input = Vec::new();
+        File::open(path)?.read_to_end(&mut input)?;
+        let output = if compress_mode {
+            let compressed = compressor.compress_parallel(&input, threads);
+            report(&parallel_stats(&input, &compressed), verbose);
+            compressed
+        } else {
+            compressor.decompress_parallel(&input)?
+        };
+        return Ok(compression::write_to_new_file(&output, output_path, force)?);
+    }
+
+    if algo == "lz77" && compress_mode {
+        // lz77 is slow enough on big files to be worth a progress readout
+        let mut compressor = lz77_compressor(args);
+        if args.is_present("name") {
+            compressor = compressor.with_original_file(OriginalFile::of(Path::new(path))?);
+        }
+        let mut input = File::open(path)?;
+        let stats = compression::write_atomically(output_path, force, |output| {
+            compressor.compress_with_progress(&mut input, output, |done, total| {
+                eprint!("\rcompressing: {:3}%", done * 100 / total.max(1))
+            })
+        })?;
+        eprintln!();
+        report(&stats, verbose);
+        return Ok(());
+    }
+
+    let algorithms = compression::registry();
+    let compressor = compressor(algo, args);
+
+    let mut file = File::open(path)?;
+
+    if compress_mode {
+        report(
+            &compressor.compress_file(file, output_path, force)?,
+            verbose,
+        );
+        Ok(())
+    } else {
+        check_format(compressor.name(), &algorithms, &mut file)?;
+        if algo == "lz77" && Path::new(output_path).is_dir() {
+            return decompress_into_dir(compressor.as_ref(), file, output_path, force);
+        }
+        compressor.decompress_file(file, output_path, force)
+    }
+}
+
+/// Decompresses an lz77 `file` into the directory `dir`, under the name it was compressed
+/// from and with its modification time, as recorded by `--name`.
+fn decompress_into_dir(
+    compressor: &dyn Algorithm,
+    mut file: File,
+    dir: &str,
+    force: bool,
+) -> Result<()> {
+    let mut start = Vec::new();
+    Read::by_ref(&mut file)
+        .take(lz77::MAX_HEADER_SIZE as u64)
+        .read_to_end(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+    let original_file = lz77::Lz77Compression::original_file(&start)?.ok_or_else(|| {
+        io::Error::other(format!(
+            "{} is a directory and the file was compressed without --name, give an output file",
+            dir
+        ))
+    })?;
+
+    let path = original_file.path_in(Path::new(dir));
+    // fail on a time that can't be restored before writing anything
+    original_file.modified_time()?;
+    compressor.decompress_file(file, path.to_str().unwrap(), force)?;
+    original_file.restore_modified(&path)
+}
+
+/// Prints the sizes, warning when compression made the file larger. `verbose` adds the node
+/// histogram when the algorithm has one.
+fn report(stats: &CompressionStats, verbose: bool) {
+    println!("{}", stats);
+    if let Some(hash) = stats.content_hash_hex() {
+        println!("content hash: {}", hash);
+    }
+    if let (true, Some(histogram)) = (verbose, &stats.histogram) {
+        println!("{}", histogram);
+    }
+    if stats.expanded() {
+        eprintln!(
+            "sloppy-compressor: warning: the compressed file is {} bytes larger than the input",
+            stats.compressed_bytes - stats.original_bytes
+        );
+    }
+}
+
+/// Refuses a file that starts with the magic bytes of an algorithm other than `expected`,
+/// leaving the file positioned back at its start.
+fn check_format(
+    expected: &'static str,
+    algorithms: &BTreeMap<&'static str, compression::Constructor>,
+    file: &mut File,
+) -> Result<()> {
+    let mut start = Vec::new();
+    Read::by_ref(file).take(16).read_to_end(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    match frame::Format::detect(&start) {
+        Some(format) if format.name() != expected && algorithms.contains_key(format.name()) => {
+            Err(DeserialiseError::WrongAlgorithm {
+                expected,
+                found: format.name(),
+            }
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Compresses and decompresses each built-in case with every algorithm, printing a
+/// pass/fail table. Returns false if any case failed to round-trip.
+fn self_test() -> bool {
+    let mut noise_seed: u32 = 1;
+    let noise: Vec<u8> = (0..16 * 1024)
+        .map(|_| {
+            noise_seed = noise_seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (noise_seed >> 16) as u8
+        })
+        .collect();
+    let text = "It was the best of times, it was the worst of times, it was the age of wisdom, \
+        it was the age of foolishness, it was the epoch of belief, it was the epoch of \
+        incredulity, it was the season of Light, it was the season of Darkness."
+        .repeat(20);
+    let cases: [(&str, Vec<u8>); 5] = [
+        ("empty", vec![]),
+        ("single byte", vec![b'x']),
+        ("repetitive", vec![b'a'; 10_000]),
+        ("random", noise),
+        ("text", text.into_bytes()),
+    ];
+
+    let mut all_passed = true;
+    println!(
+        "{:<10} {:<12} {:>8} {:>10}  result",
+        "algorithm", "case", "input", "compressed"
+    );
+    for (name, constructor) in compression::registry() {
+        let compressor = constructor();
+        for (case, input) in cases.iter() {
+            let outcome = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                let compressed = compressor.compress_to_vec(input)?;
+                let decompressed = compressor.decompress_to_vec(&compressed)?;
+                Ok::<_, Error>((compressed.len(), &decompressed == input))
+            }));
+            let (compressed_size, result) = match outcome {
+                Ok(Ok((size, true))) => (size.to_string(), "pass".to_string()),
+                Ok(Ok((size, false))) => (size.to_string(), "FAIL: output differs".to_string()),
+                Ok(Err(err)) => ("-".to_string(), format!("FAIL: {}", err)),
+                Err(_) => ("-".to_string(), "FAIL: panicked".to_string()),
+            };
+            all_passed &= result == "pass";
+            println!(
+                "{:<10} {:<12} {:>8} {:>10}  {}",
+                name,
+                case,
+                input.len(),
+                compressed_size,
+                result
+            );
+        }
+    }
+    all_passed
+}
+
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Io(_)
+        | Error::InvalidFileName(_)
+        | Error::InvalidModifiedTime(_)
+        | Error::RangeOutOfBounds { .. } => 1,
+        Error::Bincode(_)
+        | Error::Deserialise(_)
+        | Error::Utf8(_)
+        | Error::InvalidWindowSize(_)
+        | Error::InvalidStages
+        | Error::OutputTooSmall { .. }
+        | Error::OutputLimitExceeded { .. }
+        | Error::OutOfMemory { .. } => 2,
+    }
+}
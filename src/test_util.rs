@@ -0,0 +1,46 @@
+//! Helpers shared by the unit tests.
+
+/// Bytes from a linear congruential generator seeded with `seed`, the same on every run.
+pub fn pseudo_random(seed: u32) -> impl Iterator<Item = u8> {
+    let mut state = seed;
+    core::iter::repeat_with(move || {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    })
+}
+
+/// The first `len` bytes of `pseudo_random(1)`.
+pub fn pseudo_random_bytes(len: usize) -> alloc::vec::Vec<u8> {
+    pseudo_random(1).take(len).collect()
+}
+
+/// A fresh directory under the system temp directory, removed on drop even when the test panics.
+#[cfg(feature = "std")]
+pub struct ScratchDir(std::path::PathBuf);
+
+#[cfg(feature = "std")]
+impl ScratchDir {
+    /// Creates `sloppy-<name>-<pid>`, emptying whatever an earlier run left there.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("sloppy-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::ops::Deref for ScratchDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,480 @@
+use std::fs::{self, File};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, SystemTime};
+
+fn sloppy_compressor() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_sloppy-compressor"))
+}
+
+/// A fresh directory under the system temp directory, removed on drop even when the test panics.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("sloppy-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn selftest_passes_for_every_algorithm() {
+    let output = sloppy_compressor().arg("selftest").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{}", stdout);
+    assert!(!stdout.contains("FAIL"), "{}", stdout);
+    for algorithm in ["block", "lz77"] {
+        let passed = stdout
+            .lines()
+            .filter(|line| line.starts_with(algorithm) && line.ends_with("pass"))
+            .count();
+        assert_eq!(5, passed, "{}", stdout);
+    }
+}
+
+#[test]
+fn list_prints_every_algorithm() {
+    let output = sloppy_compressor().arg("list").output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        "block\nlz77\npipeline\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+}
+
+#[test]
+fn decompressing_with_the_wrong_algorithm_is_refused() {
+    let dir = ScratchDir::new("cli");
+    let plain = dir.join("plain");
+    let compressed = dir.join("compressed");
+    let decompressed = dir.join("decompressed");
+    fs::write(&plain, b"some text, some text, some text").unwrap();
+
+    let compress = sloppy_compressor()
+        .args(["block", "compress"])
+        .args([&plain, &compressed])
+        .output()
+        .unwrap();
+    assert!(compress.status.success());
+
+    let decompress = sloppy_compressor()
+        .args(["lz77", "decompress"])
+        .args([&compressed, &decompressed])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(decompress.stderr).unwrap();
+
+    assert_eq!(Some(2), decompress.status.code());
+    assert!(
+        stderr.contains("this file was compressed with 'block', not 'lz77'"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn compressing_warns_when_the_output_is_larger() {
+    let dir = ScratchDir::new("cli-warn");
+    let plain = dir.join("plain");
+    fs::write(&plain, b"too short to shrink").unwrap();
+
+    let output = sloppy_compressor()
+        .args(["block", "compress"])
+        .args([&plain, &dir.join("compressed")])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Original size: 19"), "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("warning"), "{}", stderr);
+}
+
+#[test]
+fn verbose_compression_prints_the_node_histogram() {
+    let dir = ScratchDir::new("cli-verbose");
+    let plain = dir.join("plain");
+    fs::write(&plain, b"abcdabcdabcdabcd").unwrap();
+
+    let output = sloppy_compressor()
+        .args(["lz77", "compress"])
+        .args([&plain, &dir.join("compressed")])
+        .arg("--verbose")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // "abcd", then 4 bytes from 4 back and 8 bytes from 8 back
+    assert!(stdout.contains("literals: 4, references: 2"), "{}", stdout);
+    assert!(stdout.contains("offsets: 1-127: 2,"), "{}", stdout);
+    assert!(
+        stdout.contains("lengths: 2-4: 1, 5-7: 0, 8-22: 1,"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn max_output_refuses_files_that_decompress_past_it() {
+    let dir = ScratchDir::new("cli-max-output");
+    let plain = dir.join("plain");
+    let compressed = dir.join("compressed");
+    let decompressed = dir.join("decompressed");
+    fs::write(&plain, vec![b'a'; 100_000]).unwrap();
+
+    let compress = sloppy_compressor()
+        .args(["lz77", "compress"])
+        .args([&plain, &compressed])
+        .output()
+        .unwrap();
+    assert!(compress.status.success());
+
+    let decompress = |limit: &str| {
+        sloppy_compressor()
+            .args(["lz77", "decompress"])
+            .args([&compressed, &decompressed])
+            .args(["--max-output", limit])
+            .output()
+            .unwrap()
+    };
+    let refused = decompress("1000");
+    let refused_output_exists = decompressed.exists();
+    let allowed = decompress("100000");
+    let allowed_output = fs::read(&decompressed).unwrap();
+
+    assert_eq!(Some(2), refused.status.code());
+    let stderr = String::from_utf8(refused.stderr).unwrap();
+    assert!(stderr.contains("1000 byte output limit"), "{}", stderr);
+    assert!(!refused_output_exists);
+
+    assert!(allowed.status.success());
+    assert_eq!(vec![b'a'; 100_000], allowed_output);
+}
+
+#[test]
+fn existing_outputs_are_only_overwritten_with_force() {
+    let dir = ScratchDir::new("cli-force");
+    let plain = dir.join("plain");
+    let compressed = dir.join("compressed");
+    fs::write(&plain, b"some text, some text, some text").unwrap();
+    fs::write(&compressed, b"precious").unwrap();
+
+    let compress = |force: bool| {
+        let mut command = sloppy_compressor();
+        command
+            .args(["lz77", "compress"])
+            .args([&plain, &compressed]);
+        if force {
+            command.arg("--force");
+        }
+        command.output().unwrap()
+    };
+    let refused = compress(false);
+    let after_refusal = fs::read(&compressed).unwrap();
+    let forced = compress(true);
+    let after_force = fs::read(&compressed).unwrap();
+
+    assert_eq!(Some(1), refused.status.code());
+    let stderr = String::from_utf8(refused.stderr).unwrap();
+    assert!(stderr.contains("already exists"), "{}", stderr);
+    assert_eq!(b"precious".to_vec(), after_refusal);
+
+    assert!(forced.status.success());
+    assert!(after_force.starts_with(b"SLZ7"));
+}
+
+#[test]
+fn help_lists_the_subcommands_and_options() {
+    let output = sloppy_compressor().arg("--help").output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for subcommand in ["block", "disasm", "list", "lz77", "pipeline", "selftest"] {
+        assert!(stdout.contains(subcommand), "{}", stdout);
+    }
+
+    let output = sloppy_compressor()
+        .args(["lz77", "--help"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for option in [
+        "--force",
+        "aliases: stats",
+        "--threads",
+        "--window",
+        "--max-output",
+        "--fastest",
+        "--best",
+        "--suffix-matcher",
+        "--name",
+    ] {
+        assert!(stdout.contains(option), "{}", stdout);
+    }
+}
+
+#[test]
+fn bad_arguments_are_usage_errors() {
+    let lz77_only = sloppy_compressor()
+        .args(["block", "compress", "in", "out", "--threads", "2"])
+        .output()
+        .unwrap();
+    let bad_window = sloppy_compressor()
+        .args(["lz77", "compress", "in", "out", "--window", "0"])
+        .output()
+        .unwrap();
+    let unknown_algorithm = sloppy_compressor()
+        .args(["zip", "compress", "in", "out"])
+        .output()
+        .unwrap();
+    let missing_output = sloppy_compressor()
+        .args(["lz77", "compress", "in"])
+        .output()
+        .unwrap();
+
+    for output in [lz77_only, bad_window, unknown_algorithm, missing_output] {
+        assert_eq!(Some(1), output.status.code());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("USAGE") || stderr.contains("65535"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn lz77_options_round_trip() {
+    let dir = ScratchDir::new("cli-options");
+    let plain = dir.join("plain");
+    let mut bytes = b"some text, some text, some text".repeat(50);
+    bytes.extend(vec![0; 1000]);
+    fs::write(&plain, &bytes).unwrap();
+
+    let option_sets: [&[&str]; 11] = [
+        &["--window", "300", "--max-candidates", "8"],
+        &["--profitable-matches"],
+        &["--optimal", "--nice-length", "16"],
+        &["--token-stream"],
+        &["--adaptive-literal-runs"],
+        &["--repeat-offsets", "--sync-interval", "500"],
+        &["--run-length", "64", "--stats"],
+        &["-0"],
+        &["-6", "--token-stream"],
+        &["--best", "--window", "500"],
+        &["--suffix-matcher", "--window", "65535"],
+    ];
+    for (i, options) in option_sets.iter().enumerate() {
+        let compressed = dir.join(format!("compressed-{}", i));
+        let decompressed = dir.join(format!("decompressed-{}", i));
+        let compress = sloppy_compressor()
+            .args(["lz77", "compress"])
+            .args([&plain, &compressed])
+            .args(*options)
+            .output()
+            .unwrap();
+        assert!(compress.status.success(), "{:?}", options);
+        let decompress = sloppy_compressor()
+            .args(["lz77", "decompress"])
+            .args([&compressed, &decompressed])
+            .output()
+            .unwrap();
+        assert!(decompress.status.success(), "{:?}", options);
+        assert_eq!(bytes, fs::read(&decompressed).unwrap(), "{:?}", options);
+    }
+}
+
+#[test]
+fn info_reports_the_format_and_decompressed_size() {
+    let dir = ScratchDir::new("cli-info");
+    let plain = dir.join("plain");
+    fs::write(&plain, b"some text, some text, some text".repeat(10)).unwrap();
+
+    let mut reports = Vec::new();
+    for algorithm in ["block", "lz77"] {
+        let compressed = dir.join(algorithm);
+        let compress = sloppy_compressor()
+            .args([algorithm, "compress"])
+            .args([&plain, &compressed])
+            .output()
+            .unwrap();
+        assert!(compress.status.success());
+        let info = sloppy_compressor()
+            .arg("info")
+            .arg(&compressed)
+            .output()
+            .unwrap();
+        assert!(info.status.success());
+        reports.push(String::from_utf8(info.stdout).unwrap());
+    }
+    let not_compressed = sloppy_compressor()
+        .arg("info")
+        .arg(&plain)
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        "algorithm: block\nformat version: 8\nchecksum: yes\ndecompressed size: 310\n",
+        reports[0]
+    );
+    assert_eq!(
+        "algorithm: lz77\nformat version: 11\nchecksum: no\ndecompressed size: 310\n",
+        reports[1]
+    );
+    assert_eq!(Some(2), not_compressed.status.code());
+}
+
+#[test]
+fn disasm_prints_each_node_and_where_it_starts() {
+    let dir = ScratchDir::new("cli-disasm");
+    let plain = dir.join("plain");
+    let compressed = dir.join("compressed");
+    fs::write(&plain, b"abababab!").unwrap();
+    let compress = sloppy_compressor()
+        .args(["lz77", "compress"])
+        .args([&plain, &compressed])
+        .output()
+        .unwrap();
+    assert!(compress.status.success());
+    let disasm = sloppy_compressor()
+        .arg("disasm")
+        .arg(&compressed)
+        .output()
+        .unwrap();
+    let not_compressed = sloppy_compressor()
+        .arg("disasm")
+        .arg(&plain)
+        .output()
+        .unwrap();
+
+    assert!(disasm.status.success());
+    assert_eq!(
+        "@72: Literal 'a'\n\
+         @81: Literal 'b'\n\
+         @90: Reference { offset: 2, length: 2 }\n\
+         @101: Reference { offset: 4, length: 4 }\n\
+         @112: Literal '!'\n",
+        String::from_utf8(disasm.stdout).unwrap()
+    );
+    assert_eq!(Some(2), not_compressed.status.code());
+}
+
+#[test]
+fn benchmark_prints_sizes_and_speed_without_writing() {
+    let dir = ScratchDir::new("cli-bench");
+    let plain = dir.join("plain");
+    fs::write(&plain, b"benchmark this, benchmark this. ".repeat(100)).unwrap();
+
+    for args in [&["lz77"][..], &["block"], &["lz77", "--threads", "2"]] {
+        let output = sloppy_compressor()
+            .arg(args[0])
+            .arg("benchmark")
+            .arg(&plain)
+            .args(&args[1..])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(output.status.success(), "{:?}: {}", args, stdout);
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(3, lines.len(), "{}", stdout);
+        assert!(
+            lines[0].starts_with("Original size: 3200, compressed size: "),
+            "{}",
+            stdout
+        );
+        assert!(lines[0].contains(", ratio: "), "{}", stdout);
+        assert!(
+            lines[1].starts_with("time: ") && lines[1].ends_with('s'),
+            "{}",
+            stdout
+        );
+        assert!(
+            lines[2].starts_with("throughput: ") && lines[2].ends_with(" MB/s"),
+            "{}",
+            stdout
+        );
+    }
+    assert_eq!(1, dir.read_dir().unwrap().count());
+
+    // the other modes still need an output
+    let missing_output = sloppy_compressor()
+        .args(["lz77", "compress", "input"])
+        .output()
+        .unwrap();
+    assert_eq!(Some(1), missing_output.status.code());
+}
+
+#[test]
+fn name_restores_the_file_name_and_time_into_a_directory() {
+    let dir = ScratchDir::new("cli-name");
+    let restored = dir.join("restored");
+    fs::create_dir_all(&restored).unwrap();
+    let plain = dir.join("notes.txt");
+    let compressed = dir.join("notes.slz");
+    fs::write(&plain, b"some text, some text, some text").unwrap();
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    File::options()
+        .write(true)
+        .open(&plain)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    let compress = sloppy_compressor()
+        .args(["lz77", "compress"])
+        .args([&plain, &compressed])
+        .arg("--name")
+        .output()
+        .unwrap();
+    assert!(compress.status.success());
+    let decompress = sloppy_compressor()
+        .args(["lz77", "decompress"])
+        .args([&compressed, &restored])
+        .output()
+        .unwrap();
+    assert!(decompress.status.success());
+    let restored_file = restored.join("notes.txt");
+    let restored_bytes = fs::read(&restored_file).unwrap();
+    let restored_modified = fs::metadata(&restored_file).unwrap().modified().unwrap();
+
+    // without a recorded name there's nothing to call the file
+    let unnamed = dir.join("unnamed.slz");
+    let compress = sloppy_compressor()
+        .args(["lz77", "compress"])
+        .args([&plain, &unnamed])
+        .output()
+        .unwrap();
+    assert!(compress.status.success());
+    let decompress_unnamed = sloppy_compressor()
+        .args(["lz77", "decompress"])
+        .args([&unnamed, &restored])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(decompress_unnamed.stderr).unwrap();
+
+    assert_eq!(b"some text, some text, some text".to_vec(), restored_bytes);
+    assert_eq!(modified, restored_modified);
+    assert_eq!(Some(1), decompress_unnamed.status.code());
+    assert!(stderr.contains("without --name"), "{}", stderr);
+}
@@ -0,0 +1,164 @@
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use sloppycomp::compression::{self, Algorithm};
+use sloppycomp::lz77::nodes::NodeType;
+use sloppycomp::lz77::Lz77Compression;
+
+const INPUTS: [&[u8]; 3] = [b"", b"x", b"xy"];
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sloppy-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn tiny_inputs_round_trip_in_memory() {
+    for (name, constructor) in compression::registry() {
+        let compressor = constructor();
+        for input in INPUTS {
+            let compressed = compressor.compress_to_vec(input).unwrap();
+            assert_eq!(
+                input,
+                &compressor.decompress_to_vec(&compressed).unwrap()[..],
+                "{} with {:?}",
+                name,
+                input
+            );
+        }
+    }
+}
+
+#[test]
+fn tiny_inputs_round_trip_through_files() {
+    let dir = scratch_dir("degenerate-files");
+    for (name, constructor) in compression::registry() {
+        let compressor = constructor();
+        for (i, input) in INPUTS.iter().enumerate() {
+            let plain = dir.join(format!("{}-{}", name, i));
+            let compressed = dir.join(format!("{}-{}.compressed", name, i));
+            let decompressed = dir.join(format!("{}-{}.decompressed", name, i));
+            fs::write(&plain, input).unwrap();
+
+            let stats = compressor
+                .compress_file(
+                    File::open(&plain).unwrap(),
+                    compressed.to_str().unwrap(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(input.len(), stats.original_bytes);
+            assert_eq!(
+                fs::metadata(&compressed).unwrap().len() as usize,
+                stats.compressed_bytes
+            );
+            compressor
+                .decompress_file(
+                    File::open(&compressed).unwrap(),
+                    decompressed.to_str().unwrap(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(
+                *input,
+                &fs::read(&decompressed).unwrap()[..],
+                "{} with {:?}",
+                name,
+                input
+            );
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn lz77_encodes_tiny_inputs_as_nothing_or_a_literal() {
+    let compressor = Lz77Compression::new();
+    assert_eq!(None, compressor.nodes(b"").next());
+    assert_eq!(
+        vec![NodeType::literal(b'x')],
+        compressor.nodes(b"x").collect::<Vec<_>>()
+    );
+
+    // no nodes at all is stored as just the header
+    let empty = compressor.encode(b"");
+    let info = compression::inspect(&mut &empty[..]).unwrap();
+    assert_eq!(Some(0), info.original_len);
+    assert_eq!(empty, compressor.compress_to_vec(b"").unwrap());
+    assert!(empty.len() < 16, "{:?}", empty);
+
+    // and the other node encodings decode back to nothing too
+    for compressor in [
+        Lz77Compression::new().literals_only(),
+        Lz77Compression::new().with_token_stream(),
+        Lz77Compression::new().with_optimal_parse(),
+    ] {
+        for input in INPUTS {
+            assert_eq!(
+                input,
+                &compressor.decode(&compressor.encode(input)).unwrap()[..]
+            );
+        }
+    }
+}
+
+#[test]
+fn tiny_inputs_survive_parallel_compression() {
+    let compressor = Lz77Compression::new();
+    for input in INPUTS {
+        for threads in [1, 4] {
+            let compressed = compressor.compress_parallel(input, threads);
+            assert_eq!(
+                input,
+                &compressor.decompress_parallel(&compressed).unwrap()[..]
+            );
+        }
+    }
+}
+
+#[test]
+fn empty_files_survive_archives_and_the_cli() {
+    let dir = scratch_dir("degenerate-archive");
+    let empty = dir.join("empty");
+    let single = dir.join("single");
+    fs::write(&empty, b"").unwrap();
+    fs::write(&single, b"x").unwrap();
+
+    let compressor = Lz77Compression::new();
+    let mut archive = Vec::new();
+    compressor
+        .compress_files(&[empty.clone(), single.clone()], &mut archive)
+        .unwrap();
+    let extracted = dir.join("extracted");
+    fs::create_dir_all(&extracted).unwrap();
+    compressor
+        .decompress_files(&mut &archive[..], &extracted)
+        .unwrap();
+    let extracted_empty = fs::read(extracted.join("empty")).unwrap();
+    let extracted_single = fs::read(extracted.join("single")).unwrap();
+
+    let mut cli_outputs = Vec::new();
+    for algorithm in ["block", "lz77"] {
+        let compressed = dir.join(format!("{}.compressed", algorithm));
+        let decompressed = dir.join(format!("{}.decompressed", algorithm));
+        for (mode, from, to) in [
+            ("compress", &empty, &compressed),
+            ("decompress", &compressed, &decompressed),
+        ] {
+            let status = std::process::Command::new(env!("CARGO_BIN_EXE_sloppy-compressor"))
+                .args([algorithm, mode])
+                .args([from, to])
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "{} {}", algorithm, mode);
+        }
+        cli_outputs.push(fs::read(&decompressed).unwrap());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(extracted_empty.is_empty());
+    assert_eq!(b"x".to_vec(), extracted_single);
+    assert!(cli_outputs.iter().all(Vec::is_empty));
+}
@@ -1,24 +1,40 @@
 use std::fs::File;
-use std::io::prelude::*;
 use std::path::PathBuf;
 
 use sloppycomp::compression::Algorithm;
 use sloppycomp::lz77;
 
+/// compressed size / original size for the bench plaintext at the time of the last
+/// optimisation. Lower it when the compression improves.
+const BASELINE_RATIO: f64 = 0.4834;
+
 #[test]
-fn test_compression_size() {
+fn test_compression_ratio() {
     // test exists so we can monitor and commit changes in optimisations to the
     // compression - slow in debug mode, so run with `cargo test --release`.
     let mut input_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     input_file.push("benches/test-files/sloppy-compressor-bench-plaintext");
+    let original_size = std::fs::metadata(&input_file).unwrap().len();
     let mut file = File::open(input_file).unwrap();
 
-    let compressor = lz77::Lz77Compression {};
-    compressor.compress(file, "/tmp/sloppycomp-ratio-test");
-
-    let compressed_size = std::fs::metadata("/tmp/sloppycomp-ratio-test")
-        .unwrap()
-        .len();
+    // compress into memory so a failed run can't leave a stale output file to measure
+    let compressor = lz77::Lz77Compression::new();
+    let mut compressed = Vec::new();
+    compressor.compress(&mut file, &mut compressed).unwrap();
 
-    assert_eq!(16143356, compressed_size);
+    let ratio = compressed.len() as f64 / original_size as f64;
+    println!(
+        "compressed {} bytes to {} bytes, ratio {:.4} (baseline {:.4})",
+        original_size,
+        compressed.len(),
+        ratio,
+        BASELINE_RATIO
+    );
+    assert!(!compressed.is_empty());
+    assert!(
+        ratio <= BASELINE_RATIO + 0.0001,
+        "compression ratio regressed from {:.4} to {:.4}",
+        BASELINE_RATIO,
+        ratio
+    );
 }
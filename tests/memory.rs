@@ -0,0 +1,272 @@
+//! Measures the heap used while compressing. Kept in its own test binary so only these tests
+//! allocate, and each holds `MEASURING` so they don't overlap.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use sloppycomp::block_compress::BlockCompression;
+use sloppycomp::compression::Algorithm;
+use sloppycomp::lz77::Lz77Compression;
+
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static MEASURING: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(current, Ordering::SeqCst);
+        TOTAL.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// The most extra heap `run` had allocated at any one time.
+fn peak_heap(run: impl FnOnce()) -> usize {
+    let before = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    run();
+    PEAK.load(Ordering::SeqCst) - before
+}
+
+/// The bytes `run` allocated in all, including any it freed again.
+fn total_allocated(run: impl FnOnce()) -> usize {
+    let before = TOTAL.load(Ordering::SeqCst);
+    run();
+    TOTAL.load(Ordering::SeqCst) - before
+}
+
+const INPUT_SIZE: usize = 8 << 20;
+
+/// `INPUT_SIZE` bytes of noise, generated as they're read, in which every eighth 128 byte
+/// block repeats one a little earlier.
+struct Synthetic {
+    state: u64,
+    produced: usize,
+    recent: Vec<u8>,
+}
+
+impl Synthetic {
+    fn new() -> Self {
+        Synthetic {
+            state: 0x9e37_79b9_7f4a_7c15,
+            produced: 0,
+            recent: Vec::with_capacity(1024),
+        }
+    }
+}
+
+impl Read for Synthetic {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() && self.produced < INPUT_SIZE {
+            let block = self.produced / 128;
+            buf[n] = if block % 8 == 7 {
+                self.recent[self.produced % 128]
+            } else {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 7;
+                self.state ^= self.state << 17;
+                self.state as u8
+            };
+            if self.produced % 1024 < 128 {
+                self.recent.truncate(self.produced % 128);
+                self.recent.push(buf[n]);
+            }
+            self.produced += 1;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[test]
+fn streaming_block_compression_takes_bounded_memory() {
+    let _measuring = MEASURING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let compressor = BlockCompression::new()
+        .with_streaming()
+        .with_max_retained_blocks(1024);
+
+    let compressing = peak_heap(|| {
+        compressor
+            .compress(&mut Synthetic::new(), &mut io::sink())
+            .unwrap();
+    });
+    assert!(
+        compressing < 1 << 20,
+        "compressing took {} bytes",
+        compressing
+    );
+
+    let mut compressed = Vec::new();
+    let stats = compressor
+        .compress(&mut Synthetic::new(), &mut compressed)
+        .unwrap();
+    assert_eq!(INPUT_SIZE, stats.original_bytes);
+    assert_eq!(compressed.len(), stats.compressed_bytes);
+    // the repeated blocks were found
+    assert!(compressed.len() < INPUT_SIZE);
+
+    let mut decompressed = 0;
+    let decompressing = peak_heap(|| {
+        decompressed = compressor.decompressed_size(&mut &compressed[..]).unwrap();
+    });
+    assert_eq!(INPUT_SIZE as u64, decompressed);
+    assert!(
+        decompressing < 1 << 20,
+        "decompressing took {} bytes",
+        decompressing
+    );
+
+    // collecting the blocks before writing takes more than the whole input
+    let buffered = peak_heap(|| {
+        BlockCompression::new()
+            .with_max_retained_blocks(1024)
+            .compress(&mut Synthetic::new(), &mut io::sink())
+            .unwrap();
+    });
+    assert!(buffered > INPUT_SIZE, "buffering took {} bytes", buffered);
+}
+
+#[test]
+fn lz77_compress_writes_the_body_without_copying_it() {
+    let _measuring = MEASURING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut input = vec![0; 1 << 20];
+    Synthetic::new().read_exact(&mut input).unwrap();
+    let input: Vec<u8> = input.iter().map(|byte| b'a' + byte % 4).collect();
+    let compressor = Lz77Compression::new();
+    let encoded_len = compressor.encode(&input).len();
+
+    let encoding = total_allocated(|| {
+        compressor.encode(&input);
+    });
+    let compressing = total_allocated(|| {
+        compressor
+            .compress(&mut &input[..], &mut io::sink())
+            .unwrap();
+    });
+    // compress reads the input into a buffer of its own, but shouldn't copy the encoded body
+    // in after the header as `encode` has to
+    assert!(
+        compressing + encoded_len / 2 < encoding + input.len(),
+        "compressing allocated {} bytes, encoding {}",
+        compressing,
+        encoding
+    );
+}
+
+#[test]
+fn lz77_compresses_from_a_reader_a_chunk_at_a_time() {
+    let _measuring = MEASURING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut input = vec![0; INPUT_SIZE];
+    Synthetic::new().read_exact(&mut input).unwrap();
+    let path = std::env::temp_dir().join(format!("sloppy-memory-{}.lz", process::id()));
+    let compressor = Lz77Compression::new();
+    let compress = |input: &[u8]| {
+        compressor
+            .compress_from_reader(&mut Cursor::new(input), path.to_str().unwrap(), true)
+            .unwrap();
+    };
+
+    let two_chunks = peak_heap(|| compress(&input[..2 << 20]));
+    let streaming = peak_heap(|| compress(&input));
+    let compressed = fs::read(&path);
+    fs::remove_file(&path).unwrap();
+    // a chunk's nodes and match tables take a few times the chunk, but no more for a longer
+    // input, where compressing it in one go takes over ten times the input
+    assert!(
+        streaming < two_chunks + (64 << 10),
+        "streaming took {} bytes, {} for two chunks",
+        streaming,
+        two_chunks
+    );
+    assert!(
+        streaming < 2 * INPUT_SIZE,
+        "streaming took {} bytes",
+        streaming
+    );
+    assert_eq!(
+        input,
+        compressor
+            .decompress_concatenated(&compressed.unwrap())
+            .unwrap()
+    );
+}
+
+#[test]
+fn lz77_reuses_its_match_tables_across_inputs() {
+    let _measuring = MEASURING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let payloads: Vec<Vec<u8>> = (0..8)
+        .map(|i| format!("payload {} says hello, hello, hello", i).into_bytes())
+        .collect();
+    let fresh = total_allocated(|| {
+        for payload in &payloads {
+            Lz77Compression::new().compress_to_vec(payload).unwrap();
+        }
+    });
+    let compressor = Lz77Compression::new();
+    compressor.compress_to_vec(&payloads[0]).unwrap();
+    let reused = total_allocated(|| {
+        for payload in &payloads {
+            compressor.compress_to_vec(payload).unwrap();
+        }
+    });
+    // the last pair table alone is 64K entries
+    assert!(
+        reused + payloads.len() * (64 << 10) < fresh,
+        "one compressor allocated {} bytes, a compressor each {}",
+        reused,
+        fresh
+    );
+
+    let outputs: Vec<Vec<u8>> = payloads
+        .iter()
+        .map(|payload| compressor.compress_to_vec(payload).unwrap())
+        .collect();
+    for (payload, output) in payloads.iter().zip(&outputs) {
+        assert_eq!(
+            &Lz77Compression::new().compress_to_vec(payload).unwrap(),
+            output
+        );
+    }
+}
+
+#[test]
+fn lz77_decodes_nodes_into_a_single_allocation() {
+    let _measuring = MEASURING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let input = b"decoded into one buffer, sized from the nodes. ".repeat(20_000);
+    let compressor = Lz77Compression::new();
+    let nodes: Vec<_> = compressor.nodes(&input).collect();
+
+    let mut decoded = Vec::new();
+    let allocated = total_allocated(|| {
+        decoded = compressor.decode_nodes_to_vec(&nodes).unwrap();
+    });
+    assert_eq!(input, decoded);
+    assert_eq!(input.len(), allocated);
+}
@@ -0,0 +1,83 @@
+//! Round-trips the crate's own source and benchmark files through every algorithm, as a broad
+//! regression guard on real inputs of all sizes.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sloppycomp::compression;
+use sloppycomp::lz77::Lz77Compression;
+
+/// Bytes read in all, so the test stays quick in debug builds. The large benchmark fixture
+/// comes last and only contributes what's left.
+const TOTAL_SIZE_CAP: u64 = 512 << 10;
+
+/// Every regular file under `dir`, sorted by path.
+fn regular_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect();
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let file_type = entry.file_type().unwrap();
+        if file_type.is_dir() {
+            regular_files(&entry.path(), files);
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+}
+
+#[test]
+fn repo_files_round_trip_through_every_algorithm() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let mut files = Vec::new();
+    regular_files(&root.join("src"), &mut files);
+    regular_files(&root.join("benches"), &mut files);
+
+    let mut remaining = TOTAL_SIZE_CAP;
+    let mut inputs = Vec::new();
+    for path in files {
+        let mut bytes = Vec::new();
+        File::open(&path)
+            .unwrap()
+            .take(remaining)
+            .read_to_end(&mut bytes)
+            .unwrap();
+        remaining -= bytes.len() as u64;
+        inputs.push((path, bytes));
+    }
+    assert!(inputs.len() > 10, "only found {} files", inputs.len());
+
+    for (name, constructor) in compression::registry() {
+        let compressor = constructor();
+        for (path, bytes) in &inputs {
+            let compressed = compressor.compress_to_vec(bytes).unwrap();
+            assert_eq!(
+                bytes,
+                &compressor.decompress_to_vec(&compressed).unwrap(),
+                "{} on {}",
+                name,
+                path.display()
+            );
+        }
+    }
+
+    // and through the settings that change how lz77 nodes are found and written
+    for compressor in [
+        Lz77Compression::new().with_token_stream(),
+        Lz77Compression::new().with_repeat_offsets(),
+        Lz77Compression::new().with_run_length_encoding(16),
+    ] {
+        for (path, bytes) in &inputs {
+            let encoded = compressor.encode(bytes);
+            assert_eq!(
+                bytes,
+                &compressor.decode(&encoded).unwrap(),
+                "{}",
+                path.display()
+            );
+        }
+    }
+}